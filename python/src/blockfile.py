@@ -1,7 +1,16 @@
+import os
 from io import BytesIO
+from typing import Any, Dict
 from p2p_framework.object import CBlock
 from config import ConfigType
 
+# A block store position is the segment number above the low 40 bits and the offset in the segment
+OFFSET_BITS = 40
+# Each block in a segment is preceded by a magic, length and checksum
+FRAME_HEADER_SIZE = 12
+# The position of a block whose body has been pruned from the block store
+NOT_STORED = (1 << 64) - 1
+
 
 # Blockheaders
 def load_block_at_offset(fname: str, offset: int) -> CBlock:
@@ -22,18 +31,35 @@ def load_block_at_offset(fname: str, offset: int) -> CBlock:
     return block
 
 
+def block_store_dir(settings: Dict[str, Any]) -> str:
+    """ The block store directory, by default the block_file path with a .blocks extension
+    """
+    if settings.get("block_store"):
+        return settings["block_store"]
+    return os.path.splitext(settings["block_file"])[0] + ".blocks"
+
+
+def load_block_at_position(store_dir: str, position: int) -> CBlock:
+    """ Load the block at a position in the block store
+    """
+    segment = position >> OFFSET_BITS
+    offset = position & ((1 << OFFSET_BITS) - 1)
+    fname = os.path.join(store_dir, f"blk{segment:05}.dat")
+    return load_block_at_offset(fname, offset + FRAME_HEADER_SIZE)
+
+
 class BlockFile:
-    """ Used to provide an interface to the block file
+    """ Used to provide an interface to the block store
     """
     def __init__(self):
-        self.block_file: str
+        self.store_dir: str
 
     def set_config(self, config: ConfigType):
         network = config['service']['network']
-        self.block_file = config[network]["block_file"]
+        self.store_dir = block_store_dir(config[network])
 
-    def load_at_offset(self, offset: int) -> CBlock:
-        return load_block_at_offset(self.block_file, offset)
+    def load_at_position(self, position: int) -> CBlock:
+        return load_block_at_position(self.store_dir, position)
 
 
 blockfile = BlockFile()
@@ -91,8 +91,9 @@ class Collection:
     def get_collection_contents(self, monitor_name: str) -> List[Any]:
         """ Return the collection hashes associated with this collection name """
         assert self.is_valid_collection(monitor_name)
+        # Tenant monitors are stored with their owner, the operator's with none
         return database.query(
-            "SELECT hash FROM collection WHERE name = %s;",
+            "SELECT hash FROM collection WHERE owner = '' AND name = %s;",
             (monitor_name,),
         )
 
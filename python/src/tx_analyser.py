@@ -2,7 +2,7 @@ import datetime
 from typing import List, Dict, Any, Optional
 
 from database import database
-from blockfile import blockfile
+from blockfile import blockfile, NOT_STORED
 from merkle import create_merkle_branch
 from p2p_framework.object import CTransaction
 from config import ConfigType
@@ -168,22 +168,22 @@ class TxAnalyser:
         """
         # Get tx
         offset = self._read_block_offset(hash)
-        if offset is None:
+        if offset is None or offset == NOT_STORED:
             return {
                 "tx": f"Transaction {hash} not found in block"
             }
-        block = blockfile.load_at_offset(offset)
+        block = blockfile.load_at_position(offset)
         tx = list(filter(lambda x: x.hash == hash, block.vtx))[0]
         return self.decode_tx(hash, tx)
 
     def get_tx_raw_entry(self, hash: str) -> Dict[str, Any]:
         """ return the serialised form of the transaction """
         offset = self._read_block_offset(hash)
-        if offset is None:
+        if offset is None or offset == NOT_STORED:
             return {
                 "tx": f"Transaction {hash} not found in block"
             }
-        block = blockfile.load_at_offset(offset)
+        block = blockfile.load_at_position(offset)
         tx = list(filter(lambda x: x.hash == hash, block.vtx))[0]
         b = tx.serialize()
         return {
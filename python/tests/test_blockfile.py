@@ -1,22 +1,25 @@
 import os
 import tempfile
 
-from blockfile import load_block_at_offset
+from blockfile import block_store_dir, load_block_at_offset, load_block_at_position
 from p2p_framework.hash import hash256
 
 
+def synthetic_block() -> bytes:
+    # Minimal synthetic block: header fields + varint(0) tx count
+    version = (1).to_bytes(4, "little")
+    prev_hash = b"\x01" * 32
+    merkle_root = hash256(b"merkle-test")
+    timestamp = (1_700_000_000).to_bytes(4, "little")
+    bits = (0x1D00FFFF).to_bytes(4, "little")
+    nonce = (0).to_bytes(4, "little")
+    tx_count = b"\x00"
+    return version + prev_hash + merkle_root + timestamp + bits + nonce + tx_count
+
+
 class TestBlockFileRequirements:
     def test_data04_block_offset_locates_serialized_block(self) -> None:
-        # Minimal synthetic block: header fields + varint(0) tx count
-        version = (1).to_bytes(4, "little")
-        prev_hash = b"\x01" * 32
-        merkle_root = hash256(b"merkle-test")
-        timestamp = (1_700_000_000).to_bytes(4, "little")
-        bits = (0x1D00FFFF).to_bytes(4, "little")
-        nonce = (0).to_bytes(4, "little")
-        tx_count = b"\x00"
-        block_bytes = version + prev_hash + merkle_root + timestamp + bits + nonce + tx_count
-
+        block_bytes = synthetic_block()
         with tempfile.NamedTemporaryFile(delete=False) as handle:
             handle.write(block_bytes)
             temp_path = handle.name
@@ -27,3 +30,19 @@ class TestBlockFileRequirements:
             assert len(block.vtx) == 0
         finally:
             os.remove(temp_path)
+
+    def test_block_store_position_locates_framed_block(self) -> None:
+        block_bytes = synthetic_block()
+        frame = b"UBLK" + len(block_bytes).to_bytes(4, "little") + hash256(block_bytes)[:4] + block_bytes
+
+        with tempfile.TemporaryDirectory() as store_dir:
+            # Second block of segment 1
+            with open(os.path.join(store_dir, "blk00001.dat"), "wb") as handle:
+                handle.write(frame + frame)
+            block = load_block_at_position(store_dir, (1 << 40) + len(frame))
+            assert block.hash is not None
+            assert len(block.vtx) == 0
+
+    def test_block_store_defaults_next_to_block_file(self) -> None:
+        assert block_store_dir({"block_file": "../data/test-net.dat"}) == "../data/test-net.blocks"
+        assert block_store_dir({"block_file": "../data/test-net.dat", "block_store": "/blocks"}) == "/blocks"
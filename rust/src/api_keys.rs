@@ -0,0 +1,361 @@
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chain_gang::util::sha256d;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ConfigApiKey, tenant::Scope};
+
+// Keys are handed out as uaas_<id>_<secret>, the id is used to find the stored hash
+const KEY_PREFIX: &str = "uaas";
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn random_hex<const N: usize>() -> String {
+    hex::encode(rand::random::<[u8; N]>())
+}
+
+pub fn hash_secret(salt: &str, secret: &str) -> String {
+    let mut data = salt.as_bytes().to_vec();
+    data.extend_from_slice(secret.as_bytes());
+    hex::encode(sha256d(&data).0)
+}
+
+// A new salt and the hash of the key with it, for the api_key_salt and api_key_hash config fields
+pub fn hash_config_key(secret: &str) -> (String, String) {
+    let salt = random_hex::<16>();
+    let hash = hash_secret(&salt, secret);
+    (salt, hash)
+}
+
+// Compare without returning early, so the time taken does not leak the position of a mismatch
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+// Details of a key that are safe to return from the admin endpoints
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub scopes: Vec<Scope>,
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+// A newly created or rotated key, the only time the secret is available
+#[derive(Debug, Clone, Serialize)]
+pub struct NewApiKey {
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+    pub api_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiKeyRecord {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub scopes: Vec<Scope>,
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    salt: String,
+    hash: String,
+}
+
+impl ApiKeyRecord {
+    fn new(id: &str, tenant: Option<String>, scopes: Vec<Scope>) -> Self {
+        ApiKeyRecord {
+            id: id.to_string(),
+            tenant,
+            scopes,
+            created_at: unix_time(),
+            expires_at: None,
+            salt: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    // Hash a key that was provided in plain text, for example from the config file
+    pub fn from_secret(id: &str, tenant: Option<String>, scopes: Vec<Scope>, secret: &str) -> Self {
+        let mut record = ApiKeyRecord::new(id, tenant, scopes);
+        record.salt = random_hex::<16>();
+        record.hash = hash_secret(&record.salt, secret);
+        record
+    }
+
+    // A key from the config file, which may already be hashed
+    pub fn from_config(
+        id: &str,
+        tenant: Option<String>,
+        scopes: Vec<Scope>,
+        key: &ConfigApiKey,
+    ) -> Self {
+        match key {
+            ConfigApiKey::Plain(secret) => ApiKeyRecord::from_secret(id, tenant, scopes, secret),
+            ConfigApiKey::Hashed { salt, hash } => {
+                let mut record = ApiKeyRecord::new(id, tenant, scopes);
+                record.salt = salt.clone();
+                record.hash = hash.clone();
+                record
+            }
+        }
+    }
+
+    pub fn matches(&self, secret: &str) -> bool {
+        constant_time_eq(
+            hash_secret(&self.salt, secret).as_bytes(),
+            self.hash.as_bytes(),
+        )
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn info(&self) -> ApiKeyInfo {
+        ApiKeyInfo {
+            id: self.id.clone(),
+            tenant: self.tenant.clone(),
+            scopes: self.scopes.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+        }
+    }
+
+    // Replace the secret, returning the new key
+    fn issue_secret(&mut self) -> NewApiKey {
+        let secret = random_hex::<32>();
+        self.salt = random_hex::<16>();
+        self.hash = hash_secret(&self.salt, &secret);
+        NewApiKey {
+            info: self.info(),
+            api_key: format!("{KEY_PREFIX}_{}_{secret}", self.id),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ApiKeysFile {
+    #[serde(default)]
+    key: Vec<ApiKeyRecord>,
+}
+
+/// Hashed API keys created through the admin endpoints, persisted to a file.
+pub struct ApiKeyStore {
+    // Empty if the keys are only held in memory
+    filename: String,
+    keys: Vec<ApiKeyRecord>,
+}
+
+impl ApiKeyStore {
+    pub fn new(filename: &str) -> Result<Self, String> {
+        if filename.is_empty() {
+            return Ok(ApiKeyStore::in_memory());
+        }
+        let keys = match std::fs::read_to_string(filename) {
+            Ok(content) => {
+                toml::from_str::<ApiKeysFile>(&content)
+                    .map_err(|err| format!("error parsing api key file {filename}: {err}"))?
+                    .key
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(format!("error reading api key file {filename}: {err}")),
+        };
+        Ok(ApiKeyStore {
+            filename: filename.to_string(),
+            keys,
+        })
+    }
+
+    pub fn in_memory() -> Self {
+        ApiKeyStore {
+            filename: String::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn list(&self) -> Vec<ApiKeyInfo> {
+        self.keys.iter().map(ApiKeyRecord::info).collect()
+    }
+
+    // Return the record for a key in the uaas_<id>_<secret> form, if it is valid
+    pub fn verify(&self, provided: &str, now: u64) -> Option<&ApiKeyRecord> {
+        let (id, secret) = provided
+            .strip_prefix(KEY_PREFIX)?
+            .strip_prefix('_')?
+            .split_once('_')?;
+        self.keys
+            .iter()
+            .find(|record| record.id == id)
+            .filter(|record| record.matches(secret) && !record.is_expired(now))
+    }
+
+    pub fn create(
+        &mut self,
+        tenant: Option<String>,
+        scopes: Vec<Scope>,
+        expires_at: Option<u64>,
+    ) -> Result<NewApiKey, String> {
+        let mut record = ApiKeyRecord::new(&random_hex::<8>(), tenant, scopes);
+        record.expires_at = expires_at;
+        let key = record.issue_secret();
+        self.keys.push(record);
+        if let Err(err) = self.save() {
+            self.keys.pop();
+            return Err(err);
+        }
+        Ok(key)
+    }
+
+    // Return false if there is no key with this id
+    pub fn revoke(&mut self, id: &str) -> Result<bool, String> {
+        let Some(index) = self.keys.iter().position(|record| record.id == id) else {
+            return Ok(false);
+        };
+        let record = self.keys.remove(index);
+        if let Err(err) = self.save() {
+            self.keys.insert(index, record);
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    // Issue a new secret for an existing key, the old secret stops working immediately
+    pub fn rotate(&mut self, id: &str) -> Result<Option<NewApiKey>, String> {
+        let Some(index) = self.keys.iter().position(|record| record.id == id) else {
+            return Ok(None);
+        };
+        let previous = self.keys[index].clone();
+        let key = self.keys[index].issue_secret();
+        if let Err(err) = self.save() {
+            self.keys[index] = previous;
+            return Err(err);
+        }
+        Ok(Some(key))
+    }
+
+    fn save(&self) -> Result<(), String> {
+        if self.filename.is_empty() {
+            return Ok(());
+        }
+        let file = ApiKeysFile {
+            key: self.keys.clone(),
+        };
+        let content = toml::to_string(&file).map_err(|err| err.to_string())?;
+        write_private(&self.filename, content.as_bytes()).map_err(|err| {
+            log::error!("Unable to save api keys to {}: {err:?}", self.filename);
+            format!("Unable to save api keys: {err}")
+        })
+    }
+}
+
+// Write to a temporary file only the owner can read, then rename it over the original,
+// so a crash part way through does not leave a truncated key file
+fn write_private(filename: &str, content: &[u8]) -> io::Result<()> {
+    let tmp = format!("{filename}.tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn created_key_verifies_until_rotated() {
+        let mut store = ApiKeyStore::in_memory();
+        let key = store
+            .create(Some("team-a".to_string()), vec![Scope::Read], None)
+            .expect("create key");
+        let record = store.verify(&key.api_key, unix_time()).expect("valid key");
+        assert_eq!(record.tenant.as_deref(), Some("team-a"));
+        assert!(store.verify("uaas_unknown_secret", unix_time()).is_none());
+
+        let rotated = store
+            .rotate(&key.info.id)
+            .expect("rotate key")
+            .expect("key exists");
+        assert_eq!(rotated.info.id, key.info.id);
+        assert!(store.verify(&key.api_key, unix_time()).is_none());
+        assert!(store.verify(&rotated.api_key, unix_time()).is_some());
+
+        assert_eq!(store.revoke(&key.info.id), Ok(true));
+        assert!(store.verify(&rotated.api_key, unix_time()).is_none());
+        assert_eq!(store.revoke(&key.info.id), Ok(false));
+    }
+
+    #[test]
+    fn expired_key_is_rejected() {
+        let mut store = ApiKeyStore::in_memory();
+        let key = store
+            .create(None, vec![Scope::Read], Some(1_000))
+            .expect("create key");
+        assert!(store.verify(&key.api_key, 999).is_some());
+        assert!(store.verify(&key.api_key, 1_000).is_none());
+    }
+
+    #[test]
+    fn store_persists_hashes_only() {
+        let path = std::env::temp_dir().join(format!("uaas_api_keys_{}.toml", std::process::id()));
+        let filename = path.to_str().expect("temp path");
+        let mut store = ApiKeyStore::new(filename).expect("empty store");
+        let key = store
+            .create(None, vec![Scope::Admin], None)
+            .expect("create key");
+
+        let content = std::fs::read_to_string(&path).expect("read key file");
+        assert!(!content.contains(key.api_key.rsplit('_').next().expect("secret")));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path)
+                .expect("key file")
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(!std::path::Path::new(&format!("{filename}.tmp")).exists());
+
+        let reloaded = ApiKeyStore::new(filename).expect("reload store");
+        assert!(reloaded.verify(&key.api_key, unix_time()).is_some());
+        std::fs::remove_file(&path).expect("remove key file");
+    }
+
+    #[test]
+    fn constant_time_eq_compares_contents() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}
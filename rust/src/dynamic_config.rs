@@ -1,4 +1,7 @@
-use crate::config::{CollectionConfig, Config};
+use crate::{
+    config::{CollectionConfig, Config},
+    uaas::monitor::MonitorKey,
+};
 use serde::{Deserialize, Serialize};
 use std::io;
 
@@ -57,9 +60,27 @@ impl DynamicConfig {
         self.save();
     }
 
-    pub fn delete(&mut self, name: &str) {
-        if let Some(index) = self.collection.iter().position(|c| c.name == name) {
-            log::info!("delete monitor {}", name);
+    pub fn update(&mut self, monitor: &CollectionConfig) {
+        let key = MonitorKey::of(monitor);
+        if let Some(entry) = self
+            .collection
+            .iter_mut()
+            .find(|c| MonitorKey::of(c) == key)
+        {
+            log::info!("update monitor {:?}", &monitor);
+
+            *entry = monitor.clone();
+            self.save();
+        }
+    }
+
+    pub fn delete(&mut self, key: &MonitorKey) {
+        if let Some(index) = self
+            .collection
+            .iter()
+            .position(|c| MonitorKey::of(c) == *key)
+        {
+            log::info!("delete monitor {}", key);
 
             self.collection.remove(index);
             self.save();
@@ -80,8 +101,9 @@ impl DynamicConfig {
 mod tests {
     use super::*;
     use crate::config::{
-        CollectionConfig, Config, DatabaseConfig, DynamicConfigConfig as RootDynamicConfigConfig,
-        LoggingConfig, NetworkSettings, OrphanConfig, Service, WebInterfaceConfig,
+        BackfillConfig, CollectionConfig, Config, DatabaseConfig,
+        DynamicConfigConfig as RootDynamicConfigConfig, LoggingConfig, NetworkSettings,
+        OrphanConfig, Service, StorageType, SyncMode, WebInterfaceConfig, WriteErrorPolicy,
     };
 
     fn sample_root_config(filename: &str) -> Config {
@@ -99,8 +121,11 @@ mod tests {
                 start_block_height: 1,
                 startup_load_from_database: true,
                 block_file: "../data/main-block.dat".to_string(),
+                block_store: String::new(),
+                block_segment_size: 128 * 1024 * 1024,
                 save_blocks: false,
                 save_txs: false,
+                sync_mode: SyncMode::Full,
             },
             testnet: NetworkSettings {
                 ip: vec!["127.0.0.1".to_string()],
@@ -110,14 +135,23 @@ mod tests {
                 start_block_height: 1,
                 startup_load_from_database: false,
                 block_file: "../data/test-net.dat".to_string(),
+                block_store: String::new(),
+                block_segment_size: 128 * 1024 * 1024,
                 save_blocks: false,
                 save_txs: false,
+                sync_mode: SyncMode::Full,
             },
             database: DatabaseConfig {
+                backend: StorageType::Sql,
                 mysql_url: "mysql://local".to_string(),
                 mysql_url_docker: "mysql://docker".to_string(),
+                embedded_path: String::new(),
                 ms_delay: 300,
                 retries: 3,
+                startup_repair: true,
+                write_queue_size: 100,
+                on_write_error: WriteErrorPolicy::Retry,
+                utxo_cache_size: 1000,
             },
             orphan: OrphanConfig {
                 detect: false,
@@ -130,6 +164,9 @@ mod tests {
                 filename: filename.to_string(),
             },
             web_interface: WebInterfaceConfig::default(),
+            backfill: BackfillConfig::default(),
+            api_keys: Default::default(),
+            tenant: Vec::new(),
             collection: Vec::new(),
         }
     }
@@ -147,6 +184,7 @@ mod tests {
             track_descendants: false,
             address: Some("mgzhRq55hEYFgyCrtNxEsP1MdusZZ31hH5".to_string()),
             locking_script_pattern: None,
+            owner: None,
         });
         let saved = std::fs::read_to_string(&path).expect("dynamic config file");
         assert!(saved.contains("runtime-monitor"));
@@ -86,6 +86,8 @@ impl EventHandler {
 
     fn on_block(&self, block: &Block, peer: &Arc<Peer>) {
         // println!("on_block {:?}", block);
+        // Observers are passed the message by reference, so the block has to be copied here while
+        // the connection still holds the parsed message; from here on the copy is moved, not cloned
         let msg = PeerEventMessage {
             time: time::SystemTime::now(),
             peer: peer.ip,
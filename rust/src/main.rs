@@ -2,15 +2,17 @@
 extern crate lazy_static;
 
 use actix_web::{web, App, HttpServer};
-use mysql::Pool;
 use std::{
     net::{IpAddr, Ipv4Addr},
-    panic, process,
+    panic,
+    path::Path,
+    process,
     sync::{mpsc, Arc},
     thread, time,
 };
 use tokio::signal;
 
+mod api_keys;
 mod config;
 mod dynamic_config;
 mod event_handler;
@@ -20,20 +22,35 @@ mod peer_thread;
 mod rate_limit;
 mod rest_api;
 mod services;
+mod tenant;
 mod thread_manager;
 mod thread_tracker;
 mod thread_util;
 mod uaas;
 
 use crate::{
-    config::get_config,
+    api_keys::{hash_config_key, ApiKeyStore},
+    config::{get_config, SyncMode},
     peer_event::{PeerEventMessage, PeerEventType},
     rate_limit::RateLimiter,
-    rest_api::{add_monitor, broadcast_tx, delete_monitor, health, version, AppState},
+    rest_api::{
+        add_monitor, broadcast_tx, create_api_key, delete_monitor, get_block_txids,
+        get_collection_txs, get_metrics, get_monitor, get_raw_block, get_raw_tx, get_tx_beef,
+        get_tx_proof, get_utxo_commitment, health, list_api_keys, list_collections, list_monitors,
+        revoke_api_key, rotate_api_key, update_monitor, verify_proof, version, AppState,
+    },
+    tenant::Tenants,
     thread_manager::ThreadManager,
     thread_tracker::ThreadTracker,
     thread_util::catch_unwind_logged,
-    uaas::logic::Logic,
+    uaas::{
+        block_store::{self, MIN_PRUNE_BLOCKS},
+        logic::Logic,
+        metrics::{DatabaseMetrics, UtxoCacheMetrics},
+        monitor::MonitorRegistry,
+        snapshot,
+        storage::open_backend,
+    },
 };
 
 #[actix_web::main]
@@ -52,6 +69,13 @@ async fn run() -> Result<(), String> {
         orig_hook(panic_info);
     }));
 
+    let options = parse_options(std::env::args().skip(1))?;
+    if let Some(key) = &options.hash_api_key {
+        let (salt, hash) = hash_config_key(key);
+        println!("api_key_hash = \"{hash}\"\napi_key_salt = \"{salt}\"");
+        return Ok(());
+    }
+
     let config = get_config("UAASR_CONFIG", "../data/uaasr.toml")?;
 
     simple_logger::init_with_level(config.get_log_level())
@@ -66,27 +90,127 @@ async fn run() -> Result<(), String> {
     let max_broadcast_tx_bytes = config.web_interface.max_broadcast_tx_bytes;
     let payload_limit = max_broadcast_tx_bytes.saturating_mul(2).max(1024);
 
-    let db_pool = Pool::new(config.get_mysql_url()).map_err(|err| {
-        log::error!(
-            "Problem connecting to database. Check database is connected and configuration is correct: {err:?}"
+    let storage = open_backend(&config)?;
+    // Bring the schema up to date before any component reads the database
+    let (from, to) = storage.open("migration")?.migrate()?;
+    if from == to {
+        log::info!("Database schema is at version {to}");
+    } else {
+        log::info!("Database schema migrated from version {from} to {to}");
+    }
+    if options.migrate_only {
+        return Ok(());
+    }
+
+    if options.compact_blocks || options.prune_blocks.is_some() {
+        let settings = config.get_network_settings()?;
+        let dir = settings.block_store_dir();
+        let info = block_store::compact(
+            &dir,
+            settings.block_segment_size,
+            storage.open("block store compaction")?.as_mut(),
+            options.prune_blocks,
+        )?;
+        log::info!(
+            "Compacted block store {}, kept {} blocks and removed {}, from {} to {} bytes",
+            dir.display(),
+            info.kept,
+            info.removed,
+            info.size_before,
+            info.size_after
+        );
+        return Ok(());
+    }
+
+    let network_name = config.service.network.as_str();
+    if let Some(path) = &options.export_snapshot {
+        let info = snapshot::export(
+            storage.open("snapshot export")?.as_mut(),
+            network_name,
+            path,
+        )?;
+        log::info!(
+            "Exported {} utxo entries at height {} ({}) to {path}, commitment {}",
+            info.entries,
+            info.height,
+            info.tip.encode(),
+            info.commitment.encode()
+        );
+        return Ok(());
+    }
+    if let Some(path) = &options.import_snapshot {
+        // The imported tip is picked up by loading the block headers from the database
+        if !config.get_network_settings()?.startup_load_from_database {
+            return Err(
+                "--import-snapshot needs startup_load_from_database = true for the network"
+                    .to_string(),
+            );
+        }
+        let info = snapshot::import(
+            storage.open("snapshot import")?.as_mut(),
+            network_name,
+            path,
+        )?;
+        log::info!(
+            "Imported {} utxo entries from {path}, resuming from height {} ({})",
+            info.entries,
+            info.height,
+            info.tip.encode()
+        );
+    }
+
+    let network = config.get_network().map_err(|err| err.to_string())?;
+    let monitors = Arc::new(MonitorRegistry::new());
+    let db_metrics = Arc::new(DatabaseMetrics::new(config.database.write_queue_size));
+    let utxo_metrics = Arc::new(UtxoCacheMetrics::new(config.database.utxo_cache_size));
+    let api_keys = ApiKeyStore::new(&config.api_keys.filename)?;
+
+    let mut logic = Logic::new(
+        &config,
+        storage.clone(),
+        monitors.clone(),
+        db_metrics.clone(),
+        utxo_metrics.clone(),
+    )?;
+    logic.setup()?;
+
+    if let Some(dir) = &options.import_blk {
+        if config.get_network_settings()?.sync_mode == SyncMode::Headers {
+            return Err("--import-blk needs sync_mode = \"full\"".to_string());
+        }
+        let info = logic.import_blk_files(Path::new(dir), network.magic())?;
+        let tip = logic.header_chain().tip();
+        // Wait for the blocks to be written before exiting
+        logic.close();
+        log::info!(
+            "Imported {} of the {} blocks in {} files from {dir}, the tip is at height {}",
+            info.imported,
+            info.indexed,
+            info.files,
+            tip.map_or(0, |(height, _header)| height)
         );
-        format!(
-            "Problem connecting to database. Check database is connected and database connection configuration is correct: {err:?}"
-        )
-    })?;
+        return Ok(());
+    }
 
     let app_state = AppState {
         msg_from_rest_api: tx_rest,
-        api_key: config.web_interface.api_key.clone(),
+        tenants: Arc::new(Tenants::new(
+            config.web_interface.configured_key()?,
+            &config.tenant,
+            api_keys,
+        )),
         rate_limiter,
         max_broadcast_tx_bytes,
-        db_pool: db_pool.clone(),
+        storage,
+        network,
+        monitors,
+        db_metrics,
+        utxo_metrics,
+        block_reader: logic.block_reader(),
+        headers: logic.header_chain(),
     };
     let web_state = web::Data::new(app_state);
 
-    let mut logic = Logic::new(&config, db_pool)?;
-    logic.setup();
-
     let mut children = ThreadTracker::new();
     let mut manager = ThreadManager::new(rx_rest);
     let tx = manager.get_tx();
@@ -112,8 +236,25 @@ async fn run() -> Result<(), String> {
             .service(health)
             .service(broadcast_tx)
             .service(version)
+            .service(list_monitors)
+            .service(get_monitor)
             .service(add_monitor)
+            .service(update_monitor)
             .service(delete_monitor)
+            .service(list_collections)
+            .service(get_collection_txs)
+            .service(list_api_keys)
+            .service(create_api_key)
+            .service(revoke_api_key)
+            .service(rotate_api_key)
+            .service(get_metrics)
+            .service(get_utxo_commitment)
+            .service(get_raw_block)
+            .service(get_block_txids)
+            .service(get_raw_tx)
+            .service(get_tx_proof)
+            .service(get_tx_beef)
+            .service(verify_proof)
     })
     .workers(1)
     .bind(&server_address)
@@ -149,6 +290,57 @@ async fn run() -> Result<(), String> {
     Ok(())
 }
 
+// Command line options
+#[derive(Default)]
+struct Options {
+    // Apply the database migrations and exit, without connecting to the network
+    migrate_only: bool,
+    // Write a utxo snapshot to this file and exit
+    export_snapshot: Option<String>,
+    // Load a utxo snapshot into the (empty) database and start from its tip
+    import_snapshot: Option<String>,
+    // Rewrite the block store with only the main chain blocks and exit
+    compact_blocks: bool,
+    // As compact_blocks, keeping only the bodies of this many blocks below the tip
+    prune_blocks: Option<u32>,
+    // Process the blocks of a node's blk*.dat files in this directory and exit
+    import_blk: Option<String>,
+    // Print the api_key_hash and api_key_salt config fields for this key and exit
+    hash_api_key: Option<String>,
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--migrate-only" => options.migrate_only = true,
+            "--export-snapshot" => options.export_snapshot = Some(value()?),
+            "--import-snapshot" => options.import_snapshot = Some(value()?),
+            "--compact-blocks" => options.compact_blocks = true,
+            "--import-blk" => options.import_blk = Some(value()?),
+            "--hash-api-key" => options.hash_api_key = Some(value()?),
+            "--prune-blocks" => {
+                let value = value()?;
+                let blocks = value
+                    .parse()
+                    .map_err(|_| format!("--prune-blocks needs a number of blocks, not {value}"))?;
+                if blocks < MIN_PRUNE_BLOCKS {
+                    return Err(format!(
+                        "--prune-blocks needs to keep at least {MIN_PRUNE_BLOCKS} blocks, not {blocks}"
+                    ));
+                }
+                options.prune_blocks = Some(blocks);
+            }
+            _ => return Err(format!("Unknown argument {arg}")),
+        }
+    }
+    if options.export_snapshot.is_some() && options.import_snapshot.is_some() {
+        return Err("--export-snapshot and --import-snapshot cannot be used together".to_string());
+    }
+    Ok(options)
+}
+
 async fn wait_for_shutdown_signal() {
     let ctrl_c = async {
         if let Err(err) = signal::ctrl_c().await {
@@ -177,3 +369,22 @@ async fn wait_for_shutdown_signal() {
         _ = terminate => {},
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_options(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn prune_blocks_keeps_at_least_the_minimum() {
+        let options = parse(&["--prune-blocks", "1000"]).expect("valid options");
+        assert_eq!(options.prune_blocks, Some(1000));
+        assert!(parse(&["--prune-blocks", "100"]).is_ok());
+        assert!(parse(&["--prune-blocks", "99"]).is_err());
+        assert!(parse(&["--prune-blocks", "0"]).is_err());
+        assert!(parse(&["--prune-blocks", "many"]).is_err());
+    }
+}
@@ -2,16 +2,32 @@ use std::io::Cursor;
 use std::sync::{mpsc, Arc};
 
 use actix_web::{
-    delete, get, http::header::ContentType, post, web, HttpRequest, HttpResponse, Responder, Result,
+    delete, get, http::header::ContentType, post, put, web, HttpRequest, HttpResponse, Responder,
+    Result,
 };
-use mysql::{prelude::*, Pool};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use chain_gang::{messages::Tx, util::Serializable};
+use chain_gang::{
+    messages::Tx,
+    network::Network,
+    util::{Hash256, Serializable},
+};
 
+use crate::api_keys::ApiKeyInfo;
 use crate::config::CollectionConfig;
 use crate::rate_limit::RateLimiter;
-use crate::uaas::util::decode_hexstr;
+use crate::tenant::{Caller, KeyError, Scope, Tenants};
+use crate::uaas::{
+    beef::{build_beef, Beef, BeefError},
+    block_store::{self, BlockReader, NOT_STORED},
+    collection::{validate_monitor, BROADCAST_COLLECTION},
+    header_chain::HeaderChain,
+    merkle::{tree_depth, MerklePath, MerkleProof, ProofTarget, TscProofJson},
+    metrics::{DatabaseMetrics, DatabaseMetricsSnapshot, UtxoCacheMetrics, UtxoCacheSnapshot},
+    monitor::{MonitorKey, MonitorRegistry, MonitorSource, MonitorStatus, RegisterError},
+    storage::StorageBackend,
+    util::decode_hexstr,
+};
 
 // RestEventMessage - used for sending messages from REST API to main event processing loop
 
@@ -19,16 +35,23 @@ use crate::uaas::util::decode_hexstr;
 pub enum RestEventMessage {
     TxForBroadcast(Tx),
     AddMonitor(CollectionConfig),
-    DeleteMonitor(String),
+    UpdateMonitor(CollectionConfig),
+    DeleteMonitor(MonitorKey),
 }
 
 // web interface state
 pub struct AppState {
     pub msg_from_rest_api: mpsc::Sender<RestEventMessage>,
-    pub api_key: Option<String>,
+    pub tenants: Arc<Tenants>,
     pub rate_limiter: Arc<RateLimiter>,
     pub max_broadcast_tx_bytes: usize,
-    pub db_pool: Pool,
+    pub storage: Arc<dyn StorageBackend>,
+    pub network: Network,
+    pub monitors: Arc<MonitorRegistry>,
+    pub db_metrics: Arc<DatabaseMetrics>,
+    pub utxo_metrics: Arc<UtxoCacheMetrics>,
+    pub block_reader: BlockReader,
+    pub headers: HeaderChain,
 }
 
 fn tx_hex_exceeds_limit(hex_len: usize, max_tx_bytes: usize) -> bool {
@@ -47,28 +70,168 @@ fn rate_limit(req: &HttpRequest, limiter: &RateLimiter) -> Option<HttpResponse>
     }
 }
 
-fn authorize(req: &HttpRequest, api_key: &Option<String>) -> Option<HttpResponse> {
-    let expected = api_key.as_ref()?;
-    let authorized = req
+fn failure(mut builder: actix_web::HttpResponseBuilder, detail: &str) -> HttpResponse {
+    builder.json(serde_json::json!({
+        "failure": detail,
+    }))
+}
+
+enum Denied {
+    Unauthorized,
+    MissingScope,
+}
+
+impl Denied {
+    fn response(&self) -> HttpResponse {
+        match self {
+            Denied::Unauthorized => failure(HttpResponse::Unauthorized(), "Unauthorized"),
+            Denied::MissingScope => failure(
+                HttpResponse::Forbidden(),
+                "API key does not have the required scope",
+            ),
+        }
+    }
+}
+
+fn authorize(req: &HttpRequest, tenants: &Tenants, scope: Scope) -> Result<Caller, Denied> {
+    let provided = req
         .headers()
         .get(API_KEY_HEADER)
-        .and_then(|value| value.to_str().ok())
-        .is_some_and(|provided| provided == expected);
-    if authorized {
-        None
-    } else {
-        Some(HttpResponse::Unauthorized().json(serde_json::json!({
-            "failure": "Unauthorized",
-        })))
+        .and_then(|value| value.to_str().ok());
+    let caller = tenants.authenticate(provided).ok_or(Denied::Unauthorized)?;
+    if !caller.has_scope(scope) {
+        return Err(Denied::MissingScope);
+    }
+    Ok(caller)
+}
+
+// The monitor a caller means by a name. Tenants name their own monitors,
+// operator keys name the operator's, or a tenant's with ?owner=
+fn monitor_key(caller: &Caller, query: &OwnerQuery, name: &str) -> MonitorKey {
+    MonitorKey {
+        owner: caller.owner().or_else(|| query.owner.clone()),
+        name: name.to_string(),
     }
 }
 
+// Return the monitor only if the caller is allowed to see it
+fn visible_monitor(data: &AppState, caller: &Caller, key: &MonitorKey) -> Option<MonitorStatus> {
+    data.monitors
+        .get(key)
+        .filter(|status| caller.can_see(status.config.owner.as_deref()))
+}
+
 #[derive(Serialize)]
 struct BroadcastTxResponse {
     status: String,
     detail: String,
 }
 
+#[derive(Serialize)]
+struct MonitorListResponse {
+    monitors: Vec<MonitorStatus>,
+}
+
+#[derive(Serialize)]
+struct CollectionListResponse {
+    collections: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CollectionTxsResponse {
+    name: String,
+    txs: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct OwnerQuery {
+    // The tenant whose monitor an operator key means, ignored for tenant keys
+    #[serde(default)]
+    owner: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UtxoCommitmentQuery {
+    height: u32,
+}
+
+#[derive(Serialize)]
+struct UtxoCommitmentResponse {
+    height: u32,
+    block_hash: String,
+    commitment: String,
+}
+
+#[derive(Deserialize)]
+struct RawQuery {
+    // "hex" for hex text rather than bytes
+    #[serde(default)]
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProofQuery {
+    // "binary" or "hex" for the binary form rather than JSON
+    #[serde(default)]
+    format: Option<String>,
+    // "hash", "header" or "merkleRoot"
+    #[serde(default)]
+    target: Option<String>,
+}
+
+// A TSC proof, or a BUMP as hex with the tx to check
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum VerifyProofRequest {
+    Bump { bump: String, txid: String },
+    Tsc(TscProofJson),
+}
+
+#[derive(Serialize)]
+struct VerifyProofResponse {
+    valid: bool,
+    txid: String,
+    merkle_root: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confirmations: Option<u32>,
+    // Why the proof is not valid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BlockTxidsResponse {
+    hash: String,
+    txids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ApiKeyListResponse {
+    keys: Vec<ApiKeyInfo>,
+}
+
+#[derive(Deserialize)]
+struct CreateApiKeyRequest {
+    // Tenant the key belongs to, None for an operator key
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(default)]
+    scopes: Option<Vec<Scope>>,
+    // Seconds since the unix epoch
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct MetricsResponse {
+    database: DatabaseMetricsSnapshot,
+    utxo_cache: UtxoCacheSnapshot,
+}
+
 #[derive(Serialize)]
 struct HealthResponse {
     status: &'static str,
@@ -79,18 +242,223 @@ struct HealthResponse {
     database: Option<String>,
 }
 
-fn check_database(pool: &Pool) -> Result<(), String> {
-    let mut conn = pool.get_conn().map_err(|err| err.to_string())?;
-    conn.query_first::<u8, _>("SELECT 1")
-        .map_err(|err| err.to_string())?
-        .ok_or_else(|| "database health check returned no rows".to_string())?;
-    Ok(())
+fn check_database(storage: &dyn StorageBackend, metrics: &DatabaseMetrics) -> Result<(), String> {
+    if let Some(err) = metrics.halted_error() {
+        return Err(format!("database writer halted: {err}"));
+    }
+    storage.open("health check")?.ping()
+}
+
+// Bytes from the block store, as bytes or as hex text
+fn raw_response(bytes: Vec<u8>, query: &RawQuery) -> HttpResponse {
+    match query.format.as_deref() {
+        Some("hex") => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body(hex::encode(bytes)),
+        Some(format) => failure(
+            HttpResponse::BadRequest(),
+            &format!("Unknown format '{format}', expected hex"),
+        ),
+        None => HttpResponse::Ok()
+            .content_type(ContentType::octet_stream())
+            .body(bytes),
+    }
+}
+
+fn decode_hash(hash: &str) -> Result<Hash256, String> {
+    Hash256::decode(hash).map_err(|_| format!("Invalid hash '{hash}'"))
+}
+
+// Why a block or tx could not be served
+enum BlockStoreError {
+    NotFound(String),
+    Unavailable(String),
+}
+
+impl BlockStoreError {
+    fn response(&self) -> HttpResponse {
+        match self {
+            BlockStoreError::NotFound(detail) => failure(HttpResponse::NotFound(), detail),
+            BlockStoreError::Unavailable(err) => {
+                log::error!("Unable to read block store: {err}");
+                failure(
+                    HttpResponse::ServiceUnavailable(),
+                    "Block store unavailable",
+                )
+            }
+        }
+    }
+}
+
+fn read_raw_block(reader: &BlockReader, hash: &Hash256) -> Result<Vec<u8>, BlockStoreError> {
+    let position = reader.position(hash).ok_or_else(|| {
+        BlockStoreError::NotFound(format!("Block {} not in the block store", hash.encode()))
+    })?;
+    reader
+        .read_raw(position)
+        .map_err(BlockStoreError::Unavailable)
+}
+
+fn tx_not_found(txid: &Hash256) -> BlockStoreError {
+    BlockStoreError::NotFound(format!(
+        "Transaction {} not found in the block store",
+        txid.encode()
+    ))
+}
+
+// The block store position of the tx's block and the index of the tx in it, from the tx table
+fn locate_tx(storage: &dyn StorageBackend, txid: &Hash256) -> Result<(u64, u32), BlockStoreError> {
+    let (position, blockindex) = storage
+        .open("rest api")
+        .and_then(|mut storage| storage.tx_location(txid))
+        .map_err(BlockStoreError::Unavailable)?
+        .ok_or_else(|| tx_not_found(txid))?;
+    if position == NOT_STORED {
+        return Err(tx_not_found(txid));
+    }
+    Ok((position, blockindex))
+}
+
+// Find the tx's block with the tx table, then cut the tx out of the stored block
+fn read_raw_tx(
+    storage: &dyn StorageBackend,
+    reader: &BlockReader,
+    txid: &Hash256,
+) -> Result<Vec<u8>, BlockStoreError> {
+    let (position, blockindex) = locate_tx(storage, txid)?;
+    let block = reader
+        .read_raw(position)
+        .map_err(BlockStoreError::Unavailable)?;
+    let tx = block_store::tx_bytes(&block, blockindex).map_err(BlockStoreError::Unavailable)?;
+    Ok(tx.to_vec())
+}
+
+// The merkle proof of a confirmed tx, worked out from the txs of its stored block
+fn read_tx_proof(
+    storage: &dyn StorageBackend,
+    reader: &BlockReader,
+    txid: &Hash256,
+    target: &str,
+) -> Result<MerkleProof, BlockStoreError> {
+    let (position, blockindex) = locate_tx(storage, txid)?;
+    let block = reader
+        .read(position)
+        .map_err(BlockStoreError::Unavailable)?;
+    let txids: Vec<Hash256> = block.txns.iter().map(|tx| tx.hash()).collect();
+    if txids.get(blockindex as usize) != Some(txid) {
+        return Err(tx_not_found(txid));
+    }
+    let target = match target {
+        "header" => ProofTarget::Header(block.header),
+        "merkleRoot" => ProofTarget::MerkleRoot(block.header.merkle_root),
+        _ => ProofTarget::BlockHash(block.header.hash()),
+    };
+    let proof =
+        MerkleProof::new(&txids, blockindex, target).map_err(BlockStoreError::Unavailable)?;
+    if proof.root() != Ok(block.header.merkle_root) {
+        return Err(BlockStoreError::Unavailable(format!(
+            "Stored block {} does not match its merkle root",
+            block.header.hash().encode()
+        )));
+    }
+    Ok(proof)
+}
+
+// Work out the merkle root of a proof and check it against the main chain header of the
+// block it claims, Err for a proof that can not be read.
+// Where the block is stored the proof must also have the shape of the block's merkle tree,
+// so that an inner node of the tree can not be passed off as a tx
+fn check_proof(
+    headers: &HeaderChain,
+    reader: &BlockReader,
+    request: &VerifyProofRequest,
+) -> Result<VerifyProofResponse, String> {
+    let (txid, merkle_root, block, index, depth) = match request {
+        VerifyProofRequest::Bump { bump, txid } => {
+            let bytes = decode_hexstr(bump).map_err(|_| "Invalid bump hex".to_string())?;
+            let mut reader = bytes.as_slice();
+            let path = MerklePath::read(&mut reader)?;
+            if !reader.is_empty() {
+                return Err("Invalid merkle path: data after the last level".to_string());
+            }
+            let txid = decode_hash(txid)?;
+            let merkle_root = path.root(&txid)?;
+            let block = headers
+                .header_at(path.height)
+                .map(|header| (path.height, header));
+            let offset = path.offset(&txid).unwrap_or(0);
+            (txid, merkle_root, block, offset, path.depth())
+        }
+        VerifyProofRequest::Tsc(json) => {
+            let proof = MerkleProof::from_json(json)?;
+            let block = match &proof.target {
+                ProofTarget::BlockHash(hash) => headers.find(hash),
+                ProofTarget::Header(header) => headers.find(&header.hash()),
+                ProofTarget::MerkleRoot(merkle_root) => headers.find_merkle_root(merkle_root),
+            };
+            let merkle_root = proof.root()?;
+            let index = u64::from(proof.index);
+            (proof.txid, merkle_root, block, index, proof.nodes.len())
+        }
+    };
+
+    let mut response = VerifyProofResponse {
+        valid: false,
+        txid: txid.encode(),
+        merkle_root: merkle_root.encode(),
+        block_hash: None,
+        height: None,
+        confirmations: None,
+        detail: None,
+    };
+    let Some((height, header)) = block else {
+        response.detail = Some("Block is not in the main chain".to_string());
+        return Ok(response);
+    };
+    response.block_hash = Some(header.hash().encode());
+    response.height = Some(height);
+    if header.merkle_root != merkle_root {
+        response.detail = Some("Merkle root does not match the block header".to_string());
+        return Ok(response);
+    }
+    let tx_count = reader.tx_count(&header.hash()).unwrap_or_else(|err| {
+        log::warn!("Unable to read the tx count of a stored block: {err}");
+        None
+    });
+    match tx_count {
+        Some(tx_count) if depth != tree_depth(tx_count) || index >= tx_count => {
+            response.detail = Some(format!(
+                "Proof of tx {index} with {depth} levels does not fit a block of {tx_count} txs"
+            ));
+            return Ok(response);
+        }
+        // A tx is its own merkle root only if it is alone in its block
+        None if depth == 0 => {
+            response.detail =
+                Some("Proof without nodes needs the block in the block store".to_string());
+            return Ok(response);
+        }
+        _ => {}
+    }
+    let tip = headers.tip().map_or(height, |(tip, _header)| tip);
+    response.valid = true;
+    response.confirmations = Some(tip.saturating_sub(height) + 1);
+    Ok(response)
+}
+
+fn read_collection_txs(
+    storage: &dyn StorageBackend,
+    key: &MonitorKey,
+) -> Result<Vec<String>, String> {
+    let hashes = storage.open("rest api")?.load_collection_txs(key)?;
+    Ok(hashes.iter().map(|hash| hash.encode()).collect())
 }
 
 #[get("/health")]
 async fn health(data: web::Data<AppState>) -> impl Responder {
-    let pool = data.db_pool.clone();
-    match web::block(move || check_database(&pool)).await {
+    let storage = data.storage.clone();
+    let metrics = data.db_metrics.clone();
+    match web::block(move || check_database(storage.as_ref(), &metrics)).await {
         Ok(Ok(())) => HttpResponse::Ok().json(HealthResponse {
             status: "ok",
             service: "uaas-service",
@@ -137,8 +505,15 @@ async fn broadcast_tx(
     if let Some(response) = rate_limit(&req, &data.rate_limiter) {
         return Ok(response);
     }
-    if let Some(response) = authorize(&req, &data.api_key) {
-        return Ok(response);
+    let caller = match authorize(&req, &data.tenants, Scope::Broadcast) {
+        Ok(caller) => caller,
+        Err(denied) => return Ok(denied.response()),
+    };
+    if !caller.allow_broadcast() {
+        return Ok(failure(
+            HttpResponse::TooManyRequests(),
+            "Broadcast quota exceeded",
+        ));
     }
 
     if tx_hex_exceeds_limit(hexstr.len(), data.max_broadcast_tx_bytes) {
@@ -162,29 +537,53 @@ async fn broadcast_tx(
         }
     };
 
-    let tx = match Tx::read(&mut Cursor::new(&bytes)) {
-        Ok(tx) => tx,
-        Err(_) => {
-            return Ok(HttpResponse::Ok().json(BroadcastTxResponse {
-                status: "Failed".to_string(),
-                detail: "Failed to convert hex to tx".to_string(),
-            }));
+    // A BEEF bundle broadcasts its unconfirmed txs, parents first
+    let txs = if Beef::is_beef(&bytes) {
+        match Beef::read(&bytes) {
+            Ok(beef) => beef
+                .txs
+                .into_iter()
+                .filter(|entry| entry.bump.is_none())
+                .map(|entry| entry.tx)
+                .collect(),
+            Err(err) => {
+                return Ok(HttpResponse::Ok().json(BroadcastTxResponse {
+                    status: "Failed".to_string(),
+                    detail: err,
+                }));
+            }
+        }
+    } else {
+        match Tx::read(&mut Cursor::new(&bytes)) {
+            Ok(tx) => vec![tx],
+            Err(_) => {
+                return Ok(HttpResponse::Ok().json(BroadcastTxResponse {
+                    status: "Failed".to_string(),
+                    detail: "Failed to convert hex to tx".to_string(),
+                }));
+            }
         }
     };
-
-    let hash = tx.hash().encode();
-
-    // Send Tx for broadcast
-    if data
-        .msg_from_rest_api
-        .send(RestEventMessage::TxForBroadcast(tx))
-        .is_err()
-    {
-        log::error!("REST API channel closed; cannot broadcast transaction");
+    let Some(hash) = txs.last().map(|tx| tx.hash().encode()) else {
         return Ok(HttpResponse::Ok().json(BroadcastTxResponse {
             status: "Failed".to_string(),
-            detail: "Service unavailable".to_string(),
+            detail: "BEEF has no unconfirmed transaction to broadcast".to_string(),
         }));
+    };
+
+    // Send Tx for broadcast
+    for tx in txs {
+        if data
+            .msg_from_rest_api
+            .send(RestEventMessage::TxForBroadcast(tx))
+            .is_err()
+        {
+            log::error!("REST API channel closed; cannot broadcast transaction");
+            return Ok(HttpResponse::Ok().json(BroadcastTxResponse {
+                status: "Failed".to_string(),
+                detail: "Service unavailable".to_string(),
+            }));
+        }
     }
 
     // Return hash as hex_str, if successful
@@ -194,6 +593,51 @@ async fn broadcast_tx(
     }))
 }
 
+#[get("/collection/monitor")]
+async fn list_monitors(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    let caller = match authorize(&req, &data.tenants, Scope::Read) {
+        Ok(caller) => caller,
+        Err(denied) => return denied.response(),
+    };
+
+    HttpResponse::Ok().json(MonitorListResponse {
+        monitors: data
+            .monitors
+            .list()
+            .into_iter()
+            .filter(|status| caller.can_see(status.config.owner.as_deref()))
+            .collect(),
+    })
+}
+
+#[get("/collection/monitor/{monitor_name}")]
+async fn get_monitor(
+    monitor_name: web::Path<String>,
+    query: web::Query<OwnerQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    let caller = match authorize(&req, &data.tenants, Scope::Read) {
+        Ok(caller) => caller,
+        Err(denied) => return denied.response(),
+    };
+
+    let key = monitor_key(&caller, &query, &monitor_name);
+    match visible_monitor(&data, &caller, &key) {
+        Some(status) => HttpResponse::Ok().json(status),
+        None => failure(
+            HttpResponse::NotFound(),
+            &format!("Monitor '{monitor_name}' not found"),
+        ),
+    }
+}
+
 #[post("/collection/monitor")]
 async fn add_monitor(
     monitor: web::Json<CollectionConfig>,
@@ -203,147 +647,716 @@ async fn add_monitor(
     if let Some(response) = rate_limit(&req, &data.rate_limiter) {
         return Ok(response);
     }
-    if let Some(response) = authorize(&req, &data.api_key) {
-        return Ok(response);
-    }
+    let caller = match authorize(&req, &data.tenants, Scope::MonitorWrite) {
+        Ok(caller) => caller,
+        Err(denied) => return Ok(denied.response()),
+    };
 
     log::info!("add_monitor");
 
-    let cc = monitor.into_inner();
+    let mut cc = monitor.into_inner();
+    cc.owner = caller.owner();
+
+    if let Err(err) = validate_monitor(&cc, data.network) {
+        return Ok(failure(HttpResponse::BadRequest(), &err.to_string()));
+    }
+    match data.monitors.register_owned(&cc, caller.max_monitors()) {
+        Ok(()) => {}
+        Err(RegisterError::Exists) => {
+            return Ok(failure(
+                HttpResponse::Conflict(),
+                &format!("Monitor '{}' already exists", cc.name),
+            ))
+        }
+        Err(RegisterError::QuotaReached(max_monitors)) => {
+            return Ok(failure(
+                HttpResponse::Forbidden(),
+                &format!("Monitor quota of {max_monitors} reached"),
+            ))
+        }
+    }
 
+    let key = MonitorKey::of(&cc);
     if data
         .msg_from_rest_api
         .send(RestEventMessage::AddMonitor(cc))
         .is_err()
     {
         log::error!("REST API channel closed; cannot add monitor");
+        data.monitors.remove(&key);
         return Ok(HttpResponse::ServiceUnavailable().body("Service unavailable"));
     }
 
     Ok(HttpResponse::Ok().finish())
 }
 
-#[delete("/collection/monitor/{monitor_name}")]
-async fn delete_monitor(
+#[put("/collection/monitor/{monitor_name}")]
+async fn update_monitor(
     monitor_name: web::Path<String>,
+    monitor: web::Json<CollectionConfig>,
+    query: web::Query<OwnerQuery>,
     req: HttpRequest,
     data: web::Data<AppState>,
 ) -> Result<impl Responder> {
     if let Some(response) = rate_limit(&req, &data.rate_limiter) {
         return Ok(response);
     }
-    if let Some(response) = authorize(&req, &data.api_key) {
+    let caller = match authorize(&req, &data.tenants, Scope::MonitorWrite) {
+        Ok(caller) => caller,
+        Err(denied) => return Ok(denied.response()),
+    };
+
+    log::info!("update_monitor '{}'", &monitor_name);
+
+    let mut cc = monitor.into_inner();
+
+    if cc.name != *monitor_name {
+        return Ok(failure(
+            HttpResponse::BadRequest(),
+            "Monitor name in body does not match the path",
+        ));
+    }
+    if let Err(err) = validate_monitor(&cc, data.network) {
+        return Ok(failure(HttpResponse::BadRequest(), &err.to_string()));
+    }
+    let key = monitor_key(&caller, &query, &cc.name);
+    let previous = match visible_monitor(&data, &caller, &key) {
+        None => {
+            return Ok(failure(
+                HttpResponse::NotFound(),
+                &format!("Monitor '{}' not found", cc.name),
+            ));
+        }
+        Some(status) if status.source == MonitorSource::Static => {
+            return Ok(failure(
+                HttpResponse::Conflict(),
+                &format!("Monitor '{}' is static and can not be changed", cc.name),
+            ));
+        }
+        Some(status) => status,
+    };
+    // Ownership does not change on update
+    cc.owner = previous.config.owner.clone();
+
+    data.monitors.update(&cc);
+    if data
+        .msg_from_rest_api
+        .send(RestEventMessage::UpdateMonitor(cc))
+        .is_err()
+    {
+        log::error!("REST API channel closed; cannot update monitor");
+        data.monitors.update(&previous.config);
+        return Ok(HttpResponse::ServiceUnavailable().body("Service unavailable"));
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[delete("/collection/monitor/{monitor_name}")]
+async fn delete_monitor(
+    monitor_name: web::Path<String>,
+    query: web::Query<OwnerQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder> {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
         return Ok(response);
     }
+    let caller = match authorize(&req, &data.tenants, Scope::MonitorWrite) {
+        Ok(caller) => caller,
+        Err(denied) => return Ok(denied.response()),
+    };
 
     log::info!("delete_monitor '{}'", &monitor_name);
 
+    let key = monitor_key(&caller, &query, &monitor_name);
+    match visible_monitor(&data, &caller, &key) {
+        None => {
+            return Ok(failure(
+                HttpResponse::NotFound(),
+                &format!("Monitor '{monitor_name}' not found"),
+            ));
+        }
+        Some(status) if status.source == MonitorSource::Static => {
+            return Ok(failure(
+                HttpResponse::Conflict(),
+                &format!("Monitor '{monitor_name}' is static and can not be deleted"),
+            ));
+        }
+        Some(_) => {}
+    }
+
     if data
         .msg_from_rest_api
-        .send(RestEventMessage::DeleteMonitor(monitor_name.to_string()))
+        .send(RestEventMessage::DeleteMonitor(key.clone()))
         .is_err()
     {
         log::error!("REST API channel closed; cannot delete monitor");
         return Ok(HttpResponse::ServiceUnavailable().body("Service unavailable"));
     }
+    data.monitors.remove(&key);
 
     Ok(HttpResponse::Ok().finish())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use actix_web::{test as actix_test, App};
-    use std::sync::mpsc;
+#[get("/collection")]
+async fn list_collections(
+    query: web::Query<OwnerQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    let caller = match authorize(&req, &data.tenants, Scope::Read) {
+        Ok(caller) => caller,
+        Err(denied) => return denied.response(),
+    };
 
-    fn mysql_test_url() -> Option<String> {
-        std::env::var("UAAS_TEST_MYSQL_URL").ok()
+    // The collections of one owner, as names are only unique within an owner
+    let owner = monitor_key(&caller, &query, "").owner;
+    let mut collections: Vec<String> = data
+        .monitors
+        .list()
+        .into_iter()
+        .filter(|status| status.config.owner == owner)
+        .map(|status| status.config.name)
+        .collect();
+    if owner.is_none() && caller.can_see(None) {
+        collections.push(BROADCAST_COLLECTION.to_string());
     }
 
-    fn live_db_pool() -> Option<Pool> {
-        let url = mysql_test_url()?;
-        Some(Pool::new(url.as_str()).expect("failed to connect to test database"))
+    HttpResponse::Ok().json(CollectionListResponse { collections })
+}
+
+#[get("/collection/txs/{collection_name}")]
+async fn get_collection_txs(
+    collection_name: web::Path<String>,
+    query: web::Query<OwnerQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
     }
+    let caller = match authorize(&req, &data.tenants, Scope::Read) {
+        Ok(caller) => caller,
+        Err(denied) => return denied.response(),
+    };
 
-    fn invalid_credentials_pool() -> Option<Pool> {
-        let url = mysql_test_url()?;
-        let bad_url = url.replacen("maas:maas-password", "maas:not-the-password", 1);
-        Pool::new(bad_url.as_str()).ok()
+    let name = collection_name.into_inner();
+    let key = monitor_key(&caller, &query, &name);
+    let visible = if key == MonitorKey::new(None, BROADCAST_COLLECTION) {
+        caller.can_see(None)
+    } else {
+        visible_monitor(&data, &caller, &key).is_some()
+    };
+    if !visible {
+        return failure(
+            HttpResponse::NotFound(),
+            &format!("Collection '{name}' not found"),
+        );
     }
 
-    fn skip_without_mysql(test_name: &str) -> Option<Pool> {
-        live_db_pool().or_else(|| {
-            eprintln!("skipping {test_name}: UAAS_TEST_MYSQL_URL not set");
-            None
-        })
+    let storage = data.storage.clone();
+    match web::block(move || read_collection_txs(storage.as_ref(), &key)).await {
+        Ok(Ok(txs)) => HttpResponse::Ok().json(CollectionTxsResponse { name, txs }),
+        Ok(Err(err)) => {
+            log::error!("Unable to read collection {name}: {err}");
+            failure(HttpResponse::ServiceUnavailable(), "Database unavailable")
+        }
+        Err(err) => {
+            log::error!("Unable to read collection {name}: {err}");
+            failure(HttpResponse::ServiceUnavailable(), "Database unavailable")
+        }
     }
+}
 
-    mod broadcast_limits {
-        use super::tx_hex_exceeds_limit;
+// The utxo set commitment stored with the block at a height, to compare instances
+#[get("/utxo/commitment")]
+async fn get_utxo_commitment(
+    query: web::Query<UtxoCommitmentQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Read) {
+        return denied.response();
+    }
 
-        #[test]
-        fn tx_hex_within_limit() {
-            assert!(!tx_hex_exceeds_limit(1_999_998, 1_000_000));
+    let height = query.height;
+    let storage = data.storage.clone();
+    let result = web::block(move || storage.open("rest api")?.utxo_commitment(height)).await;
+    match result {
+        Ok(Ok(Some((block_hash, commitment)))) => HttpResponse::Ok().json(UtxoCommitmentResponse {
+            height,
+            block_hash: block_hash.encode(),
+            commitment: commitment.encode(),
+        }),
+        Ok(Ok(None)) => failure(
+            HttpResponse::NotFound(),
+            &format!("No utxo commitment stored for height {height}"),
+        ),
+        Ok(Err(err)) => {
+            log::error!("Unable to read utxo commitment for height {height}: {err}");
+            failure(HttpResponse::ServiceUnavailable(), "Database unavailable")
         }
-
-        #[test]
-        fn tx_hex_at_limit() {
-            assert!(!tx_hex_exceeds_limit(2_000_000, 1_000_000));
+        Err(err) => {
+            log::error!("Unable to read utxo commitment for height {height}: {err}");
+            failure(HttpResponse::ServiceUnavailable(), "Database unavailable")
         }
+    }
+}
 
-        #[test]
-        fn tx_hex_over_limit() {
-            assert!(tx_hex_exceeds_limit(2_000_002, 1_000_000));
-        }
+#[get("/block/{hash}/raw")]
+async fn get_raw_block(
+    hash: web::Path<String>,
+    query: web::Query<RawQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
     }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Read) {
+        return denied.response();
+    }
+    let hash = match decode_hash(&hash) {
+        Ok(hash) => hash,
+        Err(detail) => return failure(HttpResponse::BadRequest(), &detail),
+    };
 
-    mod database_checks {
-        use super::*;
+    let reader = data.block_reader.clone();
+    match web::block(move || read_raw_block(&reader, &hash)).await {
+        Ok(Ok(block)) => raw_response(block, &query),
+        Ok(Err(err)) => err.response(),
+        Err(err) => BlockStoreError::Unavailable(err.to_string()).response(),
+    }
+}
 
-        #[test]
-        fn live_mysql_passes_health_check() {
-            let Some(pool) = skip_without_mysql("live_mysql_passes_health_check") else {
-                return;
-            };
-            check_database(&pool).expect("database health check should succeed");
+#[get("/block/{hash}/txids")]
+async fn get_block_txids(
+    hash: web::Path<String>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Read) {
+        return denied.response();
+    }
+    let hash = match decode_hash(&hash) {
+        Ok(hash) => hash,
+        Err(detail) => return failure(HttpResponse::BadRequest(), &detail),
+    };
+
+    let reader = data.block_reader.clone();
+    match web::block(move || reader.read_by_hash(&hash)).await {
+        Ok(Ok(Some(block))) => HttpResponse::Ok().json(BlockTxidsResponse {
+            hash: hash.encode(),
+            txids: block.txns.iter().map(|tx| tx.hash().encode()).collect(),
+        }),
+        Ok(Ok(None)) => failure(
+            HttpResponse::NotFound(),
+            &format!("Block {} not in the block store", hash.encode()),
+        ),
+        Ok(Err(err)) => BlockStoreError::Unavailable(err).response(),
+        Err(err) => BlockStoreError::Unavailable(err.to_string()).response(),
+    }
+}
+
+#[get("/tx/{txid}/raw")]
+async fn get_raw_tx(
+    txid: web::Path<String>,
+    query: web::Query<RawQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Read) {
+        return denied.response();
+    }
+    let txid = match decode_hash(&txid) {
+        Ok(txid) => txid,
+        Err(detail) => return failure(HttpResponse::BadRequest(), &detail),
+    };
+
+    let storage = data.storage.clone();
+    let reader = data.block_reader.clone();
+    match web::block(move || read_raw_tx(storage.as_ref(), &reader, &txid)).await {
+        Ok(Ok(tx)) => raw_response(tx, &query),
+        Ok(Err(err)) => err.response(),
+        Err(err) => BlockStoreError::Unavailable(err.to_string()).response(),
+    }
+}
+
+#[get("/tx/{txid}/proof")]
+async fn get_tx_proof(
+    txid: web::Path<String>,
+    query: web::Query<ProofQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Read) {
+        return denied.response();
+    }
+    let txid = match decode_hash(&txid) {
+        Ok(txid) => txid,
+        Err(detail) => return failure(HttpResponse::BadRequest(), &detail),
+    };
+    let target = query.target.clone().unwrap_or_else(|| "hash".to_string());
+    if !["hash", "header", "merkleRoot"].contains(&target.as_str()) {
+        return failure(
+            HttpResponse::BadRequest(),
+            &format!("Unknown target '{target}', expected hash, header or merkleRoot"),
+        );
+    }
+
+    let storage = data.storage.clone();
+    let reader = data.block_reader.clone();
+    let proof =
+        match web::block(move || read_tx_proof(storage.as_ref(), &reader, &txid, &target)).await {
+            Ok(Ok(proof)) => proof,
+            Ok(Err(err)) => return err.response(),
+            Err(err) => return BlockStoreError::Unavailable(err.to_string()).response(),
+        };
+    match query.format.as_deref() {
+        None | Some("json") => HttpResponse::Ok().json(proof.json()),
+        Some("binary") => HttpResponse::Ok()
+            .content_type(ContentType::octet_stream())
+            .body(proof.binary()),
+        Some("hex") => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body(hex::encode(proof.binary())),
+        Some(format) => failure(
+            HttpResponse::BadRequest(),
+            &format!("Unknown format '{format}', expected json, binary or hex"),
+        ),
+    }
+}
+
+#[get("/tx/{txid}/beef")]
+async fn get_tx_beef(
+    txid: web::Path<String>,
+    query: web::Query<RawQuery>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Read) {
+        return denied.response();
+    }
+    let txid = match decode_hash(&txid) {
+        Ok(txid) => txid,
+        Err(detail) => return failure(HttpResponse::BadRequest(), &detail),
+    };
+
+    let storage = data.storage.clone();
+    let reader = data.block_reader.clone();
+    let beef = web::block(move || {
+        let mut storage = storage.open("rest api").map_err(BeefError::Unavailable)?;
+        build_beef(storage.as_mut(), &reader, &txid)
+    })
+    .await;
+    match beef {
+        Ok(Ok(beef)) => raw_response(beef.write(), &query),
+        Ok(Err(BeefError::NotFound(detail))) => failure(HttpResponse::NotFound(), &detail),
+        Ok(Err(BeefError::TooLarge(detail))) => {
+            failure(HttpResponse::UnprocessableEntity(), &detail)
+        }
+        Ok(Err(BeefError::Unavailable(err))) => BlockStoreError::Unavailable(err).response(),
+        Err(err) => BlockStoreError::Unavailable(err.to_string()).response(),
+    }
+}
+
+#[post("/proof/verify")]
+async fn verify_proof(
+    request: web::Json<VerifyProofRequest>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Read) {
+        return denied.response();
+    }
+    match check_proof(&data.headers, &data.block_reader, &request) {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(detail) => failure(HttpResponse::BadRequest(), &detail),
+    }
+}
+
+#[get("/admin/keys")]
+async fn list_api_keys(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Admin) {
+        return denied.response();
+    }
+
+    HttpResponse::Ok().json(ApiKeyListResponse {
+        keys: data.tenants.list_keys(),
+    })
+}
+
+#[post("/admin/keys")]
+async fn create_api_key(
+    request: web::Json<CreateApiKeyRequest>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Admin) {
+        return denied.response();
+    }
+
+    let request = request.into_inner();
+    match data
+        .tenants
+        .create_key(request.tenant, request.scopes, request.expires_at)
+    {
+        Ok(key) => {
+            log::info!("Created API key {}", key.info.id);
+            HttpResponse::Ok().json(key)
+        }
+        Err(KeyError::Invalid(err)) => failure(HttpResponse::BadRequest(), &err),
+        Err(KeyError::Storage(err)) => failure(HttpResponse::InternalServerError(), &err),
+    }
+}
+
+#[delete("/admin/keys/{key_id}")]
+async fn revoke_api_key(
+    key_id: web::Path<String>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Admin) {
+        return denied.response();
+    }
+
+    match data.tenants.revoke_key(&key_id) {
+        Ok(true) => {
+            log::info!("Revoked API key {key_id}");
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => failure(
+            HttpResponse::NotFound(),
+            &format!("API key '{key_id}' not found"),
+        ),
+        Err(err) => failure(HttpResponse::InternalServerError(), &err),
+    }
+}
+
+#[post("/admin/keys/{key_id}/rotate")]
+async fn rotate_api_key(
+    key_id: web::Path<String>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Admin) {
+        return denied.response();
+    }
+
+    match data.tenants.rotate_key(&key_id) {
+        Ok(Some(key)) => {
+            log::info!("Rotated API key {key_id}");
+            HttpResponse::Ok().json(key)
+        }
+        Ok(None) => failure(
+            HttpResponse::NotFound(),
+            &format!("API key '{key_id}' not found"),
+        ),
+        Err(err) => failure(HttpResponse::InternalServerError(), &err),
+    }
+}
+
+#[get("/admin/metrics")]
+async fn get_metrics(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Some(response) = rate_limit(&req, &data.rate_limiter) {
+        return response;
+    }
+    if let Err(denied) = authorize(&req, &data.tenants, Scope::Admin) {
+        return denied.response();
+    }
+
+    HttpResponse::Ok().json(MetricsResponse {
+        database: data.db_metrics.snapshot(),
+        utxo_cache: data.utxo_metrics.snapshot(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::ApiKeyStore;
+    use crate::config::{ConfigApiKey, DatabaseConfig, StorageType, WriteErrorPolicy};
+    use crate::uaas::block_store::BlockStore;
+    use crate::uaas::storage::{MemoryBackend, MySqlBackend};
+    use actix_web::{test as actix_test, App};
+    use mysql::Pool;
+    use std::sync::mpsc;
+
+    fn mysql_test_url() -> Option<String> {
+        std::env::var("UAAS_TEST_MYSQL_URL").ok()
+    }
+
+    fn mysql_backend(pool: Pool, url: &str) -> Arc<dyn StorageBackend> {
+        let config = DatabaseConfig {
+            backend: StorageType::Sql,
+            mysql_url: url.to_string(),
+            mysql_url_docker: url.to_string(),
+            embedded_path: String::new(),
+            ms_delay: 300,
+            retries: 3,
+            startup_repair: true,
+            write_queue_size: 100,
+            on_write_error: WriteErrorPolicy::Retry,
+            utxo_cache_size: 1000,
+        };
+        Arc::new(MySqlBackend::new(pool, &config))
+    }
+
+    fn live_mysql_backend() -> Option<Arc<dyn StorageBackend>> {
+        let url = mysql_test_url()?;
+        let pool = Pool::new(url.as_str()).expect("failed to connect to test database");
+        Some(mysql_backend(pool, &url))
+    }
+
+    fn invalid_credentials_backend() -> Option<Arc<dyn StorageBackend>> {
+        let url = mysql_test_url()?;
+        let bad_url = url.replacen("maas:maas-password", "maas:not-the-password", 1);
+        let pool = Pool::new(bad_url.as_str()).ok()?;
+        Some(mysql_backend(pool, &bad_url))
+    }
+
+    fn skip_without_mysql(test_name: &str) -> Option<Arc<dyn StorageBackend>> {
+        live_mysql_backend().or_else(|| {
+            eprintln!("skipping {test_name}: UAAS_TEST_MYSQL_URL not set");
+            None
+        })
+    }
+
+    mod broadcast_limits {
+        use super::tx_hex_exceeds_limit;
+
+        #[test]
+        fn tx_hex_within_limit() {
+            assert!(!tx_hex_exceeds_limit(1_999_998, 1_000_000));
+        }
+
+        #[test]
+        fn tx_hex_at_limit() {
+            assert!(!tx_hex_exceeds_limit(2_000_000, 1_000_000));
+        }
+
+        #[test]
+        fn tx_hex_over_limit() {
+            assert!(tx_hex_exceeds_limit(2_000_002, 1_000_000));
+        }
+    }
+
+    mod database_checks {
+        use super::*;
+
+        #[test]
+        fn live_mysql_passes_health_check() {
+            let Some(storage) = skip_without_mysql("live_mysql_passes_health_check") else {
+                return;
+            };
+            check_database(storage.as_ref(), &DatabaseMetrics::new(0))
+                .expect("database health check should succeed");
         }
 
         #[test]
         fn invalid_credentials_fail_health_check() {
-            let Some(pool) = invalid_credentials_pool() else {
+            let Some(storage) = invalid_credentials_backend() else {
                 eprintln!(
                     "skipping invalid_credentials_fail_health_check: \
                      could not create pool with invalid credentials"
                 );
                 return;
             };
-            let result = check_database(&pool);
+            let result = check_database(storage.as_ref(), &DatabaseMetrics::new(0));
             assert!(
                 result.is_err(),
                 "expected database check to fail: {result:?}"
             );
         }
+
+        #[test]
+        fn halted_database_writer_fails_health_check() {
+            let metrics = DatabaseMetrics::new(0);
+            metrics.halt("block write: duplicate key".to_string());
+            let result = check_database(&MemoryBackend::new(), &metrics);
+            assert_eq!(
+                result,
+                Err("database writer halted: block write: duplicate key".to_string())
+            );
+        }
+    }
+
+    fn block_store_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("uaas-rest-{name}-{}", std::process::id()))
+    }
+
+    fn empty_block_reader() -> BlockReader {
+        BlockStore::open(&block_store_dir("empty"), 1 << 20)
+            .expect("block store")
+            .reader()
+    }
+
+    fn test_app_state(storage: Arc<dyn StorageBackend>) -> web::Data<AppState> {
+        test_app_state_with_blocks(storage, empty_block_reader())
     }
 
-    fn test_app_state(db_pool: Pool) -> web::Data<AppState> {
+    fn test_app_state_with_blocks(
+        storage: Arc<dyn StorageBackend>,
+        block_reader: BlockReader,
+    ) -> web::Data<AppState> {
         let (tx, _rx) = mpsc::channel();
         web::Data::new(AppState {
             msg_from_rest_api: tx,
-            api_key: None,
+            tenants: Arc::new(Tenants::new(None, &[], ApiKeyStore::in_memory())),
             rate_limiter: Arc::new(RateLimiter::new(0)),
             max_broadcast_tx_bytes: 1_000_000,
-            db_pool,
+            storage,
+            network: Network::BSV_Testnet,
+            monitors: Arc::new(MonitorRegistry::new()),
+            db_metrics: Arc::new(DatabaseMetrics::new(0)),
+            utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+            block_reader,
+            headers: HeaderChain::default(),
         })
     }
 
     #[actix_web::test]
     async fn health_returns_ok_with_database() {
-        let Some(pool) = skip_without_mysql("health_returns_ok_with_database") else {
+        let Some(storage) = skip_without_mysql("health_returns_ok_with_database") else {
             return;
         };
 
         let app =
-            actix_test::init_service(App::new().app_data(test_app_state(pool)).service(health))
+            actix_test::init_service(App::new().app_data(test_app_state(storage)).service(health))
                 .await;
 
         let response = actix_test::call_service(
@@ -363,7 +1376,7 @@ mod tests {
 
     #[actix_web::test]
     async fn health_returns_503_when_database_unreachable() {
-        let Some(pool) = invalid_credentials_pool() else {
+        let Some(storage) = invalid_credentials_backend() else {
             eprintln!(
                 "skipping health_returns_503_when_database_unreachable: \
                  could not create pool with invalid credentials"
@@ -372,7 +1385,7 @@ mod tests {
         };
 
         let app =
-            actix_test::init_service(App::new().app_data(test_app_state(pool)).service(health))
+            actix_test::init_service(App::new().app_data(test_app_state(storage)).service(health))
                 .await;
 
         let response = actix_test::call_service(
@@ -392,13 +1405,16 @@ mod tests {
 
     #[actix_web::test]
     async fn version_returns_package_version() {
-        let Some(pool) = skip_without_mysql("version_returns_package_version") else {
+        let Some(storage) = skip_without_mysql("version_returns_package_version") else {
             return;
         };
 
-        let app =
-            actix_test::init_service(App::new().app_data(test_app_state(pool)).service(version))
-                .await;
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(test_app_state(storage))
+                .service(version),
+        )
+        .await;
 
         let response = actix_test::call_service(
             &app,
@@ -414,7 +1430,7 @@ mod tests {
 
     #[actix_web::test]
     async fn health_does_not_require_api_key() {
-        let Some(pool) = skip_without_mysql("health_does_not_require_api_key") else {
+        let Some(storage) = skip_without_mysql("health_does_not_require_api_key") else {
             return;
         };
 
@@ -423,10 +1439,20 @@ mod tests {
             App::new()
                 .app_data(web::Data::new(AppState {
                     msg_from_rest_api: tx,
-                    api_key: Some("secret-key".to_string()),
+                    tenants: Arc::new(Tenants::new(
+                        Some(ConfigApiKey::Plain("secret-key".to_string())),
+                        &[],
+                        ApiKeyStore::in_memory(),
+                    )),
                     rate_limiter: Arc::new(RateLimiter::new(0)),
                     max_broadcast_tx_bytes: 1_000_000,
-                    db_pool: pool,
+                    storage,
+                    network: Network::BSV_Testnet,
+                    monitors: Arc::new(MonitorRegistry::new()),
+                    db_metrics: Arc::new(DatabaseMetrics::new(0)),
+                    utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+                    block_reader: empty_block_reader(),
+                    headers: HeaderChain::default(),
                 }))
                 .service(health),
         )
@@ -443,7 +1469,8 @@ mod tests {
 
     #[actix_web::test]
     async fn broadcast_tx_requires_api_key_when_configured() {
-        let Some(pool) = skip_without_mysql("broadcast_tx_requires_api_key_when_configured") else {
+        let Some(storage) = skip_without_mysql("broadcast_tx_requires_api_key_when_configured")
+        else {
             return;
         };
 
@@ -452,10 +1479,20 @@ mod tests {
             App::new()
                 .app_data(web::Data::new(AppState {
                     msg_from_rest_api: tx,
-                    api_key: Some("secret-key".to_string()),
+                    tenants: Arc::new(Tenants::new(
+                        Some(ConfigApiKey::Plain("secret-key".to_string())),
+                        &[],
+                        ApiKeyStore::in_memory(),
+                    )),
                     rate_limiter: Arc::new(RateLimiter::new(0)),
                     max_broadcast_tx_bytes: 1_000_000,
-                    db_pool: pool,
+                    storage,
+                    network: Network::BSV_Testnet,
+                    monitors: Arc::new(MonitorRegistry::new()),
+                    db_metrics: Arc::new(DatabaseMetrics::new(0)),
+                    utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+                    block_reader: empty_block_reader(),
+                    headers: HeaderChain::default(),
                 }))
                 .service(broadcast_tx),
         )
@@ -482,7 +1519,7 @@ mod tests {
 
     #[actix_web::test]
     async fn sec04_health_is_exempt_from_rate_limit() {
-        let Some(pool) = skip_without_mysql("sec04_health_is_exempt_from_rate_limit") else {
+        let Some(storage) = skip_without_mysql("sec04_health_is_exempt_from_rate_limit") else {
             return;
         };
 
@@ -491,10 +1528,16 @@ mod tests {
             App::new()
                 .app_data(web::Data::new(AppState {
                     msg_from_rest_api: tx,
-                    api_key: None,
+                    tenants: Arc::new(Tenants::new(None, &[], ApiKeyStore::in_memory())),
                     rate_limiter: Arc::new(RateLimiter::new(1)),
                     max_broadcast_tx_bytes: 1_000_000,
-                    db_pool: pool,
+                    storage,
+                    network: Network::BSV_Testnet,
+                    monitors: Arc::new(MonitorRegistry::new()),
+                    db_metrics: Arc::new(DatabaseMetrics::new(0)),
+                    utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+                    block_reader: empty_block_reader(),
+                    headers: HeaderChain::default(),
                 }))
                 .service(health),
         )
@@ -514,7 +1557,8 @@ mod tests {
     async fn bcast05_broadcast_tx_queues_valid_transaction() {
         use chain_gang::{messages::Tx, util::Serializable};
 
-        let Some(pool) = skip_without_mysql("bcast05_broadcast_tx_queues_valid_transaction") else {
+        let Some(storage) = skip_without_mysql("bcast05_broadcast_tx_queues_valid_transaction")
+        else {
             return;
         };
 
@@ -533,10 +1577,16 @@ mod tests {
             App::new()
                 .app_data(web::Data::new(AppState {
                     msg_from_rest_api: rest_tx,
-                    api_key: None,
+                    tenants: Arc::new(Tenants::new(None, &[], ApiKeyStore::in_memory())),
                     rate_limiter: Arc::new(RateLimiter::new(0)),
                     max_broadcast_tx_bytes: 1_000_000,
-                    db_pool: pool,
+                    storage,
+                    network: Network::BSV_Testnet,
+                    monitors: Arc::new(MonitorRegistry::new()),
+                    db_metrics: Arc::new(DatabaseMetrics::new(0)),
+                    utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+                    block_reader: empty_block_reader(),
+                    headers: HeaderChain::default(),
                 }))
                 .service(broadcast_tx),
         )
@@ -557,4 +1607,875 @@ mod tests {
             Ok(RestEventMessage::TxForBroadcast(_))
         ));
     }
+
+    #[actix_web::test]
+    async fn broadcast_tx_accepts_beef() {
+        use chain_gang::messages::{OutPoint, TxIn};
+
+        let parent = Tx {
+            lock_time: 1,
+            ..Default::default()
+        };
+        let child = Tx {
+            inputs: vec![TxIn {
+                prev_output: OutPoint {
+                    hash: parent.hash(),
+                    index: 0,
+                },
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut beef = Beef::default();
+        let proof =
+            MerkleProof::new(&[parent.hash()], 0, ProofTarget::MerkleRoot(parent.hash())).unwrap();
+        beef.add_proven_tx(parent, 5, &proof);
+        beef.add_tx(child.clone());
+
+        let (rest_tx, rest_rx) = mpsc::channel();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState {
+                    msg_from_rest_api: rest_tx,
+                    tenants: Arc::new(Tenants::new(None, &[], ApiKeyStore::in_memory())),
+                    rate_limiter: Arc::new(RateLimiter::new(0)),
+                    max_broadcast_tx_bytes: 1_000_000,
+                    storage: Arc::new(MemoryBackend::new()),
+                    network: Network::BSV_Testnet,
+                    monitors: Arc::new(MonitorRegistry::new()),
+                    db_metrics: Arc::new(DatabaseMetrics::new(0)),
+                    utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+                    block_reader: empty_block_reader(),
+                    headers: HeaderChain::default(),
+                }))
+                .service(broadcast_tx),
+        )
+        .await;
+
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            actix_test::TestRequest::post()
+                .uri("/tx/raw")
+                .set_payload(hex::encode(beef.write()))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(body["status"], "Success");
+        assert_eq!(body["detail"], child.hash().encode());
+        // Only the unconfirmed tx is broadcast
+        assert_eq!(
+            rest_rx.try_recv(),
+            Ok(RestEventMessage::TxForBroadcast(child))
+        );
+        assert!(rest_rx.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn add_monitor_rejects_invalid_pattern() {
+        let Some(storage) = skip_without_mysql("add_monitor_rejects_invalid_pattern") else {
+            return;
+        };
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(test_app_state(storage))
+                .service(add_monitor),
+        )
+        .await;
+
+        let response = actix_test::call_service(
+            &app,
+            actix_test::TestRequest::post()
+                .uri("/collection/monitor")
+                .set_json(serde_json::json!({
+                    "name": "bad-pattern",
+                    "track_descendants": false,
+                    "locking_script_pattern": "76a9[",
+                }))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn monitor_is_listed_after_add_and_removed_after_delete() {
+        let Some(storage) =
+            skip_without_mysql("monitor_is_listed_after_add_and_removed_after_delete")
+        else {
+            return;
+        };
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(test_app_state(storage))
+                .service(list_monitors)
+                .service(get_monitor)
+                .service(add_monitor)
+                .service(delete_monitor),
+        )
+        .await;
+
+        let monitor = serde_json::json!({
+            "name": "listed",
+            "track_descendants": false,
+            "locking_script_pattern": "76a914",
+        });
+        for expected in [200, 409] {
+            let response = actix_test::call_service(
+                &app,
+                actix_test::TestRequest::post()
+                    .uri("/collection/monitor")
+                    .set_json(&monitor)
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(response.status(), expected);
+        }
+
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            actix_test::TestRequest::get()
+                .uri("/collection/monitor")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(body["monitors"][0]["name"], "listed");
+        assert_eq!(body["monitors"][0]["source"], "dynamic");
+
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            actix_test::TestRequest::get()
+                .uri("/collection/monitor/listed")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(body["match_count"], 0);
+
+        let response = actix_test::call_service(
+            &app,
+            actix_test::TestRequest::delete()
+                .uri("/collection/monitor/listed")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+
+        let response = actix_test::call_service(
+            &app,
+            actix_test::TestRequest::get()
+                .uri("/collection/monitor/listed")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn tenant_monitors_are_private_and_limited_by_quota() {
+        use crate::config::TenantConfig;
+
+        let Some(storage) = skip_without_mysql("tenant_monitors_are_private_and_limited_by_quota")
+        else {
+            return;
+        };
+
+        let tenant = |name: &str| TenantConfig {
+            name: name.to_string(),
+            api_key: Some(format!("{name}-key")),
+            api_key_hash: None,
+            api_key_salt: None,
+            scopes: vec![Scope::Read, Scope::MonitorWrite],
+            max_monitors: Some(1),
+            broadcast_per_minute: 0,
+        };
+        let (tx, _rx) = mpsc::channel();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState {
+                    msg_from_rest_api: tx,
+                    tenants: Arc::new(Tenants::new(
+                        Some(ConfigApiKey::Plain("operator-key".to_string())),
+                        &[tenant("team-a"), tenant("team-b")],
+                        ApiKeyStore::in_memory(),
+                    )),
+                    rate_limiter: Arc::new(RateLimiter::new(0)),
+                    max_broadcast_tx_bytes: 1_000_000,
+                    storage,
+                    network: Network::BSV_Testnet,
+                    monitors: Arc::new(MonitorRegistry::new()),
+                    db_metrics: Arc::new(DatabaseMetrics::new(0)),
+                    utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+                    block_reader: empty_block_reader(),
+                    headers: HeaderChain::default(),
+                }))
+                .service(list_monitors)
+                .service(get_monitor)
+                .service(add_monitor),
+        )
+        .await;
+
+        let monitor = |name: &str| {
+            serde_json::json!({
+                "name": name,
+                "track_descendants": false,
+                "locking_script_pattern": "76a914",
+            })
+        };
+        for (name, expected) in [("team-a-1", 200), ("team-a-2", 403)] {
+            let response = actix_test::call_service(
+                &app,
+                actix_test::TestRequest::post()
+                    .uri("/collection/monitor")
+                    .insert_header((API_KEY_HEADER, "team-a-key"))
+                    .set_json(monitor(name))
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(response.status(), expected);
+        }
+
+        let list = |key: &'static str| {
+            actix_test::TestRequest::get()
+                .uri("/collection/monitor")
+                .insert_header((API_KEY_HEADER, key))
+                .to_request()
+        };
+        let body: serde_json::Value =
+            actix_test::call_and_read_body_json(&app, list("team-a-key")).await;
+        assert_eq!(body["monitors"][0]["owner"], "team-a");
+        let body: serde_json::Value =
+            actix_test::call_and_read_body_json(&app, list("team-b-key")).await;
+        assert_eq!(body["monitors"].as_array().map(Vec::len), Some(0));
+        let body: serde_json::Value =
+            actix_test::call_and_read_body_json(&app, list("operator-key")).await;
+        assert_eq!(body["monitors"].as_array().map(Vec::len), Some(1));
+
+        // Each tenant has its own monitor names
+        let response = actix_test::call_service(
+            &app,
+            actix_test::TestRequest::post()
+                .uri("/collection/monitor")
+                .insert_header((API_KEY_HEADER, "team-b-key"))
+                .set_json(monitor("team-a-1"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+
+        let get = |uri: &str, key: &'static str| {
+            actix_test::TestRequest::get()
+                .uri(uri)
+                .insert_header((API_KEY_HEADER, key))
+                .to_request()
+        };
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            get("/collection/monitor/team-a-1", "team-b-key"),
+        )
+        .await;
+        assert_eq!(body["owner"], "team-b");
+        // The operator names a tenant's monitor with its owner
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            get("/collection/monitor/team-a-1?owner=team-a", "operator-key"),
+        )
+        .await;
+        assert_eq!(body["owner"], "team-a");
+        let response =
+            actix_test::call_service(&app, get("/collection/monitor/team-a-1", "operator-key"))
+                .await;
+        assert_eq!(response.status(), 404);
+        // A tenant can not name another tenant's monitor
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            get("/collection/monitor/team-a-1?owner=team-a", "team-b-key"),
+        )
+        .await;
+        assert_eq!(body["owner"], "team-b");
+    }
+
+    #[actix_web::test]
+    async fn admin_keys_can_be_created_rotated_and_revoked() {
+        let Some(storage) = skip_without_mysql("admin_keys_can_be_created_rotated_and_revoked")
+        else {
+            return;
+        };
+
+        let (tx, _rx) = mpsc::channel();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState {
+                    msg_from_rest_api: tx,
+                    tenants: Arc::new(Tenants::new(
+                        Some(ConfigApiKey::Plain("operator-key".to_string())),
+                        &[],
+                        ApiKeyStore::in_memory(),
+                    )),
+                    rate_limiter: Arc::new(RateLimiter::new(0)),
+                    max_broadcast_tx_bytes: 1_000_000,
+                    storage,
+                    network: Network::BSV_Testnet,
+                    monitors: Arc::new(MonitorRegistry::new()),
+                    db_metrics: Arc::new(DatabaseMetrics::new(0)),
+                    utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+                    block_reader: empty_block_reader(),
+                    headers: HeaderChain::default(),
+                }))
+                .service(list_monitors)
+                .service(list_api_keys)
+                .service(create_api_key)
+                .service(revoke_api_key)
+                .service(rotate_api_key),
+        )
+        .await;
+
+        let created: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            actix_test::TestRequest::post()
+                .uri("/admin/keys")
+                .insert_header((API_KEY_HEADER, "operator-key"))
+                .set_json(serde_json::json!({ "scopes": ["read"] }))
+                .to_request(),
+        )
+        .await;
+        let id = created["id"].as_str().expect("key id").to_string();
+        let key = created["api_key"].as_str().expect("api key").to_string();
+
+        let status_with = |key: String, uri: &str| {
+            actix_test::TestRequest::get()
+                .uri(uri)
+                .insert_header((API_KEY_HEADER, key))
+                .to_request()
+        };
+        let response =
+            actix_test::call_service(&app, status_with(key.clone(), "/collection/monitor")).await;
+        assert_eq!(response.status(), 200);
+        // A read key can not manage keys
+        let response =
+            actix_test::call_service(&app, status_with(key.clone(), "/admin/keys")).await;
+        assert_eq!(response.status(), 403);
+
+        let rotated: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            actix_test::TestRequest::post()
+                .uri(&format!("/admin/keys/{id}/rotate"))
+                .insert_header((API_KEY_HEADER, "operator-key"))
+                .to_request(),
+        )
+        .await;
+        let rotated_key = rotated["api_key"].as_str().expect("api key").to_string();
+        let response =
+            actix_test::call_service(&app, status_with(key, "/collection/monitor")).await;
+        assert_eq!(response.status(), 401);
+
+        let response = actix_test::call_service(
+            &app,
+            actix_test::TestRequest::delete()
+                .uri(&format!("/admin/keys/{id}"))
+                .insert_header((API_KEY_HEADER, "operator-key"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        let response =
+            actix_test::call_service(&app, status_with(rotated_key, "/collection/monitor")).await;
+        assert_eq!(response.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn admin_keys_need_a_configured_key() {
+        let (tx, _rx) = mpsc::channel();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState {
+                    msg_from_rest_api: tx,
+                    tenants: Arc::new(Tenants::new(None, &[], ApiKeyStore::in_memory())),
+                    rate_limiter: Arc::new(RateLimiter::new(0)),
+                    max_broadcast_tx_bytes: 1_000_000,
+                    storage: Arc::new(MemoryBackend::new()),
+                    network: Network::BSV_Testnet,
+                    monitors: Arc::new(MonitorRegistry::new()),
+                    db_metrics: Arc::new(DatabaseMetrics::new(0)),
+                    utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+                    block_reader: empty_block_reader(),
+                    headers: HeaderChain::default(),
+                }))
+                .service(list_api_keys)
+                .service(create_api_key),
+        )
+        .await;
+
+        // Without any keys the API is open, but not for managing keys
+        let response = actix_test::call_service(
+            &app,
+            actix_test::TestRequest::post()
+                .uri("/admin/keys")
+                .set_json(serde_json::json!({ "scopes": ["admin"] }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 403);
+        let response = actix_test::call_service(
+            &app,
+            actix_test::TestRequest::get()
+                .uri("/admin/keys")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn utxo_commitment_is_returned_for_a_stored_block() {
+        use crate::uaas::database::BlockHeaderWriteDB;
+        use chain_gang::util::Hash256;
+
+        let backend = MemoryBackend::new();
+        backend
+            .open("test")
+            .unwrap()
+            .block_header_write(&BlockHeaderWriteDB {
+                height: 5,
+                hash: Hash256([1; 32]),
+                utxo_commitment: Some(Hash256([2; 32])),
+                ..Default::default()
+            })
+            .unwrap();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(test_app_state(Arc::new(backend)))
+                .service(get_utxo_commitment),
+        )
+        .await;
+
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            actix_test::TestRequest::get()
+                .uri("/utxo/commitment?height=5")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(body["height"], 5);
+        assert_eq!(body["block_hash"], Hash256([1; 32]).encode());
+        assert_eq!(body["commitment"], Hash256([2; 32]).encode());
+
+        let response = actix_test::call_service(
+            &app,
+            actix_test::TestRequest::get()
+                .uri("/utxo/commitment?height=6")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn raw_blocks_and_txs_are_served_from_the_block_store() {
+        use crate::uaas::database::{BlockHeaderWriteDB, TxEntryWriteDB};
+        use chain_gang::messages::{Block, BlockHeader};
+
+        let tx = |lock_time: u32| Tx {
+            lock_time,
+            ..Default::default()
+        };
+        let block = Block {
+            header: BlockHeader::default(),
+            txns: vec![tx(1), tx(2)],
+        };
+        let hash = block.header.hash();
+        let txid = block.txns[1].hash();
+        let dir = block_store_dir("raw");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut store = BlockStore::open(&dir, 1 << 20).unwrap();
+        let position = store.append(&block).unwrap();
+
+        let backend = MemoryBackend::new();
+        backend.data().blocks.insert(
+            hash,
+            BlockHeaderWriteDB {
+                height: 7,
+                hash,
+                position,
+                ..Default::default()
+            },
+        );
+        backend.data().tx.insert(
+            txid,
+            TxEntryWriteDB {
+                hash: txid,
+                height: 7,
+                blockindex: 1,
+                size: 0,
+                satoshis: 0,
+            },
+        );
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(test_app_state_with_blocks(
+                    Arc::new(backend),
+                    store.reader(),
+                ))
+                .service(get_raw_block)
+                .service(get_block_txids)
+                .service(get_raw_tx),
+        )
+        .await;
+        let get = |uri: String| actix_test::TestRequest::get().uri(&uri).to_request();
+
+        let mut serialised = Vec::new();
+        block.write(&mut serialised).unwrap();
+        let body =
+            actix_test::call_and_read_body(&app, get(format!("/block/{}/raw", hash.encode())))
+                .await;
+        assert_eq!(body.as_ref(), serialised.as_slice());
+
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            get(format!("/block/{}/txids", hash.encode())),
+        )
+        .await;
+        assert_eq!(body["txids"][1], txid.encode());
+
+        let mut expected = Vec::new();
+        block.txns[1].write(&mut expected).unwrap();
+        let body = actix_test::call_and_read_body(
+            &app,
+            get(format!("/tx/{}/raw?format=hex", txid.encode())),
+        )
+        .await;
+        assert_eq!(body.as_ref(), hex::encode(expected).as_bytes());
+
+        for uri in [
+            format!("/tx/{}/raw", block.txns[0].hash().encode()),
+            format!("/block/{}/raw", Hash256([3; 32]).encode()),
+        ] {
+            let response = actix_test::call_service(&app, get(uri)).await;
+            assert_eq!(response.status(), 404);
+        }
+        let response = actix_test::call_service(&app, get("/block/xyz/txids".to_string())).await;
+        assert_eq!(response.status(), 400);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn merkle_proofs_are_built_from_the_stored_block() {
+        use crate::uaas::database::{BlockHeaderWriteDB, TxEntryWriteDB};
+        use chain_gang::messages::{Block, BlockHeader};
+
+        let txns: Vec<Tx> = (1..4)
+            .map(|lock_time| Tx {
+                lock_time,
+                ..Default::default()
+            })
+            .collect();
+        let txids: Vec<Hash256> = txns.iter().map(|tx| tx.hash()).collect();
+        let merkle_root = MerkleProof::new(&txids, 0, ProofTarget::BlockHash(Hash256::default()))
+            .unwrap()
+            .root()
+            .unwrap();
+        let block = Block {
+            header: BlockHeader {
+                merkle_root,
+                ..Default::default()
+            },
+            txns,
+        };
+        let hash = block.header.hash();
+        let dir = block_store_dir("proof");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut store = BlockStore::open(&dir, 1 << 20).unwrap();
+        let position = store.append(&block).unwrap();
+
+        let backend = MemoryBackend::new();
+        backend.data().blocks.insert(
+            hash,
+            BlockHeaderWriteDB {
+                height: 7,
+                hash,
+                position,
+                ..Default::default()
+            },
+        );
+        for (blockindex, txid) in txids.iter().enumerate() {
+            backend.data().tx.insert(
+                *txid,
+                TxEntryWriteDB {
+                    hash: *txid,
+                    height: 7,
+                    blockindex: blockindex as u32,
+                    size: 0,
+                    satoshis: 0,
+                },
+            );
+        }
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(test_app_state_with_blocks(
+                    Arc::new(backend),
+                    store.reader(),
+                ))
+                .service(get_tx_proof),
+        )
+        .await;
+        let get = |uri: String| actix_test::TestRequest::get().uri(&uri).to_request();
+
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            get(format!("/tx/{}/proof", txids[2].encode())),
+        )
+        .await;
+        assert_eq!(body["index"], 2);
+        assert_eq!(body["txOrId"], txids[2].encode());
+        assert_eq!(body["targetType"], "hash");
+        assert_eq!(body["target"], hash.encode());
+        assert_eq!(body["nodes"][0], "*");
+
+        let body = actix_test::call_and_read_body(
+            &app,
+            get(format!(
+                "/tx/{}/proof?format=binary&target=merkleRoot",
+                txids[1].encode()
+            )),
+        )
+        .await;
+        let expected = MerkleProof::new(&txids, 1, ProofTarget::MerkleRoot(merkle_root)).unwrap();
+        assert_eq!(body.as_ref(), expected.binary().as_slice());
+
+        let response = actix_test::call_service(
+            &app,
+            get(format!("/tx/{}/proof", Hash256([3; 32]).encode())),
+        )
+        .await;
+        assert_eq!(response.status(), 404);
+        let response = actix_test::call_service(
+            &app,
+            get(format!("/tx/{}/proof?target=block", txids[0].encode())),
+        )
+        .await;
+        assert_eq!(response.status(), 400);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn proofs_are_verified_against_the_header_chain() {
+        use chain_gang::messages::BlockHeader;
+
+        let txids: Vec<Hash256> = (0..3).map(|value| Hash256([value; 32])).collect();
+        let merkle_root = MerkleProof::new(&txids, 0, ProofTarget::BlockHash(Hash256::default()))
+            .unwrap()
+            .root()
+            .unwrap();
+        let header = BlockHeader {
+            merkle_root,
+            ..Default::default()
+        };
+        let headers = HeaderChain::default();
+        headers.push(10, header);
+        headers.push(
+            11,
+            BlockHeader {
+                prev_hash: header.hash(),
+                ..Default::default()
+            },
+        );
+
+        let (tx, _rx) = mpsc::channel();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState {
+                    msg_from_rest_api: tx,
+                    tenants: Arc::new(Tenants::new(None, &[], ApiKeyStore::in_memory())),
+                    rate_limiter: Arc::new(RateLimiter::new(0)),
+                    max_broadcast_tx_bytes: 1_000_000,
+                    storage: Arc::new(MemoryBackend::new()),
+                    network: Network::BSV_Testnet,
+                    monitors: Arc::new(MonitorRegistry::new()),
+                    db_metrics: Arc::new(DatabaseMetrics::new(0)),
+                    utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+                    block_reader: empty_block_reader(),
+                    headers,
+                }))
+                .service(verify_proof),
+        )
+        .await;
+        let verify = |body: serde_json::Value| {
+            actix_test::TestRequest::post()
+                .uri("/proof/verify")
+                .set_json(body)
+                .to_request()
+        };
+
+        let proof = MerkleProof::new(&txids, 2, ProofTarget::BlockHash(header.hash())).unwrap();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            verify(serde_json::to_value(proof.json()).unwrap()),
+        )
+        .await;
+        assert_eq!(body["valid"], true);
+        assert_eq!(body["height"], 10);
+        assert_eq!(body["confirmations"], 2);
+
+        let mut path = MerklePath::new(10);
+        path.add(&proof);
+        let mut bump = Vec::new();
+        path.write(&mut bump);
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            verify(serde_json::json!({ "bump": hex::encode(&bump), "txid": txids[2].encode() })),
+        )
+        .await;
+        assert_eq!(body["valid"], true);
+        assert_eq!(body["block_hash"], header.hash().encode());
+
+        // A proof of a tx that is not in the block
+        let mut forged = proof.json();
+        forged.tx_or_id = Hash256([9; 32]).encode();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            verify(serde_json::to_value(forged).unwrap()),
+        )
+        .await;
+        assert_eq!(body["valid"], false);
+        assert_eq!(body["height"], 10);
+
+        let response = actix_test::call_service(
+            &app,
+            verify(serde_json::json!({ "bump": "zz", "txid": txids[2].encode() })),
+        )
+        .await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn forged_proofs_are_not_valid() {
+        use crate::uaas::merkle::merkle_parent;
+        use chain_gang::messages::{Block, BlockHeader};
+
+        let txns: Vec<Tx> = (1..4)
+            .map(|lock_time| Tx {
+                lock_time,
+                ..Default::default()
+            })
+            .collect();
+        let txids: Vec<Hash256> = txns.iter().map(|tx| tx.hash()).collect();
+        let proof =
+            MerkleProof::new(&txids, 2, ProofTarget::BlockHash(Hash256::default())).unwrap();
+        let block = Block {
+            header: BlockHeader {
+                merkle_root: proof.root().unwrap(),
+                ..Default::default()
+            },
+            txns,
+        };
+        let hash = block.header.hash();
+        let dir = block_store_dir("forged-proof");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut store = BlockStore::open(&dir, 1 << 20).unwrap();
+        store.append(&block).unwrap();
+        // A block that is not in the block store
+        let unstored = BlockHeader {
+            prev_hash: hash,
+            merkle_root: Hash256([7; 32]),
+            ..Default::default()
+        };
+        let headers = HeaderChain::default();
+        headers.push(10, block.header);
+        headers.push(11, unstored);
+
+        let (tx, _rx) = mpsc::channel();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState {
+                    msg_from_rest_api: tx,
+                    tenants: Arc::new(Tenants::new(None, &[], ApiKeyStore::in_memory())),
+                    rate_limiter: Arc::new(RateLimiter::new(0)),
+                    max_broadcast_tx_bytes: 1_000_000,
+                    storage: Arc::new(MemoryBackend::new()),
+                    network: Network::BSV_Testnet,
+                    monitors: Arc::new(MonitorRegistry::new()),
+                    db_metrics: Arc::new(DatabaseMetrics::new(0)),
+                    utxo_metrics: Arc::new(UtxoCacheMetrics::new(1)),
+                    block_reader: store.reader(),
+                    headers,
+                }))
+                .service(verify_proof),
+        )
+        .await;
+        let verify = |body: serde_json::Value| {
+            actix_test::TestRequest::post()
+                .uri("/proof/verify")
+                .set_json(body)
+                .to_request()
+        };
+        let tsc = |index: u32, txid: Hash256, target: Hash256, nodes: Vec<Option<Hash256>>| {
+            verify(
+                serde_json::to_value(
+                    MerkleProof {
+                        index,
+                        txid,
+                        target: ProofTarget::BlockHash(target),
+                        nodes,
+                    }
+                    .json(),
+                )
+                .unwrap(),
+            )
+        };
+
+        let body: serde_json::Value =
+            actix_test::call_and_read_body_json(&app, tsc(2, txids[2], hash, proof.nodes.clone()))
+                .await;
+        assert_eq!(body["valid"], true);
+
+        // The last tx as the copy of itself, and with bits of the index above the proof
+        for index in [3, 6] {
+            let response =
+                actix_test::call_service(&app, tsc(index, txids[2], hash, proof.nodes.clone()))
+                    .await;
+            assert_eq!(response.status(), 400, "index {index}");
+        }
+
+        // An inner node of the tree passed off as a tx, as a proof and as a merkle path
+        let inner = MerkleProof {
+            index: 0,
+            txid: merkle_parent(&txids[0], &txids[1]),
+            target: ProofTarget::BlockHash(hash),
+            nodes: vec![Some(merkle_parent(&txids[2], &txids[2]))],
+        };
+        assert_eq!(inner.root(), Ok(block.header.merkle_root));
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            tsc(0, inner.txid, hash, inner.nodes.clone()),
+        )
+        .await;
+        assert_eq!(body["valid"], false);
+        let mut path = MerklePath::new(10);
+        path.add(&inner);
+        let mut bump = Vec::new();
+        path.write(&mut bump);
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            verify(serde_json::json!({ "bump": hex::encode(&bump), "txid": inner.txid.encode() })),
+        )
+        .await;
+        assert_eq!(body["valid"], false);
+
+        // The merkle root as a tx with no nodes, in a stored block and in one that is not
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            tsc(0, block.header.merkle_root, hash, Vec::new()),
+        )
+        .await;
+        assert_eq!(body["valid"], false);
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            tsc(0, unstored.merkle_root, unstored.hash(), Vec::new()),
+        )
+        .await;
+        assert_eq!(body["valid"], false);
+        assert_eq!(body["height"], 11);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
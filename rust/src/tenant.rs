@@ -0,0 +1,369 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api_keys::{unix_time, ApiKeyInfo, ApiKeyRecord, ApiKeyStore, NewApiKey},
+    config::{ConfigApiKey, TenantConfig},
+    rate_limit::RateLimiter,
+};
+
+// What an API key is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Scope {
+    #[serde(rename = "broadcast")]
+    Broadcast,
+    #[serde(rename = "monitor:write")]
+    MonitorWrite,
+    #[serde(rename = "read")]
+    Read,
+    // Manage API keys, only available to operator keys
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    // Scopes given to a key when none are requested
+    pub fn defaults() -> Vec<Scope> {
+        vec![Scope::Broadcast, Scope::MonitorWrite, Scope::Read]
+    }
+}
+
+pub struct Tenant {
+    pub name: String,
+    // The most that any key of this tenant may be granted
+    pub scopes: Vec<Scope>,
+    pub max_monitors: Option<usize>,
+    broadcast_limiter: RateLimiter,
+}
+
+// The authenticated caller of a REST endpoint
+#[derive(Clone)]
+pub struct Caller {
+    // None for operator keys, which see everything
+    tenant: Option<Arc<Tenant>>,
+    scopes: Vec<Scope>,
+}
+
+impl Caller {
+    fn operator() -> Self {
+        let mut scopes = Scope::defaults();
+        scopes.push(Scope::Admin);
+        Caller {
+            tenant: None,
+            scopes,
+        }
+    }
+
+    // The caller when no keys are configured or stored, which can not manage keys,
+    // so a caller without a key can not lock out the operator by creating the first one
+    fn anonymous() -> Self {
+        Caller {
+            tenant: None,
+            scopes: Scope::defaults(),
+        }
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    // Owner to record against monitors created by this caller
+    pub fn owner(&self) -> Option<String> {
+        self.tenant.as_ref().map(|tenant| tenant.name.clone())
+    }
+
+    // Tenants only see their own monitors and collections
+    pub fn can_see(&self, owner: Option<&str>) -> bool {
+        match &self.tenant {
+            None => true,
+            Some(tenant) => owner == Some(tenant.name.as_str()),
+        }
+    }
+
+    pub fn max_monitors(&self) -> Option<usize> {
+        self.tenant.as_ref().and_then(|tenant| tenant.max_monitors)
+    }
+
+    // Apply the tenant broadcast quota
+    pub fn allow_broadcast(&self) -> bool {
+        match &self.tenant {
+            None => true,
+            Some(tenant) => tenant.broadcast_limiter.allow(&tenant.name),
+        }
+    }
+}
+
+// Reasons a request for a new key can fail
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyError {
+    Invalid(String),
+    Storage(String),
+}
+
+/// The API keys accepted by the REST API and the tenants they belong to.
+pub struct Tenants {
+    tenants: HashMap<String, Arc<Tenant>>,
+    // Keys from the config file, hashed when loaded
+    config_keys: Vec<ApiKeyRecord>,
+    // Keys managed through the admin endpoints
+    store: RwLock<ApiKeyStore>,
+}
+
+impl Tenants {
+    pub fn new(
+        api_key: Option<ConfigApiKey>,
+        tenants: &[TenantConfig],
+        store: ApiKeyStore,
+    ) -> Self {
+        let mut config_keys = Vec::new();
+        if let Some(api_key) = api_key {
+            config_keys.push(ApiKeyRecord::from_config(
+                "config",
+                None,
+                Caller::operator().scopes,
+                &api_key,
+            ));
+        }
+        for config in tenants.iter() {
+            // The keys have been checked by validate_startup
+            if let Ok(Some(api_key)) = config.configured_key() {
+                config_keys.push(ApiKeyRecord::from_config(
+                    &format!("config:{}", config.name),
+                    Some(config.name.clone()),
+                    config.scopes.clone(),
+                    &api_key,
+                ));
+            }
+        }
+
+        let tenants = tenants
+            .iter()
+            .map(|config| {
+                let tenant = Tenant {
+                    name: config.name.clone(),
+                    scopes: config.scopes.clone(),
+                    max_monitors: config.max_monitors,
+                    broadcast_limiter: RateLimiter::new(config.broadcast_per_minute),
+                };
+                (config.name.clone(), Arc::new(tenant))
+            })
+            .collect();
+
+        Tenants {
+            tenants,
+            config_keys,
+            store: RwLock::new(store),
+        }
+    }
+
+    fn read_store(&self) -> RwLockReadGuard<'_, ApiKeyStore> {
+        self.store.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_store(&self) -> RwLockWriteGuard<'_, ApiKeyStore> {
+        self.store.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn caller(&self, record: &ApiKeyRecord) -> Option<Caller> {
+        let Some(name) = &record.tenant else {
+            return Some(Caller {
+                tenant: None,
+                scopes: record.scopes.clone(),
+            });
+        };
+        let Some(tenant) = self.tenants.get(name) else {
+            log::warn!("API key {} belongs to unknown tenant {name}", record.id);
+            return None;
+        };
+        // Scopes removed from the tenant in the config also stop working for its keys
+        let scopes = record
+            .scopes
+            .iter()
+            .filter(|scope| tenant.scopes.contains(scope))
+            .copied()
+            .collect();
+        Some(Caller {
+            tenant: Some(tenant.clone()),
+            scopes,
+        })
+    }
+
+    // Return the caller for the provided key, None if the key is not accepted
+    pub fn authenticate(&self, provided: Option<&str>) -> Option<Caller> {
+        let store = self.read_store();
+        if self.config_keys.is_empty() && store.is_empty() {
+            return Some(Caller::anonymous());
+        }
+        let provided = provided?;
+        let record = store.verify(provided, unix_time()).or_else(|| {
+            self.config_keys
+                .iter()
+                .find(|record| record.matches(provided))
+        })?;
+        self.caller(record)
+    }
+
+    pub fn list_keys(&self) -> Vec<ApiKeyInfo> {
+        self.read_store().list()
+    }
+
+    pub fn create_key(
+        &self,
+        tenant: Option<String>,
+        scopes: Option<Vec<Scope>>,
+        expires_at: Option<u64>,
+    ) -> Result<NewApiKey, KeyError> {
+        let allowed = match &tenant {
+            Some(name) => match self.tenants.get(name) {
+                Some(tenant) => tenant.scopes.clone(),
+                None => return Err(KeyError::Invalid(format!("Unknown tenant '{name}'"))),
+            },
+            None => Caller::operator().scopes,
+        };
+        let scopes = scopes.unwrap_or_else(|| {
+            let mut scopes = Scope::defaults();
+            scopes.retain(|scope| allowed.contains(scope));
+            scopes
+        });
+        if scopes.is_empty() {
+            return Err(KeyError::Invalid("Key must have at least one scope".into()));
+        }
+        if let Some(scope) = scopes.iter().find(|scope| !allowed.contains(scope)) {
+            return Err(KeyError::Invalid(format!(
+                "Scope {scope:?} is not allowed for this key"
+            )));
+        }
+        if expires_at.is_some_and(|expires_at| expires_at <= unix_time()) {
+            return Err(KeyError::Invalid("expires_at is in the past".into()));
+        }
+        self.write_store()
+            .create(tenant, scopes, expires_at)
+            .map_err(KeyError::Storage)
+    }
+
+    // Return false if there is no key with this id
+    pub fn revoke_key(&self, id: &str) -> Result<bool, String> {
+        self.write_store().revoke(id)
+    }
+
+    pub fn rotate_key(&self, id: &str) -> Result<Option<NewApiKey>, String> {
+        self.write_store().rotate(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::hash_config_key;
+
+    fn tenant(name: &str, scopes: Vec<Scope>, broadcast_per_minute: u32) -> TenantConfig {
+        TenantConfig {
+            name: name.to_string(),
+            api_key: Some(format!("{name}-key")),
+            api_key_hash: None,
+            api_key_salt: None,
+            scopes,
+            max_monitors: Some(1),
+            broadcast_per_minute,
+        }
+    }
+
+    #[test]
+    fn open_without_keys() {
+        let tenants = Tenants::new(None, &[], ApiKeyStore::in_memory());
+        let caller = tenants.authenticate(None).expect("anonymous");
+        assert!(caller.has_scope(Scope::MonitorWrite));
+        assert!(!caller.has_scope(Scope::Admin));
+        assert!(caller.owner().is_none());
+    }
+
+    #[test]
+    fn tenant_keys_resolve_to_tenant() {
+        let tenants = Tenants::new(
+            Some(ConfigApiKey::Plain("operator-key".to_string())),
+            &[tenant("team-a", vec![Scope::Read], 0)],
+            ApiKeyStore::in_memory(),
+        );
+        assert!(tenants.authenticate(None).is_none());
+        assert!(tenants.authenticate(Some("unknown")).is_none());
+        let operator = tenants
+            .authenticate(Some("operator-key"))
+            .expect("operator");
+        assert!(operator.owner().is_none());
+
+        let caller = tenants.authenticate(Some("team-a-key")).expect("tenant");
+        assert_eq!(caller.owner().as_deref(), Some("team-a"));
+        assert!(caller.has_scope(Scope::Read));
+        assert!(!caller.has_scope(Scope::MonitorWrite));
+        assert!(caller.can_see(Some("team-a")));
+        assert!(!caller.can_see(Some("team-b")));
+        assert!(!caller.can_see(None));
+    }
+
+    #[test]
+    fn hashed_config_keys_are_accepted() {
+        let (salt, hash) = hash_config_key("operator-key");
+        let mut team = tenant("team-a", vec![Scope::Read], 0);
+        let (team_salt, team_hash) = hash_config_key("team-a-key");
+        team.api_key = None;
+        team.api_key_hash = Some(team_hash);
+        team.api_key_salt = Some(team_salt);
+        let tenants = Tenants::new(
+            Some(ConfigApiKey::Hashed { salt, hash }),
+            &[team],
+            ApiKeyStore::in_memory(),
+        );
+        assert!(tenants.authenticate(Some("operator-key")).is_some());
+        assert!(tenants.authenticate(Some("other-key")).is_none());
+        let caller = tenants.authenticate(Some("team-a-key")).expect("tenant");
+        assert_eq!(caller.owner().as_deref(), Some("team-a"));
+    }
+
+    #[test]
+    fn broadcast_quota_is_per_tenant() {
+        let tenants = Tenants::new(
+            None,
+            &[
+                tenant("team-a", vec![Scope::Broadcast], 1),
+                tenant("team-b", vec![Scope::Broadcast], 1),
+            ],
+            ApiKeyStore::in_memory(),
+        );
+        let a = tenants.authenticate(Some("team-a-key")).expect("tenant");
+        let b = tenants.authenticate(Some("team-b-key")).expect("tenant");
+        assert!(a.allow_broadcast());
+        assert!(!a.allow_broadcast());
+        assert!(b.allow_broadcast());
+    }
+
+    #[test]
+    fn created_keys_are_limited_to_tenant_scopes() {
+        let tenants = Tenants::new(
+            Some(ConfigApiKey::Plain("operator-key".to_string())),
+            &[tenant("team-a", vec![Scope::Read], 0)],
+            ApiKeyStore::in_memory(),
+        );
+        assert!(matches!(
+            tenants.create_key(Some("team-a".into()), Some(vec![Scope::Broadcast]), None),
+            Err(KeyError::Invalid(_))
+        ));
+        assert!(matches!(
+            tenants.create_key(Some("team-z".into()), None, None),
+            Err(KeyError::Invalid(_))
+        ));
+
+        let key = tenants
+            .create_key(Some("team-a".into()), None, None)
+            .expect("create key");
+        assert_eq!(key.info.scopes, vec![Scope::Read]);
+        let caller = tenants.authenticate(Some(&key.api_key)).expect("tenant");
+        assert_eq!(caller.owner().as_deref(), Some("team-a"));
+
+        assert_eq!(tenants.revoke_key(&key.info.id), Ok(true));
+        assert!(tenants.authenticate(Some(&key.api_key)).is_none());
+    }
+}
@@ -162,10 +162,12 @@ impl ThreadManager {
         let timeout_period = Duration::from_millis(100);
 
         while keep_looping {
+            logic.poll_backfill();
             if let Ok(received) = self.rx_peer.recv_timeout(timeout_period) {
                 should_stop = received.event == PeerEventType::Stop;
                 // Process the event
-                keep_looping = self.process_event(received.clone(), thread_tracker, logic);
+                // Moved rather than cloned, a block event can be hundreds of MB
+                keep_looping = self.process_event(received, thread_tracker, logic);
                 // Check to see if logic has a message or more to send
                 logic.message_to_send().iter().for_each(|msg| {
                     if let Some(peer) = thread_tracker.get_connected_peer() {
@@ -195,10 +197,9 @@ impl ThreadManager {
                             }
                         }
                     }
-                    RestEventMessage::AddMonitor(monitor) => logic.tx_analyser.add_monitor(monitor),
-                    RestEventMessage::DeleteMonitor(monitor_name) => {
-                        logic.tx_analyser.delete_monitor(&monitor_name)
-                    }
+                    RestEventMessage::AddMonitor(monitor) => logic.add_monitor(monitor),
+                    RestEventMessage::UpdateMonitor(monitor) => logic.update_monitor(monitor),
+                    RestEventMessage::DeleteMonitor(key) => logic.delete_monitor(&key),
                 }
             }
         }
@@ -0,0 +1,318 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use chain_gang::{network::Network, util::Hash256};
+
+use crate::{
+    config::{CollectionConfig, Config},
+    thread_util::catch_unwind_logged,
+    uaas::{
+        block_store::BlockReader,
+        collection::{CollectionDatabase, WorkingCollection},
+        header_chain::HeaderChain,
+        monitor::{BackfillState, BackfillStatus, MonitorKey, MonitorRegistry},
+        storage::StorageBackend,
+    },
+};
+
+// Return the (start, end) heights to rescan, given the main chain block index
+fn scan_range(
+    hash_to_index: &HashMap<Hash256, u32>,
+    start_height: Option<u32>,
+) -> Option<(u32, u32)> {
+    let end = *hash_to_index.values().max()?;
+    let start = match start_height {
+        Some(start) => start,
+        None => *hash_to_index.values().min()?,
+    };
+    (start <= end).then_some((start, end))
+}
+
+// A running rescan of the block store for one monitor
+struct BackfillJob {
+    key: MonitorKey,
+    running: Arc<AtomicBool>,
+    // Held while a match is written, so that once cancelled the job writes nothing more
+    writing: Arc<Mutex<()>>,
+    // Matching tx hashes found by the rescan, to be added to the live collection
+    rx: mpsc::Receiver<Hash256>,
+    thread: thread::JoinHandle<()>,
+}
+
+// Everything the rescan thread needs
+struct BackfillScan {
+    // The job id, see BackfillStatus
+    job: u64,
+    monitor: CollectionConfig,
+    network: Network,
+    block_reader: BlockReader,
+    // Main chain blocks to scan, (height, block hash) in height order
+    blocks: Vec<(u32, Hash256)>,
+    backend: Arc<dyn StorageBackend>,
+    monitors: Arc<MonitorRegistry>,
+    running: Arc<AtomicBool>,
+    writing: Arc<Mutex<()>>,
+    tx: mpsc::Sender<Hash256>,
+}
+
+impl BackfillScan {
+    fn scan(&self) -> Result<(), String> {
+        let key = MonitorKey::of(&self.monitor);
+        let mut wc = WorkingCollection::new(self.monitor.clone(), self.network)
+            .map_err(|err| format!("Unable to parse monitor: {err}"))?;
+        let storage = self.backend.open("backfill")?;
+        let mut collection_db = CollectionDatabase::new(storage);
+
+        let total = self.blocks.len() as u64;
+        let mut scanned = 0u64;
+        for (height, hash) in self.blocks.iter() {
+            if !self.running.load(Ordering::Relaxed) {
+                break;
+            }
+            // Blocks that were not saved, or are damaged, are skipped
+            let block = match self.block_reader.read_by_hash(hash) {
+                Ok(Some(block)) => block,
+                Ok(None) => continue,
+                Err(err) => {
+                    log::warn!("Backfill {key} skipping block {}: {err}", hash.encode());
+                    continue;
+                }
+            };
+
+            let mut matches = 0u64;
+            for tx in block.txns.iter() {
+                let hash = tx.hash();
+                if !wc.have_tx(hash) && wc.matches(tx) {
+                    // The monitor may have been deleted, and its collection with it, since the
+                    // last check; cancel waits for a write in progress
+                    let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+                    if !self.running.load(Ordering::Relaxed) {
+                        return Ok(());
+                    }
+                    wc.push(hash);
+                    collection_db.write_tx_to_database(&key, tx);
+                    if self.tx.send(hash).is_err() {
+                        return Err("Backfill channel closed".to_string());
+                    }
+                    matches += 1;
+                }
+            }
+            scanned += 1;
+            self.monitors.update_backfill(&key, self.job, |backfill| {
+                backfill.current_height = Some(*height);
+                backfill.blocks_scanned = scanned;
+                backfill.matches += matches;
+            });
+        }
+
+        if scanned < total && self.running.load(Ordering::Relaxed) {
+            log::warn!("Backfill {key} only found {scanned} of {total} blocks in the block store");
+        }
+        Ok(())
+    }
+
+    fn run(self) {
+        let start = Instant::now();
+        let key = MonitorKey::of(&self.monitor);
+        let result = self.scan();
+        let state = match &result {
+            Ok(()) if !self.running.load(Ordering::Relaxed) => BackfillState::Cancelled,
+            Ok(()) => BackfillState::Completed,
+            Err(err) => {
+                log::error!("Backfill {key} failed: {err}");
+                BackfillState::Failed
+            }
+        };
+        self.monitors.update_backfill(&key, self.job, |backfill| {
+            backfill.state = state;
+            backfill.error = result.err();
+        });
+        log::info!(
+            "Backfill {} {:?} in {} seconds",
+            key,
+            state,
+            start.elapsed().as_millis() as f64 / 1000.0
+        );
+    }
+}
+
+/// Rescans the stored blocks for monitors added at runtime, in background threads,
+/// so that the main event processing loop is not held up.
+pub struct BackfillManager {
+    enabled: bool,
+    start_height: Option<u32>,
+    block_reader: BlockReader,
+    network: Network,
+    backend: Arc<dyn StorageBackend>,
+    monitors: Arc<MonitorRegistry>,
+    jobs: Vec<BackfillJob>,
+    // The id of the last job started
+    last_job: u64,
+}
+
+impl BackfillManager {
+    pub fn new(
+        config: &Config,
+        backend: Arc<dyn StorageBackend>,
+        monitors: Arc<MonitorRegistry>,
+        block_reader: BlockReader,
+    ) -> Result<Self, String> {
+        let network = config.get_network().map_err(|err| err.to_string())?;
+
+        Ok(BackfillManager {
+            enabled: config.backfill.enabled,
+            start_height: config.backfill.start_height,
+            block_reader,
+            network,
+            backend,
+            monitors,
+            jobs: Vec::new(),
+            last_job: 0,
+        })
+    }
+
+    pub fn start(&mut self, monitor: &CollectionConfig, headers: &HeaderChain) {
+        if !self.enabled {
+            return;
+        }
+        // Restart any rescan already running for this monitor
+        let key = MonitorKey::of(monitor);
+        self.cancel(&key);
+
+        let scan = headers.with_index(|hash_to_index| {
+            let (start_height, end_height) = scan_range(hash_to_index, self.start_height)?;
+            let blocks: Vec<(u32, Hash256)> = hash_to_index
+                .iter()
+                .filter(|(_hash, height)| (start_height..=end_height).contains(*height))
+                .map(|(hash, height)| (*height, *hash))
+                .collect();
+            Some((start_height, end_height, blocks))
+        });
+        let Some((start_height, end_height, mut blocks)) = scan else {
+            log::info!("No stored blocks to backfill for {key}");
+            return;
+        };
+        blocks.sort_unstable();
+
+        log::info!("Starting backfill of {key} from height {start_height} to {end_height}");
+        self.last_job += 1;
+        let job = self.last_job;
+        self.monitors.set_backfill(
+            &key,
+            BackfillStatus {
+                job,
+                state: BackfillState::Running,
+                start_height,
+                end_height,
+                current_height: None,
+                blocks_scanned: 0,
+                blocks_total: blocks.len() as u64,
+                matches: 0,
+                error: None,
+            },
+        );
+
+        let running = Arc::new(AtomicBool::new(true));
+        let writing = Arc::new(Mutex::new(()));
+        let (tx, rx) = mpsc::channel();
+        let scan = BackfillScan {
+            job,
+            monitor: monitor.clone(),
+            network: self.network,
+            block_reader: self.block_reader.clone(),
+            blocks,
+            backend: self.backend.clone(),
+            monitors: self.monitors.clone(),
+            running: running.clone(),
+            writing: writing.clone(),
+            tx,
+        };
+        let thread_key = key.clone();
+        let monitors = self.monitors.clone();
+        let thread = thread::spawn(move || {
+            let label = format!("backfill {thread_key}");
+            if catch_unwind_logged(&label, || scan.run()).is_none() {
+                monitors.update_backfill(&thread_key, job, |backfill| {
+                    backfill.state = BackfillState::Failed;
+                    backfill.error = Some("Backfill thread panicked".to_string());
+                });
+            }
+        });
+
+        self.jobs.push(BackfillJob {
+            key,
+            running,
+            writing,
+            rx,
+            thread,
+        });
+    }
+
+    // Stop the rescans of a monitor. Once this returns they write nothing more to its collection
+    pub fn cancel(&mut self, key: &MonitorKey) {
+        for job in self.jobs.iter().filter(|job| job.key == *key) {
+            job.running.store(false, Ordering::Relaxed);
+            drop(job.writing.lock().unwrap_or_else(|e| e.into_inner()));
+        }
+    }
+
+    // Collect the matches found so far, returned as (monitor, tx hashes)
+    // Finished jobs are removed once their matches have been collected
+    pub fn poll(&mut self) -> Vec<(MonitorKey, Vec<Hash256>)> {
+        let mut found = Vec::new();
+        let mut index = 0;
+        while index < self.jobs.len() {
+            let job = &self.jobs[index];
+            let finished = job.thread.is_finished();
+            let hashes: Vec<Hash256> = job.rx.try_iter().collect();
+            // Cancelled jobs belong to deleted or changed monitors, so drop their matches
+            if !hashes.is_empty() && job.running.load(Ordering::Relaxed) {
+                found.push((job.key.clone(), hashes));
+            }
+            if finished {
+                let job = self.jobs.remove(index);
+                if job.thread.join().is_err() {
+                    log::error!("Backfill thread for {} panicked", job.key);
+                }
+            } else {
+                index += 1;
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(heights: &[u32]) -> HashMap<Hash256, u32> {
+        heights
+            .iter()
+            .map(|height| {
+                let mut hash = Hash256::default();
+                hash.0[..4].copy_from_slice(&height.to_le_bytes());
+                (hash, *height)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scan_range_defaults_to_first_stored_block() {
+        assert_eq!(scan_range(&index(&[10, 11, 12]), None), Some((10, 12)));
+        assert_eq!(scan_range(&index(&[10, 11, 12]), Some(11)), Some((11, 12)));
+    }
+
+    #[test]
+    fn scan_range_is_empty_without_blocks_in_range() {
+        assert_eq!(scan_range(&HashMap::new(), None), None);
+        assert_eq!(scan_range(&index(&[10, 11]), Some(20)), None);
+    }
+}
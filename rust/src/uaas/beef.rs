@@ -0,0 +1,427 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+
+use chain_gang::{
+    messages::{Block, Tx},
+    util::{var_int, Hash256, Serializable},
+};
+
+use crate::uaas::{
+    block_store::{BlockReader, NOT_STORED},
+    merkle::{MerklePath, MerkleProof, ProofTarget},
+    storage::Storage,
+};
+
+// The BRC-62 version, serialised as the bytes 01 00 be ef
+const BEEF_VERSION: u32 = 0xefbe_0001;
+// Unconfirmed ancestry can be long, limit the txs in a bundle
+pub const MAX_BEEF_TXS: usize = 1000;
+
+const NO_BUMP: u8 = 0;
+const HAS_BUMP: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BeefTx {
+    pub tx: Tx,
+    // Index of the merkle path that proves the tx, None for an unconfirmed tx
+    pub bump: Option<usize>,
+}
+
+/// A Background Evaluation Extended Format (BEEF, BRC-62) bundle.
+///
+/// Holds a tx and the ancestors needed to check it, parents before children, where each tx
+/// is either proven by one of the merkle paths or has its parents earlier in the bundle.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Beef {
+    pub bumps: Vec<MerklePath>,
+    pub txs: Vec<BeefTx>,
+}
+
+impl Beef {
+    pub fn is_beef(bytes: &[u8]) -> bool {
+        bytes.starts_with(&BEEF_VERSION.to_le_bytes())
+    }
+
+    // Add a confirmed tx, merging its proof into the merkle path of its block
+    pub fn add_proven_tx(&mut self, tx: Tx, height: u32, proof: &MerkleProof) {
+        let bump = match self.bumps.iter().position(|bump| bump.height == height) {
+            Some(bump) => bump,
+            None => {
+                self.bumps.push(MerklePath::new(height));
+                self.bumps.len() - 1
+            }
+        };
+        self.bumps[bump].add(proof);
+        self.txs.push(BeefTx {
+            tx,
+            bump: Some(bump),
+        });
+    }
+
+    // Add an unconfirmed tx, after its parents
+    pub fn add_tx(&mut self, tx: Tx) {
+        self.txs.push(BeefTx { tx, bump: None });
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut bytes = BEEF_VERSION.to_le_bytes().to_vec();
+        // Writes to a vec do not fail
+        let _ = var_int::write(self.bumps.len() as u64, &mut bytes);
+        for bump in self.bumps.iter() {
+            bump.write(&mut bytes);
+        }
+        let _ = var_int::write(self.txs.len() as u64, &mut bytes);
+        for entry in self.txs.iter() {
+            let _ = entry.tx.write(&mut bytes);
+            match entry.bump {
+                Some(bump) => {
+                    bytes.push(HAS_BUMP);
+                    let _ = var_int::write(bump as u64, &mut bytes);
+                }
+                None => bytes.push(NO_BUMP),
+            }
+        }
+        bytes
+    }
+
+    // Parse a bundle, checking that the proven txs are in their paths and the order of the txs
+    pub fn read(bytes: &[u8]) -> Result<Self, String> {
+        let invalid = |err: std::io::Error| format!("Invalid BEEF: {err}");
+        if !Beef::is_beef(bytes) {
+            return Err("Invalid BEEF: unknown version".to_string());
+        }
+        let mut reader = Cursor::new(&bytes[4..]);
+        let count = var_int::read(&mut reader).map_err(invalid)?;
+        let mut bumps = Vec::new();
+        for _ in 0..count {
+            bumps.push(MerklePath::read(&mut reader)?);
+        }
+        let count = var_int::read(&mut reader).map_err(invalid)?;
+        if count == 0 || count > MAX_BEEF_TXS as u64 {
+            return Err(format!("Invalid BEEF: {count} txs"));
+        }
+        let mut txs = Vec::new();
+        for _ in 0..count {
+            let tx = Tx::read(&mut reader).map_err(|err| format!("Invalid BEEF tx: {err:?}"))?;
+            let mut flag = [0u8];
+            std::io::Read::read_exact(&mut reader, &mut flag).map_err(invalid)?;
+            let bump = match flag[0] {
+                NO_BUMP => None,
+                HAS_BUMP => Some(var_int::read(&mut reader).map_err(invalid)? as usize),
+                flag => return Err(format!("Invalid BEEF: tx flag {flag}")),
+            };
+            txs.push(BeefTx { tx, bump });
+        }
+        if reader.position() as usize != bytes.len() - 4 {
+            return Err("Invalid BEEF: data after the last tx".to_string());
+        }
+
+        let txids: Vec<Hash256> = txs.iter().map(|entry| entry.tx.hash()).collect();
+        for (position, entry) in txs.iter().enumerate() {
+            match entry.bump {
+                Some(bump) => {
+                    let path = bumps
+                        .get(bump)
+                        .ok_or_else(|| format!("Invalid BEEF: no merkle path {bump}"))?;
+                    if !path.txids().any(|txid| txid == txids[position]) {
+                        return Err(format!(
+                            "Invalid BEEF: tx {} is not in its merkle path",
+                            txids[position].encode()
+                        ));
+                    }
+                }
+                None => {
+                    let later = &txids[position..];
+                    if let Some(input) = entry
+                        .tx
+                        .inputs
+                        .iter()
+                        .find(|input| later.contains(&input.prev_output.hash))
+                    {
+                        return Err(format!(
+                            "Invalid BEEF: tx {} comes before its parent {}",
+                            txids[position].encode(),
+                            input.prev_output.hash.encode()
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(Beef { bumps, txs })
+    }
+}
+
+// Why a bundle could not be built
+#[derive(Debug, PartialEq)]
+pub enum BeefError {
+    NotFound(String),
+    TooLarge(String),
+    Unavailable(String),
+}
+
+// Where a tx was found
+enum FoundTx {
+    Mempool(Tx),
+    Block {
+        tx: Tx,
+        height: u32,
+        proof: MerkleProof,
+    },
+}
+
+// Finds txs in the tx table and block store, or the mempool table
+struct TxFinder<'a> {
+    storage: &'a mut dyn Storage,
+    reader: &'a BlockReader,
+    // The blocks read so far and their txids, by block store position
+    blocks: HashMap<u64, (Block, Vec<Hash256>)>,
+}
+
+impl TxFinder<'_> {
+    fn find(&mut self, txid: &Hash256) -> Result<Option<FoundTx>, String> {
+        if let Some((position, blockindex)) = self.storage.tx_location(txid)? {
+            if let Some(found) = self.find_in_block(txid, position, blockindex)? {
+                return Ok(Some(found));
+            }
+        }
+        let Some(tx_hex) = self.storage.mempool_tx(txid)? else {
+            return Ok(None);
+        };
+        let tx = hex::decode(&tx_hex)
+            .ok()
+            .and_then(|bytes| Tx::read(&mut Cursor::new(&bytes)).ok())
+            .ok_or_else(|| format!("Invalid mempool tx {}", txid.encode()))?;
+        Ok(Some(FoundTx::Mempool(tx)))
+    }
+
+    fn find_in_block(
+        &mut self,
+        txid: &Hash256,
+        position: u64,
+        blockindex: u32,
+    ) -> Result<Option<FoundTx>, String> {
+        if position == NOT_STORED {
+            return Ok(None);
+        }
+        let Some(height) = self.storage.tx_height(txid)? else {
+            return Ok(None);
+        };
+        if !self.blocks.contains_key(&position) {
+            let block = self.reader.read(position)?;
+            let txids = block.txns.iter().map(|tx| tx.hash()).collect();
+            self.blocks.insert(position, (block, txids));
+        }
+        let (block, txids) = &self.blocks[&position];
+        let merkle_root = block.header.merkle_root;
+        let proof = MerkleProof::new(txids, blockindex, ProofTarget::MerkleRoot(merkle_root))?;
+        if proof.root() != Ok(merkle_root) {
+            return Err(format!(
+                "Stored block {} does not match its merkle root",
+                block.header.hash().encode()
+            ));
+        }
+        Ok(Some(FoundTx::Block {
+            tx: block.txns[blockindex as usize].clone(),
+            height,
+            proof,
+        }))
+    }
+}
+
+// Build the bundle for a tx. Confirmed txs get a merkle path from their stored block,
+// unconfirmed txs are taken from the mempool table along with their ancestors.
+pub fn build_beef(
+    storage: &mut dyn Storage,
+    reader: &BlockReader,
+    txid: &Hash256,
+) -> Result<Beef, BeefError> {
+    let mut finder = TxFinder {
+        storage,
+        reader,
+        blocks: HashMap::new(),
+    };
+    let mut beef = Beef::default();
+    let mut seen = HashSet::new();
+    // Unconfirmed txs waiting for their parents to be added
+    let mut waiting: HashMap<Hash256, Tx> = HashMap::new();
+    // (txid, whether its parents have been added)
+    let mut stack = vec![(*txid, false)];
+    while let Some((hash, parents_added)) = stack.pop() {
+        if parents_added {
+            if let Some(tx) = waiting.remove(&hash) {
+                beef.add_tx(tx);
+            }
+            continue;
+        }
+        if !seen.insert(hash) {
+            continue;
+        }
+        if seen.len() > MAX_BEEF_TXS {
+            return Err(BeefError::TooLarge(format!(
+                "Transaction {} has more than {MAX_BEEF_TXS} unconfirmed ancestors",
+                txid.encode()
+            )));
+        }
+        match finder.find(&hash).map_err(BeefError::Unavailable)? {
+            Some(FoundTx::Block { tx, height, proof }) => beef.add_proven_tx(tx, height, &proof),
+            Some(FoundTx::Mempool(tx)) => {
+                stack.push((hash, true));
+                for input in tx.inputs.iter() {
+                    stack.push((input.prev_output.hash, false));
+                }
+                waiting.insert(hash, tx);
+            }
+            None if hash == *txid => {
+                return Err(BeefError::NotFound(format!(
+                    "Transaction {} not found",
+                    txid.encode()
+                )))
+            }
+            None => {
+                return Err(BeefError::NotFound(format!(
+                    "Ancestor {} of transaction {} not found",
+                    hash.encode(),
+                    txid.encode()
+                )))
+            }
+        }
+    }
+    Ok(beef)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uaas::{
+        block_store::BlockStore,
+        database::{BlockHeaderWriteDB, MempoolEntryDB, TxEntryWriteDB},
+        storage::{MemoryBackend, StorageBackend},
+    };
+    use chain_gang::messages::{BlockHeader, OutPoint, TxIn};
+
+    fn spending(parents: &[Hash256], lock_time: u32) -> Tx {
+        Tx {
+            inputs: parents
+                .iter()
+                .map(|hash| TxIn {
+                    prev_output: OutPoint {
+                        hash: *hash,
+                        index: 0,
+                    },
+                    ..Default::default()
+                })
+                .collect(),
+            lock_time,
+            ..Default::default()
+        }
+    }
+
+    fn hex_tx(tx: &Tx) -> String {
+        let mut bytes = Vec::new();
+        tx.write(&mut bytes).unwrap();
+        hex::encode(bytes)
+    }
+
+    #[test]
+    fn bundle_has_unconfirmed_ancestors_and_paths_for_confirmed_ones() {
+        // Two confirmed txs in a block, spent by mempool txs
+        let txns = vec![spending(&[], 1), spending(&[], 2), spending(&[], 3)];
+        let txids: Vec<Hash256> = txns.iter().map(|tx| tx.hash()).collect();
+        let merkle_root = MerkleProof::new(&txids, 0, ProofTarget::MerkleRoot(Hash256::default()))
+            .unwrap()
+            .root()
+            .unwrap();
+        let block = Block {
+            header: BlockHeader {
+                merkle_root,
+                ..Default::default()
+            },
+            txns,
+        };
+        let dir = std::env::temp_dir().join(format!("uaas_beef_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut store = BlockStore::open(&dir, 1 << 20).unwrap();
+        let position = store.append(&block).unwrap();
+
+        let parent = spending(&[txids[1]], 10);
+        let child = spending(&[parent.hash(), txids[2], parent.hash()], 11);
+        let backend = MemoryBackend::new();
+        backend.data().blocks.insert(
+            block.header.hash(),
+            BlockHeaderWriteDB {
+                height: 9,
+                hash: block.header.hash(),
+                position,
+                ..Default::default()
+            },
+        );
+        for (blockindex, txid) in txids.iter().enumerate() {
+            backend.data().tx.insert(
+                *txid,
+                TxEntryWriteDB {
+                    hash: *txid,
+                    height: 9,
+                    blockindex: blockindex as u32,
+                    size: 0,
+                    satoshis: 0,
+                },
+            );
+        }
+        for tx in [&parent, &child] {
+            backend.data().mempool.insert(
+                tx.hash(),
+                MempoolEntryDB {
+                    hash: tx.hash(),
+                    locktime: tx.lock_time,
+                    fee: 0,
+                    age: 0,
+                    tx: hex_tx(tx),
+                },
+            );
+        }
+        let mut storage = backend.open("test").unwrap();
+
+        let beef = build_beef(storage.as_mut(), &store.reader(), &child.hash()).unwrap();
+        let order: Vec<Hash256> = beef.txs.iter().map(|entry| entry.tx.hash()).collect();
+        assert_eq!(order.len(), 4);
+        assert_eq!(order[3], child.hash());
+        let parent_at = order
+            .iter()
+            .position(|hash| *hash == parent.hash())
+            .unwrap();
+        let txid1_at = order.iter().position(|hash| *hash == txids[1]).unwrap();
+        assert!(txid1_at < parent_at);
+        // Both confirmed txs share the path of their block
+        assert_eq!(beef.bumps.len(), 1);
+        assert_eq!(beef.bumps[0].height, 9);
+        assert_eq!(beef.bumps[0].txids().count(), 2);
+
+        let bytes = beef.write();
+        assert!(Beef::is_beef(&bytes));
+        assert_eq!(Beef::read(&bytes).unwrap(), beef);
+
+        // A missing ancestor
+        backend.data().mempool.remove(&parent.hash());
+        assert!(matches!(
+            build_beef(storage.as_mut(), &store.reader(), &child.hash()),
+            Err(BeefError::NotFound(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bundles_out_of_order_are_rejected() {
+        let parent = spending(&[], 1);
+        let child = spending(&[parent.hash()], 2);
+        let mut beef = Beef::default();
+        beef.add_tx(child);
+        beef.add_tx(parent);
+        let err = Beef::read(&beef.write()).unwrap_err();
+        assert!(err.contains("comes before its parent"), "{err}");
+
+        let mut bytes = Beef::default().write();
+        assert!(Beef::read(&bytes).is_err());
+        bytes[0] = 2;
+        assert!(Beef::read(&bytes).is_err());
+    }
+}
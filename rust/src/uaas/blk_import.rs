@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use chain_gang::{
+    messages::{Block, BlockHeader},
+    util::{Hash256, Serializable},
+};
+
+use crate::uaas::block_store::decode_block;
+
+// A node writes blocks with a length of u32::MAX followed by a u64 length when they are 4 GB or more
+const LARGE_BLOCK_LENGTH: u32 = u32::MAX;
+const HEADER_SIZE: u64 = 80;
+
+// Where a block is in the blk*.dat files
+struct BlkLocation {
+    prev_hash: Hash256,
+    file: usize,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BlkImportInfo {
+    pub files: usize,
+    // Blocks found in the files, including those not on the imported chain
+    pub indexed: usize,
+    pub imported: usize,
+}
+
+// The blk*.dat files of a node's blocks directory, in file number order
+fn blk_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries =
+        fs::read_dir(dir).map_err(|err| format!("Unable to read {}: {err}", dir.display()))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("blk") && name.ends_with(".dat"))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+// Index the blocks of a file by their hash, reading only the headers.
+// A file ends at the first record without the network magic, nodes preallocate files with zeros
+fn index_file(
+    path: &Path,
+    file: usize,
+    magic: [u8; 4],
+    blocks: &mut HashMap<Hash256, BlkLocation>,
+) -> Result<(), String> {
+    let err_msg = |err: io::Error| format!("Unable to read {}: {err}", path.display());
+    let mut reader = BufReader::new(File::open(path).map_err(err_msg)?);
+    let file_size = reader.get_ref().metadata().map_err(err_msg)?.len();
+    let mut offset = 0u64;
+    loop {
+        let mut record_magic = [0u8; 4];
+        match reader.read_exact(&mut record_magic) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err_msg(err)),
+        }
+        if record_magic != magic {
+            if record_magic != [0u8; 4] {
+                log::warn!(
+                    "Unexpected magic {record_magic:02x?} in {} at {offset}, ignoring the rest of the file",
+                    path.display()
+                );
+            }
+            break;
+        }
+        let mut size = u64::from(read_u32(&mut reader).map_err(err_msg)?);
+        offset += 8;
+        if size == u64::from(LARGE_BLOCK_LENGTH) {
+            let mut bytes = [0u8; 8];
+            reader.read_exact(&mut bytes).map_err(err_msg)?;
+            size = u64::from_le_bytes(bytes);
+            offset += 8;
+        }
+        if size < HEADER_SIZE || offset + size > file_size {
+            log::warn!(
+                "Truncated block in {} at {offset}, ignoring the rest of the file",
+                path.display()
+            );
+            break;
+        }
+        let header = BlockHeader::read(&mut reader)
+            .map_err(|err| format!("Unable to read {}: {err:?}", path.display()))?;
+        blocks.insert(
+            header.hash(),
+            BlkLocation {
+                prev_hash: header.prev_hash,
+                file,
+                offset,
+                size,
+            },
+        );
+        offset += size;
+        reader.seek(SeekFrom::Start(offset)).map_err(err_msg)?;
+    }
+    Ok(())
+}
+
+// The hashes of the longest chain of indexed blocks after start, in height order
+fn longest_chain(blocks: &HashMap<Hash256, BlkLocation>, start: Hash256) -> Vec<Hash256> {
+    let mut children: HashMap<Hash256, Vec<Hash256>> = HashMap::new();
+    for (hash, location) in blocks {
+        children.entry(location.prev_hash).or_default().push(*hash);
+    }
+    // Breadth first, so the last block reached is at the greatest height
+    let mut tip = start;
+    let mut queue = VecDeque::from([start]);
+    while let Some(hash) = queue.pop_front() {
+        tip = hash;
+        if let Some(next) = children.get(&hash) {
+            queue.extend(next.iter().copied());
+        }
+    }
+    let mut chain = Vec::new();
+    while tip != start {
+        chain.push(tip);
+        tip = blocks[&tip].prev_hash;
+    }
+    chain.reverse();
+    chain
+}
+
+/// Read the blocks of a node's `blk*.dat` files that follow `start`, in chain order.
+/// Blocks in the files can be in any order; where the chain forks the longest branch is followed.
+pub fn import(
+    dir: &Path,
+    magic: [u8; 4],
+    start: Hash256,
+    on_block: &mut dyn FnMut(Block) -> Result<(), String>,
+) -> Result<BlkImportInfo, String> {
+    let files = blk_files(dir)?;
+    let mut blocks = HashMap::new();
+    for (file, path) in files.iter().enumerate() {
+        index_file(path, file, magic, &mut blocks)?;
+    }
+    log::info!(
+        "Indexed {} blocks in {} files from {}",
+        blocks.len(),
+        files.len(),
+        dir.display()
+    );
+
+    let chain = longest_chain(&blocks, start);
+    // Only the file being read is open, the chain mostly runs through the files in order
+    let mut current: Option<(usize, File)> = None;
+    for hash in &chain {
+        let location = &blocks[hash];
+        let path = &files[location.file];
+        let err_msg = |err: io::Error| format!("Unable to read {}: {err}", path.display());
+        let reader = match current {
+            Some((file, ref mut reader)) if file == location.file => reader,
+            _ => {
+                let reader = File::open(path).map_err(err_msg)?;
+                &mut current.insert((location.file, reader)).1
+            }
+        };
+        reader
+            .seek(SeekFrom::Start(location.offset))
+            .map_err(err_msg)?;
+        let mut payload = vec![0u8; location.size as usize];
+        reader.read_exact(&mut payload).map_err(err_msg)?;
+        let block = decode_block(&payload).map_err(|err| {
+            format!(
+                "Unable to decode block {} in {}: {err}",
+                hash.encode(),
+                path.display()
+            )
+        })?;
+        on_block(block)?;
+    }
+    Ok(BlkImportInfo {
+        files: files.len(),
+        indexed: blocks.len(),
+        imported: chain.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uaas::block_store::test_blocks::{chain, test_dir};
+    use std::io::Write;
+
+    const MAGIC: [u8; 4] = [0xe3, 0xe1, 0xf3, 0xe8];
+
+    // Write blocks in the node's framing, followed by the zeros of a preallocated file
+    fn write_blk(path: &Path, blocks: &[&Block]) {
+        let mut file = File::create(path).unwrap();
+        for block in blocks {
+            let mut payload = Vec::new();
+            block.write(&mut payload).unwrap();
+            file.write_all(&MAGIC).unwrap();
+            file.write_all(&(payload.len() as u32).to_le_bytes())
+                .unwrap();
+            file.write_all(&payload).unwrap();
+        }
+        file.write_all(&[0u8; 64]).unwrap();
+    }
+
+    #[test]
+    fn blocks_are_imported_in_chain_order() {
+        let dir = test_dir("blk-import");
+        fs::create_dir_all(&dir).unwrap();
+        let start = Hash256([1; 32]);
+        let main = chain(start, 5, 1);
+        // A shorter branch from the second block
+        let fork = chain(main[1].header.hash(), 2, 2);
+        write_blk(
+            &dir.join("blk00001.dat"),
+            &[&main[4], &fork[0], &main[2], &main[3]],
+        );
+        write_blk(&dir.join("blk00000.dat"), &[&main[1], &fork[1], &main[0]]);
+        fs::write(dir.join("rev00000.dat"), [0u8; 16]).unwrap();
+
+        let mut imported = Vec::new();
+        let info = import(&dir, MAGIC, start, &mut |block| {
+            imported.push(block.header.hash());
+            Ok(())
+        })
+        .unwrap();
+        let expected: Vec<Hash256> = main.iter().map(|block| block.header.hash()).collect();
+        assert_eq!(imported, expected);
+        assert_eq!(
+            info,
+            BlkImportInfo {
+                files: 2,
+                indexed: 7,
+                imported: 5
+            }
+        );
+
+        // Importing from a later block continues the chain
+        imported.clear();
+        import(&dir, MAGIC, main[2].header.hash(), &mut |block| {
+            imported.push(block.header.hash());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(imported, expected[3..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,58 +1,46 @@
-use std::{
-    collections::HashMap,
-    fs::OpenOptions,
-    io::{Seek, SeekFrom},
-    sync::mpsc,
-    time::Instant,
-};
-
-use mysql::{prelude::*, PooledConn};
+use std::{fs, path::Path, time::Instant};
 
 use chain_gang::{
     messages::{Block, BlockHeader, Payload},
-    util::{Hash256, Serializable},
+    util::Hash256,
 };
 
 use crate::{
-    config::Config,
+    config::{Config, SyncMode},
     uaas::{
-        database::{BlockHeaderWriteDB, DBOperationType, OrphanBlockHeaderWriteDB},
+        block_queue::BlockQueue,
+        block_store::{self, BlockReader, BlockStore, NOT_STORED},
+        consistency,
+        database::{
+            BlockHeaderWriteDB, BlockWriteDB, DBOperationType, DBSender, OrphanBlockHeaderWriteDB,
+        },
+        header_chain::HeaderChain,
+        pow::{chain_work, DifficultyRules, DAA_HEADERS},
+        storage::Storage,
         tx_analyser::TxAnalyser,
         util::{delay_as_string, timestamp_age_as_sec, timestamp_as_string},
     },
 };
 
-// database header structure
-struct DBHeader {
-    height: u32,
-    _hash: String,
-    version: u32,
-    prev_hash: String,
-    merkle_root: String,
-    timestamp: u32,
-    bits: u32,
-    nonce: u32,
-    _position: u64,
-    _blocksize: u32,
-    _numtxs: u32,
-}
-
-// Used to record the block with a position in the block file
-struct BlockWithPosition {
-    pub position: Option<u64>,
-    pub block: Block,
-}
-
 pub struct BlockManager {
     start_block_hash: String,
     // Startup read data from database or file
     startup_load_from_database: bool,
 
-    block_file: String,
+    block_store: BlockStore,
     save_blocks: bool,
-
-    pub block_headers: Vec<BlockHeader>,
-    pub hash_to_index: HashMap<Hash256, u32>,
+    save_txs: bool,
+    // Repair the stored state at startup if it is inconsistent
+    startup_repair: bool,
+    // Blocks were removed by the startup repair, read them again from the block store
+    replay_block_file: bool,
+    // Only the headers are followed, see SyncMode::Headers
+    headers_only: bool,
+
+    // The main chain headers
+    headers: HeaderChain,
+    // The network's difficulty adjustment, checked for the headers received in headers mode
+    difficulty: DifficultyRules,
     // BlockManager status
     // last block hash we processed
     last_hash_processed: Hash256,
@@ -60,31 +48,64 @@ pub struct BlockManager {
     height: u32,
 
     // Queue of blocks that have arrived out of order - for later proceessing
-    // we have changed to hashmap indexed by prev_hash for quicker processing
-    // block_queue: Vec<Block>,
-    block_queue: HashMap<Hash256, BlockWithPosition>,
+    // indexed by prev_hash, the blocks are kept on disk
+    block_queue: BlockQueue,
 
-    // Database connection
-    conn: PooledConn,
+    // Persistent store
+    storage: Box<dyn Storage>,
 
     // Channel to database
-    tx: mpsc::Sender<DBOperationType>,
+    tx: DBSender,
     // orphan
     threshold: usize,
 }
 
-impl BlockManager {
-    fn send_db_op(&self, op: DBOperationType) {
-        if self.tx.send(op).is_err() {
-            log::error!("Failed to send block database operation; channel closed");
-        }
+// Copy the blocks of a block file written before the block store into a new block store,
+// and move the stored blocks to their new positions.
+// The store is built next to its directory and renamed, so an interrupted import starts again
+fn import_block_file(
+    dir: &Path,
+    segment_size: u64,
+    block_file: &str,
+    storage: &mut dyn Storage,
+) -> Result<(), String> {
+    if dir.exists() || !Path::new(block_file).is_file() {
+        return Ok(());
+    }
+    let start = Instant::now();
+    let importing = dir.with_extension("import");
+    if importing.exists() {
+        fs::remove_dir_all(&importing)
+            .map_err(|err| format!("Unable to remove {}: {err}", importing.display()))?;
     }
+    log::info!(
+        "Importing block file {block_file} into block store {}",
+        dir.display()
+    );
+    let positions =
+        BlockStore::open(&importing, segment_size)?.import_legacy_file(Path::new(block_file))?;
+    storage.block_positions_write(&positions)?;
+    fs::rename(&importing, dir).map_err(|err| {
+        format!(
+            "Unable to rename {} to {}: {err}",
+            importing.display(),
+            dir.display()
+        )
+    })?;
+    log::info!(
+        "Imported {} blocks in {} seconds, {block_file} is no longer used",
+        positions.len(),
+        start.elapsed().as_secs()
+    );
+    Ok(())
+}
 
-    fn decode_stored_hash(label: &str, value: &str) -> Option<Hash256> {
-        match Hash256::decode(value) {
-            Ok(hash) => Some(hash),
+impl BlockManager {
+    fn send_db_op(&self, op: DBOperationType) -> Option<u64> {
+        match self.tx.send(op) {
+            Ok(sent) => Some(sent),
             Err(err) => {
-                log::error!("Invalid {label} hash {value}: {err:?}");
+                log::error!("Failed to send block database operation: {err}");
                 None
             }
         }
@@ -92,167 +113,93 @@ impl BlockManager {
 
     pub fn new(
         config: &Config,
-        conn: PooledConn,
-        tx: mpsc::Sender<DBOperationType>,
+        mut storage: Box<dyn Storage>,
+        tx: DBSender,
     ) -> Result<Self, String> {
         let settings = config
             .get_network_settings()
             .map_err(|err| err.to_string())?;
+        let network = config.get_network().map_err(|err| err.to_string())?;
         let start_block_hash = settings.start_block_hash.clone();
         let last_hash_processed = Hash256::decode(&start_block_hash)
             .map_err(|err| format!("Invalid start_block_hash '{start_block_hash}': {err:?}"))?;
+        let dir = settings.block_store_dir();
+        block_store::finish_compaction(&dir, settings.block_segment_size, storage.as_mut())?;
+        import_block_file(
+            &dir,
+            settings.block_segment_size,
+            &settings.block_file,
+            storage.as_mut(),
+        )?;
+        let block_store = BlockStore::open(&dir, settings.block_segment_size)?;
 
         Ok(BlockManager {
             start_block_hash,
             startup_load_from_database: settings.startup_load_from_database,
-            block_file: settings.block_file.clone(),
+            block_store,
             save_blocks: settings.save_blocks,
-            block_headers: Vec::new(),
-            hash_to_index: HashMap::new(),
+            save_txs: settings.save_txs,
+            startup_repair: config.database.startup_repair,
+            replay_block_file: false,
+            headers_only: settings.sync_mode == SyncMode::Headers,
+            headers: HeaderChain::default(),
+            difficulty: DifficultyRules::new(network),
             height: settings.start_block_height + 1,
             last_hash_processed,
-            block_queue: HashMap::new(),
-            conn,
+            block_queue: BlockQueue::new(&dir.with_extension("queue")),
+            storage,
             tx,
             threshold: config.orphan.threshold,
         })
     }
 
-    fn create_tables(&mut self) {
-        // Create tables, if required
-        // Check for the tables
-        let tables: Vec<String> = match self.conn.query(
-            "SELECT TABLE_NAME FROM INFORMATION_SCHEMA.TABLES WHERE TABLE_TYPE = 'BASE TABLE';",
-        ) {
-            Ok(tables) => tables,
-            Err(err) => {
-                log::error!("Unable to list database tables: {err:?}");
-                return;
-            }
-        };
-
-        if !tables.iter().any(|x| x.as_str() == "blocks") {
-            log::info!("Table blocks not found - creating");
-            if let Err(err) = self.conn.query_drop(
-                r"CREATE TABLE blocks (
-                    height int unsigned not null,
-                    hash varchar(64) not null,
-                    version int unsigned not null,
-                    prev_hash varchar(64) not null,
-                    merkle_root varchar(64) not null,
-                    timestamp int unsigned not null,
-                    bits int unsigned not null,
-                    nonce int unsigned not null,
-                    `offset` bigint unsigned not null,
-                    blocksize int unsigned not null,
-                    numtxs int unsigned not null,
-                    CONSTRAINT PK_Entry PRIMARY KEY (hash));",
-            ) {
-                log::error!("Unable to create blocks table: {err:?}");
-                return;
-            }
-            if let Err(err) = self
-                .conn
-                .query_drop(r"CREATE INDEX IF NOT EXISTS idx_blocks_height ON blocks (height);")
-            {
-                log::error!("Unable to create blocks height index: {err:?}");
-            }
-        }
-
-        if !tables.iter().any(|x| x.as_str() == "orphans") {
-            log::info!("Table orphans not found - creating");
-            if let Err(err) = self.conn.query_drop(
-                r"CREATE TABLE orphans (
-                    height int unsigned not null,
-                    hash varchar(64) not null,
-                    version int unsigned not null,
-                    prev_hash varchar(64) not null,
-                    merkle_root varchar(64) not null,
-                    timestamp int unsigned not null,
-                    bits int unsigned not null,
-                    nonce int unsigned not null,
-                    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP);",
-            ) {
-                log::error!("Unable to create orphans table: {err:?}");
-            }
-        }
-
-        // Disable safe mode... wa ha ha - what could possibly go wrong?
-        if let Err(err) = self.conn.query_drop("SET sql_safe_updates=0;") {
-            log::warn!("Unable to disable sql_safe_updates: {err:?}");
-        }
-    }
-
-    fn load_blockheaders_from_database(&mut self) {
+    fn load_blockheaders_from_database(&mut self) -> Result<(), String> {
         // load headers from database
         let start = Instant::now();
 
-        let headers: Vec<DBHeader> = match self.conn.query_map(
-            "SELECT * FROM blocks ORDER BY height asc",
-            |(
-                height,
-                _hash,
-                version,
-                prev_hash,
-                merkle_root,
-                timestamp,
-                bits,
-                nonce,
-                position,
-                _blocksize,
-                _numtxs,
-            )| {
-                DBHeader {
-                    height,
-                    _hash,
-                    version,
-                    prev_hash,
-                    merkle_root,
-                    timestamp,
-                    bits,
-                    nonce,
-                    _position: position,
-                    _blocksize,
-                    _numtxs,
-                }
-            },
-        ) {
-            Ok(headers) => headers,
-            Err(err) => {
-                log::error!("Unable to load block headers from database: {err:?}");
-                return;
+        let mut headers = self
+            .storage
+            .load_block_headers()
+            .map_err(|err| format!("Unable to load block headers from database: {err}"))?;
+
+        // Check the stored state before it is used, this is also where a crash is recovered from
+        let report = consistency::check(
+            self.storage.as_mut(),
+            &headers,
+            self.height - 1,
+            self.save_txs,
+        )?;
+        if !report.is_consistent() {
+            if !self.startup_repair {
+                return Err(format!(
+                    "Stored state is inconsistent, refusing to start (set startup_repair = true to repair it): {report}"
+                ));
             }
-        };
+            log::warn!("Repairing stored state: {report}");
+            report.repair(self.storage.as_mut())?;
+            headers.truncate(report.valid_headers);
+            self.replay_block_file = report.replay_from_block_file();
+        }
 
-        for b in headers {
-            let Some(prev_hash) = Self::decode_stored_hash("prev_hash", &b.prev_hash) else {
-                continue;
-            };
-            let Some(merkle_root) = Self::decode_stored_hash("merkle_root", &b.merkle_root) else {
-                continue;
-            };
-            let block_header = BlockHeader {
-                version: b.version,
-                prev_hash,
-                merkle_root,
-                timestamp: b.timestamp,
-                bits: b.bits,
-                nonce: b.nonce,
-            };
+        for (height, block_header) in headers {
             // Store the block header
-            let hash = block_header.hash();
-            self.hash_to_index.insert(hash, b.height);
-            self.block_headers.push(block_header);
-            self.height = b.height + 1;
+            self.headers.push(height, block_header);
+            self.height = height + 1;
         }
         log::info!(
             "Loaded {} headers in {} seconds",
-            self.block_headers.len(),
+            self.headers.len(),
             start.elapsed().as_secs()
         );
+        Ok(())
     }
 
-    fn process_block(&mut self, block: Block, tx_analyser: &mut TxAnalyser) {
+    fn process_block(
+        &mut self,
+        block: Block,
+        tx_analyser: &mut TxAnalyser,
+        header: Option<BlockHeaderWriteDB>,
+    ) {
         // Block processing functionality
         // This method is shared with reading from file and receiving blocks from network
         let hash = block.header.hash();
@@ -285,25 +232,42 @@ impl BlockManager {
                 return;
             }
         };
-        tx_analyser.process_block(&block, block_height);
+        let Block {
+            header: block_header,
+            txns,
+        } = block;
+        let mut writes = tx_analyser.process_block(txns, block_height);
+        let utxo_commitment = tx_analyser.utxo.block_commitment();
+        // The header is written last, so a stored header means the block's txs are stored
+        writes
+            .headers
+            .extend(header.map(|header| BlockHeaderWriteDB {
+                utxo_commitment: Some(utxo_commitment),
+                ..header
+            }));
+        if let Some(sent) = self.send_db_op(DBOperationType::BlockWrite(writes)) {
+            // The utxo changes of the block are held in memory until they are written
+            tx_analyser.utxo.writes_sent(sent);
+        }
         // Store the block header
-        self.hash_to_index.insert(hash, self.height);
-        self.block_headers.push(block.header);
+        self.headers.push(self.height, block_header);
         self.height += 1;
     }
 
-    fn write_blockheader_to_database(
-        &mut self,
+    fn block_header_record(
+        &self,
         header: &BlockHeader,
         position: u64,
         blocksize: u32,
         numtxs: u32,
-    ) {
+    ) -> Option<BlockHeaderWriteDB> {
         // Double check we haven't already written it
-        if !self.hash_to_index.contains_key(&header.hash()) {
-            // Write the block header to a database
+        if self.headers.contains(&header.hash()) {
+            None
+        } else {
+            // The block header to write to the database
             // Needs to be called before process block as process block increments the self.height
-            let block_header = BlockHeaderWriteDB {
+            Some(BlockHeaderWriteDB {
                 height: self.height,
                 hash: header.hash(),
                 version: header.version,
@@ -315,9 +279,9 @@ impl BlockManager {
                 position,
                 blocksize,
                 numtxs,
-            };
-
-            self.send_db_op(DBOperationType::BlockHeaderWrite(block_header));
+                // Set once the block has been processed
+                utxo_commitment: None,
+            })
         }
     }
 
@@ -349,21 +313,29 @@ impl BlockManager {
         // if found then check again
 
         // Remove block from block_queue
-        while let Some(blockwithpos) = self.block_queue.remove(&self.last_hash_processed) {
+        let reader = self.block_store.reader();
+        while let Some(queued) = self.block_queue.take(&self.last_hash_processed, &reader) {
             // do block processing
-
-            let b = blockwithpos.block.clone();
+            let (b, position) = match queued {
+                Ok(queued) => queued,
+                Err(err) => {
+                    // They are received again when blocks are next requested from the last block processed
+                    log::error!("Unable to read queued block, dropping the queue: {err}");
+                    self.block_queue.clear();
+                    return;
+                }
+            };
             let blocksize = b.size() as u32;
             let numtxs = b.txns.len() as u32;
 
-            // pos is either in the blockfile or we need to write to file
-            let pos = match blockwithpos.position {
+            // pos is either in the block store or we need to write to it
+            let pos = match position {
                 Some(pos) => pos,
-                None => self.write_block_to_file(&b),
+                None => self.write_block_to_store(&b),
             };
             // Write to database
-            self.write_blockheader_to_database(&b.header, pos, blocksize, numtxs);
-            self.process_block(b, tx_analyser);
+            let header = self.block_header_record(&b.header, pos, blocksize, numtxs);
+            self.process_block(b, tx_analyser, header);
         }
     }
 
@@ -372,11 +344,11 @@ impl BlockManager {
             log::info!("self.block_queue.len() = {}", self.block_queue.len());
             if self.block_queue.len() < 5 {
                 // print all block_queue entries
-                for (_k, v) in self.block_queue.iter() {
+                for header in self.block_queue.headers() {
                     log::info!(
                         "q_block = {} {}",
-                        v.block.header.hash().encode(),
-                        timestamp_as_string(v.block.header.timestamp)
+                        header.hash().encode(),
+                        timestamp_as_string(header.timestamp)
                     );
                 }
             }
@@ -387,63 +359,39 @@ impl BlockManager {
         // Process each block as it is read from file
         let hash = block.header.hash();
         // Check to see if we already have this hash && blocks are in correct order
-        if !self.hash_to_index.contains_key(&hash) {
+        if !self.headers.contains(&hash) {
             if self.last_hash_processed == block.header.prev_hash {
                 let blocksize = block.size() as u32;
                 let numtxs = block.txns.len() as u32;
-                self.write_blockheader_to_database(&block.header, position, blocksize, numtxs);
-                self.process_block(block, tx_analyser);
+                let header = self.block_header_record(&block.header, position, blocksize, numtxs);
+                self.process_block(block, tx_analyser, header);
 
                 // Check block_queue to see if there are blocks that we can now process
                 self.process_block_queue(tx_analyser);
             } else {
-                // Store block for later processing - if it is not already present
-                if !self.block_queue.contains_key(&block.header.prev_hash) {
-                    let prev_hash = block.header.prev_hash;
-                    let entry = BlockWithPosition {
-                        position: Some(position),
-                        block,
-                    };
-                    self.block_queue.insert(prev_hash, entry);
+                // Queue block for later processing - if it is not already present
+                // It is read again from the store when its parent has been processed
+                if !self.block_queue.contains(&block.header.prev_hash) {
+                    self.block_queue.push_stored(block.header, position);
                 }
             }
         }
         self.print_block_queue();
     }
 
-    fn read_blocks_from_file(&mut self, tx_analyser: &mut TxAnalyser) {
+    fn read_blocks_from_store(&mut self, tx_analyser: &mut TxAnalyser) {
         // On loading check blocks are in the correct order and assert if not
         log::info!("read blocks");
         let start = Instant::now();
 
-        // Read blocks from a file
-        match OpenOptions::new().read(true).open(&self.block_file) {
-            Ok(mut file) => {
-                let mut position = match file.stream_position() {
-                    Ok(pos) => pos,
-                    Err(err) => {
-                        log::warn!(
-                            "Unable to read block file stream position for {}: {err}",
-                            &self.block_file
-                        );
-                        0
-                    }
-                };
-                while let Ok(block) = Block::read(&mut file) {
-                    self.process_read_block(block, tx_analyser, position);
-                    position = match file.stream_position() {
-                        Ok(pos) => pos,
-                        Err(err) => {
-                            log::warn!(
-                                "Unable to read block file stream position for {}: {err}",
-                                &self.block_file
-                            );
-                            position
-                        }
-                    };
-                }
-            }
-            Err(e) => log::info!("Unable to open block file {} - {}", &self.block_file, &e),
+        // Read the blocks in the order they were stored
+        let reader = self.block_store.reader();
+        let result = reader.scan(0, &mut |position, block| {
+            self.process_read_block(block, tx_analyser, position);
+            true
+        });
+        if let Err(err) = result {
+            log::error!("Unable to read block store: {err}");
         }
         // Print blocks read
         let elapsed_time = start.elapsed().as_millis() as f64;
@@ -454,51 +402,78 @@ impl BlockManager {
         );
     }
 
+    pub fn load(&mut self) -> Result<(), String> {
+        // Load (and check) the stored block headers.
+        // Called before the tx analyser loads, as the repair may remove txs, utxo and mempool entries
+        if !self.startup_load_from_database {
+            return Ok(());
+        }
+        self.load_blockheaders_from_database()?;
+        // Set the status - note that the height is updated by the load_blockheaders_from_database method
+        if let Some((_height, last_header)) = self.headers.tip() {
+            self.last_hash_processed = last_header.hash();
+        }
+        Ok(())
+    }
+
     pub fn setup(&mut self, tx_analyser: &mut TxAnalyser) {
         // Does all the startup stuff a BlockManager needs to do
-        self.create_tables();
-        super::schema::ensure_performance_indexes(&mut self.conn);
-        if self.startup_load_from_database {
-            self.load_blockheaders_from_database();
-            // Set the status - note that the height is updated by the load_blockheaders_from_database method
-            if let Some(last_header) = self.block_headers.last() {
-                self.last_hash_processed = last_header.hash();
+        if self.headers_only {
+            return;
+        }
+        if !self.headers.is_empty() {
+            tx_analyser.utxo.load_commitment(self.height - 1);
+        }
+        if !self.startup_load_from_database || self.replay_block_file {
+            // Read in the blocks from the store, those already loaded are skipped
+            self.read_blocks_from_store(tx_analyser);
+        }
+    }
+
+    // Keep an out of order block on disk until its parent has been processed, in the block store
+    // if blocks are saved, otherwise in the queue's spill file
+    fn queue_block(&mut self, block: &Block) {
+        let hash = block.header.hash();
+        if self.save_blocks {
+            match self.block_store.append(block) {
+                Ok(position) => {
+                    self.block_queue.push_stored(block.header, position);
+                    return;
+                }
+                Err(err) => log::error!("Unable to store block {}: {err}", hash.encode()),
             }
-        } else {
-            // Read in the blocks from the file
-            self.read_blocks_from_file(tx_analyser);
+        }
+        if let Err(err) = self.block_queue.push(block) {
+            log::error!("Unable to queue block {}: {err}", hash.encode());
         }
     }
 
-    fn write_block_to_file(&mut self, block: &Block) -> u64 {
-        // Write a block to a block file - should only be called for blocks received on network
+    fn write_block_to_store(&mut self, block: &Block) -> u64 {
+        // Write a block to the block store - should only be called for blocks received on network
+        // The block is recorded with NOT_STORED if it is not kept
         if !self.save_blocks {
-            return 0;
+            return NOT_STORED;
         }
-
-        let mut file = match OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.block_file)
-        {
-            Ok(file) => file,
+        match self.block_store.append(block) {
+            Ok(position) => position,
             Err(err) => {
-                log::error!("Unable to open block file {}: {err}", self.block_file);
-                return 0;
-            }
-        };
-        let pos = match file.seek(SeekFrom::End(0)) {
-            Ok(pos) => pos,
-            Err(err) => {
-                log::error!("Unable to seek block file {}: {err}", self.block_file);
-                return 0;
+                log::error!(
+                    "Unable to store block {}: {err}",
+                    block.header.hash().encode()
+                );
+                NOT_STORED
             }
-        };
-        if let Err(err) = block.write(&mut file) {
-            log::error!("Unable to write block to {}: {err}", self.block_file);
-            return 0;
         }
-        pos
+    }
+
+    // For reading stored blocks in other threads
+    pub fn block_reader(&self) -> BlockReader {
+        self.block_store.reader()
+    }
+
+    // The main chain headers, clone to share them with other threads
+    pub fn headers(&self) -> &HeaderChain {
+        &self.headers
     }
 
     pub fn handle_orphan_block(&mut self, tx_analyser: &mut TxAnalyser) {
@@ -509,9 +484,8 @@ impl BlockManager {
             self.block_queue.clear();
         }
         // Drop the last block - from block headers
-        if let Some(last_block) = self.block_headers.pop() {
+        if let Some(last_block) = self.headers.pop() {
             log::info!("Removing block {}", last_block.hash().encode());
-            self.hash_to_index.remove(&last_block.hash());
 
             // Copy from blockheader from blocks to orphan table
             self.write_orphan_to_database(&last_block);
@@ -524,13 +498,129 @@ impl BlockManager {
         }
     }
 
+    // Remove the tip header, when a longer chain forks below it in headers mode
+    fn remove_tip_header(&mut self) {
+        if let Some(header) = self.headers.pop() {
+            self.height -= 1;
+            log::info!(
+                "Removing block {} at height {}",
+                header.hash().encode(),
+                self.height
+            );
+            self.write_orphan_to_database(&header);
+            self.delete_blockheader_from_database(&header.hash());
+            self.last_hash_processed = header.prev_hash;
+        }
+    }
+
+    // Add the headers received in headers mode to the main chain, returning the hashes added.
+    // Headers that fork below the tip replace the blocks above the fork if they have more work
+    pub fn on_headers(&mut self, headers: &[BlockHeader]) -> Vec<Hash256> {
+        let new: Vec<&BlockHeader> = headers
+            .iter()
+            .skip_while(|header| self.headers.contains(&header.hash()))
+            .collect();
+        let Some(first) = new.first() else {
+            return Vec::new();
+        };
+        let tip = self.height - 1;
+        let fork_height = if first.prev_hash == self.last_hash_processed {
+            tip
+        } else {
+            let Some((fork_height, _header)) = self.headers.find(&first.prev_hash) else {
+                log::warn!(
+                    "Ignoring headers from {}, they do not connect to the chain",
+                    first.hash().encode()
+                );
+                return Vec::new();
+            };
+            fork_height
+        };
+
+        // Check the whole branch against the headers below the fork before changing the chain
+        let mut previous = self.headers.last_at(fork_height, DAA_HEADERS);
+        let mut valid: Vec<BlockHeader> = Vec::new();
+        let mut prev_hash = first.prev_hash;
+        for header in new.iter().copied() {
+            let hash = header.hash();
+            if header.prev_hash != prev_hash {
+                log::warn!("Header {} does not follow the chain", hash.encode());
+                break;
+            }
+            // The difficulty for the height, where the headers it is worked out from are held
+            let height = fork_height + 1 + valid.len() as u32;
+            if let Some(bits) = self.difficulty.expected_bits(height, header, &previous) {
+                if header.bits != bits {
+                    log::warn!(
+                        "Invalid header {}: bits {:08x} where {bits:08x} is expected",
+                        hash.encode(),
+                        header.bits
+                    );
+                    break;
+                }
+            }
+            // The proof of work, and the timestamp against the blocks before it
+            let last_11 = &previous[previous.len().saturating_sub(11)..];
+            if let Err(err) = header.validate(&hash, last_11) {
+                log::warn!("Invalid header {}: {err:?}", hash.encode());
+                break;
+            }
+            if previous.len() == DAA_HEADERS {
+                previous.remove(0);
+            }
+            previous.push(*header);
+            valid.push(*header);
+            prev_hash = hash;
+        }
+        if valid.is_empty() {
+            return Vec::new();
+        }
+
+        if fork_height < tip {
+            // The chain with the most work wins, not the one with the most headers
+            let replaced: Vec<BlockHeader> = (fork_height + 1..=tip)
+                .filter_map(|height| self.headers.header_at(height))
+                .collect();
+            if chain_work(&valid) <= chain_work(&replaced) {
+                log::info!(
+                    "Ignoring {} headers forking at height {fork_height}, they do not have more work than the {} they would replace",
+                    valid.len(),
+                    replaced.len()
+                );
+                return Vec::new();
+            }
+            while self.last_hash_processed != first.prev_hash {
+                self.remove_tip_header();
+            }
+        }
+
+        let mut added = Vec::new();
+        let mut records = Vec::new();
+        for header in valid {
+            let hash = header.hash();
+            records.extend(self.block_header_record(&header, NOT_STORED, 0, 0));
+            self.headers.push(self.height, header);
+            self.last_hash_processed = hash;
+            self.height += 1;
+            added.push(hash);
+        }
+        if !records.is_empty() {
+            self.send_db_op(DBOperationType::BlockWrite(BlockWriteDB {
+                headers: records,
+                ..Default::default()
+            }));
+        }
+        log::info!("Added {} headers, height {}", added.len(), self.height - 1);
+        added
+    }
+
     // if there are more than n entries return the timestamp of the first
     // Used for detecting orphans
     pub fn get_start_block_timestamp(&self) -> Option<u32> {
-        if self.block_headers.len() > self.threshold {
+        if self.headers.len() > self.threshold {
             // Just in case they are out of order for some reason we could get the smallest timestamp,
             // as this would be the earliest time
-            self.block_headers.iter().map(|bh| bh.timestamp).min()
+            self.headers.min_timestamp()
         } else {
             None
         }
@@ -544,29 +634,24 @@ impl BlockManager {
         let hash = block.header.hash();
 
         // Check to see if we already have this hash - if so ignore it
-        if !self.hash_to_index.contains_key(&hash) {
+        if !self.headers.contains(&hash) {
             // Check to see if block arrived in correct order
             if block.header.prev_hash == self.last_hash_processed {
-                let pos = self.write_block_to_file(&block);
+                let pos = self.write_block_to_store(&block);
                 // write to database
                 let blocksize = block.size() as u32;
                 let numtxs = block.txns.len() as u32;
 
-                self.write_blockheader_to_database(&block.header, pos, blocksize, numtxs);
+                let header = self.block_header_record(&block.header, pos, blocksize, numtxs);
                 // Note process_block increments the self.height
-                self.process_block(block.clone(), tx_analyser);
+                self.process_block(block, tx_analyser, header);
 
                 // Check block_queue to see if there are blocks that we can now process
                 self.process_block_queue(tx_analyser);
             } else {
-                // Store block for later processing - if it is not already present
-                let prev_hash = block.header.prev_hash;
-                if !self.block_queue.contains_key(&block.header.prev_hash) {
-                    let entry = BlockWithPosition {
-                        position: None,
-                        block,
-                    };
-                    self.block_queue.insert(prev_hash, entry);
+                // Queue block for later processing - if it is not already present
+                if !self.block_queue.contains(&block.header.prev_hash) {
+                    self.queue_block(&block);
                 }
             }
             self.print_block_queue();
@@ -577,16 +662,16 @@ impl BlockManager {
 
     pub fn get_last_known_block_hash(&self) -> String {
         // Return the last known block_hash as a String
-        match self.block_headers.last() {
+        match self.headers.tip() {
             None => self.start_block_hash.clone(),
-            Some(header) => header.hash().encode(),
+            Some((_height, header)) => header.hash().encode(),
         }
     }
 
     pub fn has_chain_tip(&self) -> bool {
         // Return true if we have the chain tip
         // This is called after we receive a block
-        if let Some(header) = self.block_headers.last() {
+        if let Some((_height, header)) = self.headers.tip() {
             let diff = timestamp_age_as_sec(header.timestamp);
             log::info!(
                 "last header = {}, time behind tip = {}",
@@ -602,3 +687,190 @@ impl BlockManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uaas::{database::write_queue, metrics::DatabaseMetrics, storage::MemoryBackend};
+    use std::{sync::mpsc::Receiver, sync::Arc};
+
+    const START_HEIGHT: u32 = 100;
+
+    fn headers_manager(name: &str) -> (BlockManager, Receiver<DBOperationType>) {
+        let dir = std::env::temp_dir().join(format!("uaas-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let content = format!(
+            r#"
+            [service]
+            user_agent = "/Bitcoin SV:1.0.11/"
+            network = "testnet"
+            rust_address = "127.0.0.1:8081"
+
+            [mainnet]
+            ip = ["127.0.0.1"]
+            port = 8333
+            start_block_hash = "{start}"
+            start_block_height = 1
+            timeout_period = 60.0
+            startup_load_from_database = false
+            block_file = ""
+            save_blocks = false
+            save_txs = false
+
+            [testnet]
+            ip = ["127.0.0.1"]
+            port = 18333
+            start_block_hash = "{start}"
+            start_block_height = {START_HEIGHT}
+            timeout_period = 60.0
+            startup_load_from_database = false
+            block_file = ""
+            block_store = "{store}"
+            save_blocks = false
+            save_txs = false
+            sync_mode = "headers"
+
+            [database]
+            mysql_url = "mysql://local"
+            mysql_url_docker = "mysql://docker"
+            ms_delay = 300
+            retries = 3
+
+            [orphan]
+            detect = false
+            threshold = 100
+
+            [logging]
+            level = "info"
+
+            [dynamic_config]
+            filename = ""
+            "#,
+            start = Hash256::default().encode(),
+            store = dir.display(),
+        );
+        let config: Config = toml::from_str(&content).expect("config should parse");
+        let (tx, rx) = write_queue(100, Arc::new(DatabaseMetrics::new(100)));
+        let manager = BlockManager::new(&config, Box::new(MemoryBackend::new()), tx).unwrap();
+        (manager, rx)
+    }
+
+    // Headers with an easy proof of work, following prev_hash
+    fn headers(prev_hash: Hash256, count: u32, fork: u32) -> Vec<BlockHeader> {
+        headers_with_bits(prev_hash, count, fork, 0x207fffff)
+    }
+
+    // Headers mined for bits with a target of the form 0x..ffff << 232
+    fn headers_with_bits(
+        mut prev_hash: Hash256,
+        count: u32,
+        fork: u32,
+        bits: u32,
+    ) -> Vec<BlockHeader> {
+        (0..count)
+            .map(|index| {
+                let mut header = BlockHeader {
+                    version: 1,
+                    prev_hash,
+                    merkle_root: Hash256([fork as u8; 32]),
+                    timestamp: 1_600_000_000 + index * 600,
+                    bits,
+                    nonce: 0,
+                };
+                while u32::from(header.hash().0[31]) >= (bits >> 16 & 0xff) {
+                    header.nonce += 1;
+                }
+                prev_hash = header.hash();
+                header
+            })
+            .collect()
+    }
+
+    fn orphans_written(rx: &Receiver<DBOperationType>) -> usize {
+        rx.try_iter()
+            .filter(|op| matches!(op, DBOperationType::OrphanBlockHeaderWrite(_)))
+            .count()
+    }
+
+    #[test]
+    fn headers_mode_follows_the_longest_header_chain() {
+        let (mut manager, rx) = headers_manager("headers-mode");
+        let main = headers(Hash256::default(), 3, 1);
+        let hashes: Vec<Hash256> = main.iter().map(|header| header.hash()).collect();
+        assert_eq!(manager.on_headers(&main), hashes);
+        assert_eq!(manager.on_headers(&main), Vec::<Hash256>::new());
+        assert_eq!(manager.headers().tip(), Some((START_HEIGHT + 3, main[2])));
+
+        // A fork with less work is ignored
+        let short = headers(hashes[0], 2, 2);
+        assert!(manager.on_headers(&short).is_empty());
+        assert_eq!(orphans_written(&rx), 0);
+
+        // A longer fork of equal difficulty replaces the blocks above the fork
+        let fork = headers(hashes[0], 3, 3);
+        assert_eq!(manager.on_headers(&fork).len(), 3);
+        assert_eq!(orphans_written(&rx), 2);
+        assert_eq!(manager.headers().tip(), Some((START_HEIGHT + 4, fork[2])));
+        assert!(!manager.headers().contains(&hashes[2]));
+        assert_eq!(manager.get_last_known_block_hash(), fork[2].hash().encode());
+
+        // Headers that do not connect are ignored
+        assert!(manager
+            .on_headers(&headers(Hash256([9; 32]), 1, 4))
+            .is_empty());
+    }
+
+    #[test]
+    fn headers_mode_follows_the_chain_with_the_most_work() {
+        let (mut manager, rx) = headers_manager("headers-work");
+        let main = headers(Hash256::default(), 3, 1);
+        let hashes: Vec<Hash256> = main.iter().map(|header| header.hash()).collect();
+        assert_eq!(manager.on_headers(&main), hashes);
+
+        // One header with more work than the two it replaces wins over the longer chain
+        let harder = headers_with_bits(hashes[0], 1, 2, 0x200fffff);
+        assert_eq!(manager.on_headers(&harder), vec![harder[0].hash()]);
+        assert_eq!(orphans_written(&rx), 2);
+        assert_eq!(manager.headers().tip(), Some((START_HEIGHT + 2, harder[0])));
+
+        // More headers with less work in total are ignored
+        let longer = headers(hashes[0], 4, 3);
+        assert!(manager.on_headers(&longer).is_empty());
+        assert_eq!(manager.headers().tip(), Some((START_HEIGHT + 2, harder[0])));
+    }
+
+    #[test]
+    fn headers_mode_checks_the_difficulty() {
+        let (mut manager, _rx) = headers_manager("headers-bits");
+        // Past the testnet difficulty adjustment height, with the headers it needs
+        manager.height = 1_200_000;
+        let chain = headers(Hash256::default(), DAA_HEADERS as u32 + 1, 1);
+        let added = manager.on_headers(&chain);
+
+        // The headers easier than the pow limit are taken until the difficulty can be worked out
+        assert_eq!(added.len(), DAA_HEADERS);
+        assert_eq!(
+            manager.get_last_known_block_hash(),
+            chain[DAA_HEADERS - 1].hash().encode()
+        );
+    }
+
+    #[test]
+    fn headers_mode_checks_a_fork_before_removing_the_tip() {
+        let (mut manager, rx) = headers_manager("headers-fork-check");
+        manager.difficulty = DifficultyRules::easy();
+        let main = headers(Hash256::default(), DAA_HEADERS as u32 + 3, 1);
+        assert_eq!(manager.on_headers(&main).len(), main.len());
+        let tip = manager.headers().tip();
+        rx.try_iter().count();
+
+        // The second header claims more work than the two it would replace, but has the wrong bits,
+        // so only the work of the first counts and the chain is left as it was
+        let fork_point = main[main.len() - 3].hash();
+        let mut fork = headers(fork_point, 1, 2);
+        fork.extend(headers_with_bits(fork[0].hash(), 1, 2, 0x200fffff));
+        assert!(manager.on_headers(&fork).is_empty());
+        assert_eq!(orphans_written(&rx), 0);
+        assert_eq!(manager.headers().tip(), tip);
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use chain_gang::{
+    messages::{Block, BlockHeader},
+    util::{Hash256, Serializable},
+};
+
+use crate::uaas::block_store::{decode_block, BlockReader};
+
+// Where a queued block is kept
+enum Location {
+    // In the block store, at this position
+    Stored(u64),
+    // In the spill file, for blocks that are not saved to the block store
+    Spilled { offset: u64, size: u64 },
+}
+
+struct QueuedBlock {
+    header: BlockHeader,
+    location: Location,
+}
+
+/// Blocks that arrived before their parent, indexed by the parent hash.
+/// Only the headers are held in memory, the blocks are read back from disk when their parent
+/// has been processed, so a run of large out-of-order blocks does not fill the memory.
+pub struct BlockQueue {
+    path: PathBuf,
+    spill: Option<File>,
+    spill_size: u64,
+    queue: HashMap<Hash256, QueuedBlock>,
+}
+
+impl BlockQueue {
+    pub fn new(path: &Path) -> Self {
+        // Left over from an earlier run, the queue is not kept between runs
+        if path.exists() {
+            if let Err(err) = fs::remove_file(path) {
+                log::warn!("Unable to remove {}: {err}", path.display());
+            }
+        }
+        BlockQueue {
+            path: path.to_path_buf(),
+            spill: None,
+            spill_size: 0,
+            queue: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn contains(&self, prev_hash: &Hash256) -> bool {
+        self.queue.contains_key(prev_hash)
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = &BlockHeader> {
+        self.queue.values().map(|queued| &queued.header)
+    }
+
+    // Queue a block that is in the block store
+    pub fn push_stored(&mut self, header: BlockHeader, position: u64) {
+        self.queue.insert(
+            header.prev_hash,
+            QueuedBlock {
+                header,
+                location: Location::Stored(position),
+            },
+        );
+    }
+
+    // Queue a block by writing it to the spill file
+    pub fn push(&mut self, block: &Block) -> Result<(), String> {
+        let err_msg =
+            |err: std::io::Error| format!("Unable to write {}: {err}", self.path.display());
+        let file = match &mut self.spill {
+            Some(file) => file,
+            None => self.spill.insert(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&self.path)
+                    .map_err(err_msg)?,
+            ),
+        };
+        let offset = self.spill_size;
+        file.seek(SeekFrom::Start(offset)).map_err(err_msg)?;
+        let mut writer = BufWriter::new(&mut *file);
+        block.write(&mut writer).map_err(err_msg)?;
+        writer.flush().map_err(err_msg)?;
+        drop(writer);
+        let end = file.stream_position().map_err(err_msg)?;
+        self.spill_size = end;
+        self.queue.insert(
+            block.header.prev_hash,
+            QueuedBlock {
+                header: block.header,
+                location: Location::Spilled {
+                    offset,
+                    size: end - offset,
+                },
+            },
+        );
+        Ok(())
+    }
+
+    // Remove the block that follows prev_hash, returning it with its block store position if stored
+    pub fn take(
+        &mut self,
+        prev_hash: &Hash256,
+        reader: &BlockReader,
+    ) -> Option<Result<(Block, Option<u64>), String>> {
+        let queued = self.queue.remove(prev_hash)?;
+        let result = match queued.location {
+            Location::Stored(position) => {
+                reader.read(position).map(|block| (block, Some(position)))
+            }
+            Location::Spilled { offset, size } => {
+                self.read_spilled(offset, size).map(|block| (block, None))
+            }
+        };
+        if self.queue.is_empty() {
+            self.clear();
+        }
+        Some(result)
+    }
+
+    fn read_spilled(&mut self, offset: u64, size: u64) -> Result<Block, String> {
+        let err_msg =
+            |err: std::io::Error| format!("Unable to read {}: {err}", self.path.display());
+        let file = self
+            .spill
+            .as_mut()
+            .ok_or_else(|| format!("{} is not open", self.path.display()))?;
+        file.seek(SeekFrom::Start(offset)).map_err(err_msg)?;
+        let mut payload = vec![0u8; size as usize];
+        file.read_exact(&mut payload).map_err(err_msg)?;
+        decode_block(&payload)
+    }
+
+    // Drop the queued blocks, and empty the spill file
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        if let Some(file) = &self.spill {
+            if let Err(err) = file.set_len(0) {
+                log::warn!("Unable to truncate {}: {err}", self.path.display());
+            }
+        }
+        self.spill_size = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uaas::block_store::{
+        test_blocks::{chain, test_dir},
+        BlockStore,
+    };
+
+    #[test]
+    fn queued_blocks_are_read_back_from_disk() {
+        let dir = test_dir("block-queue");
+        let mut store = BlockStore::open(&dir.join("store"), 1024 * 1024).unwrap();
+        let reader = store.reader();
+        let blocks = chain(Hash256::default(), 4, 0);
+
+        let mut queue = BlockQueue::new(&dir.join("queue"));
+        queue.push(&blocks[3]).unwrap();
+        let position = store.append(&blocks[2]).unwrap();
+        queue.push_stored(blocks[2].header, position);
+        queue.push(&blocks[1]).unwrap();
+        assert_eq!(queue.len(), 3);
+        assert!(queue.contains(&blocks[0].header.hash()));
+
+        let (block, stored) = queue
+            .take(&blocks[0].header.hash(), &reader)
+            .unwrap()
+            .unwrap();
+        assert_eq!((block, stored), (blocks[1].clone(), None));
+        let (block, stored) = queue
+            .take(&blocks[1].header.hash(), &reader)
+            .unwrap()
+            .unwrap();
+        assert_eq!((block, stored), (blocks[2].clone(), Some(position)));
+        assert!(queue.take(&blocks[0].header.hash(), &reader).is_none());
+        let (block, _stored) = queue
+            .take(&blocks[2].header.hash(), &reader)
+            .unwrap()
+            .unwrap();
+        assert_eq!(block, blocks[3]);
+
+        // The spill file is emptied once the queue is
+        assert!(queue.is_empty());
+        assert_eq!(fs::metadata(dir.join("queue")).unwrap().len(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,902 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use chain_gang::{
+    messages::{Block, Payload, Tx},
+    util::{sha256d, var_int, Hash256, Serializable},
+};
+
+use crate::uaas::storage::Storage;
+
+// Each block is stored as a frame: magic, payload length, payload checksum, serialised block
+const MAGIC: [u8; 4] = *b"UBLK";
+const FRAME_HEADER_SIZE: u64 = 12;
+// An index record: block hash, frame offset, frame size
+const INDEX_RECORD_SIZE: usize = 44;
+// Smallest payload, a block header and a tx count
+const MIN_PAYLOAD_SIZE: u64 = 81;
+
+// A position is the segment number in the high bits and the offset within the segment below
+const OFFSET_BITS: u32 = 40;
+/// Segments can not be larger than this, so that the offset fits in a position
+pub const MAX_SEGMENT_SIZE: u64 = 1 << OFFSET_BITS;
+/// The stored position of a main chain block that is not in the block store
+pub const NOT_STORED: u64 = u64::MAX;
+/// The fewest blocks pruning keeps, so that a reorg can still be followed from the stored blocks
+pub const MIN_PRUNE_BLOCKS: u32 = 100;
+
+// Written to a compacted store once all its blocks are written
+const COMPACTION_COMPLETE: &str = "complete";
+
+fn position(segment: u32, offset: u64) -> u64 {
+    (u64::from(segment) << OFFSET_BITS) | offset
+}
+
+fn split_position(position: u64) -> (u32, u64) {
+    (
+        (position >> OFFSET_BITS) as u32,
+        position & (MAX_SEGMENT_SIZE - 1),
+    )
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let mut check = [0; 4];
+    check.copy_from_slice(&sha256d(payload).0[..4]);
+    check
+}
+
+// The block header is at the start of the payload, so the hash does not need the whole block
+fn payload_hash(payload: &[u8]) -> Hash256 {
+    sha256d(&payload[..80])
+}
+
+pub fn decode_block(payload: &[u8]) -> Result<Block, String> {
+    let mut cursor = Cursor::new(payload);
+    let block = Block::read(&mut cursor).map_err(|err| format!("{err:?}"))?;
+    if cursor.position() != payload.len() as u64 {
+        return Err("block does not fill its frame".to_string());
+    }
+    Ok(block)
+}
+
+/// The number of txs in a serialised block, without decoding them
+pub fn tx_count(block: &[u8]) -> Result<u64, String> {
+    let mut cursor = Cursor::new(block);
+    cursor.set_position(80);
+    var_int::read(&mut cursor).map_err(|err| format!("Invalid block: {err}"))
+}
+
+/// The bytes of the tx at an index in a serialised block, without decoding the txs after it
+pub fn tx_bytes(block: &[u8], index: u32) -> Result<&[u8], String> {
+    let mut cursor = Cursor::new(block);
+    cursor.set_position(80);
+    let count = var_int::read(&mut cursor).map_err(|err| format!("Invalid block: {err}"))?;
+    if u64::from(index) >= count {
+        return Err(format!("Block has {count} txs, there is no tx {index}"));
+    }
+    for _ in 0..index {
+        Tx::read(&mut cursor).map_err(|err| format!("Invalid tx in block: {err:?}"))?;
+    }
+    let start = cursor.position() as usize;
+    Tx::read(&mut cursor).map_err(|err| format!("Invalid tx in block: {err:?}"))?;
+    Ok(&block[start..cursor.position() as usize])
+}
+
+fn data_path(dir: &Path, segment: u32) -> PathBuf {
+    dir.join(format!("blk{segment:05}.dat"))
+}
+
+fn index_path(dir: &Path, segment: u32) -> PathBuf {
+    dir.join(format!("blk{segment:05}.idx"))
+}
+
+// The segment numbers in the directory, in order
+fn list_segments(dir: &Path) -> Result<Vec<u32>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(format!(
+                "Unable to read block store {}: {err}",
+                dir.display()
+            ))
+        }
+    };
+    let mut segments = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|err| format!("Unable to read block store: {err}"))?;
+        let name = entry.file_name();
+        let Some(number) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("blk"))
+            .and_then(|name| name.strip_suffix(".dat"))
+        else {
+            continue;
+        };
+        if let Ok(segment) = number.parse() {
+            segments.push(segment);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+// A frame read from a segment
+struct Frame {
+    offset: u64,
+    size: u64,
+    payload: Vec<u8>,
+}
+
+// Reads the frames of one segment file, skipping over damaged ones
+struct SegmentReader {
+    reader: BufReader<File>,
+    len: u64,
+    offset: u64,
+    path: PathBuf,
+}
+
+impl SegmentReader {
+    fn open(path: PathBuf, offset: u64) -> Result<Self, String> {
+        let file =
+            File::open(&path).map_err(|err| format!("Unable to open {}: {err}", path.display()))?;
+        let len = file
+            .metadata()
+            .map_err(|err| format!("Unable to read {}: {err}", path.display()))?
+            .len();
+        let mut reader = BufReader::new(file);
+        reader
+            .seek(SeekFrom::Start(offset))
+            .map_err(|err| format!("Unable to seek {}: {err}", path.display()))?;
+        Ok(SegmentReader {
+            reader,
+            len,
+            offset,
+            path,
+        })
+    }
+
+    // The frame at the current offset, None if there is not a whole valid frame there
+    fn frame_here(&mut self) -> io::Result<Option<Frame>> {
+        if self.len - self.offset < FRAME_HEADER_SIZE + MIN_PAYLOAD_SIZE {
+            return Ok(None);
+        }
+        let mut header = [0; FRAME_HEADER_SIZE as usize];
+        self.reader.read_exact(&mut header)?;
+        let length = u64::from(u32::from_le_bytes([
+            header[4], header[5], header[6], header[7],
+        ]));
+        if header[..4] != MAGIC
+            || length < MIN_PAYLOAD_SIZE
+            || length > self.len - self.offset - FRAME_HEADER_SIZE
+        {
+            return Ok(None);
+        }
+        let mut payload = vec![0; length as usize];
+        self.reader.read_exact(&mut payload)?;
+        if checksum(&payload) != header[8..] {
+            return Ok(None);
+        }
+        Ok(Some(Frame {
+            offset: self.offset,
+            size: FRAME_HEADER_SIZE + length,
+            payload,
+        }))
+    }
+
+    // Offset of the next frame magic at or after from, or the end of the file
+    fn find_magic(&mut self, from: u64) -> io::Result<u64> {
+        self.reader.seek(SeekFrom::Start(from))?;
+        let mut start = from;
+        let mut chunk = Vec::new();
+        while start < self.len {
+            // Keep the last bytes of the previous chunk, in case the magic spans chunks
+            let keep = chunk.len().min(MAGIC.len() - 1);
+            chunk.drain(..chunk.len() - keep);
+            start -= keep as u64;
+            let read = (self.len - start - keep as u64).min(1 << 16) as usize;
+            let filled = chunk.len();
+            chunk.resize(filled + read, 0);
+            self.reader.read_exact(&mut chunk[filled..])?;
+            if let Some(found) = chunk.windows(MAGIC.len()).position(|bytes| bytes == MAGIC) {
+                let offset = start + found as u64;
+                self.reader.seek(SeekFrom::Start(offset))?;
+                return Ok(offset);
+            }
+            start += chunk.len() as u64;
+        }
+        Ok(self.len)
+    }
+
+    // The next valid frame at or after the current offset
+    fn next_frame(&mut self) -> Result<Option<Frame>, String> {
+        let start = self.offset;
+        while self.offset < self.len {
+            let frame = self
+                .frame_here()
+                .map_err(|err| format!("Unable to read {}: {err}", self.path.display()))?;
+            if let Some(frame) = frame {
+                if frame.offset != start {
+                    log::warn!(
+                        "Skipped {} damaged bytes at offset {start} of {}",
+                        frame.offset - start,
+                        self.path.display()
+                    );
+                }
+                self.offset += frame.size;
+                return Ok(Some(frame));
+            }
+            // Look for the next frame after this offset
+            self.offset = self
+                .find_magic(self.offset + 1)
+                .map_err(|err| format!("Unable to read {}: {err}", self.path.display()))?;
+        }
+        if self.offset != start {
+            log::warn!(
+                "Skipped {} damaged bytes at the end of {}",
+                self.offset - start,
+                self.path.display()
+            );
+        }
+        Ok(None)
+    }
+}
+
+// The (block hash, offset, size) of a frame
+type IndexRecord = (Hash256, u64, u64);
+
+// Read the index of a segment, rebuilding what is missing from the segment data.
+// Returns the index records and the end of the last frame
+fn load_segment(dir: &Path, segment: u32) -> Result<(Vec<IndexRecord>, u64), String> {
+    let data = data_path(dir, segment);
+    let index = index_path(dir, segment);
+    let data_len = fs::metadata(&data)
+        .map_err(|err| format!("Unable to read {}: {err}", data.display()))?
+        .len();
+    let stored = match fs::read(&index) {
+        Ok(stored) => stored,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(format!("Unable to read {}: {err}", index.display())),
+    };
+
+    // Keep the index records for frames that are in the data
+    let mut records = Vec::new();
+    let mut end = 0;
+    for record in stored.chunks_exact(INDEX_RECORD_SIZE) {
+        let mut hash = Hash256::default();
+        hash.0.copy_from_slice(&record[..32]);
+        let offset = u64::from_le_bytes(record[32..40].try_into().expect("8 bytes"));
+        let size = u64::from(u32::from_le_bytes(
+            record[40..44].try_into().expect("4 bytes"),
+        ));
+        if offset < end || offset + size > data_len {
+            break;
+        }
+        records.push((hash, offset, size));
+        end = offset + size;
+    }
+    let mut rewrite = records.len() * INDEX_RECORD_SIZE != stored.len();
+
+    // Index the frames written after the last indexed frame
+    if end < data_len {
+        let mut reader = SegmentReader::open(data, end)?;
+        while let Some(frame) = reader.next_frame()? {
+            records.push((payload_hash(&frame.payload), frame.offset, frame.size));
+            end = frame.offset + frame.size;
+            rewrite = true;
+        }
+    }
+    if rewrite {
+        log::info!("Rebuilding block store index {}", index.display());
+        let mut bytes = Vec::with_capacity(records.len() * INDEX_RECORD_SIZE);
+        for (hash, offset, size) in records.iter() {
+            bytes.extend_from_slice(&index_record(hash, *offset, *size));
+        }
+        fs::write(&index, bytes)
+            .map_err(|err| format!("Unable to write {}: {err}", index.display()))?;
+    }
+    Ok((records, end))
+}
+
+fn index_record(hash: &Hash256, offset: u64, size: u64) -> [u8; INDEX_RECORD_SIZE] {
+    let mut record = [0; INDEX_RECORD_SIZE];
+    record[..32].copy_from_slice(&hash.0);
+    record[32..40].copy_from_slice(&offset.to_le_bytes());
+    record[40..].copy_from_slice(&(size as u32).to_le_bytes());
+    record
+}
+
+fn append_to(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    append_parts_to(path, &[bytes])
+}
+
+// Append the parts one after the other, so a frame does not need to be copied into one buffer
+fn append_parts_to(path: &Path, parts: &[&[u8]]) -> Result<(), String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| parts.iter().try_for_each(|part| file.write_all(part)))
+        .map_err(|err| format!("Unable to write {}: {err}", path.display()))
+}
+
+/// Stores blocks in a directory of size capped segment files.
+///
+/// Each segment `blkNNNNN.dat` has an index `blkNNNNN.idx` of the blocks in it, which is
+/// loaded at startup so that blocks can be read by hash. A block is found by its position,
+/// the segment number and offset packed in a u64, which is what the database stores.
+pub struct BlockStore {
+    dir: PathBuf,
+    segment_size: u64,
+    index: Arc<RwLock<HashMap<Hash256, u64>>>,
+    // The segment that blocks are appended to, and its length
+    segment: u32,
+    segment_len: u64,
+}
+
+impl BlockStore {
+    pub fn open(dir: &Path, segment_size: u64) -> Result<Self, String> {
+        fs::create_dir_all(dir)
+            .map_err(|err| format!("Unable to create block store {}: {err}", dir.display()))?;
+        let segments = list_segments(dir)?;
+        let mut index = HashMap::new();
+        let mut segment_len = 0;
+        for segment in segments.iter() {
+            let (records, end) = load_segment(dir, *segment)?;
+            for (hash, offset, _size) in records {
+                index.entry(hash).or_insert(position(*segment, offset));
+            }
+            segment_len = end;
+        }
+        let segment = segments.last().copied().unwrap_or(0);
+
+        // Drop a partly written block from the end, so that the next block follows the last whole one
+        let data = data_path(dir, segment);
+        if let Ok(metadata) = fs::metadata(&data) {
+            if metadata.len() > segment_len {
+                log::warn!(
+                    "Truncating {} from {} to {} bytes",
+                    data.display(),
+                    metadata.len(),
+                    segment_len
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&data)
+                    .and_then(|file| file.set_len(segment_len))
+                    .map_err(|err| format!("Unable to truncate {}: {err}", data.display()))?;
+            }
+        }
+        log::info!(
+            "Block store {} has {} blocks in {} segments",
+            dir.display(),
+            index.len(),
+            segments.len()
+        );
+        Ok(BlockStore {
+            dir: dir.to_path_buf(),
+            segment_size,
+            index: Arc::new(RwLock::new(index)),
+            segment,
+            segment_len,
+        })
+    }
+
+    pub fn position(&self, hash: &Hash256) -> Option<u64> {
+        self.index
+            .read()
+            .expect("block store index")
+            .get(hash)
+            .copied()
+    }
+
+    // Append the block, returning its position. A block that is already stored is not written again
+    pub fn append(&mut self, block: &Block) -> Result<u64, String> {
+        let hash = block.header.hash();
+        if let Some(position) = self.position(&hash) {
+            return Ok(position);
+        }
+        let mut payload = Vec::with_capacity(block.size());
+        block
+            .write(&mut payload)
+            .map_err(|err| format!("Unable to serialise block {}: {err:?}", hash.encode()))?;
+        let length = u32::try_from(payload.len())
+            .map_err(|_| format!("Block {} is too large to store", hash.encode()))?;
+        let size = FRAME_HEADER_SIZE + u64::from(length);
+
+        // Start a new segment when this one is full, a block larger than a segment gets its own
+        if self.segment_len > 0 && self.segment_len + size > self.segment_size {
+            self.segment += 1;
+            self.segment_len = 0;
+        }
+        let offset = self.segment_len;
+        if offset + size > MAX_SEGMENT_SIZE {
+            return Err(format!("Block store segment {} is full", self.segment));
+        }
+
+        let mut frame_header = [0u8; FRAME_HEADER_SIZE as usize];
+        frame_header[..4].copy_from_slice(&MAGIC);
+        frame_header[4..8].copy_from_slice(&length.to_le_bytes());
+        frame_header[8..].copy_from_slice(&checksum(&payload));
+        // The data is written first, a frame missing from the index is found again at startup
+        append_parts_to(
+            &data_path(&self.dir, self.segment),
+            &[&frame_header, &payload],
+        )?;
+        self.segment_len += size;
+        append_to(
+            &index_path(&self.dir, self.segment),
+            &index_record(&hash, offset, size),
+        )?;
+
+        let position = position(self.segment, offset);
+        self.index
+            .write()
+            .expect("block store index")
+            .insert(hash, position);
+        Ok(position)
+    }
+
+    // Copy the blocks of a block file written before the block store, returning their new positions
+    pub fn import_legacy_file(&mut self, path: &Path) -> Result<Vec<(Hash256, u64)>, String> {
+        let file =
+            File::open(path).map_err(|err| format!("Unable to open {}: {err}", path.display()))?;
+        let mut reader = BufReader::new(file);
+        let mut positions = Vec::new();
+        // As before, the file ends at the first block that can not be read
+        while let Ok(block) = Block::read(&mut reader) {
+            let position = self.append(&block)?;
+            positions.push((block.header.hash(), position));
+        }
+        Ok(positions)
+    }
+
+    // A reader that shares the index, for use in other threads
+    pub fn reader(&self) -> BlockReader {
+        BlockReader {
+            dir: self.dir.clone(),
+            index: self.index.clone(),
+        }
+    }
+}
+
+/// Reads blocks from a `BlockStore`, cheap to clone and share between threads.
+#[derive(Clone)]
+pub struct BlockReader {
+    dir: PathBuf,
+    index: Arc<RwLock<HashMap<Hash256, u64>>>,
+}
+
+impl BlockReader {
+    // The serialised block at a position, checked against the frame checksum
+    pub fn read_raw(&self, position: u64) -> Result<Vec<u8>, String> {
+        let (segment, offset) = split_position(position);
+        let mut reader = SegmentReader::open(data_path(&self.dir, segment), offset)?;
+        let frame = reader
+            .frame_here()
+            .map_err(|err| format!("Unable to read block at position {position}: {err}"))?
+            .ok_or_else(|| format!("No valid block at position {position}"))?;
+        Ok(frame.payload)
+    }
+
+    pub fn read(&self, position: u64) -> Result<Block, String> {
+        decode_block(&self.read_raw(position)?)
+            .map_err(|err| format!("Unable to decode block at position {position}: {err}"))
+    }
+
+    pub fn position(&self, hash: &Hash256) -> Option<u64> {
+        self.index
+            .read()
+            .expect("block store index")
+            .get(hash)
+            .copied()
+    }
+
+    // The number of txs in a stored block, None if the block is not in the store
+    pub fn tx_count(&self, hash: &Hash256) -> Result<Option<u64>, String> {
+        self.position(hash)
+            .map(|position| self.read_raw(position).and_then(|block| tx_count(&block)))
+            .transpose()
+    }
+
+    pub fn read_by_hash(&self, hash: &Hash256) -> Result<Option<Block>, String> {
+        self.position(hash)
+            .map(|position| self.read(position))
+            .transpose()
+    }
+
+    // Pass the blocks in store order, starting at a position, to visit until it returns false.
+    // Damaged blocks are logged and skipped
+    pub fn scan(&self, from: u64, visit: &mut dyn FnMut(u64, Block) -> bool) -> Result<(), String> {
+        let (first, from_offset) = split_position(from);
+        for segment in list_segments(&self.dir)? {
+            if segment < first {
+                continue;
+            }
+            let offset = if segment == first { from_offset } else { 0 };
+            let mut reader = SegmentReader::open(data_path(&self.dir, segment), offset)?;
+            while let Some(frame) = reader.next_frame()? {
+                let position = position(segment, frame.offset);
+                match decode_block(&frame.payload) {
+                    Ok(block) => {
+                        if !visit(position, block) {
+                            return Ok(());
+                        }
+                    }
+                    Err(err) => log::warn!("Skipping block at position {position}: {err}"),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Total size of the segment files
+fn store_size(dir: &Path) -> Result<u64, String> {
+    let mut size = 0;
+    for segment in list_segments(dir)? {
+        let path = data_path(dir, segment);
+        size += fs::metadata(&path)
+            .map_err(|err| format!("Unable to read {}: {err}", path.display()))?
+            .len();
+    }
+    Ok(size)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CompactionInfo {
+    // Blocks in the compacted store
+    pub kept: usize,
+    // Orphaned, duplicate, damaged and pruned blocks left out
+    pub removed: usize,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// Rewrite the block store with only the main chain blocks, or only the last `prune` of them,
+/// and update the block positions in the database to match. Blocks that are left out keep
+/// their header, with the position `NOT_STORED`.
+///
+/// The new store is written next to the store directory and replaces it once complete.
+/// The service must not be running.
+pub fn compact(
+    dir: &Path,
+    segment_size: u64,
+    storage: &mut dyn Storage,
+    prune: Option<u32>,
+) -> Result<CompactionInfo, String> {
+    finish_compaction(dir, segment_size, storage)?;
+    let store = BlockStore::open(dir, segment_size)?;
+    let reader = store.reader();
+    let size_before = store_size(dir)?;
+    let stored = store.index.read().expect("block store index").len();
+
+    let headers = storage.load_block_headers()?;
+    let keep_from = match (prune, headers.last()) {
+        (Some(prune), Some((tip, _header))) => (tip + 1).saturating_sub(prune),
+        _ => 0,
+    };
+
+    let target = dir.with_extension("compact");
+    if target.exists() {
+        fs::remove_dir_all(&target)
+            .map_err(|err| format!("Unable to remove {}: {err}", target.display()))?;
+    }
+    let mut compacted = BlockStore::open(&target, segment_size)?;
+    for (height, header) in headers.iter() {
+        if *height < keep_from {
+            continue;
+        }
+        let hash = header.hash();
+        match reader.read_by_hash(&hash) {
+            Ok(Some(block)) => {
+                compacted.append(&block)?;
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("Dropping block {} at height {height}: {err}", hash.encode()),
+        }
+    }
+    let kept = compacted.index.read().expect("block store index").len();
+    let path = target.join(COMPACTION_COMPLETE);
+    fs::write(&path, b"").map_err(|err| format!("Unable to write {}: {err}", path.display()))?;
+    drop(store);
+    drop(compacted);
+
+    finish_compaction(dir, segment_size, storage)?;
+    Ok(CompactionInfo {
+        kept,
+        removed: stored - kept,
+        size_before,
+        size_after: store_size(dir)?,
+    })
+}
+
+/// Finish a compaction that was interrupted, called before the block store is opened.
+/// A compacted store that was completely written replaces the store, otherwise it is removed
+pub fn finish_compaction(
+    dir: &Path,
+    segment_size: u64,
+    storage: &mut dyn Storage,
+) -> Result<(), String> {
+    let target = dir.with_extension("compact");
+    let old = dir.with_extension("old");
+    let rename = |from: &Path, to: &Path| {
+        fs::rename(from, to).map_err(|err| {
+            format!(
+                "Unable to rename {} to {}: {err}",
+                from.display(),
+                to.display()
+            )
+        })
+    };
+
+    if target.join(COMPACTION_COMPLETE).exists() {
+        // Positions are written before the stores are swapped, so this can be repeated
+        let compacted = BlockStore::open(&target, segment_size)?;
+        let positions: Vec<(Hash256, u64)> = storage
+            .load_block_headers()?
+            .iter()
+            .map(|(_height, header)| {
+                let hash = header.hash();
+                (hash, compacted.position(&hash).unwrap_or(NOT_STORED))
+            })
+            .collect();
+        storage.block_positions_write(&positions)?;
+        if dir.exists() {
+            rename(dir, &old)?;
+        }
+        rename(&target, dir)?;
+        let path = dir.join(COMPACTION_COMPLETE);
+        fs::remove_file(&path)
+            .map_err(|err| format!("Unable to remove {}: {err}", path.display()))?;
+    } else if target.exists() {
+        log::warn!(
+            "Removing incomplete block store compaction {}",
+            target.display()
+        );
+        fs::remove_dir_all(&target)
+            .map_err(|err| format!("Unable to remove {}: {err}", target.display()))?;
+    }
+    if old.exists() {
+        fs::remove_dir_all(&old)
+            .map_err(|err| format!("Unable to remove {}: {err}", old.display()))?;
+    }
+    Ok(())
+}
+
+// Blocks and directories for the tests of the modules that store blocks
+#[cfg(test)]
+pub(crate) mod test_blocks {
+    use std::{fs, path::PathBuf};
+
+    use chain_gang::{
+        messages::{Block, BlockHeader},
+        util::Hash256,
+    };
+
+    // A temporary directory for the test, removed first if an earlier run left it behind
+    pub fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("uaas-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // A chain of empty blocks after prev_hash, branches from the same block differ by fork
+    pub fn chain(mut prev_hash: Hash256, length: u32, fork: u32) -> Vec<Block> {
+        (0..length)
+            .map(|nonce| {
+                let block = Block {
+                    header: BlockHeader {
+                        prev_hash,
+                        nonce,
+                        version: fork,
+                        ..Default::default()
+                    },
+                    txns: Vec::new(),
+                };
+                prev_hash = block.header.hash();
+                block
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uaas::{
+        block_store::test_blocks::{chain, test_dir},
+        database::BlockHeaderWriteDB,
+        storage::MemoryBackend,
+    };
+    use chain_gang::messages::BlockHeader;
+
+    fn scan_all(reader: &BlockReader) -> Vec<(u64, Hash256)> {
+        let mut found = Vec::new();
+        reader
+            .scan(0, &mut |position, block| {
+                found.push((position, block.header.hash()));
+                true
+            })
+            .unwrap();
+        found
+    }
+
+    #[test]
+    fn blocks_are_read_by_position_and_hash_across_segments() {
+        let dir = test_dir("block-store-segments");
+        let blocks = chain(Hash256::default(), 5, 0);
+        // Room for two blocks per segment
+        let mut store = BlockStore::open(&dir, 200).unwrap();
+        let positions: Vec<u64> = blocks.iter().map(|b| store.append(b).unwrap()).collect();
+        assert_eq!(split_position(positions[4]), (2, 0));
+        assert_eq!(store.append(&blocks[1]).unwrap(), positions[1]);
+
+        let reader = store.reader();
+        for (block, position) in blocks.iter().zip(positions.iter()) {
+            let hash = block.header.hash();
+            assert_eq!(reader.read(*position).unwrap().header.hash(), hash);
+            assert_eq!(
+                reader.read_by_hash(&hash).unwrap().unwrap().header,
+                block.header
+            );
+        }
+        assert!(reader.read_by_hash(&Hash256([9; 32])).unwrap().is_none());
+        let scanned = scan_all(&reader);
+        assert_eq!(scanned.len(), 5);
+        assert_eq!(scanned[3], (positions[3], blocks[3].header.hash()));
+
+        // From a position, in store order
+        let mut from = Vec::new();
+        reader
+            .scan(positions[2], &mut |position, _block| {
+                from.push(position);
+                true
+            })
+            .unwrap();
+        assert_eq!(from, positions[2..].to_vec());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_rebuilds_the_index_and_drops_a_partial_block() {
+        let dir = test_dir("block-store-reopen");
+        let blocks = chain(Hash256::default(), 3, 0);
+        let mut store = BlockStore::open(&dir, MAX_SEGMENT_SIZE).unwrap();
+        for block in blocks.iter().take(2) {
+            store.append(block).unwrap();
+        }
+        drop(store);
+
+        // Lose the index, and leave half a block at the end of the data
+        fs::remove_file(index_path(&dir, 0)).unwrap();
+        append_to(&data_path(&dir, 0), &MAGIC).unwrap();
+
+        let mut store = BlockStore::open(&dir, MAX_SEGMENT_SIZE).unwrap();
+        assert!(store.position(&blocks[1].header.hash()).is_some());
+        let position = store.append(&blocks[2]).unwrap();
+        assert_eq!(
+            scan_all(&store.reader()).last(),
+            Some(&(position, blocks[2].header.hash()))
+        );
+        assert_eq!(
+            fs::read(index_path(&dir, 0)).unwrap().len(),
+            3 * INDEX_RECORD_SIZE
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_block_is_skipped() {
+        let dir = test_dir("block-store-damaged");
+        let blocks = chain(Hash256::default(), 3, 0);
+        let mut store = BlockStore::open(&dir, MAX_SEGMENT_SIZE).unwrap();
+        let positions: Vec<u64> = blocks.iter().map(|b| store.append(b).unwrap()).collect();
+
+        // Change a byte of the second block
+        let path = data_path(&dir, 0);
+        let mut data = fs::read(&path).unwrap();
+        data[positions[1] as usize + 20] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let reader = store.reader();
+        assert!(reader.read(positions[1]).is_err());
+        let scanned: Vec<u64> = scan_all(&reader).iter().map(|(p, _)| *p).collect();
+        assert_eq!(scanned, vec![positions[0], positions[2]]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_the_main_chain_and_prunes_old_blocks() {
+        let dir = test_dir("block-store-compaction");
+        let blocks = chain(Hash256::default(), 4, 0);
+        let orphan = Block {
+            header: BlockHeader {
+                nonce: 99,
+                ..blocks[3].header
+            },
+            txns: Vec::new(),
+        };
+        let mut store = BlockStore::open(&dir, 200).unwrap();
+        let backend = MemoryBackend::new();
+        for (height, block) in blocks.iter().enumerate() {
+            let header = &block.header;
+            backend.data().blocks.insert(
+                header.hash(),
+                BlockHeaderWriteDB {
+                    height: height as u32,
+                    hash: header.hash(),
+                    version: header.version,
+                    prev_hash: header.prev_hash,
+                    merkle_root: header.merkle_root,
+                    timestamp: header.timestamp,
+                    bits: header.bits,
+                    nonce: header.nonce,
+                    position: store.append(block).unwrap(),
+                    ..Default::default()
+                },
+            );
+            if height == 2 {
+                store.append(&orphan).unwrap();
+            }
+        }
+        drop(store);
+        // Left behind by an interrupted compaction
+        fs::create_dir_all(dir.with_extension("compact")).unwrap();
+
+        let mut storage = backend.clone();
+        let info = compact(&dir, 200, &mut storage, None).unwrap();
+        assert_eq!((info.kept, info.removed), (4, 1));
+        assert!(info.size_after < info.size_before);
+        assert!(!dir.with_extension("compact").exists());
+        let store = BlockStore::open(&dir, 200).unwrap();
+        assert!(store.position(&orphan.header.hash()).is_none());
+        for block in blocks.iter() {
+            let hash = block.header.hash();
+            let position = backend.data().blocks[&hash].position;
+            assert_eq!(store.position(&hash), Some(position));
+            assert_eq!(store.reader().read(position).unwrap().header, block.header);
+        }
+        drop(store);
+
+        let info = compact(&dir, 200, &mut storage, Some(2)).unwrap();
+        assert_eq!((info.kept, info.removed), (2, 2));
+        let store = BlockStore::open(&dir, 200).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            let hash = block.header.hash();
+            let position = backend.data().blocks[&hash].position;
+            if height < 2 {
+                assert_eq!(position, NOT_STORED);
+                assert!(store.position(&hash).is_none());
+            } else {
+                assert_eq!(store.position(&hash), Some(position));
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tx_bytes_are_cut_from_the_serialised_block() {
+        let tx = |version: u32| Tx {
+            version,
+            lock_time: version,
+            ..Default::default()
+        };
+        let block = Block {
+            header: BlockHeader::default(),
+            txns: vec![tx(1), tx(2), tx(3)],
+        };
+        let mut bytes = Vec::new();
+        block.write(&mut bytes).unwrap();
+        for (index, expected) in block.txns.iter().enumerate() {
+            let found = tx_bytes(&bytes, index as u32).unwrap();
+            assert_eq!(sha256d(found), expected.hash());
+        }
+        assert!(tx_bytes(&bytes, 3).is_err());
+    }
+}
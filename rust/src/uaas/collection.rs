@@ -1,10 +1,8 @@
 use std::time::Instant;
 
-use mysql::{prelude::*, PooledConn, *};
-
 use crate::{
-    config::{CollectionConfig, Config},
-    uaas::hexslice::HexSlice,
+    config::CollectionConfig,
+    uaas::{hexslice::HexSlice, monitor::MonitorKey, storage::Storage},
 };
 use anyhow::{anyhow, Result};
 use chain_gang::{
@@ -15,7 +13,6 @@ use chain_gang::{
     util::{Hash256, Serializable},
 };
 use regex::Regex;
-use retry::{delay, retry};
 
 /// Given an address return a locking script in hexstr format
 fn address_to_lock_script(address: &str, network: Network) -> Result<String> {
@@ -29,105 +26,77 @@ fn address_to_lock_script(address: &str, network: Network) -> Result<String> {
     Ok(hex::encode(script.0))
 }
 
+/// Name of the collection used to catch txs broadcast through uaas
+pub const BROADCAST_COLLECTION: &str = "broadcast";
+
+/// Check that a monitor can be turned into a working collection
+pub fn validate_monitor(monitor: &CollectionConfig, network: Network) -> Result<()> {
+    if monitor.name.is_empty() {
+        return Err(anyhow!("Monitor name must not be empty"));
+    }
+    if monitor.name == BROADCAST_COLLECTION {
+        return Err(anyhow!("Monitor name '{BROADCAST_COLLECTION}' is reserved"));
+    }
+    WorkingCollection::new(monitor.clone(), network)?;
+    Ok(())
+}
+
 /// Database interface used by all collections
 ///
 ///
-#[derive(Debug)]
 pub struct CollectionDatabase {
-    // Retry database connections
-    ms_delay: u64,
-    retries: usize,
-    conn: PooledConn,
+    storage: Box<dyn Storage>,
 }
 
 impl CollectionDatabase {
-    pub fn new(conn: PooledConn, config: &Config) -> Self {
-        CollectionDatabase {
-            ms_delay: config.database.ms_delay,
-            retries: config.database.retries,
-            conn,
-        }
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        CollectionDatabase { storage }
     }
 
-    fn decode_stored_hash(value: &str) -> Option<Hash256> {
-        match Hash256::decode(value) {
-            Ok(hash) => Some(hash),
-            Err(err) => {
-                log::error!("Invalid stored collection tx hash {value}: {err:?}");
-                None
-            }
-        }
-    }
-
-    pub fn create_table(&self, conn: &mut PooledConn) {
-        log::info!("Table collection not found - creating");
-
-        let table = "CREATE TABLE collection (hash varchar(64), name varchar(64), tx longtext, CONSTRAINT PK_Entry PRIMARY KEY (hash, name));";
-        if let Err(err) = conn.query_drop(table) {
-            log::error!("Unable to create collection table: {err:?}");
-            return;
-        }
-
-        let index = "CREATE INDEX collect_key ON collection (hash, name);";
-        if let Err(err) = conn.query_drop(index) {
-            log::error!("Unable to create collection index: {err:?}");
-        }
-    }
-
-    pub fn load_txs(&mut self, collection_name: &str) -> Vec<Hash256> {
+    pub fn load_txs(&mut self, key: &MonitorKey) -> Vec<Hash256> {
         // load txs- tx hash from database
         let start = Instant::now();
-        let txs: Vec<String> = match self.conn.exec_map(
-            "SELECT hash FROM collection WHERE name = :name",
-            params! { "name" => collection_name },
-            |hash| hash,
-        ) {
+        let retval = match self.storage.load_collection_txs(key) {
             Ok(txs) => txs,
             Err(err) => {
-                log::error!("Unable to load collection txs for {collection_name}: {err:?}");
+                log::error!("Unable to load collection txs for {key}: {err}");
                 return Vec::new();
             }
         };
 
-        let retval: Vec<Hash256> = txs
-            .iter()
-            .filter_map(|hash| Self::decode_stored_hash(hash))
-            .collect();
-
         log::info!(
             "Collection {} Loaded {} in {} seconds",
-            collection_name,
+            key,
             retval.len(),
             start.elapsed().as_millis() as f64 / 1000.0
         );
         retval
     }
 
-    pub fn write_tx_to_database(&mut self, collection_name: &str, tx: &Tx) {
-        let hash = tx.hash().encode();
+    pub fn delete_txs(&mut self, key: &MonitorKey) {
+        if let Err(err) = self.storage.collection_delete(key) {
+            log::error!("Unable to delete collection txs for {key}: {err}");
+        }
+    }
+
+    pub fn write_tx_to_database(&mut self, key: &MonitorKey, tx: &Tx) {
+        let hash = tx.hash();
         // Write the tx as hexstr
         let mut b = Vec::with_capacity(tx.size());
         if let Err(err) = tx.write(&mut b) {
-            log::error!("Unable to serialize collection tx {hash}: {err:?}");
+            log::error!(
+                "Unable to serialize collection tx {}: {err:?}",
+                hash.encode()
+            );
             return;
         }
         let tx_hex = format!("{}", HexSlice::new(&b));
 
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn.exec_drop(
-                    "INSERT INTO collection (hash, name, tx) VALUES (:hash, :name, :tx)",
-                    params! {
-                        "hash" => hash.as_str(),
-                        "name" => collection_name,
-                        "tx" => tx_hex.as_str(),
-                    },
-                )
-            },
-        );
-        if let Err(err) = result {
-            log::error!("Unable to write collection tx {hash} for {collection_name}: {err:?}");
+        if let Err(err) = self.storage.collection_tx_write(key, &hash, &tx_hex) {
+            log::error!(
+                "Unable to write collection tx {} for {key}: {err}",
+                hash.encode()
+            );
         }
     }
 }
@@ -135,6 +104,8 @@ impl CollectionDatabase {
 pub struct WorkingCollection {
     // this is a collection that also maintains a list of tx hashes that it has used
     pub collection: CollectionConfig,
+    // The owner and name the txs are stored under
+    key: MonitorKey,
     pub txs: Vec<Hash256>,
     // No point to the Collection if there is no locking_script_regex
     // Actually there is for is_uaas_broadcast txs
@@ -148,6 +119,7 @@ impl WorkingCollection {
             let pattern = address_to_lock_script(addr, network)?;
             let locking_script_regex = Regex::new(&pattern)?;
             return Ok(WorkingCollection {
+                key: MonitorKey::of(&collection),
                 collection: collection.clone(),
                 txs: Vec::new(),
                 locking_script_regex: Some(locking_script_regex),
@@ -158,6 +130,7 @@ impl WorkingCollection {
             let locking_script_regex = Regex::new(pattern)?;
 
             return Ok(WorkingCollection {
+                key: MonitorKey::of(&collection),
                 collection: collection.clone(),
                 txs: Vec::new(),
                 locking_script_regex: Some(locking_script_regex),
@@ -172,13 +145,15 @@ impl WorkingCollection {
     // Create a special form of collection just to catch broadcasts
     pub fn create_broadcast_collection() -> Self {
         let broadcast_collection = CollectionConfig {
-            name: "broadcast".to_string(),
+            name: BROADCAST_COLLECTION.to_string(),
             track_descendants: false,
             address: None,
             locking_script_pattern: None,
+            owner: None,
         };
 
         WorkingCollection {
+            key: MonitorKey::of(&broadcast_collection),
             // this is a collection that also maintains a list of tx hashes that it has used
             collection: broadcast_collection,
             txs: Vec::new(),
@@ -192,6 +167,17 @@ impl WorkingCollection {
         self.collection.name.as_str()
     }
 
+    pub fn key(&self) -> &MonitorKey {
+        &self.key
+    }
+
+    // True if the config matches the same txs, so the txs already collected still belong to it
+    pub fn same_filter(&self, config: &CollectionConfig) -> bool {
+        self.collection.address == config.address
+            && self.collection.locking_script_pattern == config.locking_script_pattern
+            && self.collection.track_descendants == config.track_descendants
+    }
+
     pub fn track_descendants(&self) -> bool {
         self.collection.track_descendants
     }
@@ -215,6 +201,11 @@ impl WorkingCollection {
         false
     }
 
+    pub fn matches(&self, tx: &Tx) -> bool {
+        // Return true if the tx belongs in this collection
+        (self.track_descendants() && self.is_decendant(tx)) || self.match_any_locking_script(tx)
+    }
+
     pub fn push(&mut self, hash: Hash256) {
         // Add to our list of known txs
         self.txs.push(hash);
@@ -247,6 +238,7 @@ mod tests {
             track_descendants: false,
             address: None,
             locking_script_pattern: Some("76a914".to_string()),
+            owner: None,
         };
         let working = WorkingCollection::new(collection, Network::BSV_Testnet).expect("collection");
         let script = Script(
@@ -263,4 +255,78 @@ mod tests {
         };
         assert!(working.match_any_locking_script(&tx));
     }
+
+    #[test]
+    fn validate_monitor_rejects_bad_pattern_and_reserved_name() {
+        let mut monitor = CollectionConfig {
+            name: "bad".to_string(),
+            track_descendants: false,
+            address: None,
+            locking_script_pattern: Some("76a9[".to_string()),
+            owner: None,
+        };
+        assert!(validate_monitor(&monitor, Network::BSV_Testnet).is_err());
+
+        monitor.locking_script_pattern = Some("76a914".to_string());
+        assert!(validate_monitor(&monitor, Network::BSV_Testnet).is_ok());
+
+        monitor.name = BROADCAST_COLLECTION.to_string();
+        assert!(validate_monitor(&monitor, Network::BSV_Testnet).is_err());
+    }
+
+    #[test]
+    fn same_filter_compares_what_the_monitor_matches() {
+        let mut monitor = CollectionConfig {
+            name: "pattern".to_string(),
+            track_descendants: false,
+            address: None,
+            locking_script_pattern: Some("76a914".to_string()),
+            owner: Some("team-a".to_string()),
+        };
+        let working =
+            WorkingCollection::new(monitor.clone(), Network::BSV_Testnet).expect("collection");
+        assert!(working.same_filter(&monitor));
+        monitor.track_descendants = true;
+        assert!(!working.same_filter(&monitor));
+        monitor.track_descendants = false;
+        monitor.locking_script_pattern = Some("006a".to_string());
+        assert!(!working.same_filter(&monitor));
+    }
+
+    #[test]
+    fn tx_matches_multiple_collections() {
+        let pattern = |name: &str, pattern: &str| CollectionConfig {
+            name: name.to_string(),
+            track_descendants: false,
+            address: None,
+            locking_script_pattern: Some(pattern.to_string()),
+            owner: None,
+        };
+        let first = WorkingCollection::new(pattern("first", "76a914"), Network::BSV_Testnet)
+            .expect("collection");
+        let second = WorkingCollection::new(pattern("second", "006a"), Network::BSV_Testnet)
+            .expect("collection");
+        let broadcast = WorkingCollection::create_broadcast_collection();
+        let tx = Tx {
+            version: 1,
+            inputs: Vec::new(),
+            outputs: vec![
+                TxOut {
+                    satoshis: 1000,
+                    lock_script: Script(
+                        hex::decode("76a9147c78584493557fac782023a4ad591b64545929d988ac")
+                            .expect("script hex"),
+                    ),
+                },
+                TxOut {
+                    satoshis: 0,
+                    lock_script: Script(hex::decode("006a0568656c6c6f").expect("script hex")),
+                },
+            ],
+            lock_time: 0,
+        };
+        assert!(first.matches(&tx));
+        assert!(second.matches(&tx));
+        assert!(!broadcast.matches(&tx));
+    }
 }
@@ -0,0 +1,364 @@
+use std::fmt;
+
+use chain_gang::{messages::BlockHeader, util::Hash256};
+
+use super::storage::Storage;
+
+// A problem found in the stored chain state at startup
+#[derive(Debug, PartialEq)]
+pub enum Inconsistency {
+    // The headers skip or repeat a height
+    UnexpectedHeight { expected: u32, found: u32 },
+    // The header at this height does not follow the header below it
+    BrokenLink(u32),
+    // The tip at this height has been recorded as an orphan
+    OrphanTip(u32),
+    // Rows stored up to this height, above the tip
+    TxAboveTip(u32),
+    UtxoAboveTip(u32),
+    // Number of mempool entries that are already in a block
+    MinedInMempool(usize),
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::UnexpectedHeight { expected, found } => write!(
+                f,
+                "expected a block header at height {expected}, found one at height {found}"
+            ),
+            Inconsistency::BrokenLink(height) => write!(
+                f,
+                "block header at height {height} does not follow the header below it"
+            ),
+            Inconsistency::OrphanTip(height) => {
+                write!(f, "block at height {height} is the tip but is an orphan")
+            }
+            Inconsistency::TxAboveTip(height) => write!(f, "txs stored up to height {height}"),
+            Inconsistency::UtxoAboveTip(height) => write!(f, "utxo stored up to height {height}"),
+            Inconsistency::MinedInMempool(count) => {
+                write!(f, "{count} mempool entries are already in a block")
+            }
+        }
+    }
+}
+
+// The result of checking the stored chain state, and what is needed to repair it
+pub struct ConsistencyReport {
+    pub issues: Vec<Inconsistency>,
+    // Number of the loaded headers that are kept
+    pub valid_headers: usize,
+    // Height of the last kept header
+    pub tip: u32,
+    save_txs: bool,
+    // Headers above the tip, to remove
+    truncated: Vec<Hash256>,
+    // Highest height of the tx and utxo rows above the tip, to remove
+    clear_to: Option<u32>,
+    mined_mempool: Vec<Hash256>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    // The blocks above the tip can be read again from the block store,
+    // unless the tip was removed because it is an orphan, which the block store still contains
+    pub fn replay_from_block_file(&self) -> bool {
+        !self.truncated.is_empty()
+            && !self
+                .issues
+                .iter()
+                .any(|issue| matches!(issue, Inconsistency::OrphanTip(_)))
+    }
+
+    pub fn repair(&self, storage: &mut dyn Storage) -> Result<(), String> {
+        // Headers are removed first, so that if the repair is interrupted
+        // the rows above the tip are found again at the next startup
+        for hash in self.truncated.iter().rev() {
+            storage.block_header_delete(hash)?;
+        }
+        if let Some(clear_to) = self.clear_to {
+            for height in self.tip + 1..=clear_to {
+                if self.save_txs {
+                    storage.tx_delete_at_height(height)?;
+                }
+                storage.utxo_delete_at_height(height)?;
+            }
+        }
+        if !self.mined_mempool.is_empty() {
+            storage.mempool_batch_delete(&self.mined_mempool)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ConsistencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} consistency issue(s) found, the last consistent block is at height {}",
+            self.issues.len(),
+            self.tip
+        )?;
+        for issue in self.issues.iter() {
+            write!(f, "\n  - {issue}")?;
+        }
+        Ok(())
+    }
+}
+
+// Check the loaded block headers (in height order) against the rest of the stored state.
+// The tip is start_height when no header is kept
+pub fn check(
+    storage: &mut dyn Storage,
+    headers: &[(u32, BlockHeader)],
+    start_height: u32,
+    save_txs: bool,
+) -> Result<ConsistencyReport, String> {
+    let mut issues = Vec::new();
+
+    // Keep the headers up to the first gap or broken link
+    let mut valid_headers = headers.len();
+    for (index, pair) in headers.windows(2).enumerate() {
+        let (height, header) = &pair[0];
+        let (next_height, next_header) = &pair[1];
+        if *next_height != height + 1 {
+            issues.push(Inconsistency::UnexpectedHeight {
+                expected: height + 1,
+                found: *next_height,
+            });
+        } else if next_header.prev_hash != header.hash() {
+            issues.push(Inconsistency::BrokenLink(*next_height));
+        } else {
+            continue;
+        }
+        valid_headers = index + 1;
+        break;
+    }
+
+    // The orphan was recorded but the header was not removed
+    if let Some((height, header)) = headers[..valid_headers].last() {
+        if storage.orphan_exists(&header.hash())? {
+            issues.push(Inconsistency::OrphanTip(*height));
+            valid_headers -= 1;
+        }
+    }
+
+    let tip = match valid_headers.checked_sub(1) {
+        Some(last) => headers[last].0,
+        None => headers
+            .first()
+            .map_or(start_height, |(height, _header)| height.saturating_sub(1)),
+    };
+    let truncated = headers[valid_headers..]
+        .iter()
+        .map(|(_height, header)| header.hash())
+        .collect();
+
+    let mut clear_to = None;
+    if save_txs {
+        if let Some(height) = storage.max_tx_height()?.filter(|height| *height > tip) {
+            issues.push(Inconsistency::TxAboveTip(height));
+            clear_to = Some(height);
+        }
+    }
+    if let Some(height) = storage.max_utxo_height()?.filter(|height| *height > tip) {
+        issues.push(Inconsistency::UtxoAboveTip(height));
+        clear_to = clear_to.max(Some(height));
+    }
+
+    // Only the txs table records which txs are mined
+    let mut mined_mempool = Vec::new();
+    if save_txs {
+        for hash in storage.load_mempool()? {
+            if storage
+                .tx_height(&hash)?
+                .is_some_and(|height| height <= tip)
+            {
+                mined_mempool.push(hash);
+            }
+        }
+        if !mined_mempool.is_empty() {
+            issues.push(Inconsistency::MinedInMempool(mined_mempool.len()));
+        }
+    }
+
+    Ok(ConsistencyReport {
+        issues,
+        valid_headers,
+        tip,
+        save_txs,
+        truncated,
+        clear_to,
+        mined_mempool,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uaas::{
+        database::{
+            BlockHeaderWriteDB, MempoolEntryDB, OrphanBlockHeaderWriteDB, TxEntryWriteDB,
+            UtxoEntryDB,
+        },
+        storage::MemoryBackend,
+    };
+
+    const START_HEIGHT: u32 = 100;
+
+    // Write a chain of headers from START_HEIGHT + 1, return them as loaded
+    fn write_chain(storage: &mut MemoryBackend, count: u32) -> Vec<(u32, BlockHeader)> {
+        let mut prev_hash = Hash256::default();
+        let mut headers = Vec::new();
+        for height in START_HEIGHT + 1..=START_HEIGHT + count {
+            let header = BlockHeader {
+                version: 1,
+                prev_hash,
+                merkle_root: Hash256::default(),
+                timestamp: height,
+                bits: 0,
+                nonce: 0,
+            };
+            storage
+                .block_header_write(&BlockHeaderWriteDB {
+                    height,
+                    hash: header.hash(),
+                    version: header.version,
+                    prev_hash: header.prev_hash,
+                    merkle_root: header.merkle_root,
+                    timestamp: header.timestamp,
+                    bits: header.bits,
+                    nonce: header.nonce,
+                    position: 0,
+                    blocksize: 0,
+                    numtxs: 1,
+                    utxo_commitment: None,
+                })
+                .unwrap();
+            prev_hash = header.hash();
+            headers.push((height, header));
+        }
+        headers
+    }
+
+    fn write_tx(storage: &mut MemoryBackend, hash: Hash256, height: u32) {
+        storage
+            .tx_batch_write(&[TxEntryWriteDB {
+                hash,
+                height: height as usize,
+                blockindex: 0,
+                size: 0,
+                satoshis: 0,
+            }])
+            .unwrap();
+        storage
+            .utxo_batch_write(&[UtxoEntryDB {
+                hash: hash.encode(),
+                pos: 0,
+                satoshis: 1000,
+                height: height as i32,
+                pubkeyhash: String::new(),
+            }])
+            .unwrap();
+    }
+
+    fn write_mempool(storage: &mut MemoryBackend, hash: Hash256) {
+        storage
+            .mempool_batch_write(&[MempoolEntryDB {
+                hash,
+                locktime: 0,
+                fee: 0,
+                age: 0,
+                tx: String::new(),
+            }])
+            .unwrap();
+    }
+
+    fn hash(value: u8) -> Hash256 {
+        let mut hash = Hash256::default();
+        hash.0[0] = value;
+        hash
+    }
+
+    #[test]
+    fn consistent_state_has_no_issues() {
+        let mut storage = MemoryBackend::new();
+        let headers = write_chain(&mut storage, 3);
+        write_tx(&mut storage, hash(1), START_HEIGHT + 3);
+        write_mempool(&mut storage, hash(2));
+
+        let report = check(&mut storage, &headers, START_HEIGHT, true).unwrap();
+        assert!(report.is_consistent());
+        assert_eq!(report.valid_headers, 3);
+        assert_eq!(report.tip, START_HEIGHT + 3);
+
+        let report = check(&mut MemoryBackend::new(), &[], START_HEIGHT, true).unwrap();
+        assert!(report.is_consistent());
+        assert_eq!(report.tip, START_HEIGHT);
+    }
+
+    #[test]
+    fn gap_is_truncated_with_rows_above_it() {
+        let mut storage = MemoryBackend::new();
+        let mut headers = write_chain(&mut storage, 4);
+        // Lose the header at height 103
+        storage.block_header_delete(&headers[2].1.hash()).unwrap();
+        headers.remove(2);
+        write_tx(&mut storage, hash(1), START_HEIGHT + 2);
+        write_tx(&mut storage, hash(2), START_HEIGHT + 4);
+        // Mined in a kept block, and in a removed block
+        write_mempool(&mut storage, hash(1));
+        write_mempool(&mut storage, hash(2));
+
+        let report = check(&mut storage, &headers, START_HEIGHT, true).unwrap();
+        assert_eq!(
+            report.issues,
+            vec![
+                Inconsistency::UnexpectedHeight {
+                    expected: START_HEIGHT + 3,
+                    found: START_HEIGHT + 4
+                },
+                Inconsistency::TxAboveTip(START_HEIGHT + 4),
+                Inconsistency::UtxoAboveTip(START_HEIGHT + 4),
+                Inconsistency::MinedInMempool(1),
+            ]
+        );
+        assert_eq!(report.valid_headers, 2);
+        assert_eq!(report.tip, START_HEIGHT + 2);
+        assert!(report.replay_from_block_file());
+
+        report.repair(&mut storage).unwrap();
+        let headers = storage.load_block_headers().unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(storage.tx_height(&hash(2)).unwrap(), None);
+        assert_eq!(storage.load_mempool().unwrap(), vec![hash(2)]);
+        assert!(check(&mut storage, &headers, START_HEIGHT, true)
+            .unwrap()
+            .is_consistent());
+    }
+
+    #[test]
+    fn orphan_tip_is_removed_without_replay() {
+        let mut storage = MemoryBackend::new();
+        let headers = write_chain(&mut storage, 2);
+        let (height, tip) = &headers[1];
+        storage
+            .orphan_block_header_write(&OrphanBlockHeaderWriteDB {
+                height: *height,
+                hash: tip.hash(),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let report = check(&mut storage, &headers, START_HEIGHT, false).unwrap();
+        assert_eq!(report.issues, vec![Inconsistency::OrphanTip(*height)]);
+        assert_eq!(report.tip, START_HEIGHT + 1);
+        assert!(!report.replay_from_block_file());
+
+        report.repair(&mut storage).unwrap();
+        assert_eq!(storage.load_block_headers().unwrap().len(), 1);
+    }
+}
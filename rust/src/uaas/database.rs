@@ -1,14 +1,20 @@
-use std::sync::mpsc;
+use std::{
+    process,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
 
 use chain_gang::{messages::OutPoint, util::Hash256};
 
-use mysql::{prelude::*, PooledConn, *};
-
-use crate::config::Config;
-use retry::{delay, retry};
+use super::{
+    metrics::DatabaseMetrics,
+    storage::{is_constraint_violation, Storage},
+};
+use crate::config::{DatabaseConfig, WriteErrorPolicy};
 
 // UtxoEntry - used to store data into utxo table
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct UtxoEntryDB {
     pub hash: String,
     pub pos: u32,
@@ -28,7 +34,7 @@ pub struct TxEntryWriteDB {
 }
 
 // database header structure
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct BlockHeaderWriteDB {
     pub height: u32,
     pub hash: Hash256,
@@ -41,6 +47,8 @@ pub struct BlockHeaderWriteDB {
     pub position: u64,
     pub blocksize: u32,
     pub numtxs: u32,
+    // The utxo set hash after this block, see Utxo::block_commitment
+    pub utxo_commitment: Option<Hash256>,
 }
 
 #[derive(Clone, Default)]
@@ -55,6 +63,7 @@ pub struct OrphanBlockHeaderWriteDB {
     pub nonce: u32,
 }
 
+#[derive(Clone)]
 pub struct MempoolEntryDB {
     pub hash: Hash256,
     pub locktime: u32,
@@ -63,352 +72,317 @@ pub struct MempoolEntryDB {
     pub tx: String,
 }
 
+// All the writes for one or more blocks, applied together with the headers written last
+#[derive(Default)]
+pub struct BlockWriteDB {
+    pub utxo_writes: Vec<UtxoEntryDB>,
+    pub utxo_deletes: Vec<OutPoint>,
+    pub tx_writes: Vec<TxEntryWriteDB>,
+    pub mempool_deletes: Vec<Hash256>,
+    pub headers: Vec<BlockHeaderWriteDB>,
+}
+
+impl BlockWriteDB {
+    // Add the writes of the following block
+    pub fn append(&mut self, other: BlockWriteDB) {
+        self.utxo_writes.extend(other.utxo_writes);
+        self.utxo_deletes.extend(other.utxo_deletes);
+        self.tx_writes.extend(other.tx_writes);
+        self.mempool_deletes.extend(other.mempool_deletes);
+        self.headers.extend(other.headers);
+    }
+}
+
 // DBOperationType - used to identify the type of operation that the database needs to do
 pub enum DBOperationType {
     UtxoBatchWrite(Vec<UtxoEntryDB>),
     UtxoBatchDelete(Vec<OutPoint>),
-    TxBatchWrite(Vec<TxEntryWriteDB>),
-    MempoolBatchDelete(Vec<Hash256>),
     MempoolBatchWrite(Vec<MempoolEntryDB>),
-    BlockHeaderWrite(BlockHeaderWriteDB),
+    BlockWrite(BlockWriteDB),
     OrphanBlockHeaderWrite(OrphanBlockHeaderWriteDB),
     BlockHeaderDelete(Hash256),
     TxDelete(u32),
     UtxoDelete(u32),
 }
 
+// Sends operations to the database writer.
+// The queue is bounded, so a sender waits while the database writer catches up
+#[derive(Clone)]
+pub struct DBSender {
+    tx: mpsc::SyncSender<DBOperationType>,
+    // Number of operations sent, held while sending so that operations are numbered in queue order
+    sent: Arc<Mutex<u64>>,
+    metrics: Arc<DatabaseMetrics>,
+}
+
+impl DBSender {
+    // Returns the number of the operation, it has been written once `written()` reaches it
+    pub fn send(&self, op: DBOperationType) -> Result<u64, String> {
+        if self.metrics.is_halted() {
+            return Err("database writer has halted".to_string());
+        }
+        let mut sent = self.sent.lock().unwrap_or_else(|e| e.into_inner());
+        self.metrics.queued();
+        let result = match self.tx.try_send(op) {
+            Ok(()) => Ok(()),
+            Err(mpsc::TrySendError::Full(op)) => {
+                let start = Instant::now();
+                let result = self.tx.send(op);
+                self.metrics.record_backpressure(start.elapsed());
+                result.map_err(|_| ())
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => Err(()),
+        };
+        result.map_err(|()| {
+            self.metrics.dequeued();
+            "database writer has stopped".to_string()
+        })?;
+        *sent += 1;
+        Ok(*sent)
+    }
+
+    // Number of the operations sent that have been written
+    pub fn written(&self) -> u64 {
+        self.metrics.written()
+    }
+}
+
+// The queue from the components to the database writer
+pub fn write_queue(
+    capacity: usize,
+    metrics: Arc<DatabaseMetrics>,
+) -> (DBSender, mpsc::Receiver<DBOperationType>) {
+    let (tx, rx) = mpsc::sync_channel(capacity);
+    let sender = DBSender {
+        tx,
+        sent: Arc::new(Mutex::new(0)),
+        metrics,
+    };
+    (sender, rx)
+}
+
 // This will be run in a separate thread that will be responsible for all the database writes
 // so as not to delay the main thread of execution during IBD
 pub struct Database {
-    // Database connection
-    conn: PooledConn,
+    // Where the operations are written
+    storage: Box<dyn Storage>,
     // Channel on which to receive operations
     rx: mpsc::Receiver<DBOperationType>,
-
-    // Retry database connections
-    ms_delay: u64,
+    // Operation received while coalescing a batch of a different type
+    pending: Option<DBOperationType>,
+    // Number of operations received, including the pending one
+    received: u64,
+    on_write_error: WriteErrorPolicy,
+    // Delay between retries of a failed write, and the number of retries before halting
+    retry_delay: Duration,
     retries: usize,
+    metrics: Arc<DatabaseMetrics>,
 }
 
 /*
-Caller should set up channel and pass rx to database
-    tx: mpsc::Sender<DBOperationType>,
-    let (tx, rx) = mpsc::channel();
-    let db = Database::new(conn, rx);
+Caller should set up the queue and pass rx to database
+    let (tx, rx) = write_queue(config.write_queue_size, metrics.clone());
+    let db = Database::new(storage, rx, config, metrics);
 */
 
 impl Database {
-    pub fn new(conn: PooledConn, rx: mpsc::Receiver<DBOperationType>, config: &Config) -> Self {
+    pub fn new(
+        storage: Box<dyn Storage>,
+        rx: mpsc::Receiver<DBOperationType>,
+        config: &DatabaseConfig,
+        metrics: Arc<DatabaseMetrics>,
+    ) -> Self {
         // Used to recieve database operations for processing
         Database {
-            conn,
+            storage,
             rx,
-            ms_delay: config.database.ms_delay,
-            retries: config.database.retries,
-        }
-    }
-
-    fn log_write_error(operation: &str, err: impl std::fmt::Debug) {
-        log::error!("Database write failed during {operation}: {err:?}");
-    }
-
-    fn utxo_batch_write(&mut self, utxo_entries: Vec<UtxoEntryDB>) {
-        if utxo_entries.is_empty() {
-            return;
-        }
-        // bulk/batch write tx output to utxo table
-
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn
-            .exec_batch(
-                //"INSERT OVERWRITE utxo (hash, pos, satoshis, height) VALUES (:hash, :pos, :satoshis, :height);",
-                "REPLACE INTO utxo (hash, pos, satoshis, height, pubkeyhash) VALUES (:hash, :pos, :satoshis, :height, :pubkeyhash);",
-                utxo_entries
-                    .iter()
-                    .map(|x| params! {
-                        "hash" => x.hash.as_str(), "pos" => x.pos, "satoshis" => x.satoshis, "height" => x.height, "pubkeyhash" => x.pubkeyhash.as_str()}),
-                )
-            },
-        );
-        if let Err(err) = result {
-            Self::log_write_error("utxo batch write", err);
-        }
-    }
-
-    fn utxo_batch_delete(&mut self, utxo_deletes: Vec<OutPoint>) {
-        if utxo_deletes.is_empty() {
-            return;
-        }
-        // bulk/batch delete utxo table entries
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn.exec_batch(
-                    "DELETE FROM utxo WHERE hash = :hash AND pos = :pos;",
-                    utxo_deletes
-                        .iter()
-                        .map(|x| params! {"hash" => x.hash.encode(), "pos" => x.index}),
-                )
-            },
-        );
-        if let Err(err) = result {
-            Self::log_write_error("utxo batch delete", err);
+            pending: None,
+            received: 0,
+            on_write_error: config.on_write_error,
+            retry_delay: Duration::from_millis(config.ms_delay),
+            retries: config.retries,
+            metrics,
         }
     }
 
-    fn tx_batch_write(&mut self, tx_entries: Vec<TxEntryWriteDB>) {
-        if tx_entries.is_empty() {
-            return;
-        }
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn
-                .exec_batch(
-                    "INSERT INTO tx (hash, height, blockindex, txsize, satoshis) VALUES (:hash, :height, :blockindex, :txsize, :satoshis)",
-                    tx_entries.iter().map(
-                        |tx| params! {"hash" => tx.hash.encode(), "height" => tx.height, "blockindex"=> tx.blockindex, "txsize"=> tx.size, "satoshis" => tx.satoshis},
-                    ),
-                )
-            },
-        );
-        if let Err(err) = result {
-            Self::log_write_error("tx batch write", err);
-        }
+    fn try_next(&mut self) -> Option<DBOperationType> {
+        let op = self.rx.try_recv().ok()?;
+        self.metrics.dequeued();
+        self.received += 1;
+        Some(op)
     }
 
-    fn mempool_batch_write(&mut self, mempool_entries: Vec<MempoolEntryDB>) {
-        if mempool_entries.is_empty() {
-            return;
-        }
-
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn.exec_batch(
-                    "INSERT INTO mempool (hash, locktime, fee, time, tx) \
-                     VALUES (:hash, :locktime, :fee, :time, :tx)",
-                    mempool_entries.iter().map(|entry| {
-                        params! {
-                            "hash" => entry.hash.encode(),
-                            "locktime" => entry.locktime,
-                            "fee" => entry.fee,
-                            "time" => entry.age,
-                            "tx" => entry.tx.as_str(),
-                        }
-                    }),
-                )
-            },
-        );
-        if let Err(err) = result {
-            Self::log_write_error("mempool batch write", err);
+    // Apply a write to the storage, recording its latency, and handle a failure with the error policy
+    fn write<F>(&mut self, operation: &'static str, mut write: F)
+    where
+        F: FnMut(&mut dyn Storage) -> Result<(), String>,
+    {
+        let mut retries = 0;
+        loop {
+            let start = Instant::now();
+            let result = write(self.storage.as_mut());
+            self.metrics
+                .record_operation(operation, start.elapsed(), result.is_ok());
+            let Err(err) = result else {
+                return;
+            };
+            log::error!("Database write failed during {operation}: {err}");
+            let mut policy = self.on_write_error;
+            if policy == WriteErrorPolicy::Retry {
+                if is_constraint_violation(&err) {
+                    log::error!("Not retrying a write that breaks a database constraint");
+                    policy = WriteErrorPolicy::Halt;
+                } else if retries >= self.retries {
+                    log::error!("Database write failed after {retries} retries");
+                    policy = WriteErrorPolicy::Halt;
+                }
+            }
+            match policy {
+                WriteErrorPolicy::Retry => {
+                    retries += 1;
+                    thread::sleep(self.retry_delay);
+                }
+                WriteErrorPolicy::Halt => {
+                    log::error!("Database writer halted, restart the service to resume");
+                    self.metrics.halt(format!("{operation}: {err}"));
+                    return;
+                }
+                WriteErrorPolicy::Crash => {
+                    log::error!("Exiting after database write failure");
+                    process::exit(1);
+                }
+            }
         }
     }
 
     fn coalesce_utxo_batch_write(&mut self, mut entries: Vec<UtxoEntryDB>) -> Vec<UtxoEntryDB> {
-        while let Ok(DBOperationType::UtxoBatchWrite(more)) = self.rx.try_recv() {
-            entries.extend(more);
+        while let Some(op) = self.try_next() {
+            match op {
+                DBOperationType::UtxoBatchWrite(more) => entries.extend(more),
+                other => {
+                    self.pending = Some(other);
+                    break;
+                }
+            }
         }
         entries
     }
 
     fn coalesce_utxo_batch_delete(&mut self, mut deletes: Vec<OutPoint>) -> Vec<OutPoint> {
-        while let Ok(DBOperationType::UtxoBatchDelete(more)) = self.rx.try_recv() {
-            deletes.extend(more);
+        while let Some(op) = self.try_next() {
+            match op {
+                DBOperationType::UtxoBatchDelete(more) => deletes.extend(more),
+                other => {
+                    self.pending = Some(other);
+                    break;
+                }
+            }
         }
         deletes
     }
 
-    fn coalesce_tx_batch_write(&mut self, mut entries: Vec<TxEntryWriteDB>) -> Vec<TxEntryWriteDB> {
-        while let Ok(DBOperationType::TxBatchWrite(more)) = self.rx.try_recv() {
-            entries.extend(more);
+    fn coalesce_block_write(&mut self, mut block: BlockWriteDB) -> BlockWriteDB {
+        while let Some(op) = self.try_next() {
+            match op {
+                DBOperationType::BlockWrite(more) => block.append(more),
+                other => {
+                    self.pending = Some(other);
+                    break;
+                }
+            }
         }
-        entries
+        block
     }
 
     fn coalesce_mempool_batch_write(
         &mut self,
         mut entries: Vec<MempoolEntryDB>,
     ) -> Vec<MempoolEntryDB> {
-        while let Ok(DBOperationType::MempoolBatchWrite(more)) = self.rx.try_recv() {
-            entries.extend(more);
+        while let Some(op) = self.try_next() {
+            match op {
+                DBOperationType::MempoolBatchWrite(more) => entries.extend(more),
+                other => {
+                    self.pending = Some(other);
+                    break;
+                }
+            }
         }
         entries
     }
 
-    fn coalesce_mempool_batch_delete(&mut self, mut hashes: Vec<Hash256>) -> Vec<Hash256> {
-        while let Ok(DBOperationType::MempoolBatchDelete(more)) = self.rx.try_recv() {
-            hashes.extend(more);
-        }
-        hashes
-    }
-
-    fn mempool_batch_delete(&mut self, mempool_hashes: Vec<Hash256>) {
-        if mempool_hashes.is_empty() {
-            return;
-        }
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn.exec_batch(
-                    "DELETE FROM mempool WHERE hash = :hash;",
-                    mempool_hashes
-                        .iter()
-                        .map(|x| params! {"hash" => x.encode()}),
-                )
-            },
-        );
-        if let Err(err) = result {
-            Self::log_write_error("mempool batch delete", err);
-        }
-    }
-
-    fn block_header_write(&mut self, block_header: BlockHeaderWriteDB) {
-        let height = block_header.height;
-        let hash = block_header.hash.encode();
-        let version = block_header.version;
-        let prev_hash = block_header.prev_hash.encode();
-        let merkle_root = block_header.merkle_root.encode();
-        let timestamp = block_header.timestamp;
-        let bits = block_header.bits;
-        let nonce = block_header.nonce;
-        let position = block_header.position;
-        let blocksize = block_header.blocksize;
-        let numtxs = block_header.numtxs;
-
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn.exec_drop(
-                    r"INSERT INTO blocks
-                    (height, hash, version, prev_hash, merkle_root, timestamp, bits, nonce, `offset`, blocksize, numtxs)
-                    VALUES (:height, :hash, :version, :prev_hash, :merkle_root, :timestamp, :bits, :nonce, :offset, :blocksize, :numtxs)",
-                    params! {
-                        "height" => height,
-                        "hash" => hash.as_str(),
-                        "version" => version,
-                        "prev_hash" => prev_hash.as_str(),
-                        "merkle_root" => merkle_root.as_str(),
-                        "timestamp" => timestamp,
-                        "bits" => bits,
-                        "nonce" => nonce,
-                        "offset" => position,
-                        "blocksize" => blocksize,
-                        "numtxs" => numtxs,
-                    },
-                )
-            },
-        );
-        if let Err(err) = result {
-            Self::log_write_error("block header write", err);
-        }
-    }
-
-    fn block_header_delete(&mut self, hash: &Hash256) {
-        let hash = hash.encode();
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn.exec_drop(
-                    "DELETE FROM blocks WHERE hash = :hash",
-                    params! { "hash" => hash.as_str() },
-                )
-            },
-        );
-        if let Err(err) = result {
-            Self::log_write_error("block header delete", err);
-        }
-    }
-
-    fn tx_delete_at_height(&mut self, height: u32) {
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn.exec_drop(
-                    "DELETE FROM tx WHERE height = :height",
-                    params! { "height" => height },
-                )
-            },
-        );
-        if let Err(err) = result {
-            Self::log_write_error("tx delete at height", err);
-        }
-    }
-
-    fn utxo_delete_at_height(&mut self, height: u32) {
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn.exec_drop(
-                    "DELETE FROM utxo WHERE height = :height",
-                    params! { "height" => height },
-                )
-            },
-        );
-        if let Err(err) = result {
-            Self::log_write_error("utxo delete at height", err);
-        }
-    }
-
-    fn orphan_block_header_write(&mut self, block_header: OrphanBlockHeaderWriteDB) {
-        let result = retry(
-            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
-            || {
-                self.conn
-                .exec_drop(
-                r"INSERT INTO orphans (height, hash, version, prev_hash, merkle_root, timestamp, bits, nonce)
-                VALUES (:height, :hash, :version, :prev_hash, :merkle_root, :timestamp, :bits, :nonce)",
-                    params! {
-                        "height" => block_header.height,
-                        "hash" => block_header.hash.encode(),
-                        "version" => block_header.version,
-                        "prev_hash" => block_header.prev_hash.encode(),
-                        "merkle_root" => block_header.merkle_root.encode(),
-                        "timestamp"  => block_header.timestamp,
-                        "bits"  => block_header.bits,
-                        "nonce"  => block_header.nonce
-                    })
-            },
-        );
-        if let Err(err) = result {
-            Self::log_write_error("orphan block header write", err);
+    // Apply a single operation to the storage
+    fn perform(&mut self, op: DBOperationType) {
+        match op {
+            DBOperationType::UtxoBatchWrite(entries) => {
+                let entries = self.coalesce_utxo_batch_write(entries);
+                if !entries.is_empty() {
+                    self.write("utxo batch write", |storage| {
+                        storage.utxo_batch_write(&entries)
+                    });
+                }
+            }
+            DBOperationType::UtxoBatchDelete(deletes) => {
+                let deletes = self.coalesce_utxo_batch_delete(deletes);
+                if !deletes.is_empty() {
+                    self.write("utxo batch delete", |storage| {
+                        storage.utxo_batch_delete(&deletes)
+                    });
+                }
+            }
+            DBOperationType::MempoolBatchWrite(entries) => {
+                let entries = self.coalesce_mempool_batch_write(entries);
+                if !entries.is_empty() {
+                    self.write("mempool batch write", |storage| {
+                        storage.mempool_batch_write(&entries)
+                    });
+                }
+            }
+            DBOperationType::BlockWrite(block) => {
+                let block = self.coalesce_block_write(block);
+                self.write("block write", |storage| storage.block_write(&block));
+            }
+            DBOperationType::OrphanBlockHeaderWrite(block_header) => {
+                self.write("orphan block header write", |storage| {
+                    storage.orphan_block_header_write(&block_header)
+                });
+            }
+            DBOperationType::BlockHeaderDelete(hash) => {
+                self.write("block header delete", |storage| {
+                    storage.block_header_delete(&hash)
+                });
+            }
+            DBOperationType::TxDelete(height) => {
+                self.write("tx delete at height", |storage| {
+                    storage.tx_delete_at_height(height)
+                });
+            }
+            DBOperationType::UtxoDelete(height) => {
+                self.write("utxo delete at height", |storage| {
+                    storage.utxo_delete_at_height(height)
+                });
+            }
         }
     }
 
     pub fn perform_db_operations(&mut self) {
-        while let Ok(op) = self.rx.recv() {
-            match op {
-                DBOperationType::UtxoBatchWrite(entries) => {
-                    let entries = self.coalesce_utxo_batch_write(entries);
-                    self.utxo_batch_write(entries);
-                }
-                DBOperationType::UtxoBatchDelete(deletes) => {
-                    let deletes = self.coalesce_utxo_batch_delete(deletes);
-                    self.utxo_batch_delete(deletes);
-                }
-                DBOperationType::TxBatchWrite(entries) => {
-                    let entries = self.coalesce_tx_batch_write(entries);
-                    self.tx_batch_write(entries);
-                }
-                DBOperationType::MempoolBatchWrite(entries) => {
-                    let entries = self.coalesce_mempool_batch_write(entries);
-                    self.mempool_batch_write(entries);
-                }
-                DBOperationType::MempoolBatchDelete(hashes) => {
-                    let hashes = self.coalesce_mempool_batch_delete(hashes);
-                    self.mempool_batch_delete(hashes);
-                }
-                DBOperationType::BlockHeaderWrite(block_header) => {
-                    self.block_header_write(block_header)
-                }
-                DBOperationType::OrphanBlockHeaderWrite(block_header) => {
-                    self.orphan_block_header_write(block_header)
-                }
-                DBOperationType::BlockHeaderDelete(hash) => self.block_header_delete(&hash),
-                DBOperationType::TxDelete(height) => self.tx_delete_at_height(height),
-                DBOperationType::UtxoDelete(height) => self.utxo_delete_at_height(height),
+        // On halt the receiver is dropped when this returns, so waiting senders are released
+        while !self.metrics.is_halted() {
+            let op = match self.pending.take() {
+                Some(op) => op,
+                None => match self.rx.recv() {
+                    Ok(op) => {
+                        self.metrics.dequeued();
+                        self.received += 1;
+                        op
+                    }
+                    Err(_) => break,
+                },
+            };
+            self.perform(op);
+            if !self.metrics.is_halted() {
+                // Everything received has been written, apart from the pending operation
+                self.metrics
+                    .record_written(self.received - u64::from(self.pending.is_some()));
             }
         }
     }
@@ -417,8 +391,34 @@ impl Database {
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::sync::mpsc;
-    //use mysql::Pool;
+    use crate::config::StorageType;
+    use crate::uaas::storage::{MemoryBackend, MySqlBackend, StorageBackend};
+    use mysql::Pool;
+
+    fn memory_config(on_write_error: WriteErrorPolicy) -> DatabaseConfig {
+        DatabaseConfig {
+            backend: StorageType::Sql,
+            mysql_url: String::new(),
+            mysql_url_docker: String::new(),
+            embedded_path: String::new(),
+            ms_delay: 10,
+            retries: 1,
+            startup_repair: true,
+            write_queue_size: 100,
+            on_write_error,
+            utxo_cache_size: 1000,
+        }
+    }
+
+    fn writer(
+        storage: Box<dyn Storage>,
+        config: &DatabaseConfig,
+    ) -> (DBSender, Database, Arc<DatabaseMetrics>) {
+        let metrics = Arc::new(DatabaseMetrics::new(config.write_queue_size));
+        let (tx, rx) = write_queue(config.write_queue_size, metrics.clone());
+        let database = Database::new(storage, rx, config, metrics.clone());
+        (tx, database, metrics)
+    }
 
     #[test]
     fn test_operation() {
@@ -428,21 +428,220 @@ mod test {
         };
 
         let pool = Pool::new(url.as_str()).expect("connect to UAAS_TEST_MYSQL_URL");
-        let conn = pool
-            .get_conn()
-            .expect("get connection for database integration test");
-        let (_tx, rx) = mpsc::channel();
-        let mut database = Database {
-            conn,
-            rx,
+        let config = DatabaseConfig {
+            backend: StorageType::Sql,
+            mysql_url: url.clone(),
+            mysql_url_docker: url.clone(),
+            embedded_path: String::new(),
             ms_delay: 300,
             retries: 3,
+            startup_repair: true,
+            write_queue_size: 100,
+            on_write_error: WriteErrorPolicy::Retry,
+            utxo_cache_size: 1000,
         };
+        let storage = MySqlBackend::new(pool, &config)
+            .open("database integration test")
+            .expect("get connection for database integration test");
+        let (_tx, mut database, _metrics) = writer(storage, &config);
 
         let block_header: OrphanBlockHeaderWriteDB = OrphanBlockHeaderWriteDB::default();
 
-        database.orphan_block_header_write(block_header);
+        database.perform(DBOperationType::OrphanBlockHeaderWrite(block_header));
 
         //assert_eq!(datetime.timestamp(), 1684477516);
     }
+
+    #[test]
+    fn failed_block_write_is_rolled_back() {
+        let Some(url) = std::env::var("UAAS_TEST_MYSQL_URL").ok() else {
+            eprintln!("skipping failed_block_write_is_rolled_back: UAAS_TEST_MYSQL_URL not set");
+            return;
+        };
+
+        let pool = Pool::new(url.as_str()).expect("connect to UAAS_TEST_MYSQL_URL");
+        let config = DatabaseConfig {
+            backend: StorageType::Sql,
+            mysql_url: url.clone(),
+            mysql_url_docker: url.clone(),
+            embedded_path: String::new(),
+            ms_delay: 10,
+            retries: 1,
+            startup_repair: true,
+            write_queue_size: 100,
+            on_write_error: WriteErrorPolicy::Retry,
+            utxo_cache_size: 1000,
+        };
+        let mut storage = MySqlBackend::new(pool, &config)
+            .open("block write test")
+            .expect("get connection for block write test");
+        storage.migrate().expect("migrate database");
+
+        let header = BlockHeaderWriteDB {
+            height: u32::MAX,
+            hash: Hash256(rand::random()),
+            version: 1,
+            prev_hash: Hash256::default(),
+            merkle_root: Hash256::default(),
+            timestamp: 0,
+            bits: 0,
+            nonce: 0,
+            position: 0,
+            blocksize: 0,
+            numtxs: 1,
+            utxo_commitment: None,
+        };
+        let written = Hash256(rand::random());
+        storage
+            .block_write(&BlockWriteDB {
+                utxo_writes: vec![utxo_entry(&written.encode(), 0, -2)],
+                headers: vec![header.clone()],
+                ..Default::default()
+            })
+            .expect("first block write");
+
+        // Writing the same header again fails, so the utxo of the block must not be stored
+        let rolled_back = Hash256(rand::random());
+        assert!(storage
+            .block_write(&BlockWriteDB {
+                utxo_writes: vec![utxo_entry(&rolled_back.encode(), 0, -2)],
+                headers: vec![header.clone()],
+                ..Default::default()
+            })
+            .is_err());
+        let outpoint = |hash| OutPoint { hash, index: 0 };
+        assert!(storage
+            .get_utxo(&outpoint(written))
+            .expect("read utxo")
+            .is_some());
+        assert!(storage
+            .get_utxo(&outpoint(rolled_back))
+            .expect("read utxo")
+            .is_none());
+
+        storage
+            .utxo_batch_delete(&[outpoint(written)])
+            .expect("remove test utxo");
+        storage
+            .block_header_delete(&header.hash)
+            .expect("remove test header");
+    }
+
+    fn utxo_entry(hash: &str, pos: u32, height: i32) -> UtxoEntryDB {
+        UtxoEntryDB {
+            hash: hash.to_string(),
+            pos,
+            satoshis: 1000,
+            height,
+            pubkeyhash: String::new(),
+        }
+    }
+
+    #[test]
+    fn operations_are_applied_to_storage() {
+        let backend = MemoryBackend::new();
+        let storage = backend.open("database writer").expect("open storage");
+        let config = memory_config(WriteErrorPolicy::Retry);
+        let (tx, mut database, metrics) = writer(storage, &config);
+
+        let spent = Hash256([1; 32]);
+        tx.send(DBOperationType::UtxoBatchWrite(vec![utxo_entry(
+            &spent.encode(),
+            0,
+            10,
+        )]))
+        .unwrap();
+        tx.send(DBOperationType::UtxoBatchWrite(vec![utxo_entry(
+            "bb", 1, 11,
+        )]))
+        .unwrap();
+        tx.send(DBOperationType::UtxoBatchDelete(vec![OutPoint {
+            hash: spent,
+            index: 0,
+        }]))
+        .unwrap();
+        tx.send(DBOperationType::UtxoDelete(12)).unwrap();
+        drop(tx);
+        database.perform_db_operations();
+
+        let utxo: Vec<String> = backend
+            .data()
+            .utxo
+            .keys()
+            .map(|(hash, _pos)| hash.clone())
+            .collect();
+        assert_eq!(utxo, vec!["bb".to_string()]);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.queue_depth, 0);
+        // Coalesced operations are counted as written
+        assert_eq!(snapshot.written, 4);
+        assert_eq!(snapshot.operations["utxo batch write"].count, 1);
+        assert_eq!(snapshot.operations["utxo delete at height"].count, 1);
+
+        let (tx, mut database, _metrics) =
+            writer(backend.open("database writer").unwrap(), &config);
+        tx.send(DBOperationType::UtxoDelete(11)).unwrap();
+        drop(tx);
+        database.perform_db_operations();
+        assert!(backend.data().utxo.is_empty());
+    }
+
+    #[test]
+    fn failed_write_halts_the_writer() {
+        let backend = MemoryBackend::new();
+        backend.data().fail_writes = true;
+        let config = memory_config(WriteErrorPolicy::Halt);
+        let (tx, mut database, metrics) = writer(backend.open("database writer").unwrap(), &config);
+
+        tx.send(DBOperationType::UtxoBatchWrite(vec![utxo_entry(
+            "aa", 0, 1,
+        )]))
+        .unwrap();
+        tx.send(DBOperationType::UtxoDelete(1)).unwrap();
+        database.perform_db_operations();
+
+        assert!(metrics.is_halted());
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.operations["utxo batch write"].errors, 1);
+        // The writer stopped before the following operation
+        assert!(!snapshot.operations.contains_key("utxo delete at height"));
+        assert!(tx.send(DBOperationType::UtxoDelete(1)).is_err());
+    }
+
+    #[test]
+    fn retried_write_halts_after_the_retries() {
+        let backend = MemoryBackend::new();
+        backend.data().fail_writes = true;
+        let mut config = memory_config(WriteErrorPolicy::Retry);
+        config.ms_delay = 1;
+        config.retries = 2;
+        let (tx, mut database, metrics) = writer(backend.open("database writer").unwrap(), &config);
+
+        tx.send(DBOperationType::UtxoBatchWrite(vec![utxo_entry(
+            "aa", 0, 1,
+        )]))
+        .unwrap();
+        database.perform_db_operations();
+
+        assert!(metrics.is_halted());
+        assert_eq!(metrics.snapshot().operations["utxo batch write"].errors, 3);
+    }
+
+    #[test]
+    fn constraint_violation_is_not_retried() {
+        let backend = MemoryBackend::new();
+        backend.data().fail_constraint = true;
+        let mut config = memory_config(WriteErrorPolicy::Retry);
+        config.retries = 5;
+        let (tx, mut database, metrics) = writer(backend.open("database writer").unwrap(), &config);
+
+        tx.send(DBOperationType::UtxoBatchWrite(vec![utxo_entry(
+            "aa", 0, 1,
+        )]))
+        .unwrap();
+        database.perform_db_operations();
+
+        assert!(metrics.is_halted());
+        assert_eq!(metrics.snapshot().operations["utxo batch write"].errors, 1);
+    }
 }
@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use chain_gang::{messages::BlockHeader, util::Hash256};
+
+#[derive(Default)]
+struct Chain {
+    // (height, header) of the main chain, in height order
+    headers: Vec<(u32, BlockHeader)>,
+    hash_to_index: HashMap<Hash256, u32>,
+}
+
+/// The main chain block headers, held by the block manager and shared with the REST API
+/// so it can answer header and proof queries without waiting on the event processing loop.
+#[derive(Clone, Default)]
+pub struct HeaderChain {
+    chain: Arc<RwLock<Chain>>,
+}
+
+impl HeaderChain {
+    fn read(&self) -> RwLockReadGuard<'_, Chain> {
+        self.chain.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Chain> {
+        self.chain.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Add the next block of the main chain
+    pub fn push(&self, height: u32, header: BlockHeader) {
+        let mut chain = self.write();
+        chain.hash_to_index.insert(header.hash(), height);
+        chain.headers.push((height, header));
+    }
+
+    // Remove the last block, when it is orphaned
+    pub fn pop(&self) -> Option<BlockHeader> {
+        let mut chain = self.write();
+        let (_height, header) = chain.headers.pop()?;
+        chain.hash_to_index.remove(&header.hash());
+        Some(header)
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().headers.is_empty()
+    }
+
+    pub fn contains(&self, hash: &Hash256) -> bool {
+        self.read().hash_to_index.contains_key(hash)
+    }
+
+    pub fn header_at(&self, height: u32) -> Option<BlockHeader> {
+        let chain = self.read();
+        let index = chain
+            .headers
+            .binary_search_by_key(&height, |(height, _header)| *height)
+            .ok()?;
+        Some(chain.headers[index].1)
+    }
+
+    // (height, header) of a main chain block
+    pub fn find(&self, hash: &Hash256) -> Option<(u32, BlockHeader)> {
+        let height = *self.read().hash_to_index.get(hash)?;
+        Some((height, self.header_at(height)?))
+    }
+
+    // (height, header) of the main chain block with the merkle root, searching from the tip
+    pub fn find_merkle_root(&self, merkle_root: &Hash256) -> Option<(u32, BlockHeader)> {
+        self.read()
+            .headers
+            .iter()
+            .rev()
+            .find(|(_height, header)| header.merkle_root == *merkle_root)
+            .copied()
+    }
+
+    // The last count headers up to and including height, in height order
+    pub fn last_at(&self, height: u32, count: usize) -> Vec<BlockHeader> {
+        let chain = self.read();
+        let end = chain
+            .headers
+            .partition_point(|(header_height, _header)| *header_height <= height);
+        chain.headers[end.saturating_sub(count)..end]
+            .iter()
+            .map(|(_height, header)| *header)
+            .collect()
+    }
+
+    pub fn tip(&self) -> Option<(u32, BlockHeader)> {
+        self.read().headers.last().copied()
+    }
+
+    pub fn min_timestamp(&self) -> Option<u32> {
+        self.read()
+            .headers
+            .iter()
+            .map(|(_height, header)| header.timestamp)
+            .min()
+    }
+
+    // Call f with the main chain block index, hash to height
+    pub fn with_index<R>(&self, f: impl FnOnce(&HashMap<Hash256, u32>) -> R) -> R {
+        f(&self.read().hash_to_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(nonce: u32) -> BlockHeader {
+        BlockHeader {
+            nonce,
+            merkle_root: Hash256([nonce as u8; 32]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn headers_are_found_by_hash_height_and_merkle_root() {
+        let chain = HeaderChain::default();
+        let shared = chain.clone();
+        for height in 100..105 {
+            chain.push(height, header(height));
+        }
+        assert_eq!(shared.len(), 5);
+        assert_eq!(shared.find(&header(102).hash()), Some((102, header(102))));
+        assert_eq!(shared.header_at(103), Some(header(103)));
+        assert_eq!(shared.header_at(99), None);
+        assert_eq!(
+            shared.find_merkle_root(&Hash256([101; 32])),
+            Some((101, header(101)))
+        );
+        assert_eq!(shared.tip(), Some((104, header(104))));
+        assert_eq!(shared.last_at(104, 2), vec![header(103), header(104)]);
+        assert_eq!(shared.last_at(101, 5), vec![header(100), header(101)]);
+
+        assert_eq!(chain.pop(), Some(header(104)));
+        assert!(!shared.contains(&header(104).hash()));
+        assert_eq!(shared.tip(), Some((103, header(103))));
+    }
+}
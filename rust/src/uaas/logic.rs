@@ -1,6 +1,4 @@
-use std::{sync::mpsc, thread};
-
-use mysql::{Pool, PooledConn};
+use std::{path::Path, sync::Arc, thread};
 
 use chain_gang::{
     messages::{Addr, Block, BlockLocator, Headers, Inv, InvVect, Message, Tx},
@@ -8,11 +6,21 @@ use chain_gang::{
 };
 
 use crate::{
-    config::Config,
+    config::{CollectionConfig, Config, SyncMode},
     thread_util::catch_unwind_logged,
     uaas::{
-        address_manager::AddressManager, block_manager::BlockManager, connection::Connection,
-        database::Database, tx_analyser::TxAnalyser,
+        address_manager::AddressManager,
+        backfill::BackfillManager,
+        blk_import::{self, BlkImportInfo},
+        block_manager::BlockManager,
+        block_store::BlockReader,
+        connection::Connection,
+        database::{write_queue, Database},
+        header_chain::HeaderChain,
+        metrics::{DatabaseMetrics, UtxoCacheMetrics},
+        monitor::{MonitorKey, MonitorRegistry},
+        storage::StorageBackend,
+        tx_analyser::TxAnalyser,
     },
 };
 
@@ -44,9 +52,12 @@ pub struct Logic {
     pub tx_analyser: TxAnalyser,
     address_manager: AddressManager,
     pub connection: Connection,
+    // Historical rescans for monitors added at runtime
+    backfill: BackfillManager,
 
     //database: Database,
     thread: Option<thread::JoinHandle<()>>,
+    db_metrics: Arc<DatabaseMetrics>,
 
     // Orphan detection
     detecting_orphans: bool,
@@ -55,37 +66,67 @@ pub struct Logic {
     send_message_queue: Vec<Message>,
     // Record the block inv messages received
     block_inventory: Vec<Vec<InvVect>>,
+    // Follow the headers, fetching blocks only for the monitors
+    headers_only: bool,
 }
 
-impl Logic {
-    fn pool_conn(pool: &Pool, label: &str) -> Result<PooledConn, String> {
-        pool.get_conn().map_err(|err| {
-            log::error!("Unable to get {label} database connection: {err:?}");
-            format!("Unable to get {label} database connection")
-        })
-    }
-
-    pub fn new(config: &Config, pool: Pool) -> Result<Self, String> {
-        let block_conn = Self::pool_conn(&pool, "block")?;
-        let addr_conn = Self::pool_conn(&pool, "address")?;
-        let connection_conn = Self::pool_conn(&pool, "connection")?;
-        let db_conn = Self::pool_conn(&pool, "database writer")?;
+// The most headers a peer sends in one headers message
+const MAX_HEADERS: usize = 2000;
 
-        // Channel for database writes
-        let (tx, rx) = mpsc::channel();
+// A locator for getblocks or getheaders, from the given block hash
+fn block_locator(hash: &str) -> Option<BlockLocator> {
+    match Hash256::decode(hash) {
+        Ok(hash) => {
+            let mut locator = BlockLocator::default();
+            locator.block_locator_hashes.push(hash);
+            Some(locator)
+        }
+        Err(e) => {
+            log::error!("Invalid block hash for block locator: {} ({:?})", hash, e);
+            None
+        }
+    }
+}
 
-        let tx_analyser = TxAnalyser::new(config, pool, tx.clone())?;
-        let block_manager = BlockManager::new(config, block_conn, tx)?;
+impl Logic {
+    pub fn new(
+        config: &Config,
+        storage: Arc<dyn StorageBackend>,
+        monitors: Arc<MonitorRegistry>,
+        db_metrics: Arc<DatabaseMetrics>,
+        utxo_metrics: Arc<UtxoCacheMetrics>,
+    ) -> Result<Self, String> {
+        let addr_storage = storage.open("address")?;
+        let connection_storage = storage.open("connection")?;
+        let block_storage = storage.open("block")?;
+        let db_storage = storage.open("database writer")?;
+
+        // Queue for database writes
+        let (tx, rx) = write_queue(config.database.write_queue_size, db_metrics.clone());
+
+        let tx_analyser = TxAnalyser::new(
+            config,
+            storage.as_ref(),
+            tx.clone(),
+            monitors.clone(),
+            utxo_metrics,
+        )?;
+        let block_manager = BlockManager::new(config, block_storage, tx)?;
+        let headers_only = config.get_network_settings()?.sync_mode == SyncMode::Headers;
+        let backfill =
+            BackfillManager::new(config, storage, monitors, block_manager.block_reader())?;
 
         let mut logic = Logic {
             state: ServerStateType::Starting,
             tx_analyser,
             block_manager,
-            address_manager: AddressManager::new(config, addr_conn),
-            connection: Connection::new(config, connection_conn),
+            address_manager: AddressManager::new(addr_storage),
+            connection: Connection::new(connection_storage),
+            backfill,
 
             //database:
             thread: None,
+            db_metrics: db_metrics.clone(),
             // orphans
             detecting_orphans: config.orphan.detect,
             start_block_timestamp: None,
@@ -94,12 +135,13 @@ impl Logic {
             send_message_queue: Vec::new(),
             // For record of the block inv messages received
             block_inventory: Vec::new(),
+            headers_only,
         };
 
-        let db_config = config.clone();
+        let db_config = config.database.clone();
         logic.thread = Some(thread::spawn(move || {
             catch_unwind_logged("database writer", || {
-                let mut database = Database::new(db_conn, rx, &db_config);
+                let mut database = Database::new(db_storage, rx, &db_config, db_metrics);
                 database.perform_db_operations();
             });
         }));
@@ -107,12 +149,53 @@ impl Logic {
         Ok(logic)
     }
 
-    pub fn setup(&mut self) {
+    // For reading stored blocks outside of the event processing loop
+    pub fn block_reader(&self) -> BlockReader {
+        self.block_manager.block_reader()
+    }
+
+    pub fn header_chain(&self) -> HeaderChain {
+        self.block_manager.headers().clone()
+    }
+
+    pub fn setup(&mut self) -> Result<(), String> {
         // Do any start up component setup required
         self.address_manager.setup();
+        self.block_manager.load()?;
         self.tx_analyser.setup();
         self.block_manager.setup(&mut self.tx_analyser);
-        self.connection.setup();
+        Ok(())
+    }
+
+    // Process the blocks of a node's blk*.dat files that follow the last block processed
+    pub fn import_blk_files(
+        &mut self,
+        dir: &Path,
+        magic: [u8; 4],
+    ) -> Result<BlkImportInfo, String> {
+        let start = Hash256::decode(&self.block_manager.get_last_known_block_hash())
+            .map_err(|err| format!("Invalid last block hash: {err:?}"))?;
+        blk_import::import(dir, magic, start, &mut |block| {
+            if self.db_metrics.is_halted() {
+                return Err("The database writer has halted".to_string());
+            }
+            self.block_manager.on_block(block, &mut self.tx_analyser);
+            Ok(())
+        })
+    }
+
+    // Write out the cached database changes and wait for the database writer to finish,
+    // for when the service runs offline
+    pub fn close(mut self) {
+        self.tx_analyser.flush_database_cache();
+        let thread = self.thread.take();
+        // Dropping the components closes the database queue
+        drop(self);
+        if let Some(thread) = thread {
+            if thread.join().is_err() {
+                log::error!("Database writer thread panicked");
+            }
+        }
     }
 
     pub fn set_state(&mut self, state: ServerStateType) {
@@ -120,13 +203,47 @@ impl Logic {
         log::info!("set_state({:?})", &state);
         if state == ServerStateType::Connected {
             // Reset the request time on connection/reconnection
-            self.request_next_block(None);
+            if self.headers_only {
+                self.request_headers();
+            } else {
+                self.request_next_block(None);
+            }
         }
         self.state = state;
     }
 
-    pub fn on_headers(&self, headers: Headers) {
-        log::info!("on_headers {:?}", headers);
+    fn request_headers(&mut self) {
+        let hash = self.block_manager.get_last_known_block_hash();
+        log::info!("Requesting headers from hash = {}", &hash);
+        if let Some(locator) = block_locator(&hash) {
+            self.send_message_queue.push(Message::GetHeaders(locator));
+        }
+    }
+
+    pub fn on_headers(&mut self, headers: Headers) {
+        if !self.headers_only {
+            log::info!("on_headers {:?}", headers);
+            return;
+        }
+        let added = self.block_manager.on_headers(&headers.headers);
+        // The blocks are only needed to match the monitors
+        if !added.is_empty() && self.tx_analyser.has_monitors() {
+            let objects = added
+                .iter()
+                .map(|hash| InvVect {
+                    obj_type: BLOCK,
+                    hash: *hash,
+                })
+                .collect();
+            self.send_message_queue
+                .push(Message::GetData(Inv { objects }));
+        }
+        if !added.is_empty() && headers.headers.len() >= MAX_HEADERS {
+            self.request_headers();
+        } else if !self.state.is_ready() {
+            // The peer has no more headers
+            self.set_state(ServerStateType::Ready);
+        }
     }
 
     // Return true if this is an orphan block
@@ -170,10 +287,36 @@ impl Logic {
         }
     }
 
-    pub fn tx_exists(&self, hash: Hash256) -> bool {
+    pub fn tx_exists(&mut self, hash: Hash256) -> bool {
         self.tx_analyser.tx_exists(hash)
     }
 
+    pub fn add_monitor(&mut self, monitor: CollectionConfig) {
+        if self.tx_analyser.add_monitor(monitor.clone()) {
+            self.backfill.start(&monitor, self.block_manager.headers());
+        }
+    }
+
+    pub fn update_monitor(&mut self, monitor: CollectionConfig) {
+        // Stop the running backfill first, so it writes nothing more once the collection is cleared
+        self.backfill.cancel(&MonitorKey::of(&monitor));
+        if self.tx_analyser.update_monitor(monitor.clone()) {
+            self.backfill.start(&monitor, self.block_manager.headers());
+        }
+    }
+
+    pub fn delete_monitor(&mut self, key: &MonitorKey) {
+        self.backfill.cancel(key);
+        self.tx_analyser.delete_monitor(key);
+    }
+
+    pub fn poll_backfill(&mut self) {
+        // Add any historical matches found by the backfill to the collections
+        for (key, hashes) in self.backfill.poll() {
+            self.tx_analyser.add_collection_txs(&key, hashes);
+        }
+    }
+
     pub fn on_addr(&mut self, addr: Addr) {
         // Handle Addr message
         self.address_manager.on_addr(addr);
@@ -196,8 +339,8 @@ impl Logic {
             .into_iter()
             .filter(|x| x.obj_type == TX)
             .collect();
-        // Request all txs
-        if !txs.is_empty() {
+        // Request all txs, in headers mode only to match the monitors
+        if !txs.is_empty() && (!self.headers_only || self.tx_analyser.has_monitors()) {
             let want = Message::GetData(Inv { objects: txs });
             self.send_message_queue.push(want);
         }
@@ -207,6 +350,12 @@ impl Logic {
             .into_iter()
             .filter(|x| x.obj_type == BLOCK)
             .collect();
+        if self.headers_only {
+            if !blocks.is_empty() {
+                self.request_headers();
+            }
+            return;
+        }
 
         let is_empty = self.block_inventory.is_empty();
         // Add to block_inventory
@@ -272,21 +421,9 @@ impl Logic {
             log::info!("Requesting more blocks from hash = {}", &hash);
 
             // Build getblocks message - this results in an inv message
-            let mut locator = BlockLocator::default();
-            let hash = match Hash256::decode(&hash) {
-                Ok(hash) => hash,
-                Err(e) => {
-                    log::error!(
-                        "Invalid block hash for GetBlocks request: {} ({:?})",
-                        hash,
-                        e
-                    );
-                    return;
-                }
-            };
-            locator.block_locator_hashes.push(hash);
-            let message = Message::GetBlocks(locator);
-            self.send_message_queue.push(message)
+            if let Some(locator) = block_locator(&hash) {
+                self.send_message_queue.push(Message::GetBlocks(locator));
+            }
 
             // else take the first block off the queue
         } else if let Some(block) = self
@@ -305,7 +442,34 @@ impl Logic {
         }
     }
 
+    // In headers mode blocks are requested only to match the monitors, and are not stored
+    fn on_monitored_block(&mut self, block: Block) {
+        let hash = block.header.hash();
+        if !self.block_manager.headers().contains(&hash) {
+            log::warn!(
+                "Ignoring block {}, its header is not in the chain",
+                hash.encode()
+            );
+        } else if let Err(err) = block.validate_merkle_root() {
+            log::warn!("Ignoring block {}: {err:?}", hash.encode());
+        } else {
+            self.tx_analyser.process_block_collections(block.txns);
+        }
+    }
+
     pub fn on_block(&mut self, block: Block) {
+        // Sync halts with the database writer, as the blocks could not be stored
+        if self.db_metrics.is_halted() {
+            log::warn!(
+                "Ignoring block {}, the database writer has halted",
+                block.header.hash().encode()
+            );
+            return;
+        }
+        if self.headers_only {
+            self.on_monitored_block(block);
+            return;
+        }
         // On rx Block
         let block_hash: Option<Hash256> = if self.is_orphan(block.header.timestamp) {
             // Forget the blocks that we are going to request
@@ -0,0 +1,605 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use chain_gang::{
+    messages::BlockHeader,
+    util::{sha256d, var_int, Hash256, Serializable},
+};
+use serde::{Deserialize, Serialize};
+
+// Flags of the binary proof format
+const FLAG_TARGET_HEADER: u8 = 0x02;
+const FLAG_TARGET_MERKLE_ROOT: u8 = 0x04;
+// Node types of the binary proof format
+const NODE_HASH: u8 = 0;
+const NODE_DUPLICATE: u8 = 1;
+// Leaf flags of a BUMP
+const LEAF_HASH: u8 = 0;
+const LEAF_DUPLICATE: u8 = 1;
+const LEAF_TXID: u8 = 2;
+
+pub fn merkle_parent(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut data = [0; 64];
+    data[..32].copy_from_slice(&left.0);
+    data[32..].copy_from_slice(&right.0);
+    sha256d(&data)
+}
+
+// The branch from the tx at index to the root. None is a node that is a copy of the hash
+// being worked up, where the tx or the node below is the last of an odd numbered level
+fn merkle_branch(txids: &[Hash256], mut index: usize) -> Vec<Option<Hash256>> {
+    let mut level = txids.to_vec();
+    let mut nodes = Vec::new();
+    while level.len() > 1 {
+        nodes.push(level.get(index ^ 1).copied());
+        level = level
+            .chunks(2)
+            .map(|pair| merkle_parent(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+        index /= 2;
+    }
+    nodes
+}
+
+/// The number of levels of the merkle tree of a block with this many txs
+pub fn tree_depth(tx_count: u64) -> usize {
+    (u64::BITS - tx_count.saturating_sub(1).leading_zeros()) as usize
+}
+
+/// What a proof proves inclusion in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProofTarget {
+    BlockHash(Hash256),
+    Header(BlockHeader),
+    MerkleRoot(Hash256),
+}
+
+/// A merkle proof in the Technical Standards Committee (TSC) format
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleProof {
+    pub index: u32,
+    pub txid: Hash256,
+    pub target: ProofTarget,
+    pub nodes: Vec<Option<Hash256>>,
+}
+
+fn default_target_type() -> String {
+    "hash".to_string()
+}
+
+// The JSON form, hashes are hex in display order and a duplicated node is "*"
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TscProofJson {
+    pub index: u32,
+    // The txid, or the serialised tx as hex
+    pub tx_or_id: String,
+    #[serde(default = "default_target_type")]
+    pub target_type: String,
+    pub target: String,
+    pub nodes: Vec<String>,
+    // Only branch proofs of one tx are supported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub composite: Option<bool>,
+}
+
+fn decode_hash(what: &str, hash: &str) -> Result<Hash256, String> {
+    Hash256::decode(hash).map_err(|_| format!("Invalid {what} '{hash}'"))
+}
+
+impl MerkleProof {
+    // The proof for the tx at index, given the txids of the block in order
+    pub fn new(txids: &[Hash256], index: u32, target: ProofTarget) -> Result<Self, String> {
+        let txid = *txids
+            .get(index as usize)
+            .ok_or_else(|| format!("Block has {} txs, there is no tx {index}", txids.len()))?;
+        Ok(MerkleProof {
+            index,
+            txid,
+            target,
+            nodes: merkle_branch(txids, index as usize),
+        })
+    }
+
+    // The merkle root that the proof leads to, Err for a proof that no block could have
+    pub fn root(&self) -> Result<Hash256, String> {
+        // Otherwise the bits of the index above the proof would be ignored
+        if self.nodes.len() < 32 && self.index >> self.nodes.len() != 0 {
+            return Err(format!(
+                "Index {} is out of range for a proof of {} nodes",
+                self.index,
+                self.nodes.len()
+            ));
+        }
+        let mut hash = self.txid;
+        let mut index = self.index;
+        for (level, node) in self.nodes.iter().enumerate() {
+            let sibling = match node {
+                Some(sibling) => *sibling,
+                // Only the last node of an odd sized level is paired with itself, on its right
+                None if index & 1 == 1 => {
+                    return Err(format!(
+                        "Proof has a duplicate node on the left at level {level}"
+                    ))
+                }
+                None => hash,
+            };
+            hash = if index & 1 == 0 {
+                merkle_parent(&hash, &sibling)
+            } else {
+                merkle_parent(&sibling, &hash)
+            };
+            index /= 2;
+        }
+        Ok(hash)
+    }
+
+    pub fn from_json(json: &TscProofJson) -> Result<Self, String> {
+        if json
+            .proof_type
+            .as_deref()
+            .is_some_and(|proof_type| proof_type != "branch")
+            || json.composite == Some(true)
+        {
+            return Err("Only branch proofs of one transaction are supported".to_string());
+        }
+        let txid = if json.tx_or_id.len() == 64 {
+            decode_hash("txid", &json.tx_or_id)?
+        } else {
+            let tx = hex::decode(&json.tx_or_id).map_err(|_| "Invalid txOrId".to_string())?;
+            sha256d(&tx)
+        };
+        let target = match json.target_type.as_str() {
+            "hash" => ProofTarget::BlockHash(decode_hash("target", &json.target)?),
+            "header" => {
+                let bytes = hex::decode(&json.target)
+                    .ok()
+                    .filter(|bytes| bytes.len() == 80)
+                    .ok_or_else(|| format!("Invalid target header '{}'", json.target))?;
+                let header = BlockHeader::read(&mut bytes.as_slice())
+                    .map_err(|err| format!("Invalid target header: {err:?}"))?;
+                ProofTarget::Header(header)
+            }
+            "merkleRoot" => ProofTarget::MerkleRoot(decode_hash("target", &json.target)?),
+            target_type => return Err(format!("Unknown targetType '{target_type}'")),
+        };
+        if json.nodes.len() > 64 {
+            return Err(format!("Proof has {} nodes", json.nodes.len()));
+        }
+        let nodes = json
+            .nodes
+            .iter()
+            .map(|node| match node.as_str() {
+                "*" => Ok(None),
+                node => decode_hash("node", node).map(Some),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(MerkleProof {
+            index: json.index,
+            txid,
+            target,
+            nodes,
+        })
+    }
+
+    pub fn json(&self) -> TscProofJson {
+        let (target_type, target) = match &self.target {
+            ProofTarget::BlockHash(hash) => ("hash", hash.encode()),
+            ProofTarget::Header(header) => {
+                let mut bytes = Vec::with_capacity(80);
+                header
+                    .write(&mut bytes)
+                    .expect("writing to a vec does not fail");
+                ("header", hex::encode(bytes))
+            }
+            ProofTarget::MerkleRoot(root) => ("merkleRoot", root.encode()),
+        };
+        TscProofJson {
+            index: self.index,
+            tx_or_id: self.txid.encode(),
+            target_type: target_type.to_string(),
+            target,
+            nodes: self
+                .nodes
+                .iter()
+                .map(|node| node.map_or_else(|| "*".to_string(), |hash| hash.encode()))
+                .collect(),
+            proof_type: None,
+            composite: None,
+        }
+    }
+
+    // The binary form, hashes are in internal byte order
+    pub fn binary(&self) -> Vec<u8> {
+        let flags = match self.target {
+            ProofTarget::BlockHash(_) => 0,
+            ProofTarget::Header(_) => FLAG_TARGET_HEADER,
+            ProofTarget::MerkleRoot(_) => FLAG_TARGET_MERKLE_ROOT,
+        };
+        let mut bytes = vec![flags];
+        // Writes to a vec do not fail
+        let _ = var_int::write(u64::from(self.index), &mut bytes);
+        bytes.extend_from_slice(&self.txid.0);
+        match &self.target {
+            ProofTarget::BlockHash(hash) | ProofTarget::MerkleRoot(hash) => {
+                bytes.extend_from_slice(&hash.0)
+            }
+            ProofTarget::Header(header) => {
+                let _ = header.write(&mut bytes);
+            }
+        }
+        let _ = var_int::write(self.nodes.len() as u64, &mut bytes);
+        for node in self.nodes.iter() {
+            match node {
+                Some(hash) => {
+                    bytes.push(NODE_HASH);
+                    bytes.extend_from_slice(&hash.0);
+                }
+                None => bytes.push(NODE_DUPLICATE),
+            }
+        }
+        bytes
+    }
+}
+
+/// A leaf of a merkle path level
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PathLeaf {
+    Hash(Hash256),
+    // A tx the path proves
+    Txid(Hash256),
+    // A copy of the other leaf of the pair
+    Duplicate,
+}
+
+/// A BSV Unified Merkle Path (BUMP, BRC-74), the proofs of one or more txs in a block.
+///
+/// Each level holds the leaves needed at that height of the tree, by their offset in the level.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerklePath {
+    pub height: u32,
+    pub levels: Vec<BTreeMap<u64, PathLeaf>>,
+}
+
+impl MerklePath {
+    pub fn new(height: u32) -> Self {
+        MerklePath {
+            height,
+            levels: vec![BTreeMap::new()],
+        }
+    }
+
+    // Merge in the proof of a tx in the block
+    pub fn add(&mut self, proof: &MerkleProof) {
+        while self.levels.len() < proof.nodes.len() {
+            self.levels.push(BTreeMap::new());
+        }
+        let index = u64::from(proof.index);
+        self.levels[0].insert(index, PathLeaf::Txid(proof.txid));
+        for (level, node) in proof.nodes.iter().enumerate() {
+            let leaf = match node {
+                Some(hash) => PathLeaf::Hash(*hash),
+                None => PathLeaf::Duplicate,
+            };
+            // A proven tx is not replaced by the hash of it from another proof
+            self.levels[level]
+                .entry((index >> level) ^ 1)
+                .or_insert(leaf);
+        }
+    }
+
+    // The txs the path proves
+    pub fn txids(&self) -> impl Iterator<Item = Hash256> + '_ {
+        self.levels[0].values().filter_map(|leaf| match leaf {
+            PathLeaf::Txid(txid) => Some(*txid),
+            _ => None,
+        })
+    }
+
+    // The merkle root that the path leads to from a tx in it
+    pub fn root(&self, txid: &Hash256) -> Result<Hash256, String> {
+        let offset = self
+            .offset(txid)
+            .ok_or_else(|| format!("Transaction {} is not in the merkle path", txid.encode()))?;
+        if self.depth() < 64 && offset >> self.depth() != 0 {
+            return Err(format!(
+                "Offset {offset} is out of range for a merkle path of {} levels",
+                self.depth()
+            ));
+        }
+        // The only tx in its block
+        if self.is_lone_tx() {
+            return Ok(*txid);
+        }
+        let mut hash = *txid;
+        for level in 0..self.levels.len() {
+            let index = offset >> level;
+            let sibling = match self.hash_at(level, index ^ 1)? {
+                Some(sibling) => sibling,
+                None if index & 1 == 1 => {
+                    return Err(format!(
+                        "Merkle path has a duplicate on the left at offset {} of level {level}",
+                        index ^ 1
+                    ))
+                }
+                None => hash,
+            };
+            hash = if index & 1 == 0 {
+                merkle_parent(&hash, &sibling)
+            } else {
+                merkle_parent(&sibling, &hash)
+            };
+        }
+        Ok(hash)
+    }
+
+    // True for the path of the only tx in a block, which is its own merkle root
+    pub fn is_lone_tx(&self) -> bool {
+        self.levels.len() == 1 && self.levels[0].len() == 1
+    }
+
+    // The number of levels of the block's merkle tree that the path proves
+    pub fn depth(&self) -> usize {
+        if self.is_lone_tx() {
+            0
+        } else {
+            self.levels.len()
+        }
+    }
+
+    // The offset of a tx in its block
+    pub fn offset(&self, txid: &Hash256) -> Option<u64> {
+        self.levels[0]
+            .iter()
+            .find(|(_offset, leaf)| **leaf == PathLeaf::Txid(*txid))
+            .map(|(offset, _leaf)| *offset)
+    }
+
+    // The hash at an offset in a level, None for a duplicate.
+    // A leaf left out of the path is worked out from the level below
+    fn hash_at(&self, level: usize, offset: u64) -> Result<Option<Hash256>, String> {
+        match self.levels[level].get(&offset) {
+            Some(PathLeaf::Hash(hash)) | Some(PathLeaf::Txid(hash)) => Ok(Some(*hash)),
+            Some(PathLeaf::Duplicate) => Ok(None),
+            None if level == 0 => Err(format!(
+                "Merkle path has no leaf at offset {offset} of level {level}"
+            )),
+            None => {
+                let left = self.hash_at(level - 1, offset * 2)?.ok_or_else(|| {
+                    format!(
+                        "Merkle path has a duplicate at offset {} of level {}",
+                        offset * 2,
+                        level - 1
+                    )
+                })?;
+                let right = self.hash_at(level - 1, offset * 2 + 1)?.unwrap_or(left);
+                Ok(Some(merkle_parent(&left, &right)))
+            }
+        }
+    }
+
+    pub fn write(&self, bytes: &mut Vec<u8>) {
+        // Writes to a vec do not fail
+        let _ = var_int::write(u64::from(self.height), bytes);
+        bytes.push(self.levels.len() as u8);
+        for level in self.levels.iter() {
+            let _ = var_int::write(level.len() as u64, bytes);
+            for (offset, leaf) in level.iter() {
+                let _ = var_int::write(*offset, bytes);
+                match leaf {
+                    PathLeaf::Hash(hash) => {
+                        bytes.push(LEAF_HASH);
+                        bytes.extend_from_slice(&hash.0);
+                    }
+                    PathLeaf::Txid(txid) => {
+                        bytes.push(LEAF_TXID);
+                        bytes.extend_from_slice(&txid.0);
+                    }
+                    PathLeaf::Duplicate => bytes.push(LEAF_DUPLICATE),
+                }
+            }
+        }
+    }
+
+    pub fn read(reader: &mut dyn Read) -> Result<Self, String> {
+        let invalid = |err: std::io::Error| format!("Invalid merkle path: {err}");
+        let read_hash = |reader: &mut dyn Read| {
+            let mut hash = Hash256::default();
+            reader
+                .read_exact(&mut hash.0)
+                .map(|_| hash)
+                .map_err(invalid)
+        };
+        let height = var_int::read(reader).map_err(invalid)?;
+        let height = u32::try_from(height)
+            .map_err(|_| format!("Invalid merkle path: block height {height}"))?;
+        let mut tree_height = [0u8];
+        reader.read_exact(&mut tree_height).map_err(invalid)?;
+        if tree_height[0] == 0 || tree_height[0] > 64 {
+            return Err(format!(
+                "Invalid merkle path: tree height {}",
+                tree_height[0]
+            ));
+        }
+        let mut levels = Vec::with_capacity(tree_height[0] as usize);
+        for _ in 0..tree_height[0] {
+            let count = var_int::read(reader).map_err(invalid)?;
+            let mut level = BTreeMap::new();
+            for _ in 0..count {
+                let offset = var_int::read(reader).map_err(invalid)?;
+                let mut flags = [0u8];
+                reader.read_exact(&mut flags).map_err(invalid)?;
+                let leaf = match flags[0] {
+                    LEAF_HASH => PathLeaf::Hash(read_hash(reader)?),
+                    LEAF_DUPLICATE => PathLeaf::Duplicate,
+                    LEAF_TXID => PathLeaf::Txid(read_hash(reader)?),
+                    flags => return Err(format!("Invalid merkle path: leaf flags {flags}")),
+                };
+                level.insert(offset, leaf);
+            }
+            levels.push(level);
+        }
+        Ok(MerklePath { height, levels })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txids(count: u8) -> Vec<Hash256> {
+        (0..count).map(|value| Hash256([value; 32])).collect()
+    }
+
+    fn merkle_root(txids: &[Hash256]) -> Hash256 {
+        let mut level = txids.to_vec();
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| merkle_parent(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+                .collect();
+        }
+        level[0]
+    }
+
+    #[test]
+    fn proofs_lead_to_the_merkle_root() {
+        for count in [1, 2, 3, 5, 8, 11] {
+            let txids = txids(count);
+            let root = merkle_root(&txids);
+            for index in 0..u32::from(count) {
+                let proof = MerkleProof::new(&txids, index, ProofTarget::MerkleRoot(root)).unwrap();
+                assert_eq!(proof.root(), Ok(root), "{count} txs, tx {index}");
+            }
+        }
+        assert!(
+            MerkleProof::new(&txids(2), 2, ProofTarget::MerkleRoot(Hash256::default())).is_err()
+        );
+    }
+
+    #[test]
+    fn last_tx_of_an_odd_level_is_paired_with_itself() {
+        let txids = txids(3);
+        let proof = MerkleProof::new(&txids, 2, ProofTarget::BlockHash(Hash256([9; 32]))).unwrap();
+        assert_eq!(proof.nodes.len(), 2);
+        assert_eq!(proof.nodes[0], None);
+        assert_eq!(proof.nodes[1], Some(merkle_parent(&txids[0], &txids[1])));
+
+        let json = serde_json::to_value(proof.json()).unwrap();
+        assert_eq!(json["index"], 2);
+        assert_eq!(json["txOrId"], txids[2].encode());
+        assert_eq!(json["targetType"], "hash");
+        assert_eq!(json["nodes"][0], "*");
+
+        let binary = proof.binary();
+        // flags, index, txid, block hash, node count, duplicate node, hash node
+        assert_eq!(binary.len(), 1 + 1 + 32 + 32 + 1 + 1 + 33);
+        assert_eq!(&binary[..2], &[0, 2]);
+        assert_eq!(&binary[2..34], &txids[2].0);
+        assert_eq!(&binary[66..69], &[2, NODE_DUPLICATE, NODE_HASH]);
+    }
+
+    #[test]
+    fn forged_proofs_are_rejected() {
+        assert_eq!([1, 2, 3, 4, 5, 1000].map(tree_depth), [0, 1, 2, 2, 3, 10]);
+        let txids = txids(3);
+        let root = merkle_root(&txids);
+        let proof = MerkleProof::new(&txids, 2, ProofTarget::MerkleRoot(root)).unwrap();
+
+        // The last tx claimed as the copy of itself at index 3
+        let mut forged = proof.clone();
+        forged.index = 3;
+        assert!(forged.root().is_err());
+        // Bits of the index above the proof
+        forged.index = 6;
+        assert!(forged.root().is_err());
+
+        let mut path = MerklePath::new(100);
+        path.add(&proof);
+        let mut forged = path.clone();
+        forged.levels[0].clear();
+        forged.levels[0].insert(2, PathLeaf::Duplicate);
+        forged.levels[0].insert(3, PathLeaf::Txid(txids[2]));
+        assert!(forged.root(&txids[2]).is_err());
+        let mut forged = path.clone();
+        forged.levels[0].clear();
+        forged.levels[0].insert(6, PathLeaf::Txid(txids[2]));
+        forged.levels[0].insert(7, PathLeaf::Duplicate);
+        let left = forged.levels[1][&0];
+        forged.levels[1].insert(2, left);
+        assert!(forged.root(&txids[2]).is_err());
+    }
+
+    #[test]
+    fn merkle_paths_merge_proofs_and_round_trip() {
+        let txids = txids(5);
+        let root = merkle_root(&txids);
+        let mut path = MerklePath::new(100);
+        for index in [1, 4] {
+            let proof = MerkleProof::new(&txids, index, ProofTarget::MerkleRoot(root)).unwrap();
+            path.add(&proof);
+        }
+        assert_eq!(path.levels.len(), 3);
+        assert_eq!(path.txids().collect::<Vec<_>>(), vec![txids[1], txids[4]]);
+        assert_eq!(path.levels[0][&0], PathLeaf::Hash(txids[0]));
+        assert_eq!(path.levels[0][&5], PathLeaf::Duplicate);
+
+        let mut bytes = Vec::new();
+        path.write(&mut bytes);
+        let read = MerklePath::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read, path);
+        assert!(MerklePath::read(&mut &bytes[..bytes.len() - 1]).is_err());
+
+        for index in [1, 4] {
+            assert_eq!(path.root(&txids[index]), Ok(root));
+        }
+        assert!(path.root(&txids[0]).is_err());
+        // Leaves that can be worked out from the level below can be left out
+        let mut trimmed = path.clone();
+        trimmed.levels[1].remove(&0);
+        assert_eq!(trimmed.root(&txids[1]), Ok(root));
+        trimmed.levels[0].remove(&0);
+        assert!(trimmed.root(&txids[1]).is_err());
+
+        // A tx on its own in a block
+        let mut path = MerklePath::new(1);
+        path.add(&MerkleProof::new(&txids[..1], 0, ProofTarget::MerkleRoot(txids[0])).unwrap());
+        let mut bytes = Vec::new();
+        path.write(&mut bytes);
+        assert_eq!(&bytes[..4], &[1, 1, 1, 0]);
+        assert_eq!(MerklePath::read(&mut bytes.as_slice()).unwrap(), path);
+        assert_eq!(path.root(&txids[0]), Ok(txids[0]));
+    }
+
+    #[test]
+    fn json_proofs_round_trip() {
+        let txids = txids(6);
+        let header = BlockHeader {
+            merkle_root: merkle_root(&txids),
+            ..Default::default()
+        };
+        for target in [
+            ProofTarget::BlockHash(header.hash()),
+            ProofTarget::Header(header),
+            ProofTarget::MerkleRoot(header.merkle_root),
+        ] {
+            let proof = MerkleProof::new(&txids, 5, target).unwrap();
+            let json = serde_json::to_string(&proof.json()).unwrap();
+            let read: TscProofJson = serde_json::from_str(&json).unwrap();
+            assert_eq!(MerkleProof::from_json(&read), Ok(proof));
+        }
+
+        let json = serde_json::json!({
+            "index": 0,
+            "txOrId": txids[0].encode(),
+            "target": header.hash().encode(),
+            "nodes": ["*"],
+            "composite": true,
+        });
+        let read: TscProofJson = serde_json::from_value(json).unwrap();
+        assert_eq!(read.target_type, "hash");
+        assert!(MerkleProof::from_json(&read).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+// Latency of one type of database operation
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct OperationStats {
+    pub count: u64,
+    pub errors: u64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+    #[serde(skip)]
+    total_us: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DatabaseMetricsSnapshot {
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+    // Sends that found the queue full and waited for the database writer
+    pub backpressure_waits: u64,
+    pub backpressure_ms: u64,
+    // Operations taken from the queue and written
+    pub written: u64,
+    // The write error that halted the database writer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub halted: Option<String>,
+    pub operations: BTreeMap<&'static str, OperationStats>,
+}
+
+/// Shared view of the database write queue and writer,
+/// updated by the senders and the database writer thread and read by the REST API.
+pub struct DatabaseMetrics {
+    queue_capacity: usize,
+    queue_depth: AtomicUsize,
+    backpressure_waits: AtomicU64,
+    backpressure_us: AtomicU64,
+    written: AtomicU64,
+    is_halted: AtomicBool,
+    halted: Mutex<Option<String>>,
+    operations: Mutex<BTreeMap<&'static str, OperationStats>>,
+}
+
+impl DatabaseMetrics {
+    pub fn new(queue_capacity: usize) -> Self {
+        DatabaseMetrics {
+            queue_capacity,
+            queue_depth: AtomicUsize::new(0),
+            backpressure_waits: AtomicU64::new(0),
+            backpressure_us: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            is_halted: AtomicBool::new(false),
+            halted: Mutex::new(None),
+            operations: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn queued(&self) {
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dequeued(&self) {
+        // The depth is only decremented after it was incremented by the sender
+        let _ = self
+            .queue_depth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                depth.checked_sub(1)
+            });
+    }
+
+    pub fn record_backpressure(&self, waited: Duration) {
+        self.backpressure_waits.fetch_add(1, Ordering::Relaxed);
+        self.backpressure_us
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_operation(&self, operation: &'static str, elapsed: Duration, ok: bool) {
+        let mut operations = self.operations.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = operations.entry(operation).or_default();
+        let elapsed_us = elapsed.as_micros() as u64;
+        stats.count += 1;
+        if !ok {
+            stats.errors += 1;
+        }
+        stats.total_us += elapsed_us;
+        stats.mean_ms = stats.total_us as f64 / stats.count as f64 / 1000.0;
+        stats.max_ms = stats.max_ms.max(elapsed_us as f64 / 1000.0);
+    }
+
+    // The operations are written in the order they were queued
+    pub fn record_written(&self, written: u64) {
+        self.written.store(written, Ordering::Release);
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Acquire)
+    }
+
+    pub fn halt(&self, error: String) {
+        *self.halted.lock().unwrap_or_else(|e| e.into_inner()) = Some(error);
+        self.is_halted.store(true, Ordering::Release);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.is_halted.load(Ordering::Acquire)
+    }
+
+    pub fn halted_error(&self) -> Option<String> {
+        self.halted
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn snapshot(&self) -> DatabaseMetricsSnapshot {
+        DatabaseMetricsSnapshot {
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            queue_capacity: self.queue_capacity,
+            backpressure_waits: self.backpressure_waits.load(Ordering::Relaxed),
+            backpressure_ms: self.backpressure_us.load(Ordering::Relaxed) / 1000,
+            written: self.written(),
+            halted: self.halted_error(),
+            operations: self
+                .operations
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UtxoCacheSnapshot {
+    pub capacity: usize,
+    pub entries: usize,
+    // Changes that are not yet written to the database, these are held in addition to the entries
+    pub unflushed: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+    pub evictions: u64,
+}
+
+/// Shared view of the utxo cache, updated by the tx analyser and read by the REST API.
+pub struct UtxoCacheMetrics {
+    capacity: usize,
+    entries: AtomicUsize,
+    unflushed: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl UtxoCacheMetrics {
+    pub fn new(capacity: usize) -> Self {
+        UtxoCacheMetrics {
+            capacity,
+            entries: AtomicUsize::new(0),
+            unflushed: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn evicted(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_size(&self, entries: usize, unflushed: usize) {
+        self.entries.store(entries, Ordering::Relaxed);
+        self.unflushed.store(unflushed, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> UtxoCacheSnapshot {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = hits + misses;
+        UtxoCacheSnapshot {
+            capacity: self.capacity,
+            entries: self.entries.load(Ordering::Relaxed),
+            unflushed: self.unflushed.load(Ordering::Relaxed),
+            hits,
+            misses,
+            hit_rate: if lookups == 0 {
+                0.0
+            } else {
+                hits as f64 / lookups as f64
+            },
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operation_latency_is_recorded() {
+        let metrics = DatabaseMetrics::new(10);
+        metrics.record_operation("block write", Duration::from_millis(2), true);
+        metrics.record_operation("block write", Duration::from_millis(4), false);
+
+        let snapshot = metrics.snapshot();
+        let stats = &snapshot.operations["block write"];
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.mean_ms, 3.0);
+        assert_eq!(stats.max_ms, 4.0);
+    }
+
+    #[test]
+    fn queue_depth_does_not_underflow() {
+        let metrics = DatabaseMetrics::new(10);
+        metrics.queued();
+        metrics.dequeued();
+        metrics.dequeued();
+        assert_eq!(metrics.snapshot().queue_depth, 0);
+    }
+
+    #[test]
+    fn utxo_cache_hit_rate() {
+        let metrics = UtxoCacheMetrics::new(10);
+        assert_eq!(metrics.snapshot().hit_rate, 0.0);
+        metrics.hit();
+        metrics.hit();
+        metrics.hit();
+        metrics.miss();
+        assert_eq!(metrics.snapshot().hit_rate, 0.75);
+    }
+}
@@ -1,11 +1,25 @@
 mod address_manager;
+mod backfill;
+pub mod beef;
+pub mod blk_import;
 mod block_manager;
+mod block_queue;
+pub mod block_store;
 pub mod collection;
 mod connection;
-mod database;
+mod consistency;
+pub mod database;
+pub mod header_chain;
 mod hexslice;
 pub mod logic;
+pub mod merkle;
+pub mod metrics;
+pub mod monitor;
+mod muhash;
+mod pow;
 mod schema;
+pub mod snapshot;
+pub mod storage;
 mod tx_analyser;
 mod txdb;
 pub mod util;
@@ -0,0 +1,376 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chain_gang::util::Hash256;
+use serde::Serialize;
+
+use crate::config::CollectionConfig;
+
+/// A monitor is named within its owner, so each tenant has its own monitor names.
+/// Monitors of the operator, including those in the config file, have no owner
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MonitorKey {
+    pub owner: Option<String>,
+    pub name: String,
+}
+
+impl MonitorKey {
+    pub fn new(owner: Option<&str>, name: &str) -> Self {
+        MonitorKey {
+            owner: owner.map(str::to_string),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn of(config: &CollectionConfig) -> Self {
+        MonitorKey::new(config.owner.as_deref(), &config.name)
+    }
+
+    // The owner as stored with the collection txs, empty for the operator
+    pub fn stored_owner(&self) -> &str {
+        self.owner.as_deref().unwrap_or("")
+    }
+}
+
+impl fmt::Display for MonitorKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.owner {
+            Some(owner) => write!(f, "{owner}/{}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// Where a monitor was configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MonitorSource {
+    // From the [[collection]] entries in the config file - can not be changed at runtime
+    Static,
+    // Added through the REST API and persisted in the dynamic config file
+    Dynamic,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LastMatch {
+    pub hash: String,
+    // Seconds since the unix epoch
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackfillState {
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+// Progress of the historical rescan started when a monitor is added
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackfillStatus {
+    // The rescan this progress belongs to, a monitor changed again gets a new one
+    #[serde(skip)]
+    pub job: u64,
+    pub state: BackfillState,
+    pub start_height: u32,
+    pub end_height: u32,
+    pub current_height: Option<u32>,
+    pub blocks_scanned: u64,
+    pub blocks_total: u64,
+    pub matches: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MonitorStatus {
+    #[serde(flatten)]
+    pub config: CollectionConfig,
+    pub source: MonitorSource,
+    pub match_count: u64,
+    pub last_match: Option<LastMatch>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backfill: Option<BackfillStatus>,
+}
+
+// Why a monitor added through the REST API was not registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    // The owner already has a monitor with this name
+    Exists,
+    // The owner already has this many monitors
+    QuotaReached(usize),
+}
+
+/// Shared view of the monitors, so the REST API can validate and report on them
+/// without waiting on the main event processing loop.
+pub struct MonitorRegistry {
+    monitors: Mutex<BTreeMap<MonitorKey, MonitorStatus>>,
+}
+
+impl MonitorRegistry {
+    pub fn new() -> Self {
+        MonitorRegistry {
+            monitors: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<MonitorKey, MonitorStatus>> {
+        self.monitors.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Return false if the owner already has a monitor with this name
+    pub fn register(&self, config: &CollectionConfig, source: MonitorSource) -> bool {
+        let mut monitors = self.lock();
+        let key = MonitorKey::of(config);
+        if monitors.contains_key(&key) {
+            return false;
+        }
+        monitors.insert(
+            key,
+            MonitorStatus {
+                config: config.clone(),
+                source,
+                match_count: 0,
+                last_match: None,
+                backfill: None,
+            },
+        );
+        true
+    }
+
+    // As register, checking the name and the owner's quota under the same lock,
+    // so that adds made at the same time can not take the owner over its quota
+    pub fn register_owned(
+        &self,
+        config: &CollectionConfig,
+        max_monitors: Option<usize>,
+    ) -> Result<(), RegisterError> {
+        let mut monitors = self.lock();
+        let key = MonitorKey::of(config);
+        if monitors.contains_key(&key) {
+            return Err(RegisterError::Exists);
+        }
+        if let (Some(owner), Some(max_monitors)) = (&config.owner, max_monitors) {
+            let owned = monitors
+                .values()
+                .filter(|status| status.config.owner.as_ref() == Some(owner))
+                .count();
+            if owned >= max_monitors {
+                return Err(RegisterError::QuotaReached(max_monitors));
+            }
+        }
+        monitors.insert(
+            key,
+            MonitorStatus {
+                config: config.clone(),
+                source: MonitorSource::Dynamic,
+                match_count: 0,
+                last_match: None,
+                backfill: None,
+            },
+        );
+        Ok(())
+    }
+
+    // Replace the configuration of a monitor, keeping its match statistics
+    pub fn update(&self, config: &CollectionConfig) -> bool {
+        match self.lock().get_mut(&MonitorKey::of(config)) {
+            Some(status) => {
+                status.config = config.clone();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&self, key: &MonitorKey) -> Option<MonitorStatus> {
+        self.lock().remove(key)
+    }
+
+    pub fn get(&self, key: &MonitorKey) -> Option<MonitorStatus> {
+        self.lock().get(key).cloned()
+    }
+
+    pub fn list(&self) -> Vec<MonitorStatus> {
+        self.lock().values().cloned().collect()
+    }
+
+    pub fn set_match_count(&self, key: &MonitorKey, match_count: u64) {
+        if let Some(status) = self.lock().get_mut(key) {
+            status.match_count = match_count;
+        }
+    }
+
+    pub fn set_backfill(&self, key: &MonitorKey, backfill: BackfillStatus) {
+        if let Some(status) = self.lock().get_mut(key) {
+            status.backfill = Some(backfill);
+        }
+    }
+
+    // Apply a change to the backfill progress of a monitor, if it is still that of the job.
+    // A job replaced by a newer rescan, or for a monitor deleted and added again, is ignored
+    pub fn update_backfill<F>(&self, key: &MonitorKey, job: u64, f: F)
+    where
+        F: FnOnce(&mut BackfillStatus),
+    {
+        if let Some(backfill) = self
+            .lock()
+            .get_mut(key)
+            .and_then(|status| status.backfill.as_mut())
+            .filter(|backfill| backfill.job == job)
+        {
+            f(backfill);
+        }
+    }
+
+    pub fn record_match(&self, key: &MonitorKey, hash: Hash256) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        if let Some(status) = self.lock().get_mut(key) {
+            status.match_count += 1;
+            status.last_match = Some(LastMatch {
+                hash: hash.encode(),
+                time,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(name: &str) -> CollectionConfig {
+        CollectionConfig {
+            name: name.to_string(),
+            track_descendants: false,
+            address: None,
+            locking_script_pattern: Some("76a914".to_string()),
+            owner: None,
+        }
+    }
+
+    #[test]
+    fn register_rejects_duplicate_names() {
+        let registry = MonitorRegistry::new();
+        assert!(registry.register(&monitor("a"), MonitorSource::Dynamic));
+        assert!(!registry.register(&monitor("a"), MonitorSource::Static));
+        assert_eq!(registry.list().len(), 1);
+    }
+
+    #[test]
+    fn register_owned_checks_names_and_quota() {
+        let registry = MonitorRegistry::new();
+        let owned = |name: &str, owner: &str| CollectionConfig {
+            owner: Some(owner.to_string()),
+            ..monitor(name)
+        };
+        assert!(registry.register(&monitor("operator"), MonitorSource::Static));
+        assert_eq!(
+            registry.register_owned(&owned("a", "team-a"), Some(2)),
+            Ok(())
+        );
+        assert_eq!(
+            registry.register_owned(&owned("a", "team-a"), Some(2)),
+            Err(RegisterError::Exists)
+        );
+        // Each owner has its own names
+        assert_eq!(
+            registry.register_owned(&owned("a", "team-b"), Some(2)),
+            Ok(())
+        );
+        assert_eq!(
+            registry.register_owned(&owned("operator", "team-b"), Some(2)),
+            Ok(())
+        );
+        assert_eq!(registry.register_owned(&monitor("a"), None), Ok(()));
+        assert_eq!(
+            registry.register_owned(&monitor("operator"), None),
+            Err(RegisterError::Exists)
+        );
+
+        assert_eq!(
+            registry.register_owned(&owned("b", "team-a"), Some(2)),
+            Ok(())
+        );
+        assert_eq!(
+            registry.register_owned(&owned("c", "team-a"), Some(2)),
+            Err(RegisterError::QuotaReached(2))
+        );
+        assert_eq!(
+            registry.register_owned(&owned("c", "team-b"), Some(2)),
+            Err(RegisterError::QuotaReached(2))
+        );
+
+        let key = MonitorKey::new(Some("team-b"), "a");
+        assert_eq!(
+            registry.get(&key).map(|status| status.config),
+            Some(owned("a", "team-b"))
+        );
+        assert_eq!(key.to_string(), "team-b/a");
+        assert!(registry.remove(&key).is_some());
+        assert!(registry
+            .get(&MonitorKey::new(Some("team-a"), "a"))
+            .is_some());
+    }
+
+    #[test]
+    fn update_keeps_match_statistics() {
+        let registry = MonitorRegistry::new();
+        registry.register(&monitor("a"), MonitorSource::Dynamic);
+        registry.record_match(&MonitorKey::new(None, "a"), Hash256::default());
+
+        let mut changed = monitor("a");
+        changed.track_descendants = true;
+        assert!(registry.update(&changed));
+
+        let status = registry
+            .get(&MonitorKey::new(None, "a"))
+            .expect("monitor status");
+        assert!(status.config.track_descendants);
+        assert_eq!(status.match_count, 1);
+        assert!(status.last_match.is_some());
+    }
+
+    #[test]
+    fn update_backfill_ignores_replaced_jobs() {
+        let registry = MonitorRegistry::new();
+        registry.register(&monitor("a"), MonitorSource::Dynamic);
+        let backfill = |job| BackfillStatus {
+            job,
+            state: BackfillState::Running,
+            start_height: 1,
+            end_height: 10,
+            current_height: None,
+            blocks_scanned: 0,
+            blocks_total: 10,
+            matches: 0,
+            error: None,
+        };
+        let key = MonitorKey::new(None, "a");
+        registry.set_backfill(&key, backfill(1));
+        registry.set_backfill(&key, backfill(2));
+
+        registry.update_backfill(&key, 1, |backfill| {
+            backfill.state = BackfillState::Cancelled
+        });
+        registry.update_backfill(&key, 2, |backfill| backfill.blocks_scanned = 3);
+        let status = registry.get(&key).and_then(|status| status.backfill);
+        assert_eq!(
+            status,
+            Some(BackfillStatus {
+                blocks_scanned: 3,
+                ..backfill(2)
+            })
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use chain_gang::{
+    messages::OutPoint,
+    util::{sha256d, Hash256},
+};
+
+// Numbers modulo the prime P = 2^256 - 189, as little endian 64 bit limbs
+type Limbs = [u64; 4];
+
+const C: u64 = 189;
+// The exponent for inverting by Fermat's little theorem
+const P_MINUS_2: Limbs = [u64::MAX - C - 1, u64::MAX, u64::MAX, u64::MAX];
+const ONE: Limbs = [1, 0, 0, 0];
+
+// a + b, and whether it overflowed 2^256
+fn add(a: &Limbs, b: &Limbs) -> (Limbs, bool) {
+    let mut sum = [0; 4];
+    let mut carry = false;
+    for i in 0..4 {
+        let (value, over1) = a[i].overflowing_add(b[i]);
+        let (value, over2) = value.overflowing_add(u64::from(carry));
+        sum[i] = value;
+        carry = over1 || over2;
+    }
+    (sum, carry)
+}
+
+// Reduce a value below 2^256 to below P
+fn normalise(value: Limbs) -> Limbs {
+    // value >= P exactly when value + C overflows
+    match add(&value, &[C, 0, 0, 0]) {
+        (reduced, true) => reduced,
+        (_sum, false) => value,
+    }
+}
+
+fn mul(a: &Limbs, b: &Limbs) -> Limbs {
+    let mut wide = [0u64; 8];
+    for i in 0..4 {
+        let mut carry = 0u128;
+        for j in 0..4 {
+            let value = u128::from(a[i]) * u128::from(b[j]) + u128::from(wide[i + j]) + carry;
+            wide[i + j] = value as u64;
+            carry = value >> 64;
+        }
+        wide[i + 4] = carry as u64;
+    }
+
+    // 2^256 = C (mod P), so fold the high half down: low + high * C
+    let mut folded = [0u64; 4];
+    let mut carry = 0u128;
+    for i in 0..4 {
+        let value = u128::from(wide[i]) + u128::from(wide[i + 4]) * u128::from(C) + carry;
+        folded[i] = value as u64;
+        carry = value >> 64;
+    }
+    // And again for the few bits left over, which can overflow once more
+    let (mut folded, overflow) = add(&folded, &[carry as u64 * C, 0, 0, 0]);
+    if overflow {
+        // The sum wrapped so is small, adding C can not overflow
+        folded = add(&folded, &[C, 0, 0, 0]).0;
+    }
+    normalise(folded)
+}
+
+fn pow(base: &Limbs, exponent: &Limbs) -> Limbs {
+    let mut result = ONE;
+    for limb in exponent.iter().rev() {
+        for bit in (0..64).rev() {
+            result = mul(&result, &result);
+            if limb >> bit & 1 == 1 {
+                result = mul(&result, base);
+            }
+        }
+    }
+    result
+}
+
+fn to_limbs(bytes: &[u8; 32]) -> Limbs {
+    let mut limbs = [0; 4];
+    for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+        let mut word = [0; 8];
+        word.copy_from_slice(chunk);
+        *limb = u64::from_le_bytes(word);
+    }
+    limbs
+}
+
+fn to_bytes(limbs: &Limbs) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    for (chunk, limb) in bytes.chunks_exact_mut(8).zip(limbs.iter()) {
+        chunk.copy_from_slice(&limb.to_le_bytes());
+    }
+    bytes
+}
+
+// The number that stands for an outpoint in the set
+fn element(outpoint: &OutPoint) -> Limbs {
+    let mut data = Vec::with_capacity(36);
+    data.extend_from_slice(&outpoint.hash.0);
+    data.extend_from_slice(&outpoint.index.to_le_bytes());
+    let value = normalise(to_limbs(&sha256d(&data).0));
+    if value == [0; 4] {
+        ONE
+    } else {
+        value
+    }
+}
+
+/// Incremental hash of a set of outpoints (MuHash).
+///
+/// Each outpoint is hashed to a number modulo a 256 bit prime. The set hash is the product
+/// of the numbers of the outpoints added, divided by those removed, so it does not depend on
+/// the order of the changes. The outpoint is enough to identify an output, as the txid
+/// commits to its amount and script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MuHash {
+    numerator: Limbs,
+    denominator: Limbs,
+}
+
+impl Default for MuHash {
+    fn default() -> Self {
+        MuHash::new()
+    }
+}
+
+impl MuHash {
+    // The hash of the empty set
+    pub fn new() -> Self {
+        MuHash {
+            numerator: ONE,
+            denominator: ONE,
+        }
+    }
+
+    // Continue from a value returned by finalize
+    pub fn from_commitment(commitment: &Hash256) -> Self {
+        MuHash {
+            numerator: normalise(to_limbs(&commitment.0)),
+            denominator: ONE,
+        }
+    }
+
+    pub fn insert(&mut self, outpoint: &OutPoint) {
+        self.numerator = mul(&self.numerator, &element(outpoint));
+    }
+
+    pub fn remove(&mut self, outpoint: &OutPoint) {
+        self.denominator = mul(&self.denominator, &element(outpoint));
+    }
+
+    // The set hash, this does the one division needed for the changes since the last call
+    pub fn finalize(&mut self) -> Hash256 {
+        if self.denominator != ONE {
+            let inverse = pow(&self.denominator, &P_MINUS_2);
+            self.numerator = mul(&self.numerator, &inverse);
+            self.denominator = ONE;
+        }
+        Hash256(to_bytes(&self.numerator))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outpoint(value: u8, index: u32) -> OutPoint {
+        OutPoint {
+            hash: Hash256([value; 32]),
+            index,
+        }
+    }
+
+    #[test]
+    fn field_arithmetic() {
+        // P - 1 is -1, and (-1)^2 = 1
+        let minus_one = [u64::MAX - C, u64::MAX, u64::MAX, u64::MAX];
+        assert_eq!(mul(&minus_one, &minus_one), ONE);
+        let p = [u64::MAX - C + 1, u64::MAX, u64::MAX, u64::MAX];
+        assert_eq!(normalise(p), [0; 4]);
+        let value = [0x0123_4567_89ab_cdef, 42, u64::MAX, 7];
+        assert_eq!(mul(&value, &pow(&value, &P_MINUS_2)), ONE);
+    }
+
+    #[test]
+    fn set_hash_does_not_depend_on_order() {
+        let mut forward = MuHash::new();
+        for index in 0..5 {
+            forward.insert(&outpoint(1, index));
+        }
+        forward.remove(&outpoint(1, 2));
+
+        let mut backward = MuHash::new();
+        backward.remove(&outpoint(1, 2));
+        for index in (0..5).rev() {
+            backward.insert(&outpoint(1, index));
+        }
+        assert_eq!(forward.finalize(), backward.finalize());
+
+        let mut direct = MuHash::new();
+        for index in [0, 1, 3, 4] {
+            direct.insert(&outpoint(1, index));
+        }
+        let commitment = direct.finalize();
+        assert_eq!(forward.finalize(), commitment);
+        assert_ne!(commitment, MuHash::new().finalize());
+
+        // Continuing from a stored commitment
+        let mut resumed = MuHash::from_commitment(&commitment);
+        resumed.remove(&outpoint(1, 4));
+        direct.remove(&outpoint(1, 4));
+        assert_eq!(resumed.finalize(), direct.finalize());
+    }
+}
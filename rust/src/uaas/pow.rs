@@ -0,0 +1,365 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use chain_gang::{messages::BlockHeader, network::Network};
+
+// Seconds between blocks that the difficulty aims for
+const TARGET_SPACING: i64 = 600;
+// Blocks the difficulty adjustment averages the work over
+const DAA_WINDOW: usize = 144;
+// The headers before a block that its difficulty is worked out from, the window
+// and the two before each end to take the median timestamp of
+pub const DAA_HEADERS: usize = DAA_WINDOW + 3;
+
+/// A 256 bit number, as little endian 64 bit limbs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct U256([u64; 4]);
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+
+    pub fn from_u64(value: u64) -> Self {
+        U256([value, 0, 0, 0])
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0[index / 64] >> (index % 64) & 1 == 1
+    }
+
+    // The number of bits needed to write the value
+    fn bits(&self) -> usize {
+        for (i, limb) in self.0.iter().enumerate().rev() {
+            if *limb != 0 {
+                return 64 * i + 64 - limb.leading_zeros() as usize;
+            }
+        }
+        0
+    }
+
+    fn shl(&self, shift: usize) -> Self {
+        let mut result = [0; 4];
+        let (limbs, bits) = (shift / 64, shift % 64);
+        for i in (limbs..4).rev() {
+            result[i] = self.0[i - limbs] << bits;
+            if bits > 0 && i > limbs {
+                result[i] |= self.0[i - limbs - 1] >> (64 - bits);
+            }
+        }
+        U256(result)
+    }
+
+    fn shr(&self, shift: usize) -> Self {
+        let mut result = [0; 4];
+        let (limbs, bits) = (shift / 64, shift % 64);
+        for (i, limb) in result
+            .iter_mut()
+            .enumerate()
+            .take(4usize.saturating_sub(limbs))
+        {
+            *limb = self.0[i + limbs] >> bits;
+            if bits > 0 && i + limbs + 1 < 4 {
+                *limb |= self.0[i + limbs + 1] << (64 - bits);
+            }
+        }
+        U256(result)
+    }
+
+    fn not(&self) -> Self {
+        U256(self.0.map(|limb| !limb))
+    }
+
+    // Addition modulo 2^256, chain work does not get near it
+    pub fn wrapping_add(&self, other: &Self) -> Self {
+        let mut sum = [0; 4];
+        let mut carry = false;
+        for (i, limb) in sum.iter_mut().enumerate() {
+            let (value, over1) = self.0[i].overflowing_add(other.0[i]);
+            let (value, over2) = value.overflowing_add(u64::from(carry));
+            *limb = value;
+            carry = over1 || over2;
+        }
+        U256(sum)
+    }
+
+    fn wrapping_sub(&self, other: &Self) -> Self {
+        self.wrapping_add(&other.not().wrapping_add(&U256::from_u64(1)))
+    }
+
+    fn mul_u64(&self, value: u64) -> Self {
+        let mut product = [0; 4];
+        let mut carry = 0u128;
+        for (i, limb) in product.iter_mut().enumerate() {
+            let wide = u128::from(self.0[i]) * u128::from(value) + carry;
+            *limb = wide as u64;
+            carry = wide >> 64;
+        }
+        U256(product)
+    }
+
+    // Long division, one bit at a time
+    fn div(&self, divisor: &Self) -> Self {
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for i in (0..256).rev() {
+            let overflow = remainder.bit(255);
+            remainder = remainder.shl(1);
+            remainder.0[0] |= u64::from(self.bit(i));
+            if overflow || remainder >= *divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.0[i / 64] |= 1 << (i % 64);
+            }
+        }
+        quotient
+    }
+
+    /// The target of a header's compact bits, None if the bits are negative, zero or too large
+    pub fn from_compact(bits: u32) -> Option<Self> {
+        let size = (bits >> 24) as usize;
+        let word = bits & 0x007f_ffff;
+        if word == 0
+            || bits & 0x0080_0000 != 0
+            || size > 34
+            || (word > 0xff && size > 33)
+            || (word > 0xffff && size > 32)
+        {
+            return None;
+        }
+        Some(if size <= 3 {
+            U256::from_u64(u64::from(word >> (8 * (3 - size))))
+        } else {
+            U256::from_u64(u64::from(word)).shl(8 * (size - 3))
+        })
+    }
+
+    /// The compact bits of a target
+    pub fn compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            (self.0[0] << (8 * (3 - size))) as u32
+        } else {
+            self.shr(8 * (size - 3)).0[0] as u32
+        };
+        // The top bit of the mantissa is the sign
+        if compact & 0x0080_0000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | (size as u32) << 24
+    }
+}
+
+/// The expected number of hashes to find a header with these bits, 2^256 / (target + 1)
+pub fn header_work(bits: u32) -> U256 {
+    match U256::from_compact(bits) {
+        // Worked out as (2^256 - target - 1) / (target + 1) + 1, as 2^256 does not fit
+        Some(target) => {
+            let divisor = target.wrapping_add(&U256::from_u64(1));
+            target.not().div(&divisor).wrapping_add(&U256::from_u64(1))
+        }
+        None => U256::ZERO,
+    }
+}
+
+/// The total work of a run of headers
+pub fn chain_work<'a>(headers: impl IntoIterator<Item = &'a BlockHeader>) -> U256 {
+    let mut cache = HashMap::new();
+    headers.into_iter().fold(U256::ZERO, |total, header| {
+        let work = *cache
+            .entry(header.bits)
+            .or_insert_with(|| header_work(header.bits));
+        total.wrapping_add(&work)
+    })
+}
+
+/// The difficulty rules of a network
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DifficultyRules {
+    // The easiest target allowed, as compact bits
+    pow_limit: u32,
+    // The height from which the difficulty is adjusted every block
+    daa_height: u32,
+    // Blocks more than twice the target spacing after the one before can be at the pow limit
+    allow_min_difficulty: bool,
+}
+
+impl DifficultyRules {
+    pub fn new(network: Network) -> Self {
+        match network {
+            Network::BSV_Mainnet => DifficultyRules {
+                pow_limit: 0x1d00ffff,
+                daa_height: 504_031,
+                allow_min_difficulty: false,
+            },
+            Network::BSV_Testnet => DifficultyRules {
+                pow_limit: 0x1d00ffff,
+                daa_height: 1_188_697,
+                allow_min_difficulty: true,
+            },
+            Network::BSV_STN => DifficultyRules {
+                pow_limit: 0x1d00ffff,
+                daa_height: 2_200,
+                allow_min_difficulty: true,
+            },
+        }
+    }
+
+    // Rules with the difficulty adjusted from the start and an easy pow limit, for tests that mine headers
+    #[cfg(test)]
+    pub fn easy() -> Self {
+        DifficultyRules {
+            pow_limit: 0x207fffff,
+            daa_height: 0,
+            allow_min_difficulty: false,
+        }
+    }
+
+    /// The bits a header at height must have, given the DAA_HEADERS headers before it in height order.
+    /// None where the bits can not be checked: before the per block adjustment, or without the headers
+    pub fn expected_bits(
+        &self,
+        height: u32,
+        header: &BlockHeader,
+        previous: &[BlockHeader],
+    ) -> Option<u32> {
+        if height <= self.daa_height || previous.len() < DAA_HEADERS {
+            return None;
+        }
+        let previous = &previous[previous.len() - DAA_HEADERS..];
+        let prev = &previous[DAA_HEADERS - 1];
+        if self.allow_min_difficulty
+            && i64::from(header.timestamp) > i64::from(prev.timestamp) + 2 * TARGET_SPACING
+        {
+            return Some(self.pow_limit);
+        }
+
+        // The header with the median timestamp of the last three, at each end of the window
+        let first = median_time_index(previous, 2);
+        let last = median_time_index(previous, DAA_HEADERS - 1);
+        let work = chain_work(&previous[first + 1..=last]);
+        let timespan = (i64::from(previous[last].timestamp) - i64::from(previous[first].timestamp))
+            .clamp(72 * TARGET_SPACING, 288 * TARGET_SPACING);
+        let work = work
+            .mul_u64(TARGET_SPACING as u64)
+            .div(&U256::from_u64(timespan as u64));
+        if work == U256::ZERO {
+            return Some(self.pow_limit);
+        }
+        // (2^256 - work) / work
+        let target = U256::ZERO.wrapping_sub(&work).div(&work);
+        let pow_limit = U256::from_compact(self.pow_limit)?;
+        Some(target.min(pow_limit).compact())
+    }
+}
+
+// Of the headers at index and the two before it, the index of the one with the median timestamp
+fn median_time_index(headers: &[BlockHeader], index: usize) -> usize {
+    let mut indexes = [index - 2, index - 1, index];
+    indexes.sort_by_key(|i| headers[*i].timestamp);
+    indexes[1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_bits_round_trip() {
+        for bits in [0x1d00ffff, 0x207fffff, 0x1b0404cb, 0x180d9ce4, 0x03123456] {
+            let target = U256::from_compact(bits).expect("valid bits");
+            assert_eq!(target.compact(), bits, "{bits:08x}");
+        }
+        assert_eq!(
+            U256::from_compact(0x1d00ffff),
+            Some(U256([0, 0, 0, 0x0000_0000_ffff_0000]))
+        );
+        // Negative, zero and overflowing bits
+        for bits in [0x04923456, 0x1d000000, 0xff123456] {
+            assert_eq!(U256::from_compact(bits), None, "{bits:08x}");
+        }
+    }
+
+    #[test]
+    fn work_is_the_expected_number_of_hashes() {
+        // The work of a genesis difficulty header, as reported by the nodes
+        assert_eq!(header_work(0x1d00ffff), U256::from_u64(0x0001_0001_0001));
+        assert_eq!(header_work(0x207fffff), U256::from_u64(2));
+        let headers = [0x207fffff, 0x1d00ffff, 0x207fffff].map(|bits| BlockHeader {
+            bits,
+            ..Default::default()
+        });
+        assert_eq!(chain_work(&headers), U256::from_u64(0x0001_0001_0005));
+    }
+
+    fn chain(count: usize, bits: u32, spacing: u32) -> Vec<BlockHeader> {
+        (0..count as u32)
+            .map(|i| BlockHeader {
+                bits,
+                timestamp: 1_600_000_000 + i * spacing,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn difficulty_follows_the_block_rate() {
+        let rules = DifficultyRules::new(Network::BSV_Mainnet);
+        let bits = 0x1b0404cb;
+        let next = |previous: &[BlockHeader]| BlockHeader {
+            timestamp: previous.last().unwrap().timestamp + 600,
+            ..Default::default()
+        };
+
+        // Blocks on time keep the difficulty, up to the rounding of the compact form
+        let near = |value: U256, expected: U256| {
+            let margin = expected.shr(8);
+            value > expected.wrapping_sub(&margin) && value < expected.wrapping_add(&margin)
+        };
+        let previous = chain(DAA_HEADERS, bits, 600);
+        let expected = rules
+            .expected_bits(600_000, &next(&previous), &previous)
+            .unwrap();
+        let (target, expected) = (
+            U256::from_compact(bits).unwrap(),
+            U256::from_compact(expected).unwrap(),
+        );
+        assert!(near(expected, target));
+
+        // Blocks twice as fast double the difficulty, halving the target
+        let previous = chain(DAA_HEADERS, bits, 300);
+        let faster = rules
+            .expected_bits(600_000, &next(&previous), &previous)
+            .unwrap();
+        let faster = U256::from_compact(faster).unwrap();
+        assert!(near(faster, target.shr(1)));
+
+        // Not checked before the per block adjustment, or without the headers before
+        assert_eq!(
+            rules.expected_bits(500_000, &next(&previous), &previous),
+            None
+        );
+        assert_eq!(
+            rules.expected_bits(600_000, &next(&previous), &previous[1..]),
+            None
+        );
+
+        // Testnet allows a block at the pow limit after twenty minutes
+        let rules = DifficultyRules::new(Network::BSV_Testnet);
+        let mut late = next(&previous);
+        late.timestamp += 1200;
+        assert_eq!(
+            rules.expected_bits(1_200_000, &late, &previous),
+            Some(0x1d00ffff)
+        );
+    }
+}
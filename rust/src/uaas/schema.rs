@@ -1,174 +1,68 @@
-use mysql::{prelude::*, PooledConn};
-
-fn table_exists(conn: &mut PooledConn, table: &str) -> bool {
-    let query = format!(
-        "SELECT COUNT(*) FROM INFORMATION_SCHEMA.TABLES \
-         WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = '{table}'"
-    );
-    conn.query_first::<i64, _>(&query)
-        .ok()
-        .flatten()
-        .unwrap_or(0)
-        > 0
+/// A numbered change to the database schema.
+///
+/// Each backend keeps its own list, in version order. A migration is applied once,
+/// and its version recorded, but must be safe to run again if it was interrupted.
+pub struct Migration<A> {
+    pub version: u32,
+    pub description: &'static str,
+    pub apply: A,
 }
 
-fn ensure_index(conn: &mut PooledConn, table: &str, ddl: &str) {
-    if !table_exists(conn, table) {
-        return;
-    }
-    if let Err(err) = conn.query_drop(ddl) {
-        log::warn!("Unable to ensure index on {table}: {err:?}");
-    }
+pub fn latest_version<A>(migrations: &[Migration<A>]) -> u32 {
+    migrations.last().map_or(0, |migration| migration.version)
 }
 
-fn ensure_mempool_primary_key(conn: &mut PooledConn) {
-    if !table_exists(conn, "mempool") {
-        return;
+// The migrations to apply to a store at the current version, in order
+pub fn pending<A>(
+    migrations: &[Migration<A>],
+    current: u32,
+) -> Result<impl Iterator<Item = &Migration<A>>, String> {
+    if migrations
+        .windows(2)
+        .any(|pair| pair[0].version >= pair[1].version)
+    {
+        return Err("Database migrations are not in version order".to_string());
     }
-
-    let has_pk = conn
-        .query_first::<i64, _>(
-            "SELECT COUNT(*) FROM INFORMATION_SCHEMA.TABLE_CONSTRAINTS \
-             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'mempool' \
-             AND CONSTRAINT_TYPE = 'PRIMARY KEY'",
-        )
-        .ok()
-        .flatten()
-        .unwrap_or(0)
-        > 0;
-
-    if has_pk {
-        return;
+    let latest = latest_version(migrations);
+    if current > latest {
+        return Err(format!(
+            "Database schema version {current} is newer than this build supports ({latest})"
+        ));
     }
-
-    log::info!("Migrating mempool table: adding primary key on hash");
-
-    if let Err(err) = conn.query_drop(
-        "DELETE m1 FROM mempool m1 \
-         INNER JOIN mempool m2 ON m1.hash = m2.hash AND m1.time > m2.time",
-    ) {
-        log::warn!("Unable to deduplicate mempool rows before primary key migration: {err:?}");
-    }
-
-    if let Err(err) = conn.query_drop("DROP INDEX IF EXISTS idx_txkey ON mempool") {
-        log::warn!("Unable to drop legacy mempool hash index: {err:?}");
-    }
-
-    if let Err(err) = conn.query_drop("ALTER TABLE mempool ADD PRIMARY KEY (hash)") {
-        log::error!("Unable to add mempool primary key: {err:?}");
-    }
-}
-
-/// Apply height indexes and mempool primary key for existing and new databases.
-pub fn ensure_performance_indexes(conn: &mut PooledConn) {
-    ensure_index(
-        conn,
-        "blocks",
-        "CREATE INDEX IF NOT EXISTS idx_blocks_height ON blocks (height)",
-    );
-    ensure_index(
-        conn,
-        "tx",
-        "CREATE INDEX IF NOT EXISTS idx_tx_height_blockindex ON tx (height, blockindex)",
-    );
-    ensure_index(
-        conn,
-        "utxo",
-        "CREATE INDEX IF NOT EXISTS idx_utxo_height ON utxo (height)",
-    );
-    ensure_mempool_primary_key(conn);
+    Ok(migrations
+        .iter()
+        .filter(move |migration| migration.version > current))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn perf_indexes_apply_on_test_database() {
-        let Some(url) = std::env::var("UAAS_TEST_MYSQL_URL").ok() else {
-            eprintln!("skipping perf_indexes_apply_on_test_database: UAAS_TEST_MYSQL_URL not set");
-            return;
-        };
-
-        let pool = mysql::Pool::new(url.as_str()).expect("connect to UAAS_TEST_MYSQL_URL");
-        let mut conn = pool
-            .get_conn()
-            .expect("get connection for schema migration test");
-
-        conn.query_drop(
-            "CREATE TABLE IF NOT EXISTS blocks (
-                height int unsigned not null,
-                hash varchar(64) not null,
-                version int unsigned not null,
-                prev_hash varchar(64) not null,
-                merkle_root varchar(64) not null,
-                timestamp int unsigned not null,
-                bits int unsigned not null,
-                nonce int unsigned not null,
-                `offset` bigint unsigned not null,
-                blocksize int unsigned not null,
-                numtxs int unsigned not null,
-                PRIMARY KEY (hash)
-            )",
-        )
-        .expect("create blocks table for schema test");
-
-        conn.query_drop(
-            "CREATE TABLE IF NOT EXISTS tx (
-                hash varchar(64) not null,
-                height int unsigned not null,
-                blockindex int unsigned not null,
-                txsize int unsigned not null,
-                satoshis bigint unsigned not null,
-                PRIMARY KEY (hash)
-            )",
-        )
-        .expect("create tx table for schema test");
-
-        conn.query_drop(
-            "CREATE TABLE IF NOT EXISTS utxo (
-                hash varchar(64) not null,
-                pos int unsigned not null,
-                satoshis bigint unsigned not null,
-                height int not null,
-                pubkeyhash varchar(64),
-                PRIMARY KEY (hash, pos)
-            )",
-        )
-        .expect("create utxo table for schema test");
-
-        conn.query_drop("DROP TABLE IF EXISTS mempool")
-            .expect("drop mempool");
-        conn.query_drop(
-            "CREATE TABLE mempool (
-                hash varchar(64) not null,
-                locktime int unsigned not null,
-                fee bigint unsigned not null,
-                time int unsigned not null,
-                tx longtext not null
-            )",
-        )
-        .expect("create legacy mempool table for schema test");
-        conn.query_drop("CREATE INDEX idx_txkey ON mempool (hash)")
-            .expect("create legacy mempool index");
-
-        ensure_performance_indexes(&mut conn);
+    fn migration(version: u32) -> Migration<()> {
+        Migration {
+            version,
+            description: "test",
+            apply: (),
+        }
+    }
 
-        let indexes: Vec<String> = conn
-            .query(
-                "SELECT INDEX_NAME FROM INFORMATION_SCHEMA.STATISTICS \
-                 WHERE TABLE_SCHEMA = DATABASE() \
-                 AND INDEX_NAME IN ('idx_blocks_height', 'idx_tx_height_blockindex', 'idx_utxo_height', 'PRIMARY') \
-                 AND TABLE_NAME IN ('blocks', 'tx', 'utxo', 'mempool')",
-            )
-            .expect("query performance indexes");
+    fn versions(migrations: &[Migration<()>], current: u32) -> Result<Vec<u32>, String> {
+        pending(migrations, current).map(|pending| pending.map(|m| m.version).collect())
+    }
 
-        assert!(
-            indexes.len() >= 4,
-            "expected performance indexes to be present, found {indexes:?}"
-        );
+    #[test]
+    fn only_later_migrations_are_pending() {
+        let migrations = [migration(1), migration(2), migration(3)];
+        assert_eq!(latest_version(&migrations), 3);
+        assert_eq!(versions(&migrations, 0), Ok(vec![1, 2, 3]));
+        assert_eq!(versions(&migrations, 2), Ok(vec![3]));
+        assert_eq!(versions(&migrations, 3), Ok(vec![]));
+    }
 
-        conn.query_drop("DROP TABLE IF EXISTS mempool")
-            .expect("cleanup mempool");
+    #[test]
+    fn newer_or_unordered_schema_is_rejected() {
+        let migrations = [migration(1), migration(2)];
+        assert!(versions(&migrations, 3).is_err());
+        assert!(versions(&[migration(2), migration(1)], 0).is_err());
     }
 }
@@ -0,0 +1,459 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+use chain_gang::{
+    messages::BlockHeader,
+    util::{sha256d, Hash256, Serializable},
+};
+
+use super::database::{BlockHeaderWriteDB, UtxoEntryDB};
+use super::storage::Storage;
+
+// A utxo snapshot file is
+//   magic, format version (u32), network name (u8 length + bytes),
+//   a record for each utxo entry, tagged ENTRY,
+//   END, tip height (u32), tip block header, number of entries (u64),
+//   the utxo set commitment of the tip block (u8 present + 32 bytes, from version 2),
+//   commitment (32 bytes).
+// Numbers are little endian. The commitment is a rolling hash over the parts before it,
+// c = sha256d(c || part), starting from zero, so that a truncated or altered file is rejected.
+const MAGIC: &[u8; 8] = b"UAASUTXO";
+pub const SNAPSHOT_VERSION: u32 = 2;
+const ENTRY: u8 = 1;
+const END: u8 = 0;
+
+// Number of entries written to storage at a time on import
+const IMPORT_BATCH: usize = 10_000;
+
+// What a snapshot holds
+#[derive(Debug, PartialEq)]
+pub struct SnapshotInfo {
+    pub height: u32,
+    pub tip: Hash256,
+    pub entries: u64,
+    pub commitment: Hash256,
+    // The utxo set hash stored with the tip block, so that the importing instance continues it
+    pub utxo_commitment: Option<Hash256>,
+}
+
+// Passes the bytes through, keeping those of the current part for the commitment
+struct Committed<T> {
+    inner: T,
+    part: Vec<u8>,
+    commitment: Hash256,
+}
+
+impl<T> Committed<T> {
+    fn new(inner: T) -> Self {
+        Committed {
+            inner,
+            part: Vec::new(),
+            commitment: Hash256([0; 32]),
+        }
+    }
+
+    // Fold the current part into the commitment
+    fn end_part(&mut self) {
+        let mut data = Vec::with_capacity(32 + self.part.len());
+        data.extend_from_slice(&self.commitment.0);
+        data.extend_from_slice(&self.part);
+        self.commitment = sha256d(&data);
+        self.part.clear();
+    }
+}
+
+impl<W: Write> Write for Committed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.part.extend_from_slice(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Committed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.part.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn read_bytes<const N: usize>(reader: &mut dyn Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_string(reader: &mut dyn Read) -> Result<String, String> {
+    let [len] = read_bytes::<1>(reader).map_err(|err| err.to_string())?;
+    let mut bytes = vec![0; usize::from(len)];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(|err| err.to_string())?;
+    String::from_utf8(bytes).map_err(|_| "invalid string in snapshot".to_string())
+}
+
+fn write_string(writer: &mut dyn Write, value: &str) -> Result<(), String> {
+    let len = u8::try_from(value.len()).map_err(|_| format!("{value} is too long to store"))?;
+    writer.write_all(&[len]).map_err(|err| err.to_string())?;
+    writer
+        .write_all(value.as_bytes())
+        .map_err(|err| err.to_string())
+}
+
+fn write_entry(writer: &mut dyn Write, entry: &UtxoEntryDB) -> Result<(), String> {
+    let hash = Hash256::decode(&entry.hash)
+        .map_err(|err| format!("Invalid utxo hash {}: {err:?}", entry.hash))?;
+    let mut bytes = Vec::with_capacity(64);
+    bytes.push(ENTRY);
+    bytes.extend_from_slice(&hash.0);
+    bytes.extend_from_slice(&entry.pos.to_le_bytes());
+    bytes.extend_from_slice(&entry.satoshis.to_le_bytes());
+    bytes.extend_from_slice(&entry.height.to_le_bytes());
+    writer.write_all(&bytes).map_err(|err| err.to_string())?;
+    write_string(writer, &entry.pubkeyhash)
+}
+
+fn read_entry(reader: &mut dyn Read) -> Result<UtxoEntryDB, String> {
+    let read = |reader: &mut dyn Read| -> io::Result<(Hash256, u32, i64, i32)> {
+        Ok((
+            Hash256(read_bytes(reader)?),
+            u32::from_le_bytes(read_bytes(reader)?),
+            i64::from_le_bytes(read_bytes(reader)?),
+            i32::from_le_bytes(read_bytes(reader)?),
+        ))
+    };
+    let (hash, pos, satoshis, height) = read(reader).map_err(|err| err.to_string())?;
+    Ok(UtxoEntryDB {
+        hash: hash.encode(),
+        pos,
+        satoshis,
+        height,
+        pubkeyhash: read_string(reader)?,
+    })
+}
+
+/// Write the utxo set in storage to a snapshot file.
+///
+/// The entries and tip are read in one transaction, so this can run while the
+/// service is writing. The file is written alongside and renamed into place when complete.
+pub fn export(
+    storage: &mut dyn Storage,
+    network: &str,
+    path: &str,
+) -> Result<SnapshotInfo, String> {
+    let partial = format!("{path}.partial");
+    let result = write_snapshot(storage, network, &partial).and_then(|info| {
+        fs::rename(&partial, path)
+            .map(|_| info)
+            .map_err(|err| err.to_string())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result.map_err(|err| format!("Unable to export utxo snapshot to {path}: {err}"))
+}
+
+fn write_snapshot(
+    storage: &mut dyn Storage,
+    network: &str,
+    path: &str,
+) -> Result<SnapshotInfo, String> {
+    let file = File::create(path).map_err(|err| err.to_string())?;
+    let mut writer = Committed::new(BufWriter::new(file));
+    writer.write_all(MAGIC).map_err(|err| err.to_string())?;
+    writer
+        .write_all(&SNAPSHOT_VERSION.to_le_bytes())
+        .map_err(|err| err.to_string())?;
+    write_string(&mut writer, network)?;
+    writer.end_part();
+
+    let mut entries: u64 = 0;
+    let tip = storage.export_utxo(&mut |entry| {
+        write_entry(&mut writer, &entry)?;
+        writer.end_part();
+        entries += 1;
+        Ok(())
+    })?;
+    let Some((height, header)) = tip else {
+        return Err("there are no block headers in the database".to_string());
+    };
+    // Stored with the tip header, which is not changed once written
+    let utxo_commitment = match storage.utxo_commitment(height)? {
+        Some((hash, commitment)) if hash == header.hash() => Some(commitment),
+        _ => {
+            log::warn!("No utxo commitment stored for block {height}, none is exported");
+            None
+        }
+    };
+
+    let mut trailer = || -> io::Result<()> {
+        writer.write_all(&[END])?;
+        writer.write_all(&height.to_le_bytes())?;
+        header.write(&mut writer)?;
+        writer.write_all(&entries.to_le_bytes())?;
+        match &utxo_commitment {
+            Some(commitment) => {
+                writer.write_all(&[1])?;
+                writer.write_all(&commitment.0)
+            }
+            None => writer.write_all(&[0; 33]),
+        }
+    };
+    trailer().map_err(|err| err.to_string())?;
+    writer.end_part();
+    let commitment = writer.commitment;
+    writer
+        .inner
+        .write_all(&commitment.0)
+        .and_then(|_| writer.inner.into_inner().map_err(|err| err.into_error()))
+        .and_then(|file| file.sync_all())
+        .map_err(|err| err.to_string())?;
+
+    Ok(SnapshotInfo {
+        height,
+        tip: header.hash(),
+        entries,
+        commitment,
+        utxo_commitment,
+    })
+}
+
+// Read a snapshot, passing each entry to visit, and check it against its commitment.
+// Returns the tip header along with what the snapshot holds
+fn read_snapshot(
+    path: &str,
+    network: &str,
+    visit: &mut dyn FnMut(UtxoEntryDB) -> Result<(), String>,
+) -> Result<(SnapshotInfo, BlockHeader), String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut reader = Committed::new(BufReader::new(file));
+    let io_err = |err: io::Error| format!("snapshot is truncated or unreadable: {err}");
+
+    let magic = read_bytes::<8>(&mut reader).map_err(io_err)?;
+    if &magic != MAGIC {
+        return Err("not a utxo snapshot".to_string());
+    }
+    let version = u32::from_le_bytes(read_bytes(&mut reader).map_err(io_err)?);
+    if !(1..=SNAPSHOT_VERSION).contains(&version) {
+        return Err(format!(
+            "snapshot format version {version} is not supported (expected {SNAPSHOT_VERSION})"
+        ));
+    }
+    let snapshot_network = read_string(&mut reader)?;
+    if snapshot_network != network {
+        return Err(format!(
+            "snapshot is for network {snapshot_network}, not {network}"
+        ));
+    }
+    reader.end_part();
+
+    let mut entries: u64 = 0;
+    loop {
+        let [tag] = read_bytes::<1>(&mut reader).map_err(io_err)?;
+        match tag {
+            ENTRY => {
+                visit(read_entry(&mut reader)?)?;
+                reader.end_part();
+                entries += 1;
+            }
+            END => break,
+            _ => return Err(format!("unexpected record type {tag} in snapshot")),
+        }
+    }
+
+    let height = u32::from_le_bytes(read_bytes(&mut reader).map_err(io_err)?);
+    let header = BlockHeader::read(&mut reader).map_err(|err| format!("{err:?}"))?;
+    let count = u64::from_le_bytes(read_bytes(&mut reader).map_err(io_err)?);
+    let utxo_commitment = if version >= 2 {
+        let [present] = read_bytes::<1>(&mut reader).map_err(io_err)?;
+        let commitment = Hash256(read_bytes(&mut reader).map_err(io_err)?);
+        (present == 1).then_some(commitment)
+    } else {
+        None
+    };
+    reader.end_part();
+    let commitment = Hash256(read_bytes(&mut reader.inner).map_err(io_err)?);
+    if commitment != reader.commitment {
+        return Err("snapshot commitment does not match its contents".to_string());
+    }
+    if count != entries {
+        return Err(format!(
+            "snapshot records {count} entries but holds {entries}"
+        ));
+    }
+    if reader.inner.read(&mut [0]).map_err(io_err)? != 0 {
+        return Err("unexpected data after the snapshot commitment".to_string());
+    }
+
+    let info = SnapshotInfo {
+        height,
+        tip: header.hash(),
+        entries,
+        commitment,
+        utxo_commitment,
+    };
+    Ok((info, header))
+}
+
+/// Check a snapshot file without loading it.
+pub fn verify(path: &str, network: &str) -> Result<SnapshotInfo, String> {
+    read_snapshot(path, network, &mut |_entry| Ok(()))
+        .map(|(info, _header)| info)
+        .map_err(|err| format!("Invalid utxo snapshot {path}: {err}"))
+}
+
+/// Load a snapshot into an empty store.
+///
+/// The snapshot is verified before anything is written. The tip header is written
+/// last, so that an interrupted import does not look like a complete one.
+pub fn import(
+    storage: &mut dyn Storage,
+    network: &str,
+    path: &str,
+) -> Result<SnapshotInfo, String> {
+    let expected = verify(path, network)?;
+
+    if !storage.load_block_headers()?.is_empty() || storage.max_utxo_height()?.is_some() {
+        return Err("A utxo snapshot can only be imported into an empty database".to_string());
+    }
+
+    let mut batch = Vec::with_capacity(IMPORT_BATCH);
+    let (info, header) = read_snapshot(path, network, &mut |entry| {
+        batch.push(entry);
+        if batch.len() == IMPORT_BATCH {
+            storage.utxo_batch_write(&batch)?;
+            batch.clear();
+        }
+        Ok(())
+    })
+    .map_err(|err| format!("Unable to import utxo snapshot {path}: {err}"))?;
+    if info != expected {
+        return Err(format!(
+            "Utxo snapshot {path} changed while it was imported"
+        ));
+    }
+    if !batch.is_empty() {
+        storage.utxo_batch_write(&batch)?;
+    }
+
+    // The tip block is not in the block store
+    storage.block_header_write(&BlockHeaderWriteDB {
+        height: info.height,
+        hash: info.tip,
+        version: header.version,
+        prev_hash: header.prev_hash,
+        merkle_root: header.merkle_root,
+        timestamp: header.timestamp,
+        bits: header.bits,
+        nonce: header.nonce,
+        position: 0,
+        blocksize: 0,
+        numtxs: 0,
+        utxo_commitment: info.utxo_commitment,
+    })?;
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uaas::storage::{MemoryBackend, StorageBackend};
+
+    fn entry(value: u8, height: i32) -> UtxoEntryDB {
+        UtxoEntryDB {
+            hash: Hash256([value; 32]).encode(),
+            pos: u32::from(value),
+            satoshis: i64::from(value) * 1000,
+            height,
+            pubkeyhash: format!("{value:040x}"),
+        }
+    }
+
+    fn source() -> MemoryBackend {
+        let backend = MemoryBackend::new();
+        let mut storage = backend.open("test").unwrap();
+        let entries: Vec<UtxoEntryDB> = (1..=5).map(|value| entry(value, 100)).collect();
+        storage.utxo_batch_write(&entries).unwrap();
+        // A mempool output
+        storage.utxo_batch_write(&[entry(6, -1)]).unwrap();
+        let header = BlockHeader {
+            version: 1,
+            nonce: 7,
+            ..Default::default()
+        };
+        storage
+            .block_header_write(&BlockHeaderWriteDB {
+                height: 100,
+                hash: header.hash(),
+                version: header.version,
+                nonce: header.nonce,
+                utxo_commitment: Some(Hash256([9; 32])),
+                ..Default::default()
+            })
+            .unwrap();
+        backend
+    }
+
+    fn snapshot_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("uaas_snapshot_{name}_{}.dat", std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let source = source();
+        let path = snapshot_path("round_trip");
+        let exported = export(source.open("export").unwrap().as_mut(), "testnet", &path).unwrap();
+        assert_eq!((exported.height, exported.entries), (100, 6));
+        assert_eq!(exported.utxo_commitment, Some(Hash256([9; 32])));
+        assert_eq!(verify(&path, "testnet").unwrap(), exported);
+        assert!(verify(&path, "mainnet").is_err());
+
+        let target = MemoryBackend::new();
+        let imported = import(target.open("import").unwrap().as_mut(), "testnet", &path).unwrap();
+        assert_eq!(imported, exported);
+        assert_eq!(target.data().utxo, source.data().utxo);
+        let headers = target.open("check").unwrap().load_block_headers().unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!((headers[0].0, headers[0].1.hash()), (100, exported.tip));
+        let mut storage = target.open("check").unwrap();
+        assert_eq!(
+            storage.utxo_commitment(100).unwrap(),
+            Some((exported.tip, Hash256([9; 32])))
+        );
+
+        // Only into an empty database
+        assert!(import(target.open("import").unwrap().as_mut(), "testnet", &path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn altered_or_truncated_snapshot_is_rejected() {
+        let path = snapshot_path("altered");
+        export(source().open("export").unwrap().as_mut(), "testnet", &path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        let mut altered = bytes.clone();
+        altered[40] ^= 1;
+        fs::write(&path, &altered).unwrap();
+        assert!(verify(&path, "testnet").is_err());
+
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(verify(&path, "testnet").is_err());
+
+        // Nothing is written from an invalid snapshot
+        let target = MemoryBackend::new();
+        assert!(import(target.open("import").unwrap().as_mut(), "testnet", &path).is_err());
+        assert!(target.data().utxo.is_empty());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,1111 @@
+use std::{fs, path::Path, sync::Arc};
+
+use chain_gang::{
+    messages::{BlockHeader, OutPoint},
+    util::Hash256,
+};
+use redb::{Database, ReadTransaction, ReadableTable, TableDefinition, WriteTransaction};
+
+use super::{Storage, StorageBackend};
+use crate::uaas::{
+    database::{
+        BlockHeaderWriteDB, BlockWriteDB, MempoolEntryDB, OrphanBlockHeaderWriteDB, TxEntryWriteDB,
+        UtxoEntryDB,
+    },
+    monitor::MonitorKey,
+    schema::{pending, Migration},
+};
+
+// Hashes are stored as raw bytes, heights and indexes as big endian so that keys sort by number
+const BLOCKS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("blocks");
+// height -> block hash, for the main chain
+const BLOCK_HEIGHTS: TableDefinition<u32, &[u8]> = TableDefinition::new("block_heights");
+const ORPHANS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("orphans");
+// tx hash + output index -> utxo entry
+const UTXO: TableDefinition<&[u8], &[u8]> = TableDefinition::new("utxo");
+// height + tx hash + output index, to find the utxo of a block
+const UTXO_HEIGHTS: TableDefinition<&[u8], ()> = TableDefinition::new("utxo_heights");
+const TX: TableDefinition<&[u8], &[u8]> = TableDefinition::new("tx");
+// height + tx hash, to find the txs of a block
+const TX_HEIGHTS: TableDefinition<&[u8], ()> = TableDefinition::new("tx_heights");
+const MEMPOOL: TableDefinition<&[u8], &[u8]> = TableDefinition::new("mempool");
+// monitor owner + 0 + monitor name + 0 + tx hash -> tx hex, the owner is empty for the operator
+const COLLECTION: TableDefinition<&[u8], &[u8]> = TableDefinition::new("collection");
+const ADDR: TableDefinition<&str, &[u8]> = TableDefinition::new("addr");
+const CONNECT: TableDefinition<u64, &[u8]> = TableDefinition::new("connect");
+// Holds the schema version
+const META: TableDefinition<&str, u32> = TableDefinition::new("meta");
+const SCHEMA_VERSION: &str = "schema_version";
+
+type EmbeddedMigration = Migration<fn(&WriteTransaction) -> Result<(), EmbeddedError>>;
+
+const MIGRATIONS: &[EmbeddedMigration] = &[
+    Migration {
+        version: 1,
+        description: "create tables",
+        apply: create_tables,
+    },
+    Migration {
+        version: 2,
+        description: "add collection owner",
+        apply: add_collection_owner,
+    },
+];
+
+// redb errors are boxed as they are large
+struct EmbeddedError(Box<redb::Error>);
+
+macro_rules! embedded_error_from {
+    ($($err:ty),*) => {
+        $(impl From<$err> for EmbeddedError {
+            fn from(err: $err) -> Self {
+                EmbeddedError(Box::new(err.into()))
+            }
+        })*
+    };
+}
+
+embedded_error_from!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+impl EmbeddedError {
+    fn into_string(self) -> String {
+        self.0.to_string()
+    }
+}
+
+fn corrupted() -> EmbeddedError {
+    redb::Error::Corrupted("invalid stored value".to_string()).into()
+}
+
+// Little endian encoding of stored values
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn i32(mut self, value: i32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn i64(mut self, value: i64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn hash(mut self, hash: &Hash256) -> Self {
+        self.0.extend_from_slice(&hash.0);
+        self
+    }
+
+    fn str(self, value: &str) -> Self {
+        let mut encoder = self.u32(value.len() as u32);
+        encoder.0.extend_from_slice(value.as_bytes());
+        encoder
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], EmbeddedError> {
+        if self.0.len() < N {
+            return Err(corrupted());
+        }
+        let (head, rest) = self.0.split_at(N);
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(head);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, EmbeddedError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, EmbeddedError> {
+        Ok(i32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, EmbeddedError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, EmbeddedError> {
+        Ok(i64::from_le_bytes(self.take()?))
+    }
+
+    fn hash(&mut self) -> Result<Hash256, EmbeddedError> {
+        Ok(Hash256(self.take()?))
+    }
+
+    fn string(&mut self) -> Result<String, EmbeddedError> {
+        let len = self.u32()? as usize;
+        if self.0.len() < len {
+            return Err(corrupted());
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        String::from_utf8(head.to_vec()).map_err(|_| corrupted())
+    }
+}
+
+fn outpoint_key(hash: &Hash256, index: u32) -> Vec<u8> {
+    let mut key = hash.0.to_vec();
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+fn height_key(height: u32, id: &[u8]) -> Vec<u8> {
+    let mut key = height.to_be_bytes().to_vec();
+    key.extend_from_slice(id);
+    key
+}
+
+fn collection_prefix(key: &MonitorKey) -> Vec<u8> {
+    let mut prefix = key.stored_owner().as_bytes().to_vec();
+    prefix.push(0);
+    prefix.extend_from_slice(key.name.as_bytes());
+    prefix.push(0);
+    prefix
+}
+
+fn decode_utxo(key: &[u8], value: &[u8]) -> Result<UtxoEntryDB, EmbeddedError> {
+    let mut key = Decoder(key);
+    let hash = key.hash()?;
+    let pos = u32::from_be_bytes(key.take()?);
+    let mut value = Decoder(value);
+    Ok(UtxoEntryDB {
+        hash: hash.encode(),
+        pos,
+        satoshis: value.i64()?,
+        height: value.i32()?,
+        pubkeyhash: value.string()?,
+    })
+}
+
+fn decode_header(value: &[u8]) -> Result<(BlockHeaderWriteDB, BlockHeader), EmbeddedError> {
+    let mut value = Decoder(value);
+    let height = value.u32()?;
+    let header = BlockHeader {
+        version: value.u32()?,
+        prev_hash: value.hash()?,
+        merkle_root: value.hash()?,
+        timestamp: value.u32()?,
+        bits: value.u32()?,
+        nonce: value.u32()?,
+    };
+    let record = BlockHeaderWriteDB {
+        height,
+        hash: header.hash(),
+        version: header.version,
+        prev_hash: header.prev_hash,
+        merkle_root: header.merkle_root,
+        timestamp: header.timestamp,
+        bits: header.bits,
+        nonce: header.nonce,
+        position: value.u64()?,
+        blocksize: value.u32()?,
+        numtxs: value.u32()?,
+        utxo_commitment: if value.0.is_empty() {
+            None
+        } else {
+            Some(value.hash()?)
+        },
+    };
+    Ok((record, header))
+}
+
+// Keys of a table that start with the prefix, in order
+fn keys_with_prefix<V: redb::Value + 'static>(
+    table: &impl ReadableTable<&'static [u8], V>,
+    prefix: &[u8],
+) -> Result<Vec<Vec<u8>>, EmbeddedError> {
+    let mut keys = Vec::new();
+    for item in table.range::<&[u8]>(prefix..)? {
+        let (key, _value) = item?;
+        if !key.value().starts_with(prefix) {
+            break;
+        }
+        keys.push(key.value().to_vec());
+    }
+    Ok(keys)
+}
+
+fn create_tables(txn: &WriteTransaction) -> Result<(), EmbeddedError> {
+    txn.open_table(BLOCKS)?;
+    txn.open_table(BLOCK_HEIGHTS)?;
+    txn.open_table(ORPHANS)?;
+    txn.open_table(UTXO)?;
+    txn.open_table(UTXO_HEIGHTS)?;
+    txn.open_table(TX)?;
+    txn.open_table(TX_HEIGHTS)?;
+    txn.open_table(MEMPOOL)?;
+    txn.open_table(COLLECTION)?;
+    txn.open_table(ADDR)?;
+    txn.open_table(CONNECT)?;
+    Ok(())
+}
+
+// Monitors are named within their owner, the existing collection txs belong to the operator
+fn add_collection_owner(txn: &WriteTransaction) -> Result<(), EmbeddedError> {
+    let mut collection = txn.open_table(COLLECTION)?;
+    let mut entries = Vec::new();
+    for item in collection.iter()? {
+        let (key, value) = item?;
+        entries.push((key.value().to_vec(), value.value().to_vec()));
+    }
+    for (key, value) in entries {
+        collection.remove(key.as_slice())?;
+        let mut owned = vec![0];
+        owned.extend_from_slice(&key);
+        collection.insert(owned.as_slice(), value.as_slice())?;
+    }
+    Ok(())
+}
+
+fn write_utxo(txn: &WriteTransaction, entries: &[UtxoEntryDB]) -> Result<(), EmbeddedError> {
+    let mut utxo = txn.open_table(UTXO)?;
+    let mut heights = txn.open_table(UTXO_HEIGHTS)?;
+    for entry in entries {
+        let Ok(hash) = Hash256::decode(&entry.hash) else {
+            log::error!("Skipping utxo with invalid hash {}", entry.hash);
+            continue;
+        };
+        let key = outpoint_key(&hash, entry.pos);
+        let value = Encoder::default()
+            .i64(entry.satoshis)
+            .i32(entry.height)
+            .str(&entry.pubkeyhash)
+            .finish();
+        let previous = utxo
+            .insert(key.as_slice(), value.as_slice())?
+            .map(|previous| previous.value().to_vec());
+        // Mempool outputs are written again with the height of the block that mines them
+        if let Some(previous) = previous {
+            let previous = decode_utxo(&key, &previous)?;
+            heights.remove(height_key(previous.height as u32, &key).as_slice())?;
+        }
+        heights.insert(height_key(entry.height as u32, &key).as_slice(), ())?;
+    }
+    Ok(())
+}
+
+fn delete_utxo(txn: &WriteTransaction, outpoints: &[OutPoint]) -> Result<(), EmbeddedError> {
+    let mut utxo = txn.open_table(UTXO)?;
+    let mut heights = txn.open_table(UTXO_HEIGHTS)?;
+    for outpoint in outpoints {
+        let key = outpoint_key(&outpoint.hash, outpoint.index);
+        let removed = utxo
+            .remove(key.as_slice())?
+            .map(|removed| removed.value().to_vec());
+        if let Some(removed) = removed {
+            let removed = decode_utxo(&key, &removed)?;
+            heights.remove(height_key(removed.height as u32, &key).as_slice())?;
+        }
+    }
+    Ok(())
+}
+
+fn write_tx(txn: &WriteTransaction, entries: &[TxEntryWriteDB]) -> Result<(), EmbeddedError> {
+    let mut tx = txn.open_table(TX)?;
+    let mut heights = txn.open_table(TX_HEIGHTS)?;
+    for entry in entries {
+        let height = entry.height as u32;
+        let value = Encoder::default()
+            .u32(height)
+            .u32(entry.blockindex)
+            .u32(entry.size)
+            .u64(entry.satoshis)
+            .finish();
+        let previous = tx
+            .insert(entry.hash.0.as_slice(), value.as_slice())?
+            .map(|previous| previous.value().to_vec());
+        if let Some(previous) = previous {
+            let previous_height = Decoder(&previous).u32()?;
+            heights.remove(height_key(previous_height, &entry.hash.0).as_slice())?;
+        }
+        heights.insert(height_key(height, &entry.hash.0).as_slice(), ())?;
+    }
+    Ok(())
+}
+
+fn delete_mempool(txn: &WriteTransaction, hashes: &[Hash256]) -> Result<(), EmbeddedError> {
+    let mut mempool = txn.open_table(MEMPOOL)?;
+    for hash in hashes {
+        mempool.remove(hash.0.as_slice())?;
+    }
+    Ok(())
+}
+
+fn write_header(txn: &WriteTransaction, header: &BlockHeaderWriteDB) -> Result<(), EmbeddedError> {
+    let value = Encoder::default()
+        .u32(header.height)
+        .u32(header.version)
+        .hash(&header.prev_hash)
+        .hash(&header.merkle_root)
+        .u32(header.timestamp)
+        .u32(header.bits)
+        .u32(header.nonce)
+        .u64(header.position)
+        .u32(header.blocksize)
+        .u32(header.numtxs);
+    // Headers written before utxo commitments were added end here
+    let value = match &header.utxo_commitment {
+        Some(commitment) => value.hash(commitment),
+        None => value,
+    }
+    .finish();
+    txn.open_table(BLOCKS)?
+        .insert(header.hash.0.as_slice(), value.as_slice())?;
+    txn.open_table(BLOCK_HEIGHTS)?
+        .insert(header.height, header.hash.0.as_slice())?;
+    Ok(())
+}
+
+/// Stores everything in a single file with redb, so that no database server is required.
+///
+/// Writes are transactional, the tx table is read on demand rather than loaded at startup.
+#[derive(Clone)]
+pub struct EmbeddedBackend {
+    db: Arc<Database>,
+}
+
+impl EmbeddedBackend {
+    pub fn open(path: &str) -> Result<Self, String> {
+        if let Some(parent) = Path::new(path).parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)
+                    .map_err(|err| format!("Unable to create directory for {path}: {err}"))?;
+            }
+        }
+        let db = Database::create(path)
+            .map_err(|err| format!("Unable to open embedded database {path}: {err}"))?;
+        log::info!("Using embedded database {path}");
+        Ok(EmbeddedBackend { db: Arc::new(db) })
+    }
+
+    // Run the operation in a write transaction, which is committed if it succeeds
+    fn write<F>(&self, operation: F) -> Result<(), String>
+    where
+        F: FnOnce(&WriteTransaction) -> Result<(), EmbeddedError>,
+    {
+        let run = || -> Result<(), EmbeddedError> {
+            let txn = self.db.begin_write()?;
+            operation(&txn)?;
+            txn.commit()?;
+            Ok(())
+        };
+        run().map_err(EmbeddedError::into_string)
+    }
+
+    fn read<T, F>(&self, operation: F) -> Result<T, String>
+    where
+        F: FnOnce(&ReadTransaction) -> Result<T, EmbeddedError>,
+    {
+        let run = || -> Result<T, EmbeddedError> {
+            let txn = self.db.begin_read()?;
+            operation(&txn)
+        };
+        run().map_err(EmbeddedError::into_string)
+    }
+}
+
+impl StorageBackend for EmbeddedBackend {
+    fn open(&self, _label: &str) -> Result<Box<dyn Storage>, String> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+impl Storage for EmbeddedBackend {
+    fn migrate(&mut self) -> Result<(u32, u32), String> {
+        let current = self
+            .write(|txn| {
+                txn.open_table(META)?;
+                Ok(())
+            })
+            .and_then(|_| {
+                self.read(|txn| {
+                    Ok(txn
+                        .open_table(META)?
+                        .get(SCHEMA_VERSION)?
+                        .map_or(0, |version| version.value()))
+                })
+            })
+            .map_err(|err| format!("Unable to read schema version: {err}"))?;
+        let mut version = current;
+        for migration in pending(MIGRATIONS, current)? {
+            log::info!(
+                "Applying database migration {}: {}",
+                migration.version,
+                migration.description
+            );
+            // The migration and its version are committed together
+            self.write(|txn| {
+                (migration.apply)(txn)?;
+                txn.open_table(META)?
+                    .insert(SCHEMA_VERSION, migration.version)?;
+                Ok(())
+            })
+            .map_err(|err| {
+                format!(
+                    "Database migration {} ({}) failed: {err}",
+                    migration.version, migration.description
+                )
+            })?;
+            version = migration.version;
+        }
+        Ok((current, version))
+    }
+
+    fn ping(&mut self) -> Result<(), String> {
+        self.read(|_txn| Ok(()))
+    }
+
+    fn utxo_batch_write(&mut self, entries: &[UtxoEntryDB]) -> Result<(), String> {
+        self.write(|txn| write_utxo(txn, entries))
+    }
+
+    fn utxo_batch_delete(&mut self, outpoints: &[OutPoint]) -> Result<(), String> {
+        self.write(|txn| delete_utxo(txn, outpoints))
+    }
+
+    fn tx_batch_write(&mut self, entries: &[TxEntryWriteDB]) -> Result<(), String> {
+        self.write(|txn| write_tx(txn, entries))
+    }
+
+    fn mempool_batch_write(&mut self, entries: &[MempoolEntryDB]) -> Result<(), String> {
+        self.write(|txn| {
+            let mut mempool = txn.open_table(MEMPOOL)?;
+            for entry in entries {
+                let value = Encoder::default()
+                    .u32(entry.locktime)
+                    .i64(entry.fee)
+                    .u64(entry.age)
+                    .str(&entry.tx)
+                    .finish();
+                mempool.insert(entry.hash.0.as_slice(), value.as_slice())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn mempool_batch_delete(&mut self, hashes: &[Hash256]) -> Result<(), String> {
+        self.write(|txn| delete_mempool(txn, hashes))
+    }
+
+    fn block_header_write(&mut self, header: &BlockHeaderWriteDB) -> Result<(), String> {
+        self.write(|txn| write_header(txn, header))
+    }
+
+    fn orphan_block_header_write(
+        &mut self,
+        header: &OrphanBlockHeaderWriteDB,
+    ) -> Result<(), String> {
+        let value = Encoder::default()
+            .u32(header.height)
+            .u32(header.version)
+            .hash(&header.prev_hash)
+            .hash(&header.merkle_root)
+            .u32(header.timestamp)
+            .u32(header.bits)
+            .u32(header.nonce)
+            .finish();
+        self.write(|txn| {
+            txn.open_table(ORPHANS)?
+                .insert(header.hash.0.as_slice(), value.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn block_header_delete(&mut self, hash: &Hash256) -> Result<(), String> {
+        self.write(|txn| {
+            let removed = txn
+                .open_table(BLOCKS)?
+                .remove(hash.0.as_slice())?
+                .map(|removed| removed.value().to_vec());
+            let Some(removed) = removed else {
+                return Ok(());
+            };
+            let height = Decoder(&removed).u32()?;
+            let mut heights = txn.open_table(BLOCK_HEIGHTS)?;
+            let at_height = heights
+                .get(height)?
+                .map(|stored| stored.value() == hash.0.as_slice());
+            if at_height == Some(true) {
+                heights.remove(height)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn block_positions_write(&mut self, positions: &[(Hash256, u64)]) -> Result<(), String> {
+        self.write(|txn| {
+            for (hash, position) in positions.iter() {
+                let stored = txn
+                    .open_table(BLOCKS)?
+                    .get(hash.0.as_slice())?
+                    .map(|value| value.value().to_vec());
+                if let Some(stored) = stored {
+                    let (record, _header) = decode_header(&stored)?;
+                    let record = BlockHeaderWriteDB {
+                        position: *position,
+                        ..record
+                    };
+                    write_header(txn, &record)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn tx_delete_at_height(&mut self, height: u32) -> Result<(), String> {
+        self.write(|txn| {
+            let mut tx = txn.open_table(TX)?;
+            let mut heights = txn.open_table(TX_HEIGHTS)?;
+            for key in keys_with_prefix(&heights, &height.to_be_bytes())? {
+                heights.remove(key.as_slice())?;
+                tx.remove(&key[4..])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn utxo_delete_at_height(&mut self, height: u32) -> Result<(), String> {
+        self.write(|txn| {
+            let mut utxo = txn.open_table(UTXO)?;
+            let mut heights = txn.open_table(UTXO_HEIGHTS)?;
+            for key in keys_with_prefix(&heights, &height.to_be_bytes())? {
+                heights.remove(key.as_slice())?;
+                utxo.remove(&key[4..])?;
+            }
+            Ok(())
+        })
+    }
+
+    // All the writes of the blocks are committed in one transaction
+    fn block_write(&mut self, block: &BlockWriteDB) -> Result<(), String> {
+        self.write(|txn| {
+            write_utxo(txn, &block.utxo_writes)?;
+            delete_utxo(txn, &block.utxo_deletes)?;
+            write_tx(txn, &block.tx_writes)?;
+            delete_mempool(txn, &block.mempool_deletes)?;
+            for header in block.headers.iter() {
+                write_header(txn, header)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn loads_on_demand(&self) -> bool {
+        true
+    }
+
+    fn get_utxo(&mut self, outpoint: &OutPoint) -> Result<Option<UtxoEntryDB>, String> {
+        let key = outpoint_key(&outpoint.hash, outpoint.index);
+        self.read(|txn| {
+            let utxo = txn.open_table(UTXO)?;
+            let value = utxo.get(key.as_slice())?;
+            value
+                .map(|value| decode_utxo(&key, value.value()))
+                .transpose()
+        })
+    }
+
+    fn tx_height(&mut self, hash: &Hash256) -> Result<Option<u32>, String> {
+        self.read(|txn| {
+            let tx = txn.open_table(TX)?;
+            let value = tx.get(hash.0.as_slice())?;
+            value.map(|value| Decoder(value.value()).u32()).transpose()
+        })
+    }
+
+    fn tx_location(&mut self, hash: &Hash256) -> Result<Option<(u64, u32)>, String> {
+        self.read(|txn| {
+            let Some(value) = txn.open_table(TX)?.get(hash.0.as_slice())? else {
+                return Ok(None);
+            };
+            let mut value = Decoder(value.value());
+            let (height, blockindex) = (value.u32()?, value.u32()?);
+            let Some(block) = txn.open_table(BLOCK_HEIGHTS)?.get(height)? else {
+                return Ok(None);
+            };
+            let Some(header) = txn.open_table(BLOCKS)?.get(block.value())? else {
+                return Ok(None);
+            };
+            let (record, _header) = decode_header(header.value())?;
+            Ok(Some((record.position, blockindex)))
+        })
+    }
+
+    fn mempool_tx(&mut self, hash: &Hash256) -> Result<Option<String>, String> {
+        self.read(|txn| {
+            let Some(value) = txn.open_table(MEMPOOL)?.get(hash.0.as_slice())? else {
+                return Ok(None);
+            };
+            let mut value = Decoder(value.value());
+            let _locktime = value.u32()?;
+            let _fee = value.i64()?;
+            let _age = value.u64()?;
+            Ok(Some(value.string()?))
+        })
+    }
+
+    fn max_tx_height(&mut self) -> Result<Option<u32>, String> {
+        self.read(|txn| {
+            let heights = txn.open_table(TX_HEIGHTS)?;
+            let Some((key, _value)) = heights.last()? else {
+                return Ok(None);
+            };
+            Ok(Some(u32::from_be_bytes(Decoder(key.value()).take()?)))
+        })
+    }
+
+    fn max_utxo_height(&mut self) -> Result<Option<u32>, String> {
+        // Mempool utxo have a negative height, which sorts after the block heights
+        let negative = (i32::MIN as u32).to_be_bytes();
+        self.read(|txn| {
+            let heights = txn.open_table(UTXO_HEIGHTS)?;
+            let Some(item) = heights.range::<&[u8]>(..negative.as_slice())?.next_back() else {
+                return Ok(None);
+            };
+            let (key, _value) = item?;
+            Ok(Some(u32::from_be_bytes(Decoder(key.value()).take()?)))
+        })
+    }
+
+    fn orphan_exists(&mut self, hash: &Hash256) -> Result<bool, String> {
+        self.read(|txn| Ok(txn.open_table(ORPHANS)?.get(hash.0.as_slice())?.is_some()))
+    }
+
+    fn load_mempool(&mut self) -> Result<Vec<Hash256>, String> {
+        self.read(|txn| {
+            let mut entries = Vec::new();
+            for item in txn.open_table(MEMPOOL)?.iter()? {
+                let (key, value) = item?;
+                let hash = Decoder(key.value()).hash()?;
+                let mut value = Decoder(value.value());
+                let _locktime = value.u32()?;
+                let _fee = value.i64()?;
+                entries.push((value.u64()?, hash));
+            }
+            // Oldest first
+            entries.sort_by_key(|(age, _hash)| *age);
+            Ok(entries.into_iter().map(|(_age, hash)| hash).collect())
+        })
+    }
+
+    fn load_tx(&mut self) -> Result<Vec<(Hash256, u32)>, String> {
+        self.read(|txn| {
+            let mut txs = Vec::new();
+            for item in txn.open_table(TX_HEIGHTS)?.iter()? {
+                let (key, _value) = item?;
+                let mut key = Decoder(key.value());
+                let height = u32::from_be_bytes(key.take()?);
+                txs.push((key.hash()?, height));
+            }
+            Ok(txs)
+        })
+    }
+
+    fn load_block_headers(&mut self) -> Result<Vec<(u32, BlockHeader)>, String> {
+        self.read(|txn| {
+            let blocks = txn.open_table(BLOCKS)?;
+            let mut headers = Vec::new();
+            for item in txn.open_table(BLOCK_HEIGHTS)?.iter()? {
+                let (height, hash) = item?;
+                let Some(value) = blocks.get(hash.value())? else {
+                    return Err(corrupted());
+                };
+                let (_record, header) = decode_header(value.value())?;
+                headers.push((height.value(), header));
+            }
+            Ok(headers)
+        })
+    }
+
+    fn export_utxo(
+        &mut self,
+        visit: &mut dyn FnMut(UtxoEntryDB) -> Result<(), String>,
+    ) -> Result<Option<(u32, BlockHeader)>, String> {
+        let mut failed = None;
+        let tip = self.read(|txn| {
+            let tip = match txn.open_table(BLOCK_HEIGHTS)?.last()? {
+                Some((height, hash)) => {
+                    let Some(value) = txn.open_table(BLOCKS)?.get(hash.value())? else {
+                        return Err(corrupted());
+                    };
+                    let (_record, header) = decode_header(value.value())?;
+                    Some((height.value(), header))
+                }
+                None => None,
+            };
+            for item in txn.open_table(UTXO)?.iter()? {
+                let (key, value) = item?;
+                if let Err(err) = visit(decode_utxo(key.value(), value.value())?) {
+                    failed = Some(err);
+                    break;
+                }
+            }
+            Ok(tip)
+        })?;
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(tip),
+        }
+    }
+
+    fn utxo_commitment(&mut self, height: u32) -> Result<Option<(Hash256, Hash256)>, String> {
+        self.read(|txn| {
+            let Some(hash) = txn.open_table(BLOCK_HEIGHTS)?.get(height)? else {
+                return Ok(None);
+            };
+            let Some(value) = txn.open_table(BLOCKS)?.get(hash.value())? else {
+                return Ok(None);
+            };
+            let (record, _header) = decode_header(value.value())?;
+            Ok(record
+                .utxo_commitment
+                .map(|commitment| (record.hash, commitment)))
+        })
+    }
+
+    fn collection_tx_write(
+        &mut self,
+        monitor: &MonitorKey,
+        hash: &Hash256,
+        tx_hex: &str,
+    ) -> Result<(), String> {
+        let mut key = collection_prefix(monitor);
+        key.extend_from_slice(&hash.0);
+        self.write(|txn| {
+            let mut collection = txn.open_table(COLLECTION)?;
+            // Keep the first write, e.g. if a backfill finds the same tx
+            if collection.get(key.as_slice())?.is_none() {
+                collection.insert(key.as_slice(), tx_hex.as_bytes())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn collection_delete(&mut self, key: &MonitorKey) -> Result<(), String> {
+        let prefix = collection_prefix(key);
+        self.write(|txn| {
+            let mut collection = txn.open_table(COLLECTION)?;
+            for key in keys_with_prefix(&collection, &prefix)? {
+                collection.remove(key.as_slice())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn load_collection_txs(&mut self, key: &MonitorKey) -> Result<Vec<Hash256>, String> {
+        let prefix = collection_prefix(key);
+        self.read(|txn| {
+            let collection = txn.open_table(COLLECTION)?;
+            keys_with_prefix(&collection, &prefix)?
+                .iter()
+                .map(|key| Decoder(&key[prefix.len()..]).hash())
+                .collect()
+        })
+    }
+
+    fn load_addresses(&mut self) -> Result<Vec<String>, String> {
+        self.read(|txn| {
+            let mut addresses = Vec::new();
+            for item in txn.open_table(ADDR)?.iter()? {
+                let (ip, _value) = item?;
+                addresses.push(ip.value().to_string());
+            }
+            Ok(addresses)
+        })
+    }
+
+    fn addr_write(&mut self, ip: &str, services: u64, port: u16) -> Result<(), String> {
+        let value = Encoder::default()
+            .u64(services)
+            .u32(u32::from(port))
+            .finish();
+        self.write(|txn| {
+            txn.open_table(ADDR)?.insert(ip, value.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn connect_event_write(&mut self, date: &str, ip: &str, event: &str) -> Result<(), String> {
+        let value = Encoder::default().str(date).str(ip).str(event).finish();
+        self.write(|txn| {
+            let mut connect = txn.open_table(CONNECT)?;
+            let next = connect
+                .last()?
+                .map(|(key, _value)| key.value() + 1)
+                .unwrap_or(0);
+            connect.insert(next, value.as_slice())?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "uaas_{name}_{}_{}.redb",
+                std::process::id(),
+                hex::encode(rand::random::<[u8; 4]>())
+            ));
+            TempFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().expect("temp path")
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn utxo_entry(hash: &Hash256, pos: u32, height: i32) -> UtxoEntryDB {
+        UtxoEntryDB {
+            hash: hash.encode(),
+            pos,
+            satoshis: 1000 + i64::from(pos),
+            height,
+            pubkeyhash: "abcd".to_string(),
+        }
+    }
+
+    fn header(height: u32, prev_hash: Hash256) -> BlockHeaderWriteDB {
+        let header = BlockHeader {
+            version: 1,
+            prev_hash,
+            merkle_root: Hash256([height as u8; 32]),
+            timestamp: 1_700_000_000 + height,
+            bits: 0x1d00ffff,
+            nonce: height,
+        };
+        BlockHeaderWriteDB {
+            height,
+            hash: header.hash(),
+            version: header.version,
+            prev_hash,
+            merkle_root: header.merkle_root,
+            timestamp: header.timestamp,
+            bits: header.bits,
+            nonce: header.nonce,
+            position: u64::from(height) * 1000,
+            blocksize: 1000,
+            numtxs: 1,
+            utxo_commitment: None,
+        }
+    }
+
+    #[test]
+    fn block_writes_persist_across_reopen() {
+        let file = TempFile::new("embedded_blocks");
+        let mined = Hash256([1; 32]);
+        let first = header(10, Hash256::default());
+        let second = header(11, first.hash);
+        {
+            let mut storage = EmbeddedBackend::open(file.path()).expect("open");
+            assert_eq!(storage.migrate(), Ok((0, 2)));
+            storage
+                .utxo_batch_write(&[utxo_entry(&mined, 0, -1)])
+                .expect("mempool utxo");
+            storage
+                .block_write(&BlockWriteDB {
+                    utxo_writes: vec![utxo_entry(&mined, 0, 10), utxo_entry(&mined, 1, 10)],
+                    tx_writes: vec![TxEntryWriteDB {
+                        hash: mined,
+                        height: 10,
+                        blockindex: 0,
+                        size: 250,
+                        satoshis: 2001,
+                    }],
+                    headers: vec![first.clone()],
+                    ..Default::default()
+                })
+                .expect("block 10");
+            storage
+                .block_write(&BlockWriteDB {
+                    utxo_deletes: vec![OutPoint {
+                        hash: mined,
+                        index: 1,
+                    }],
+                    headers: vec![second.clone()],
+                    ..Default::default()
+                })
+                .expect("block 11");
+        }
+
+        let mut storage = EmbeddedBackend::open(file.path()).expect("reopen");
+        assert_eq!(storage.migrate(), Ok((2, 2)));
+        let headers = storage.load_block_headers().expect("headers");
+        assert_eq!(
+            headers
+                .iter()
+                .map(|(height, _)| *height)
+                .collect::<Vec<_>>(),
+            vec![10, 11]
+        );
+        assert_eq!(headers[1].1.hash(), second.hash);
+        let position = |storage: &EmbeddedBackend| {
+            storage.read(|txn| {
+                let value = txn.open_table(BLOCKS)?.get(second.hash.0.as_slice())?;
+                let value = value.expect("stored header");
+                Ok(decode_header(value.value())?.0.position)
+            })
+        };
+        assert_eq!(position(&storage), Ok(11_000));
+        storage
+            .block_positions_write(&[(second.hash, 12_000), (Hash256([7; 32]), 1)])
+            .expect("positions");
+        assert_eq!(position(&storage), Ok(12_000));
+        assert_eq!(storage.tx_height(&mined), Ok(Some(10)));
+
+        let spent = OutPoint {
+            hash: mined,
+            index: 1,
+        };
+        assert!(storage.get_utxo(&spent).expect("lookup").is_none());
+        let unspent = OutPoint {
+            hash: mined,
+            index: 0,
+        };
+        let entry = storage.get_utxo(&unspent).expect("lookup").expect("utxo");
+        assert_eq!((entry.satoshis, entry.height), (1000, 10));
+
+        // The mempool height index entry was replaced when the output was mined
+        storage.utxo_delete_at_height(10).expect("orphan utxo");
+        storage.tx_delete_at_height(10).expect("orphan tx");
+        storage.block_header_delete(&second.hash).expect("orphan");
+        assert!(storage.get_utxo(&unspent).expect("lookup").is_none());
+        assert_eq!(storage.tx_height(&mined), Ok(None));
+        assert_eq!(storage.load_block_headers().expect("headers").len(), 1);
+    }
+
+    #[test]
+    fn collection_txs_stored_before_owners_belong_to_the_operator() {
+        let file = TempFile::new("embedded_collection_owner");
+        let mut storage = EmbeddedBackend::open(file.path()).expect("open");
+        // A version 1 store, with the collection name at the start of the key
+        storage
+            .write(|txn| {
+                create_tables(txn)?;
+                let mut key = b"one\0".to_vec();
+                key.extend_from_slice(&[3; 32]);
+                txn.open_table(COLLECTION)?
+                    .insert(key.as_slice(), b"aa".as_slice())?;
+                txn.open_table(META)?.insert(SCHEMA_VERSION, 1)?;
+                Ok(())
+            })
+            .expect("version 1 store");
+
+        assert_eq!(storage.migrate(), Ok((1, 2)));
+        assert_eq!(
+            storage.load_collection_txs(&MonitorKey::new(None, "one")),
+            Ok(vec![Hash256([3; 32])])
+        );
+        assert_eq!(
+            storage.load_collection_txs(&MonitorKey::new(Some("one"), "")),
+            Ok(Vec::new())
+        );
+    }
+
+    #[test]
+    fn mempool_and_collections() {
+        let file = TempFile::new("embedded_mempool");
+        let mut storage = EmbeddedBackend::open(file.path()).expect("open");
+        storage.migrate().expect("migrate");
+        let mempool_entry = |byte: u8, age: u64| MempoolEntryDB {
+            hash: Hash256([byte; 32]),
+            locktime: 0,
+            fee: 10,
+            age,
+            tx: "00".to_string(),
+        };
+        storage
+            .mempool_batch_write(&[mempool_entry(1, 20), mempool_entry(2, 10)])
+            .expect("mempool write");
+        assert_eq!(
+            storage.load_mempool(),
+            Ok(vec![Hash256([2; 32]), Hash256([1; 32])])
+        );
+        assert_eq!(
+            storage.mempool_tx(&Hash256([1; 32])),
+            Ok(Some("00".to_string()))
+        );
+        assert_eq!(storage.mempool_tx(&Hash256([3; 32])), Ok(None));
+        storage
+            .mempool_batch_delete(&[Hash256([2; 32])])
+            .expect("mempool delete");
+        assert_eq!(storage.load_mempool(), Ok(vec![Hash256([1; 32])]));
+
+        // Each owner has its own collection names
+        let one = MonitorKey::new(None, "one");
+        let tenant_one = MonitorKey::new(Some("team-a"), "one");
+        storage
+            .collection_tx_write(&one, &Hash256([3; 32]), "aa")
+            .expect("collection write");
+        storage
+            .collection_tx_write(&one, &Hash256([3; 32]), "bb")
+            .expect("duplicate collection write");
+        storage
+            .collection_tx_write(&MonitorKey::new(None, "one-two"), &Hash256([4; 32]), "cc")
+            .expect("collection write");
+        storage
+            .collection_tx_write(&tenant_one, &Hash256([5; 32]), "dd")
+            .expect("collection write");
+        assert_eq!(
+            storage.load_collection_txs(&one),
+            Ok(vec![Hash256([3; 32])])
+        );
+        storage.collection_delete(&one).expect("collection delete");
+        assert_eq!(storage.load_collection_txs(&one), Ok(Vec::new()));
+        assert_eq!(
+            storage.load_collection_txs(&MonitorKey::new(None, "one-two")),
+            Ok(vec![Hash256([4; 32])])
+        );
+        assert_eq!(
+            storage.load_collection_txs(&tenant_one),
+            Ok(vec![Hash256([5; 32])])
+        );
+
+        storage.addr_write("127.0.0.1", 1, 8333).expect("addr");
+        storage
+            .connect_event_write("2026-01-01 00:00:00", "127.0.0.1", "Connect")
+            .expect("connect");
+        storage
+            .connect_event_write("2026-01-01 00:00:01", "127.0.0.1", "Disconnect")
+            .expect("disconnect");
+        assert_eq!(storage.load_addresses(), Ok(vec!["127.0.0.1".to_string()]));
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use chain_gang::{
+    messages::{BlockHeader, OutPoint},
+    util::Hash256,
+};
+
+use super::{constraint_violation, Storage, StorageBackend};
+use crate::uaas::{
+    database::{
+        BlockHeaderWriteDB, MempoolEntryDB, OrphanBlockHeaderWriteDB, TxEntryWriteDB, UtxoEntryDB,
+    },
+    monitor::MonitorKey,
+};
+
+#[derive(Default)]
+pub struct MemoryData {
+    pub utxo: BTreeMap<(String, u32), UtxoEntryDB>,
+    pub tx: HashMap<Hash256, TxEntryWriteDB>,
+    pub mempool: HashMap<Hash256, MempoolEntryDB>,
+    pub blocks: HashMap<Hash256, BlockHeaderWriteDB>,
+    pub orphans: Vec<OrphanBlockHeaderWriteDB>,
+    // (monitor, tx hash) -> tx hex
+    pub collection: HashMap<(MonitorKey, Hash256), String>,
+    pub addresses: Vec<String>,
+    // (date, ip, event)
+    pub connect: Vec<(String, String, String)>,
+    // Make the utxo writes fail
+    pub fail_writes: bool,
+    // Make the utxo writes fail as if they broke a constraint
+    pub fail_constraint: bool,
+}
+
+/// Keeps everything in memory, for tests.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    data: Arc<Mutex<MemoryData>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+
+    pub fn data(&self) -> MutexGuard<'_, MemoryData> {
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn open(&self, _label: &str) -> Result<Box<dyn Storage>, String> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+impl Storage for MemoryBackend {
+    fn migrate(&mut self) -> Result<(u32, u32), String> {
+        Ok((0, 0))
+    }
+
+    fn ping(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn utxo_batch_write(&mut self, entries: &[UtxoEntryDB]) -> Result<(), String> {
+        let mut data = self.data();
+        if data.fail_writes {
+            return Err("utxo write failed".to_string());
+        }
+        if data.fail_constraint {
+            return Err(constraint_violation("duplicate utxo"));
+        }
+        for entry in entries {
+            data.utxo
+                .insert((entry.hash.clone(), entry.pos), entry.clone());
+        }
+        Ok(())
+    }
+
+    fn utxo_batch_delete(&mut self, outpoints: &[OutPoint]) -> Result<(), String> {
+        let mut data = self.data();
+        for outpoint in outpoints {
+            data.utxo.remove(&(outpoint.hash.encode(), outpoint.index));
+        }
+        Ok(())
+    }
+
+    fn tx_batch_write(&mut self, entries: &[TxEntryWriteDB]) -> Result<(), String> {
+        let mut data = self.data();
+        for entry in entries {
+            data.tx.insert(entry.hash, entry.clone());
+        }
+        Ok(())
+    }
+
+    fn mempool_batch_write(&mut self, entries: &[MempoolEntryDB]) -> Result<(), String> {
+        let mut data = self.data();
+        for entry in entries {
+            data.mempool.insert(entry.hash, entry.clone());
+        }
+        Ok(())
+    }
+
+    fn mempool_batch_delete(&mut self, hashes: &[Hash256]) -> Result<(), String> {
+        let mut data = self.data();
+        for hash in hashes {
+            data.mempool.remove(hash);
+        }
+        Ok(())
+    }
+
+    fn block_header_write(&mut self, header: &BlockHeaderWriteDB) -> Result<(), String> {
+        self.data().blocks.insert(header.hash, header.clone());
+        Ok(())
+    }
+
+    fn orphan_block_header_write(
+        &mut self,
+        header: &OrphanBlockHeaderWriteDB,
+    ) -> Result<(), String> {
+        self.data().orphans.push(header.clone());
+        Ok(())
+    }
+
+    fn block_header_delete(&mut self, hash: &Hash256) -> Result<(), String> {
+        self.data().blocks.remove(hash);
+        Ok(())
+    }
+
+    fn block_positions_write(&mut self, positions: &[(Hash256, u64)]) -> Result<(), String> {
+        let mut data = self.data();
+        for (hash, position) in positions.iter() {
+            if let Some(block) = data.blocks.get_mut(hash) {
+                block.position = *position;
+            }
+        }
+        Ok(())
+    }
+
+    fn tx_delete_at_height(&mut self, height: u32) -> Result<(), String> {
+        self.data()
+            .tx
+            .retain(|_hash, entry| entry.height != height as usize);
+        Ok(())
+    }
+
+    fn utxo_delete_at_height(&mut self, height: u32) -> Result<(), String> {
+        self.data()
+            .utxo
+            .retain(|_key, entry| i64::from(entry.height) != i64::from(height));
+        Ok(())
+    }
+
+    fn get_utxo(&mut self, outpoint: &OutPoint) -> Result<Option<UtxoEntryDB>, String> {
+        Ok(self
+            .data()
+            .utxo
+            .get(&(outpoint.hash.encode(), outpoint.index))
+            .cloned())
+    }
+
+    fn tx_height(&mut self, hash: &Hash256) -> Result<Option<u32>, String> {
+        Ok(self.data().tx.get(hash).map(|entry| entry.height as u32))
+    }
+
+    fn tx_location(&mut self, hash: &Hash256) -> Result<Option<(u64, u32)>, String> {
+        let data = self.data();
+        let Some(entry) = data.tx.get(hash) else {
+            return Ok(None);
+        };
+        Ok(data
+            .blocks
+            .values()
+            .find(|b| b.height as usize == entry.height)
+            .map(|b| (b.position, entry.blockindex)))
+    }
+
+    fn mempool_tx(&mut self, hash: &Hash256) -> Result<Option<String>, String> {
+        Ok(self.data().mempool.get(hash).map(|entry| entry.tx.clone()))
+    }
+
+    fn max_tx_height(&mut self) -> Result<Option<u32>, String> {
+        Ok(self
+            .data()
+            .tx
+            .values()
+            .map(|entry| entry.height as u32)
+            .max())
+    }
+
+    fn max_utxo_height(&mut self) -> Result<Option<u32>, String> {
+        Ok(self
+            .data()
+            .utxo
+            .values()
+            .filter_map(|entry| u32::try_from(entry.height).ok())
+            .max())
+    }
+
+    fn orphan_exists(&mut self, hash: &Hash256) -> Result<bool, String> {
+        Ok(self
+            .data()
+            .orphans
+            .iter()
+            .any(|orphan| orphan.hash == *hash))
+    }
+
+    fn load_mempool(&mut self) -> Result<Vec<Hash256>, String> {
+        let data = self.data();
+        let mut entries: Vec<&MempoolEntryDB> = data.mempool.values().collect();
+        entries.sort_by_key(|entry| entry.age);
+        Ok(entries.iter().map(|entry| entry.hash).collect())
+    }
+
+    fn load_tx(&mut self) -> Result<Vec<(Hash256, u32)>, String> {
+        let mut txs: Vec<(Hash256, u32)> = self
+            .data()
+            .tx
+            .values()
+            .map(|entry| (entry.hash, entry.height as u32))
+            .collect();
+        txs.sort_by_key(|(_hash, height)| *height);
+        Ok(txs)
+    }
+
+    fn load_block_headers(&mut self) -> Result<Vec<(u32, BlockHeader)>, String> {
+        let mut headers: Vec<(u32, BlockHeader)> = self
+            .data()
+            .blocks
+            .values()
+            .map(|b| {
+                let header = BlockHeader {
+                    version: b.version,
+                    prev_hash: b.prev_hash,
+                    merkle_root: b.merkle_root,
+                    timestamp: b.timestamp,
+                    bits: b.bits,
+                    nonce: b.nonce,
+                };
+                (b.height, header)
+            })
+            .collect();
+        headers.sort_by_key(|(height, _header)| *height);
+        Ok(headers)
+    }
+
+    fn export_utxo(
+        &mut self,
+        visit: &mut dyn FnMut(UtxoEntryDB) -> Result<(), String>,
+    ) -> Result<Option<(u32, BlockHeader)>, String> {
+        let data = self.data();
+        for entry in data.utxo.values() {
+            visit(entry.clone())?;
+        }
+        Ok(data.blocks.values().max_by_key(|b| b.height).map(|b| {
+            let header = BlockHeader {
+                version: b.version,
+                prev_hash: b.prev_hash,
+                merkle_root: b.merkle_root,
+                timestamp: b.timestamp,
+                bits: b.bits,
+                nonce: b.nonce,
+            };
+            (b.height, header)
+        }))
+    }
+
+    fn utxo_commitment(&mut self, height: u32) -> Result<Option<(Hash256, Hash256)>, String> {
+        Ok(self
+            .data()
+            .blocks
+            .values()
+            .find(|b| b.height == height)
+            .and_then(|b| b.utxo_commitment.map(|commitment| (b.hash, commitment))))
+    }
+
+    fn collection_tx_write(
+        &mut self,
+        key: &MonitorKey,
+        hash: &Hash256,
+        tx_hex: &str,
+    ) -> Result<(), String> {
+        self.data()
+            .collection
+            .entry((key.clone(), *hash))
+            .or_insert_with(|| tx_hex.to_string());
+        Ok(())
+    }
+
+    fn collection_delete(&mut self, key: &MonitorKey) -> Result<(), String> {
+        self.data()
+            .collection
+            .retain(|(monitor, _hash), _tx| monitor != key);
+        Ok(())
+    }
+
+    fn load_collection_txs(&mut self, key: &MonitorKey) -> Result<Vec<Hash256>, String> {
+        Ok(self
+            .data()
+            .collection
+            .keys()
+            .filter(|(monitor, _hash)| monitor == key)
+            .map(|(_monitor, hash)| *hash)
+            .collect())
+    }
+
+    fn load_addresses(&mut self) -> Result<Vec<String>, String> {
+        Ok(self.data().addresses.clone())
+    }
+
+    fn addr_write(&mut self, ip: &str, _services: u64, _port: u16) -> Result<(), String> {
+        self.data().addresses.push(ip.to_string());
+        Ok(())
+    }
+
+    fn connect_event_write(&mut self, date: &str, ip: &str, event: &str) -> Result<(), String> {
+        self.data()
+            .connect
+            .push((date.to_string(), ip.to_string(), event.to_string()));
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use ::mysql::Pool;
+use chain_gang::{
+    messages::{BlockHeader, OutPoint},
+    util::Hash256,
+};
+
+use super::database::{
+    BlockHeaderWriteDB, BlockWriteDB, MempoolEntryDB, OrphanBlockHeaderWriteDB, TxEntryWriteDB,
+    UtxoEntryDB,
+};
+use super::monitor::MonitorKey;
+use crate::config::{Config, DatabaseConfig, StorageType};
+
+mod embedded;
+#[cfg(test)]
+mod memory;
+mod mysql;
+mod postgres;
+
+pub use self::mysql::MySqlBackend;
+pub use self::postgres::PostgresBackend;
+pub use embedded::EmbeddedBackend;
+#[cfg(test)]
+pub use memory::MemoryBackend;
+
+// Failed writes that retrying cannot fix, such as a duplicate key, are reported with this prefix
+const CONSTRAINT_VIOLATION: &str = "constraint violation: ";
+
+pub fn constraint_violation(err: &str) -> String {
+    format!("{CONSTRAINT_VIOLATION}{err}")
+}
+
+pub fn is_constraint_violation(err: &str) -> bool {
+    err.starts_with(CONSTRAINT_VIOLATION)
+}
+
+/// Persistent store for blocks, txs, mempool, utxo and collections.
+///
+/// Each component that needs the store opens its own `Storage` from the
+/// `StorageBackend`, in the same way that each used to hold its own database connection.
+pub trait Storage: Send {
+    // Apply any schema migrations not yet applied to the store,
+    // returns the schema version before and after
+    fn migrate(&mut self) -> Result<(u32, u32), String>;
+
+    // Check that the store can be reached
+    fn ping(&mut self) -> Result<(), String>;
+
+    // Operations performed by the database writer, one per DBOperationType
+    fn utxo_batch_write(&mut self, entries: &[UtxoEntryDB]) -> Result<(), String>;
+    fn utxo_batch_delete(&mut self, outpoints: &[OutPoint]) -> Result<(), String>;
+    fn tx_batch_write(&mut self, entries: &[TxEntryWriteDB]) -> Result<(), String>;
+    fn mempool_batch_write(&mut self, entries: &[MempoolEntryDB]) -> Result<(), String>;
+    fn mempool_batch_delete(&mut self, hashes: &[Hash256]) -> Result<(), String>;
+    fn block_header_write(&mut self, header: &BlockHeaderWriteDB) -> Result<(), String>;
+    fn orphan_block_header_write(
+        &mut self,
+        header: &OrphanBlockHeaderWriteDB,
+    ) -> Result<(), String>;
+    fn block_header_delete(&mut self, hash: &Hash256) -> Result<(), String>;
+    // Move stored blocks to new block store positions, hashes without a stored header are ignored
+    fn block_positions_write(&mut self, positions: &[(Hash256, u64)]) -> Result<(), String>;
+    fn tx_delete_at_height(&mut self, height: u32) -> Result<(), String>;
+    fn utxo_delete_at_height(&mut self, height: u32) -> Result<(), String>;
+
+    // Write the effects of one or more blocks, the headers last.
+    // The database stores override this to commit the writes in one transaction,
+    // so that a stored header means that the writes of its block are stored
+    fn block_write(&mut self, block: &BlockWriteDB) -> Result<(), String> {
+        if !block.utxo_writes.is_empty() {
+            self.utxo_batch_write(&block.utxo_writes)?;
+        }
+        if !block.utxo_deletes.is_empty() {
+            self.utxo_batch_delete(&block.utxo_deletes)?;
+        }
+        if !block.tx_writes.is_empty() {
+            self.tx_batch_write(&block.tx_writes)?;
+        }
+        if !block.mempool_deletes.is_empty() {
+            self.mempool_batch_delete(&block.mempool_deletes)?;
+        }
+        for header in block.headers.iter() {
+            self.block_header_write(header)?;
+        }
+        Ok(())
+    }
+
+    // If true the tx loader is not used at startup,
+    // entries are looked up with tx_height when needed instead.
+    // The utxo are always looked up with get_utxo
+    fn loads_on_demand(&self) -> bool {
+        false
+    }
+    fn get_utxo(&mut self, outpoint: &OutPoint) -> Result<Option<UtxoEntryDB>, String>;
+    fn tx_height(&mut self, hash: &Hash256) -> Result<Option<u32>, String>;
+    // (block store position of the block, index in the block) of a tx in a main chain block
+    fn tx_location(&mut self, hash: &Hash256) -> Result<Option<(u64, u32)>, String>;
+    // The serialised tx, as hex, of a mempool tx
+    fn mempool_tx(&mut self, hash: &Hash256) -> Result<Option<String>, String>;
+
+    // Used by the startup consistency check
+    // Highest block height of the stored txs, and of the utxo that are in blocks
+    fn max_tx_height(&mut self) -> Result<Option<u32>, String>;
+    fn max_utxo_height(&mut self) -> Result<Option<u32>, String>;
+    fn orphan_exists(&mut self, hash: &Hash256) -> Result<bool, String>;
+
+    // Startup loaders
+    // Mempool tx hashes, oldest first
+    fn load_mempool(&mut self) -> Result<Vec<Hash256>, String>;
+    // (tx hash, height) of the txs in blocks
+    fn load_tx(&mut self) -> Result<Vec<(Hash256, u32)>, String>;
+    // (height, header) of the main chain, in height order
+    fn load_block_headers(&mut self) -> Result<Vec<(u32, BlockHeader)>, String>;
+
+    // Pass every utxo entry to visit, and return the (height, header) of the tip block.
+    // Read in one transaction, so that the entries are those of the tip while blocks are written
+    fn export_utxo(
+        &mut self,
+        visit: &mut dyn FnMut(UtxoEntryDB) -> Result<(), String>,
+    ) -> Result<Option<(u32, BlockHeader)>, String>;
+
+    // (block hash, utxo commitment) of the block at this height, if it has a commitment
+    fn utxo_commitment(&mut self, height: u32) -> Result<Option<(Hash256, Hash256)>, String>;
+
+    // Collections, stored by monitor owner and name
+    fn collection_tx_write(
+        &mut self,
+        key: &MonitorKey,
+        hash: &Hash256,
+        tx_hex: &str,
+    ) -> Result<(), String>;
+    fn load_collection_txs(&mut self, key: &MonitorKey) -> Result<Vec<Hash256>, String>;
+    // Remove the txs of a deleted monitor, so a monitor added later with the name starts empty
+    fn collection_delete(&mut self, key: &MonitorKey) -> Result<(), String>;
+
+    // Peer addresses and connection events
+    fn load_addresses(&mut self) -> Result<Vec<String>, String>;
+    fn addr_write(&mut self, ip: &str, services: u64, port: u16) -> Result<(), String>;
+    fn connect_event_write(&mut self, date: &str, ip: &str, event: &str) -> Result<(), String>;
+}
+
+/// Hands out `Storage` instances that share the same underlying store.
+pub trait StorageBackend: Send + Sync {
+    // The label identifies the user of the storage in error messages
+    fn open(&self, label: &str) -> Result<Box<dyn Storage>, String>;
+}
+
+/// Open the backend selected in the `[database]` config section.
+pub fn open_backend(config: &Config) -> Result<Arc<dyn StorageBackend>, String> {
+    match config.database.backend {
+        StorageType::Sql if DatabaseConfig::is_postgres_url(config.get_mysql_url()) => {
+            Ok(Arc::new(PostgresBackend::new(
+                config.get_mysql_url(),
+                &config.database,
+            )?))
+        }
+        StorageType::Sql => {
+            let pool = Pool::new(config.get_mysql_url()).map_err(|err| {
+                log::error!(
+                    "Problem connecting to database. Check database is connected and configuration is correct: {err:?}"
+                );
+                format!(
+                    "Problem connecting to database. Check database is connected and database connection configuration is correct: {err:?}"
+                )
+            })?;
+            Ok(Arc::new(MySqlBackend::new(pool, &config.database)))
+        }
+        StorageType::Embedded => Ok(Arc::new(EmbeddedBackend::open(
+            &config.database.embedded_path,
+        )?)),
+    }
+}
@@ -0,0 +1,887 @@
+use mysql::{prelude::*, *};
+use retry::{delay, retry, OperationResult};
+
+use chain_gang::{
+    messages::{BlockHeader, OutPoint},
+    util::Hash256,
+};
+
+use super::{constraint_violation, Storage, StorageBackend};
+use crate::{
+    config::DatabaseConfig,
+    uaas::{
+        database::{
+            BlockHeaderWriteDB, BlockWriteDB, MempoolEntryDB, OrphanBlockHeaderWriteDB,
+            TxEntryWriteDB, UtxoEntryDB,
+        },
+        monitor::MonitorKey,
+        schema::{pending, Migration},
+    },
+};
+
+// database header structure
+struct DBHeader {
+    height: u32,
+    _hash: String,
+    version: u32,
+    prev_hash: String,
+    merkle_root: String,
+    timestamp: u32,
+    bits: u32,
+    nonce: u32,
+    _position: u64,
+    _blocksize: u32,
+    _numtxs: u32,
+}
+
+fn decode_stored_hash(label: &str, value: &str) -> Option<Hash256> {
+    match Hash256::decode(value) {
+        Ok(hash) => Some(hash),
+        Err(err) => {
+            log::error!("Invalid stored {label} hash {value}: {err:?}");
+            None
+        }
+    }
+}
+
+// Server errors for writes that break a key or column constraint
+const CONSTRAINT_ERRORS: &[u16] = &[
+    1048, // ER_BAD_NULL_ERROR
+    1062, // ER_DUP_ENTRY
+    1364, // ER_NO_DEFAULT_FOR_FIELD
+    1406, // ER_DATA_TOO_LONG
+    1451, // ER_ROW_IS_REFERENCED_2
+    1452, // ER_NO_REFERENCED_ROW_2
+    4025, // ER_CONSTRAINT_FAILED
+];
+
+fn is_constraint_error(err: &mysql::Error) -> bool {
+    matches!(err, mysql::Error::MySqlError(err) if CONSTRAINT_ERRORS.contains(&err.code))
+}
+
+// A broken constraint is marked, so that the write is not retried
+fn write_error(err: &mysql::Error) -> String {
+    if is_constraint_error(err) {
+        constraint_violation(&format!("{err:?}"))
+    } else {
+        format!("{err:?}")
+    }
+}
+
+// The writes that make up a block, on a connection or in a transaction
+
+fn write_utxo<Q: Queryable>(conn: &mut Q, entries: &[UtxoEntryDB]) -> mysql::Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    conn.exec_batch(
+        //"INSERT OVERWRITE utxo (hash, pos, satoshis, height) VALUES (:hash, :pos, :satoshis, :height);",
+        "REPLACE INTO utxo (hash, pos, satoshis, height, pubkeyhash) VALUES (:hash, :pos, :satoshis, :height, :pubkeyhash);",
+        entries.iter().map(|x| params! {
+            "hash" => x.hash.as_str(), "pos" => x.pos, "satoshis" => x.satoshis, "height" => x.height, "pubkeyhash" => x.pubkeyhash.as_str()}),
+    )
+}
+
+fn delete_utxo<Q: Queryable>(conn: &mut Q, outpoints: &[OutPoint]) -> mysql::Result<()> {
+    if outpoints.is_empty() {
+        return Ok(());
+    }
+    conn.exec_batch(
+        "DELETE FROM utxo WHERE hash = :hash AND pos = :pos;",
+        outpoints
+            .iter()
+            .map(|x| params! {"hash" => x.hash.encode(), "pos" => x.index}),
+    )
+}
+
+fn write_tx<Q: Queryable>(conn: &mut Q, entries: &[TxEntryWriteDB]) -> mysql::Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    conn.exec_batch(
+        "INSERT INTO tx (hash, height, blockindex, txsize, satoshis) VALUES (:hash, :height, :blockindex, :txsize, :satoshis) \
+         ON DUPLICATE KEY UPDATE height = VALUES(height), blockindex = VALUES(blockindex), \
+         txsize = VALUES(txsize), satoshis = VALUES(satoshis)",
+        entries.iter().map(
+            |tx| params! {"hash" => tx.hash.encode(), "height" => tx.height, "blockindex"=> tx.blockindex, "txsize"=> tx.size, "satoshis" => tx.satoshis},
+        ),
+    )
+}
+
+fn delete_mempool<Q: Queryable>(conn: &mut Q, hashes: &[Hash256]) -> mysql::Result<()> {
+    if hashes.is_empty() {
+        return Ok(());
+    }
+    conn.exec_batch(
+        "DELETE FROM mempool WHERE hash = :hash;",
+        hashes.iter().map(|x| params! {"hash" => x.encode()}),
+    )
+}
+
+fn write_block_header<Q: Queryable>(
+    conn: &mut Q,
+    header: &BlockHeaderWriteDB,
+) -> mysql::Result<()> {
+    conn.exec_drop(
+        r"INSERT INTO blocks
+        (height, hash, version, prev_hash, merkle_root, timestamp, bits, nonce, `offset`, blocksize, numtxs, utxo_commitment)
+        VALUES (:height, :hash, :version, :prev_hash, :merkle_root, :timestamp, :bits, :nonce, :offset, :blocksize, :numtxs, :utxo_commitment)",
+        params! {
+            "height" => header.height,
+            "hash" => header.hash.encode(),
+            "version" => header.version,
+            "prev_hash" => header.prev_hash.encode(),
+            "merkle_root" => header.merkle_root.encode(),
+            "timestamp" => header.timestamp,
+            "bits" => header.bits,
+            "nonce" => header.nonce,
+            "offset" => header.position,
+            "blocksize" => header.blocksize,
+            "numtxs" => header.numtxs,
+            "utxo_commitment" => header.utxo_commitment.map(|commitment| commitment.encode()),
+        },
+    )
+}
+
+type MySqlMigration = Migration<fn(&mut PooledConn) -> mysql::Result<()>>;
+
+// Databases created before schema versioning already have some of these tables,
+// so each migration checks what is there
+const MIGRATIONS: &[MySqlMigration] = &[
+    Migration {
+        version: 1,
+        description: "create tables",
+        apply: create_tables,
+    },
+    Migration {
+        version: 2,
+        description: "add height indexes",
+        apply: create_height_indexes,
+    },
+    Migration {
+        version: 3,
+        description: "add mempool primary key",
+        apply: add_mempool_primary_key,
+    },
+    Migration {
+        version: 4,
+        description: "add block utxo commitment",
+        apply: add_utxo_commitment,
+    },
+    Migration {
+        version: 5,
+        description: "add collection owner",
+        apply: add_collection_owner,
+    },
+];
+
+fn create_tables(conn: &mut PooledConn) -> mysql::Result<()> {
+    conn.query_drop(
+        r"CREATE TABLE IF NOT EXISTS blocks (
+        height int unsigned not null,
+        hash varchar(64) not null,
+        version int unsigned not null,
+        prev_hash varchar(64) not null,
+        merkle_root varchar(64) not null,
+        timestamp int unsigned not null,
+        bits int unsigned not null,
+        nonce int unsigned not null,
+        `offset` bigint unsigned not null,
+        blocksize int unsigned not null,
+        numtxs int unsigned not null,
+        CONSTRAINT PK_Entry PRIMARY KEY (hash));",
+    )?;
+    conn.query_drop(
+        r"CREATE TABLE IF NOT EXISTS orphans (
+        height int unsigned not null,
+        hash varchar(64) not null,
+        version int unsigned not null,
+        prev_hash varchar(64) not null,
+        merkle_root varchar(64) not null,
+        timestamp int unsigned not null,
+        bits int unsigned not null,
+        nonce int unsigned not null,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP);",
+    )?;
+    conn.query_drop(
+        r"CREATE TABLE IF NOT EXISTS utxo (
+        hash varchar(64) not null,
+        pos int unsigned not null,
+        satoshis bigint unsigned not null,
+        height int not null,
+        pubkeyhash varchar(64),
+        CONSTRAINT PK_Entry PRIMARY KEY (hash, pos));",
+    )?;
+    conn.query_drop(r"CREATE INDEX IF NOT EXISTS speed_key ON utxo (pubkeyhash);")?;
+    conn.query_drop(
+        r"CREATE TABLE IF NOT EXISTS tx (
+        hash varchar(64) not null,
+        height int unsigned not null,
+        blockindex int unsigned not null,
+        txsize int unsigned not null,
+        satoshis bigint unsigned not null,
+        CONSTRAINT PK_Entry PRIMARY KEY (hash));",
+    )?;
+    // Note that tx longtext should be good for 4GB txs
+    conn.query_drop(
+        r"CREATE TABLE IF NOT EXISTS mempool (
+        hash varchar(64) not null,
+        locktime int unsigned not null,
+        fee bigint unsigned not null,
+        time int unsigned not null,
+        tx longtext not null,
+        CONSTRAINT PK_Mempool PRIMARY KEY (hash))",
+    )?;
+    // Collection table - one table for all collections
+    conn.query_drop(
+        "CREATE TABLE IF NOT EXISTS collection (hash varchar(64), name varchar(64), tx longtext, CONSTRAINT PK_Entry PRIMARY KEY (hash, name));",
+    )?;
+    conn.query_drop("CREATE INDEX IF NOT EXISTS collect_key ON collection (hash, name);")?;
+    conn.query_drop("CREATE TABLE IF NOT EXISTS addr (ip text, services int, port int);")?;
+    conn.query_drop(
+        "CREATE TABLE IF NOT EXISTS connect (date VARCHAR(64), ip VARCHAR(64), event VARCHAR(64));",
+    )
+}
+
+fn create_height_indexes(conn: &mut PooledConn) -> mysql::Result<()> {
+    conn.query_drop("CREATE INDEX IF NOT EXISTS idx_blocks_height ON blocks (height)")?;
+    conn.query_drop(
+        "CREATE INDEX IF NOT EXISTS idx_tx_height_blockindex ON tx (height, blockindex)",
+    )?;
+    conn.query_drop("CREATE INDEX IF NOT EXISTS idx_utxo_height ON utxo (height)")
+}
+
+// Older databases indexed the mempool hash, which allowed duplicate entries
+fn add_mempool_primary_key(conn: &mut PooledConn) -> mysql::Result<()> {
+    let has_pk = conn
+        .query_first::<i64, _>(
+            "SELECT COUNT(*) FROM INFORMATION_SCHEMA.TABLE_CONSTRAINTS \
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'mempool' \
+             AND CONSTRAINT_TYPE = 'PRIMARY KEY'",
+        )?
+        .unwrap_or(0)
+        > 0;
+    if has_pk {
+        return Ok(());
+    }
+    // Keep the earliest of any duplicates
+    conn.query_drop(
+        "DELETE m1 FROM mempool m1 \
+         INNER JOIN mempool m2 ON m1.hash = m2.hash AND m1.time > m2.time",
+    )?;
+    conn.query_drop("DROP INDEX IF EXISTS idx_txkey ON mempool")?;
+    conn.query_drop("ALTER TABLE mempool ADD PRIMARY KEY (hash)")
+}
+
+fn add_utxo_commitment(conn: &mut PooledConn) -> mysql::Result<()> {
+    conn.query_drop("ALTER TABLE blocks ADD COLUMN IF NOT EXISTS utxo_commitment varchar(64)")
+}
+
+// Monitors are named within their owner, the existing rows belong to the operator
+fn add_collection_owner(conn: &mut PooledConn) -> mysql::Result<()> {
+    conn.query_drop(
+        "ALTER TABLE collection ADD COLUMN IF NOT EXISTS owner varchar(64) NOT NULL DEFAULT ''",
+    )?;
+    conn.query_drop("ALTER TABLE collection DROP PRIMARY KEY, ADD PRIMARY KEY (owner, name, hash)")
+}
+
+/// The MariaDB/MySQL store, each `Storage` holds its own pooled connection.
+pub struct MySqlBackend {
+    pool: Pool,
+    // Retry database writes
+    ms_delay: u64,
+    retries: usize,
+}
+
+impl MySqlBackend {
+    pub fn new(pool: Pool, config: &DatabaseConfig) -> Self {
+        MySqlBackend {
+            pool,
+            ms_delay: config.ms_delay,
+            retries: config.retries,
+        }
+    }
+}
+
+impl StorageBackend for MySqlBackend {
+    fn open(&self, label: &str) -> Result<Box<dyn Storage>, String> {
+        let mut conn = self.pool.get_conn().map_err(|err| {
+            log::error!("Unable to get {label} database connection: {err:?}");
+            format!("Unable to get {label} database connection")
+        })?;
+        // Disable safe mode... wa ha ha - what could possibly go wrong?
+        if let Err(err) = conn.query_drop("SET sql_safe_updates=0;") {
+            log::warn!("Unable to disable sql_safe_updates: {err:?}");
+        }
+        Ok(Box::new(MySqlStorage::new(
+            conn,
+            self.ms_delay,
+            self.retries,
+        )))
+    }
+}
+
+struct MySqlStorage {
+    conn: PooledConn,
+    ms_delay: u64,
+    retries: usize,
+}
+
+impl MySqlStorage {
+    fn new(conn: PooledConn, ms_delay: u64, retries: usize) -> Self {
+        MySqlStorage {
+            conn,
+            ms_delay,
+            retries,
+        }
+    }
+
+    // Run a write, retrying on failure unless the write breaks a constraint
+    fn with_retry<T, F>(&mut self, mut operation: F) -> Result<T, String>
+    where
+        F: FnMut(&mut PooledConn) -> mysql::Result<T>,
+    {
+        let conn = &mut self.conn;
+        retry(
+            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
+            || match operation(conn) {
+                Ok(value) => OperationResult::Ok(value),
+                Err(err) if is_constraint_error(&err) => OperationResult::Err(err),
+                Err(err) => OperationResult::Retry(err),
+            },
+        )
+        .map_err(|err| write_error(&err.error))
+    }
+
+    // Run a write once, for the writes queued to the database writer, which retries them itself
+    fn once<T, F>(&mut self, operation: F) -> Result<T, String>
+    where
+        F: FnOnce(&mut PooledConn) -> mysql::Result<T>,
+    {
+        operation(&mut self.conn).map_err(|err| write_error(&err))
+    }
+
+    fn schema_version(&mut self) -> Result<u32, String> {
+        self.conn
+            .query_drop(
+                r"CREATE TABLE IF NOT EXISTS schema_version (
+                version int unsigned not null,
+                description varchar(255) not null,
+                applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                CONSTRAINT PK_Version PRIMARY KEY (version));",
+            )
+            .map_err(|err| format!("Unable to create schema_version table: {err:?}"))?;
+        self.conn
+            .query_first::<Option<u32>, _>("SELECT MAX(version) FROM schema_version")
+            .map(|version| version.flatten().unwrap_or(0))
+            .map_err(|err| format!("Unable to read schema version: {err:?}"))
+    }
+}
+
+impl Storage for MySqlStorage {
+    fn migrate(&mut self) -> Result<(u32, u32), String> {
+        let current = self.schema_version()?;
+        let mut version = current;
+        for migration in pending(MIGRATIONS, current)? {
+            log::info!(
+                "Applying database migration {}: {}",
+                migration.version,
+                migration.description
+            );
+            (migration.apply)(&mut self.conn)
+                .and_then(|_| {
+                    self.conn.exec_drop(
+                        "INSERT INTO schema_version (version, description) VALUES (?, ?)",
+                        (migration.version, migration.description),
+                    )
+                })
+                .map_err(|err| {
+                    format!(
+                        "Database migration {} ({}) failed: {err:?}",
+                        migration.version, migration.description
+                    )
+                })?;
+            version = migration.version;
+        }
+        Ok((current, version))
+    }
+
+    fn ping(&mut self) -> Result<(), String> {
+        self.conn
+            .query_first::<u8, _>("SELECT 1")
+            .map_err(|err| err.to_string())?
+            .ok_or_else(|| "database health check returned no rows".to_string())?;
+        Ok(())
+    }
+
+    fn utxo_batch_write(&mut self, entries: &[UtxoEntryDB]) -> Result<(), String> {
+        self.once(|conn| write_utxo(conn, entries))
+    }
+
+    fn utxo_batch_delete(&mut self, outpoints: &[OutPoint]) -> Result<(), String> {
+        self.once(|conn| delete_utxo(conn, outpoints))
+    }
+
+    fn tx_batch_write(&mut self, entries: &[TxEntryWriteDB]) -> Result<(), String> {
+        self.with_retry(|conn| write_tx(conn, entries))
+    }
+
+    fn mempool_batch_write(&mut self, entries: &[MempoolEntryDB]) -> Result<(), String> {
+        self.once(|conn| {
+            let mut txn = conn.start_transaction(TxOpts::default())?;
+            txn.exec_batch(
+                "INSERT IGNORE INTO mempool (hash, locktime, fee, time, tx) \
+                 VALUES (:hash, :locktime, :fee, :time, :tx)",
+                entries.iter().map(|entry| {
+                    params! {
+                        "hash" => entry.hash.encode(),
+                        "locktime" => entry.locktime,
+                        "fee" => entry.fee,
+                        "time" => entry.age,
+                        "tx" => entry.tx.as_str(),
+                    }
+                }),
+            )?;
+            txn.commit()
+        })
+    }
+
+    fn mempool_batch_delete(&mut self, hashes: &[Hash256]) -> Result<(), String> {
+        self.with_retry(|conn| delete_mempool(conn, hashes))
+    }
+
+    fn block_header_write(&mut self, header: &BlockHeaderWriteDB) -> Result<(), String> {
+        self.with_retry(|conn| write_block_header(conn, header))
+    }
+
+    fn orphan_block_header_write(
+        &mut self,
+        header: &OrphanBlockHeaderWriteDB,
+    ) -> Result<(), String> {
+        self.once(|conn| {
+            conn.exec_drop(
+                r"INSERT INTO orphans (height, hash, version, prev_hash, merkle_root, timestamp, bits, nonce)
+                VALUES (:height, :hash, :version, :prev_hash, :merkle_root, :timestamp, :bits, :nonce)",
+                params! {
+                    "height" => header.height,
+                    "hash" => header.hash.encode(),
+                    "version" => header.version,
+                    "prev_hash" => header.prev_hash.encode(),
+                    "merkle_root" => header.merkle_root.encode(),
+                    "timestamp"  => header.timestamp,
+                    "bits"  => header.bits,
+                    "nonce"  => header.nonce
+                },
+            )
+        })
+    }
+
+    fn block_header_delete(&mut self, hash: &Hash256) -> Result<(), String> {
+        let hash = hash.encode();
+        self.once(|conn| {
+            conn.exec_drop(
+                "DELETE FROM blocks WHERE hash = :hash",
+                params! { "hash" => hash.as_str() },
+            )
+        })
+    }
+
+    fn block_positions_write(&mut self, positions: &[(Hash256, u64)]) -> Result<(), String> {
+        self.with_retry(|conn| {
+            let mut txn = conn.start_transaction(TxOpts::default())?;
+            txn.exec_batch(
+                "UPDATE blocks SET `offset` = :position WHERE hash = :hash",
+                positions.iter().map(|(hash, position)| {
+                    params! { "hash" => hash.encode(), "position" => position }
+                }),
+            )?;
+            txn.commit()
+        })
+    }
+
+    fn tx_delete_at_height(&mut self, height: u32) -> Result<(), String> {
+        self.once(|conn| {
+            conn.exec_drop(
+                "DELETE FROM tx WHERE height = :height",
+                params! { "height" => height },
+            )
+        })
+    }
+
+    fn utxo_delete_at_height(&mut self, height: u32) -> Result<(), String> {
+        self.once(|conn| {
+            conn.exec_drop(
+                "DELETE FROM utxo WHERE height = :height",
+                params! { "height" => height },
+            )
+        })
+    }
+
+    // All the writes of the blocks are committed in one transaction, the headers last
+    fn block_write(&mut self, block: &BlockWriteDB) -> Result<(), String> {
+        self.once(|conn| {
+            let mut txn = conn.start_transaction(TxOpts::default())?;
+            write_utxo(&mut txn, &block.utxo_writes)?;
+            delete_utxo(&mut txn, &block.utxo_deletes)?;
+            write_tx(&mut txn, &block.tx_writes)?;
+            delete_mempool(&mut txn, &block.mempool_deletes)?;
+            for header in block.headers.iter() {
+                write_block_header(&mut txn, header)?;
+            }
+            txn.commit()
+        })
+    }
+
+    fn get_utxo(&mut self, outpoint: &OutPoint) -> Result<Option<UtxoEntryDB>, String> {
+        let hash = outpoint.hash.encode();
+        let row: Option<(i64, i32, String)> = self
+            .conn
+            .exec_first(
+                "SELECT satoshis, height, pubkeyhash FROM utxo WHERE hash = :hash AND pos = :pos",
+                params! { "hash" => hash.as_str(), "pos" => outpoint.index },
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        Ok(row.map(|(satoshis, height, pubkeyhash)| UtxoEntryDB {
+            hash,
+            pos: outpoint.index,
+            satoshis,
+            height,
+            pubkeyhash,
+        }))
+    }
+
+    fn tx_height(&mut self, hash: &Hash256) -> Result<Option<u32>, String> {
+        self.conn
+            .exec_first(
+                "SELECT height FROM tx WHERE hash = :hash",
+                params! { "hash" => hash.encode() },
+            )
+            .map_err(|err| format!("{err:?}"))
+    }
+
+    fn tx_location(&mut self, hash: &Hash256) -> Result<Option<(u64, u32)>, String> {
+        self.conn
+            .exec_first(
+                "SELECT blocks.`offset`, tx.blockindex FROM tx \
+                 INNER JOIN blocks ON blocks.height = tx.height WHERE tx.hash = :hash LIMIT 1",
+                params! { "hash" => hash.encode() },
+            )
+            .map_err(|err| format!("{err:?}"))
+    }
+
+    fn mempool_tx(&mut self, hash: &Hash256) -> Result<Option<String>, String> {
+        self.conn
+            .exec_first(
+                "SELECT tx FROM mempool WHERE hash = :hash",
+                params! { "hash" => hash.encode() },
+            )
+            .map_err(|err| format!("{err:?}"))
+    }
+
+    fn max_tx_height(&mut self) -> Result<Option<u32>, String> {
+        self.conn
+            .query_first::<Option<u32>, _>("SELECT MAX(height) FROM tx")
+            .map(Option::flatten)
+            .map_err(|err| format!("{err:?}"))
+    }
+
+    fn max_utxo_height(&mut self) -> Result<Option<u32>, String> {
+        // Mempool utxo have a negative height
+        self.conn
+            .query_first::<Option<u32>, _>("SELECT MAX(height) FROM utxo WHERE height >= 0")
+            .map(Option::flatten)
+            .map_err(|err| format!("{err:?}"))
+    }
+
+    fn orphan_exists(&mut self, hash: &Hash256) -> Result<bool, String> {
+        self.conn
+            .exec_first::<u8, _, _>(
+                "SELECT 1 FROM orphans WHERE hash = :hash LIMIT 1",
+                params! { "hash" => hash.encode() },
+            )
+            .map(|row| row.is_some())
+            .map_err(|err| format!("{err:?}"))
+    }
+
+    fn load_mempool(&mut self) -> Result<Vec<Hash256>, String> {
+        let hashes: Vec<String> = self
+            .conn
+            .query("SELECT hash FROM mempool ORDER BY time")
+            .map_err(|err| format!("{err:?}"))?;
+        Ok(hashes
+            .iter()
+            .filter_map(|hash| decode_stored_hash("mempool tx", hash))
+            .collect())
+    }
+
+    fn load_tx(&mut self) -> Result<Vec<(Hash256, u32)>, String> {
+        let txs: Vec<(String, u32)> = self
+            .conn
+            .query("SELECT hash, height FROM tx ORDER BY height")
+            .map_err(|err| format!("{err:?}"))?;
+        Ok(txs
+            .iter()
+            .filter_map(|(hash, height)| Some((decode_stored_hash("tx", hash)?, *height)))
+            .collect())
+    }
+
+    fn load_block_headers(&mut self) -> Result<Vec<(u32, BlockHeader)>, String> {
+        let headers: Vec<DBHeader> = self
+            .conn
+            .query_map(
+                "SELECT height, hash, version, prev_hash, merkle_root, timestamp, bits, nonce, \
+                 `offset`, blocksize, numtxs FROM blocks ORDER BY height asc",
+                |(
+                    height,
+                    _hash,
+                    version,
+                    prev_hash,
+                    merkle_root,
+                    timestamp,
+                    bits,
+                    nonce,
+                    position,
+                    _blocksize,
+                    _numtxs,
+                )| {
+                    DBHeader {
+                        height,
+                        _hash,
+                        version,
+                        prev_hash,
+                        merkle_root,
+                        timestamp,
+                        bits,
+                        nonce,
+                        _position: position,
+                        _blocksize,
+                        _numtxs,
+                    }
+                },
+            )
+            .map_err(|err| format!("{err:?}"))?;
+
+        Ok(headers
+            .into_iter()
+            .filter_map(|b| {
+                let prev_hash = decode_stored_hash("prev_hash", &b.prev_hash)?;
+                let merkle_root = decode_stored_hash("merkle_root", &b.merkle_root)?;
+                let header = BlockHeader {
+                    version: b.version,
+                    prev_hash,
+                    merkle_root,
+                    timestamp: b.timestamp,
+                    bits: b.bits,
+                    nonce: b.nonce,
+                };
+                Some((b.height, header))
+            })
+            .collect())
+    }
+
+    fn export_utxo(
+        &mut self,
+        visit: &mut dyn FnMut(UtxoEntryDB) -> Result<(), String>,
+    ) -> Result<Option<(u32, BlockHeader)>, String> {
+        let options = TxOpts::default()
+            .set_with_consistent_snapshot(true)
+            .set_access_mode(Some(AccessMode::ReadOnly));
+        let mut txn = self
+            .conn
+            .start_transaction(options)
+            .map_err(|err| format!("{err:?}"))?;
+        let tip: Option<(u32, u32, String, String, u32, u32, u32)> = txn
+            .query_first(
+                "SELECT height, version, prev_hash, merkle_root, timestamp, bits, nonce \
+                 FROM blocks ORDER BY height DESC LIMIT 1",
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        let tip = match tip {
+            Some((height, version, prev_hash, merkle_root, timestamp, bits, nonce)) => {
+                let (Some(prev_hash), Some(merkle_root)) = (
+                    decode_stored_hash("prev_hash", &prev_hash),
+                    decode_stored_hash("merkle_root", &merkle_root),
+                ) else {
+                    return Err(format!("Invalid block header stored at height {height}"));
+                };
+                let header = BlockHeader {
+                    version,
+                    prev_hash,
+                    merkle_root,
+                    timestamp,
+                    bits,
+                    nonce,
+                };
+                Some((height, header))
+            }
+            None => None,
+        };
+
+        let rows = txn
+            .query_iter("SELECT hash, pos, satoshis, height, pubkeyhash FROM utxo")
+            .map_err(|err| format!("{err:?}"))?;
+        for row in rows {
+            let row = row.map_err(|err| format!("{err:?}"))?;
+            let (hash, pos, satoshis, height, pubkeyhash): (String, u32, i64, i32, String) =
+                from_row_opt(row).map_err(|err| format!("{err:?}"))?;
+            visit(UtxoEntryDB {
+                hash,
+                pos,
+                satoshis,
+                height,
+                pubkeyhash,
+            })?;
+        }
+        Ok(tip)
+    }
+
+    fn utxo_commitment(&mut self, height: u32) -> Result<Option<(Hash256, Hash256)>, String> {
+        let row: Option<(String, String)> = self
+            .conn
+            .exec_first(
+                "SELECT hash, utxo_commitment FROM blocks \
+                 WHERE height = :height AND utxo_commitment IS NOT NULL LIMIT 1",
+                params! { "height" => height },
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        let Some((hash, commitment)) = row else {
+            return Ok(None);
+        };
+        match (
+            decode_stored_hash("hash", &hash),
+            decode_stored_hash("utxo_commitment", &commitment),
+        ) {
+            (Some(hash), Some(commitment)) => Ok(Some((hash, commitment))),
+            _ => Err(format!("Invalid utxo commitment stored at height {height}")),
+        }
+    }
+
+    fn collection_tx_write(
+        &mut self,
+        key: &MonitorKey,
+        hash: &Hash256,
+        tx_hex: &str,
+    ) -> Result<(), String> {
+        let hash = hash.encode();
+        self.with_retry(|conn| {
+            conn.exec_drop(
+                // Ignore rows already written, e.g. by a backfill of the same monitor
+                "INSERT IGNORE INTO collection (hash, owner, name, tx) VALUES (:hash, :owner, :name, :tx)",
+                params! {
+                    "hash" => hash.as_str(),
+                    "owner" => key.stored_owner(),
+                    "name" => key.name.as_str(),
+                    "tx" => tx_hex,
+                },
+            )
+        })
+    }
+
+    fn collection_delete(&mut self, key: &MonitorKey) -> Result<(), String> {
+        self.with_retry(|conn| {
+            conn.exec_drop(
+                "DELETE FROM collection WHERE owner = :owner AND name = :name",
+                params! { "owner" => key.stored_owner(), "name" => key.name.as_str() },
+            )
+        })
+    }
+
+    fn load_collection_txs(&mut self, key: &MonitorKey) -> Result<Vec<Hash256>, String> {
+        let hashes: Vec<String> = self
+            .conn
+            .exec(
+                "SELECT hash FROM collection WHERE owner = :owner AND name = :name",
+                params! { "owner" => key.stored_owner(), "name" => key.name.as_str() },
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        Ok(hashes
+            .iter()
+            .filter_map(|hash| decode_stored_hash("collection tx", hash))
+            .collect())
+    }
+
+    fn load_addresses(&mut self) -> Result<Vec<String>, String> {
+        self.conn
+            .query("SELECT ip FROM addr")
+            .map_err(|err| format!("{err:?}"))
+    }
+
+    fn addr_write(&mut self, ip: &str, services: u64, port: u16) -> Result<(), String> {
+        self.with_retry(|conn| {
+            conn.exec_drop(
+                "INSERT INTO addr (ip, services, port) VALUES (:ip, :services, :port)",
+                params! {
+                    "ip" => ip,
+                    "services" => services,
+                    "port" => port
+                },
+            )
+        })
+    }
+
+    fn connect_event_write(&mut self, date: &str, ip: &str, event: &str) -> Result<(), String> {
+        self.with_retry(|conn| {
+            conn.exec_drop(
+                "INSERT INTO connect (date, ip, event) VALUES (:date, :ip, :event)",
+                params! {
+                    "date" => date,
+                    "ip" => ip,
+                    "event" => event
+                },
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uaas::schema::latest_version;
+
+    #[test]
+    fn legacy_database_is_migrated() {
+        let Some(url) = std::env::var("UAAS_TEST_MYSQL_URL").ok() else {
+            eprintln!("skipping legacy_database_is_migrated: UAAS_TEST_MYSQL_URL not set");
+            return;
+        };
+        let pool = Pool::new(url.as_str()).expect("connect to UAAS_TEST_MYSQL_URL");
+        let mut conn = pool.get_conn().expect("get connection for migration test");
+
+        // A database from before schema versioning, with the old mempool index
+        conn.query_drop("DROP TABLE IF EXISTS schema_version")
+            .expect("drop schema_version");
+        conn.query_drop("DROP TABLE IF EXISTS mempool")
+            .expect("drop mempool");
+        conn.query_drop(
+            "CREATE TABLE mempool (
+                hash varchar(64) not null,
+                locktime int unsigned not null,
+                fee bigint unsigned not null,
+                time int unsigned not null,
+                tx longtext not null
+            )",
+        )
+        .expect("create legacy mempool table");
+        conn.query_drop("CREATE INDEX idx_txkey ON mempool (hash)")
+            .expect("create legacy mempool index");
+
+        let latest = latest_version(MIGRATIONS);
+        let mut storage = MySqlStorage::new(conn, 0, 0);
+        assert_eq!(storage.migrate(), Ok((0, latest)));
+        assert_eq!(storage.migrate(), Ok((latest, latest)));
+
+        let indexes: Vec<String> = storage
+            .conn
+            .query(
+                "SELECT INDEX_NAME FROM INFORMATION_SCHEMA.STATISTICS \
+                 WHERE TABLE_SCHEMA = DATABASE() \
+                 AND INDEX_NAME IN ('idx_blocks_height', 'idx_tx_height_blockindex', 'idx_utxo_height', 'PRIMARY') \
+                 AND TABLE_NAME IN ('blocks', 'tx', 'utxo', 'mempool')",
+            )
+            .expect("query indexes");
+        assert!(
+            indexes.len() >= 4,
+            "expected height indexes and mempool primary key, found {indexes:?}"
+        );
+    }
+}
@@ -0,0 +1,927 @@
+use postgres::{GenericClient, IsolationLevel, NoTls, Row, Statement, Transaction};
+use r2d2_postgres::{
+    r2d2::{Pool, PooledConnection},
+    PostgresConnectionManager,
+};
+use retry::{delay, retry, OperationResult};
+
+use chain_gang::{
+    messages::{BlockHeader, OutPoint},
+    util::Hash256,
+};
+
+use super::{constraint_violation, Storage, StorageBackend};
+use crate::{
+    config::DatabaseConfig,
+    uaas::{
+        database::{
+            BlockHeaderWriteDB, BlockWriteDB, MempoolEntryDB, OrphanBlockHeaderWriteDB,
+            TxEntryWriteDB, UtxoEntryDB,
+        },
+        monitor::MonitorKey,
+        schema::{pending, Migration},
+    },
+};
+
+type PooledClient = PooledConnection<PostgresConnectionManager<NoTls>>;
+
+// PostgreSQL has no unsigned types, so u32 columns are bigint and read back through here
+fn column_u32(row: &Row, column: &str) -> Result<u32, String> {
+    let value: i64 = row
+        .try_get(column)
+        .map_err(|err| format!("Unable to read column {column}: {err:?}"))?;
+    u32::try_from(value).map_err(|_| format!("Column {column} value {value} out of range"))
+}
+
+fn column_str(row: &Row, column: &str) -> Result<String, String> {
+    row.try_get(column)
+        .map_err(|err| format!("Unable to read column {column}: {err:?}"))
+}
+
+fn decode_stored_hash(label: &str, value: &str) -> Option<Hash256> {
+    match Hash256::decode(value) {
+        Ok(hash) => Some(hash),
+        Err(err) => {
+            log::error!("Invalid stored {label} hash {value}: {err:?}");
+            None
+        }
+    }
+}
+
+// Run the statement once for each entry
+fn execute_each<C, E, F>(
+    client: &mut C,
+    statement: &str,
+    entries: &[E],
+    mut execute: F,
+) -> Result<(), postgres::Error>
+where
+    C: GenericClient,
+    F: FnMut(&mut C, &Statement, &E) -> Result<u64, postgres::Error>,
+{
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let stmt = client.prepare(statement)?;
+    for entry in entries {
+        execute(client, &stmt, entry)?;
+    }
+    Ok(())
+}
+
+// The writes that make up a block, on a connection or in a transaction
+
+fn write_utxo<C: GenericClient>(
+    client: &mut C,
+    entries: &[UtxoEntryDB],
+) -> Result<(), postgres::Error> {
+    execute_each(
+        client,
+        "INSERT INTO utxo (hash, pos, satoshis, height, pubkeyhash) VALUES ($1, $2, $3, $4, $5) \
+         ON CONFLICT (hash, pos) DO UPDATE SET satoshis = EXCLUDED.satoshis, \
+         height = EXCLUDED.height, pubkeyhash = EXCLUDED.pubkeyhash",
+        entries,
+        |client, stmt, x| {
+            client.execute(
+                stmt,
+                &[
+                    &x.hash,
+                    &i64::from(x.pos),
+                    &x.satoshis,
+                    &x.height,
+                    &x.pubkeyhash,
+                ],
+            )
+        },
+    )
+}
+
+fn delete_utxo<C: GenericClient>(
+    client: &mut C,
+    outpoints: &[OutPoint],
+) -> Result<(), postgres::Error> {
+    execute_each(
+        client,
+        "DELETE FROM utxo WHERE hash = $1 AND pos = $2",
+        outpoints,
+        |client, stmt, x| client.execute(stmt, &[&x.hash.encode(), &i64::from(x.index)]),
+    )
+}
+
+fn write_tx<C: GenericClient>(
+    client: &mut C,
+    entries: &[TxEntryWriteDB],
+) -> Result<(), postgres::Error> {
+    execute_each(
+        client,
+        "INSERT INTO tx (hash, height, blockindex, txsize, satoshis) VALUES ($1, $2, $3, $4, $5) \
+         ON CONFLICT (hash) DO UPDATE SET height = EXCLUDED.height, \
+         blockindex = EXCLUDED.blockindex, txsize = EXCLUDED.txsize, satoshis = EXCLUDED.satoshis",
+        entries,
+        |client, stmt, tx| {
+            client.execute(
+                stmt,
+                &[
+                    &tx.hash.encode(),
+                    &(tx.height as i64),
+                    &i64::from(tx.blockindex),
+                    &i64::from(tx.size),
+                    &(tx.satoshis as i64),
+                ],
+            )
+        },
+    )
+}
+
+fn delete_mempool<C: GenericClient>(
+    client: &mut C,
+    hashes: &[Hash256],
+) -> Result<(), postgres::Error> {
+    execute_each(
+        client,
+        "DELETE FROM mempool WHERE hash = $1",
+        hashes,
+        |client, stmt, x| client.execute(stmt, &[&x.encode()]),
+    )
+}
+
+fn write_block_header<C: GenericClient>(
+    client: &mut C,
+    header: &BlockHeaderWriteDB,
+) -> Result<(), postgres::Error> {
+    client.execute(
+        r#"INSERT INTO blocks
+        (height, hash, version, prev_hash, merkle_root, timestamp, bits, nonce, "offset", blocksize, numtxs, utxo_commitment)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)"#,
+        &[
+            &i64::from(header.height),
+            &header.hash.encode(),
+            &i64::from(header.version),
+            &header.prev_hash.encode(),
+            &header.merkle_root.encode(),
+            &i64::from(header.timestamp),
+            &i64::from(header.bits),
+            &i64::from(header.nonce),
+            &(header.position as i64),
+            &i64::from(header.blocksize),
+            &i64::from(header.numtxs),
+            &header.utxo_commitment.map(|commitment| commitment.encode()),
+        ],
+    )?;
+    Ok(())
+}
+
+// Each migration is run in a transaction, together with recording its version
+const MIGRATIONS: &[Migration<&str>] = &[
+    Migration {
+        version: 1,
+        description: "create tables",
+        apply: r#"
+        CREATE TABLE IF NOT EXISTS blocks (
+            height bigint not null,
+            hash varchar(64) not null,
+            version bigint not null,
+            prev_hash varchar(64) not null,
+            merkle_root varchar(64) not null,
+            timestamp bigint not null,
+            bits bigint not null,
+            nonce bigint not null,
+            "offset" bigint not null,
+            blocksize bigint not null,
+            numtxs bigint not null,
+            CONSTRAINT pk_blocks PRIMARY KEY (hash));
+        CREATE INDEX IF NOT EXISTS idx_blocks_height ON blocks (height);
+        CREATE TABLE IF NOT EXISTS orphans (
+            height bigint not null,
+            hash varchar(64) not null,
+            version bigint not null,
+            prev_hash varchar(64) not null,
+            merkle_root varchar(64) not null,
+            timestamp bigint not null,
+            bits bigint not null,
+            nonce bigint not null,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP);
+        CREATE TABLE IF NOT EXISTS utxo (
+            hash varchar(64) not null,
+            pos bigint not null,
+            satoshis bigint not null,
+            height integer not null,
+            pubkeyhash varchar(64),
+            CONSTRAINT pk_utxo PRIMARY KEY (hash, pos));
+        CREATE INDEX IF NOT EXISTS speed_key ON utxo (pubkeyhash);
+        CREATE INDEX IF NOT EXISTS idx_utxo_height ON utxo (height);
+        CREATE TABLE IF NOT EXISTS tx (
+            hash varchar(64) not null,
+            height bigint not null,
+            blockindex bigint not null,
+            txsize bigint not null,
+            satoshis bigint not null,
+            CONSTRAINT pk_tx PRIMARY KEY (hash));
+        CREATE INDEX IF NOT EXISTS idx_tx_height_blockindex ON tx (height, blockindex);
+        CREATE TABLE IF NOT EXISTS mempool (
+            hash varchar(64) not null,
+            locktime bigint not null,
+            fee bigint not null,
+            time bigint not null,
+            tx text not null,
+            CONSTRAINT pk_mempool PRIMARY KEY (hash));
+        CREATE TABLE IF NOT EXISTS collection (
+            hash varchar(64),
+            name varchar(64),
+            tx text,
+            CONSTRAINT pk_collection PRIMARY KEY (hash, name));
+        CREATE INDEX IF NOT EXISTS collect_name ON collection (name);
+        CREATE TABLE IF NOT EXISTS addr (ip text, services bigint, port integer);
+        CREATE TABLE IF NOT EXISTS connect (date varchar(64), ip varchar(64), event varchar(64));
+    "#,
+    },
+    Migration {
+        version: 2,
+        description: "add block utxo commitment",
+        apply: "ALTER TABLE blocks ADD COLUMN IF NOT EXISTS utxo_commitment varchar(64);",
+    },
+    // Monitors are named within their owner, the existing rows belong to the operator
+    Migration {
+        version: 3,
+        description: "add collection owner",
+        apply: r#"
+        ALTER TABLE collection ADD COLUMN IF NOT EXISTS owner varchar(64) NOT NULL DEFAULT '';
+        ALTER TABLE collection DROP CONSTRAINT IF EXISTS pk_collection;
+        ALTER TABLE collection ADD CONSTRAINT pk_collection PRIMARY KEY (owner, name, hash);
+    "#,
+    },
+];
+
+/// The PostgreSQL store, selected by a `postgres://` database url.
+///
+/// Uses the same tables and columns as the MySQL store.
+pub struct PostgresBackend {
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+    // Retry database writes
+    ms_delay: u64,
+    retries: usize,
+}
+
+impl PostgresBackend {
+    pub fn new(url: &str, config: &DatabaseConfig) -> Result<Self, String> {
+        let manager = PostgresConnectionManager::new(
+            url.parse()
+                .map_err(|err| format!("Invalid PostgreSQL url: {err:?}"))?,
+            NoTls,
+        );
+        let pool = Pool::builder().build(manager).map_err(|err| {
+            log::error!(
+                "Problem connecting to database. Check database is connected and configuration is correct: {err:?}"
+            );
+            format!(
+                "Problem connecting to database. Check database is connected and database connection configuration is correct: {err:?}"
+            )
+        })?;
+        Ok(PostgresBackend {
+            pool,
+            ms_delay: config.ms_delay,
+            retries: config.retries,
+        })
+    }
+}
+
+impl StorageBackend for PostgresBackend {
+    fn open(&self, label: &str) -> Result<Box<dyn Storage>, String> {
+        let client = self.pool.get().map_err(|err| {
+            log::error!("Unable to get {label} database connection: {err:?}");
+            format!("Unable to get {label} database connection")
+        })?;
+        Ok(Box::new(PostgresStorage {
+            client,
+            ms_delay: self.ms_delay,
+            retries: self.retries,
+        }))
+    }
+}
+
+struct PostgresStorage {
+    client: PooledClient,
+    ms_delay: u64,
+    retries: usize,
+}
+
+// SQLSTATE class 23 is an integrity constraint violation
+fn is_constraint_error(err: &postgres::Error) -> bool {
+    err.code().is_some_and(|code| code.code().starts_with("23"))
+}
+
+// A broken constraint is marked, so that the write is not retried
+fn write_error(err: &postgres::Error) -> String {
+    if is_constraint_error(err) {
+        constraint_violation(&format!("{err:?}"))
+    } else {
+        format!("{err:?}")
+    }
+}
+
+fn transaction<F>(client: &mut PooledClient, operation: &mut F) -> Result<(), postgres::Error>
+where
+    F: FnMut(&mut Transaction) -> Result<(), postgres::Error>,
+{
+    let mut txn = client.transaction()?;
+    operation(&mut txn)?;
+    txn.commit()
+}
+
+impl PostgresStorage {
+    // Run a write, retrying on failure unless the write breaks a constraint
+    fn with_retry<T, F>(&mut self, mut operation: F) -> Result<T, String>
+    where
+        F: FnMut(&mut PooledClient) -> Result<T, postgres::Error>,
+    {
+        let client = &mut self.client;
+        retry(
+            delay::Fixed::from_millis(self.ms_delay).take(self.retries),
+            || match operation(client) {
+                Ok(value) => OperationResult::Ok(value),
+                Err(err) if is_constraint_error(&err) => OperationResult::Err(err),
+                Err(err) => OperationResult::Retry(err),
+            },
+        )
+        .map_err(|err| write_error(&err.error))
+    }
+
+    // Run a write once, for the writes queued to the database writer, which retries them itself
+    fn once<T, F>(&mut self, operation: F) -> Result<T, String>
+    where
+        F: FnOnce(&mut PooledClient) -> Result<T, postgres::Error>,
+    {
+        operation(&mut self.client).map_err(|err| write_error(&err))
+    }
+
+    // Run the writes in one transaction, retrying on failure
+    fn in_transaction<F>(&mut self, mut operation: F) -> Result<(), String>
+    where
+        F: FnMut(&mut Transaction) -> Result<(), postgres::Error>,
+    {
+        self.with_retry(|client| transaction(client, &mut operation))
+    }
+
+    // Run the writes in one transaction once, for the database writer
+    fn once_in_transaction<F>(&mut self, mut operation: F) -> Result<(), String>
+    where
+        F: FnMut(&mut Transaction) -> Result<(), postgres::Error>,
+    {
+        self.once(|client| transaction(client, &mut operation))
+    }
+
+    fn schema_version(&mut self) -> Result<u32, String> {
+        self.client
+            .batch_execute(
+                r"CREATE TABLE IF NOT EXISTS schema_version (
+                version bigint not null,
+                description varchar(255) not null,
+                applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                CONSTRAINT pk_schema_version PRIMARY KEY (version));",
+            )
+            .map_err(|err| format!("Unable to create schema_version table: {err:?}"))?;
+        let row = self
+            .client
+            .query_one(
+                "SELECT COALESCE(MAX(version), 0) AS version FROM schema_version",
+                &[],
+            )
+            .map_err(|err| format!("Unable to read schema version: {err:?}"))?;
+        column_u32(&row, "version")
+    }
+}
+
+impl Storage for PostgresStorage {
+    fn migrate(&mut self) -> Result<(u32, u32), String> {
+        let current = self.schema_version()?;
+        let mut version = current;
+        for migration in pending(MIGRATIONS, current)? {
+            log::info!(
+                "Applying database migration {}: {}",
+                migration.version,
+                migration.description
+            );
+            self.in_transaction(|txn| {
+                txn.batch_execute(migration.apply)?;
+                txn.execute(
+                    "INSERT INTO schema_version (version, description) VALUES ($1, $2)",
+                    &[&i64::from(migration.version), &migration.description],
+                )?;
+                Ok(())
+            })
+            .map_err(|err| {
+                format!(
+                    "Database migration {} ({}) failed: {err}",
+                    migration.version, migration.description
+                )
+            })?;
+            version = migration.version;
+        }
+        Ok((current, version))
+    }
+
+    fn ping(&mut self) -> Result<(), String> {
+        self.client
+            .query_one("SELECT 1", &[])
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    fn utxo_batch_write(&mut self, entries: &[UtxoEntryDB]) -> Result<(), String> {
+        self.once_in_transaction(|txn| write_utxo(txn, entries))
+    }
+
+    fn utxo_batch_delete(&mut self, outpoints: &[OutPoint]) -> Result<(), String> {
+        self.once_in_transaction(|txn| delete_utxo(txn, outpoints))
+    }
+
+    fn tx_batch_write(&mut self, entries: &[TxEntryWriteDB]) -> Result<(), String> {
+        self.in_transaction(|txn| write_tx(txn, entries))
+    }
+
+    fn mempool_batch_write(&mut self, entries: &[MempoolEntryDB]) -> Result<(), String> {
+        self.once_in_transaction(|txn| {
+            execute_each(
+                txn,
+                "INSERT INTO mempool (hash, locktime, fee, time, tx) VALUES ($1, $2, $3, $4, $5) \
+                 ON CONFLICT (hash) DO NOTHING",
+                entries,
+                |txn, stmt, entry| {
+                    txn.execute(
+                        stmt,
+                        &[
+                            &entry.hash.encode(),
+                            &i64::from(entry.locktime),
+                            &entry.fee,
+                            &(entry.age as i64),
+                            &entry.tx,
+                        ],
+                    )
+                },
+            )
+        })
+    }
+
+    fn mempool_batch_delete(&mut self, hashes: &[Hash256]) -> Result<(), String> {
+        self.in_transaction(|txn| delete_mempool(txn, hashes))
+    }
+
+    fn block_header_write(&mut self, header: &BlockHeaderWriteDB) -> Result<(), String> {
+        self.with_retry(|client| write_block_header(&mut **client, header))
+    }
+
+    fn orphan_block_header_write(
+        &mut self,
+        header: &OrphanBlockHeaderWriteDB,
+    ) -> Result<(), String> {
+        let hash = header.hash.encode();
+        let prev_hash = header.prev_hash.encode();
+        let merkle_root = header.merkle_root.encode();
+
+        self.once(|client| {
+            client.execute(
+                r"INSERT INTO orphans (height, hash, version, prev_hash, merkle_root, timestamp, bits, nonce)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                &[
+                    &i64::from(header.height),
+                    &hash,
+                    &i64::from(header.version),
+                    &prev_hash,
+                    &merkle_root,
+                    &i64::from(header.timestamp),
+                    &i64::from(header.bits),
+                    &i64::from(header.nonce),
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn block_header_delete(&mut self, hash: &Hash256) -> Result<(), String> {
+        let hash = hash.encode();
+        self.once(|client| client.execute("DELETE FROM blocks WHERE hash = $1", &[&hash]))?;
+        Ok(())
+    }
+
+    fn block_positions_write(&mut self, positions: &[(Hash256, u64)]) -> Result<(), String> {
+        self.in_transaction(|txn| {
+            for (hash, position) in positions.iter() {
+                txn.execute(
+                    r#"UPDATE blocks SET "offset" = $1 WHERE hash = $2"#,
+                    &[&(*position as i64), &hash.encode()],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn tx_delete_at_height(&mut self, height: u32) -> Result<(), String> {
+        self.once(|client| {
+            client.execute("DELETE FROM tx WHERE height = $1", &[&i64::from(height)])
+        })?;
+        Ok(())
+    }
+
+    fn utxo_delete_at_height(&mut self, height: u32) -> Result<(), String> {
+        let Ok(height) = i32::try_from(height) else {
+            return Err(format!("Block height {height} out of range for utxo table"));
+        };
+        self.once(|client| client.execute("DELETE FROM utxo WHERE height = $1", &[&height]))?;
+        Ok(())
+    }
+
+    // All the writes of the blocks are committed in one transaction, the headers last
+    fn block_write(&mut self, block: &BlockWriteDB) -> Result<(), String> {
+        self.once_in_transaction(|txn| {
+            write_utxo(txn, &block.utxo_writes)?;
+            delete_utxo(txn, &block.utxo_deletes)?;
+            write_tx(txn, &block.tx_writes)?;
+            delete_mempool(txn, &block.mempool_deletes)?;
+            for header in block.headers.iter() {
+                write_block_header(txn, header)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_utxo(&mut self, outpoint: &OutPoint) -> Result<Option<UtxoEntryDB>, String> {
+        let hash = outpoint.hash.encode();
+        let row = self
+            .client
+            .query_opt(
+                "SELECT satoshis, height, pubkeyhash FROM utxo WHERE hash = $1 AND pos = $2",
+                &[&hash, &i64::from(outpoint.index)],
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        Ok(Some(UtxoEntryDB {
+            hash,
+            pos: outpoint.index,
+            satoshis: row.try_get("satoshis").map_err(|err| format!("{err:?}"))?,
+            height: row.try_get("height").map_err(|err| format!("{err:?}"))?,
+            pubkeyhash: column_str(&row, "pubkeyhash")?,
+        }))
+    }
+
+    fn tx_height(&mut self, hash: &Hash256) -> Result<Option<u32>, String> {
+        let row = self
+            .client
+            .query_opt("SELECT height FROM tx WHERE hash = $1", &[&hash.encode()])
+            .map_err(|err| format!("{err:?}"))?;
+        row.map(|row| column_u32(&row, "height")).transpose()
+    }
+
+    fn tx_location(&mut self, hash: &Hash256) -> Result<Option<(u64, u32)>, String> {
+        let row = self
+            .client
+            .query_opt(
+                r#"SELECT blocks."offset", tx.blockindex FROM tx
+                INNER JOIN blocks ON blocks.height = tx.height WHERE tx.hash = $1 LIMIT 1"#,
+                &[&hash.encode()],
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let position: i64 = row
+            .try_get("offset")
+            .map_err(|err| format!("Unable to read column offset: {err:?}"))?;
+        Ok(Some((position as u64, column_u32(&row, "blockindex")?)))
+    }
+
+    fn mempool_tx(&mut self, hash: &Hash256) -> Result<Option<String>, String> {
+        let row = self
+            .client
+            .query_opt("SELECT tx FROM mempool WHERE hash = $1", &[&hash.encode()])
+            .map_err(|err| format!("{err:?}"))?;
+        row.map(|row| column_str(&row, "tx")).transpose()
+    }
+
+    fn max_tx_height(&mut self) -> Result<Option<u32>, String> {
+        let row = self
+            .client
+            .query_one("SELECT MAX(height) AS height FROM tx", &[])
+            .map_err(|err| format!("{err:?}"))?;
+        let height: Option<i64> = row
+            .try_get("height")
+            .map_err(|err| format!("Unable to read column height: {err:?}"))?;
+        height
+            .map(|height| {
+                u32::try_from(height).map_err(|_| format!("Tx height {height} out of range"))
+            })
+            .transpose()
+    }
+
+    fn max_utxo_height(&mut self) -> Result<Option<u32>, String> {
+        // Mempool utxo have a negative height
+        let row = self
+            .client
+            .query_one(
+                "SELECT MAX(height) AS height FROM utxo WHERE height >= 0",
+                &[],
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        let height: Option<i32> = row
+            .try_get("height")
+            .map_err(|err| format!("Unable to read column height: {err:?}"))?;
+        Ok(height.map(|height| height as u32))
+    }
+
+    fn orphan_exists(&mut self, hash: &Hash256) -> Result<bool, String> {
+        self.client
+            .query_opt(
+                "SELECT 1 FROM orphans WHERE hash = $1 LIMIT 1",
+                &[&hash.encode()],
+            )
+            .map(|row| row.is_some())
+            .map_err(|err| format!("{err:?}"))
+    }
+
+    fn load_mempool(&mut self) -> Result<Vec<Hash256>, String> {
+        let rows = self
+            .client
+            .query("SELECT hash FROM mempool ORDER BY time", &[])
+            .map_err(|err| format!("{err:?}"))?;
+        let hashes = rows
+            .iter()
+            .map(|row| column_str(row, "hash"))
+            .collect::<Result<Vec<String>, String>>()?;
+        Ok(hashes
+            .iter()
+            .filter_map(|hash| decode_stored_hash("mempool tx", hash))
+            .collect())
+    }
+
+    fn load_tx(&mut self) -> Result<Vec<(Hash256, u32)>, String> {
+        let rows = self
+            .client
+            .query("SELECT hash, height FROM tx ORDER BY height", &[])
+            .map_err(|err| format!("{err:?}"))?;
+        let mut txs = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            let hash = column_str(row, "hash")?;
+            if let Some(hash) = decode_stored_hash("tx", &hash) {
+                txs.push((hash, column_u32(row, "height")?));
+            }
+        }
+        Ok(txs)
+    }
+
+    fn load_block_headers(&mut self) -> Result<Vec<(u32, BlockHeader)>, String> {
+        let rows = self
+            .client
+            .query(
+                "SELECT height, version, prev_hash, merkle_root, timestamp, bits, nonce \
+                 FROM blocks ORDER BY height asc",
+                &[],
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        let mut headers = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            let Some(prev_hash) = decode_stored_hash("prev_hash", &column_str(row, "prev_hash")?)
+            else {
+                continue;
+            };
+            let Some(merkle_root) =
+                decode_stored_hash("merkle_root", &column_str(row, "merkle_root")?)
+            else {
+                continue;
+            };
+            let header = BlockHeader {
+                version: column_u32(row, "version")?,
+                prev_hash,
+                merkle_root,
+                timestamp: column_u32(row, "timestamp")?,
+                bits: column_u32(row, "bits")?,
+                nonce: column_u32(row, "nonce")?,
+            };
+            headers.push((column_u32(row, "height")?, header));
+        }
+        Ok(headers)
+    }
+
+    fn export_utxo(
+        &mut self,
+        visit: &mut dyn FnMut(UtxoEntryDB) -> Result<(), String>,
+    ) -> Result<Option<(u32, BlockHeader)>, String> {
+        let mut txn = self
+            .client
+            .build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()
+            .map_err(|err| format!("{err:?}"))?;
+        let row = txn
+            .query_opt(
+                "SELECT height, version, prev_hash, merkle_root, timestamp, bits, nonce \
+                 FROM blocks ORDER BY height DESC LIMIT 1",
+                &[],
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        let tip = match row {
+            Some(row) => {
+                let height = column_u32(&row, "height")?;
+                let (Some(prev_hash), Some(merkle_root)) = (
+                    decode_stored_hash("prev_hash", &column_str(&row, "prev_hash")?),
+                    decode_stored_hash("merkle_root", &column_str(&row, "merkle_root")?),
+                ) else {
+                    return Err(format!("Invalid block header stored at height {height}"));
+                };
+                let header = BlockHeader {
+                    version: column_u32(&row, "version")?,
+                    prev_hash,
+                    merkle_root,
+                    timestamp: column_u32(&row, "timestamp")?,
+                    bits: column_u32(&row, "bits")?,
+                    nonce: column_u32(&row, "nonce")?,
+                };
+                Some((height, header))
+            }
+            None => None,
+        };
+
+        // Read through a portal, so that the whole table is not held in memory
+        let portal = txn
+            .bind(
+                "SELECT hash, pos, satoshis, height, pubkeyhash FROM utxo",
+                &[],
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        loop {
+            let rows = txn
+                .query_portal(&portal, 10_000)
+                .map_err(|err| format!("{err:?}"))?;
+            if rows.is_empty() {
+                break;
+            }
+            for row in rows.iter() {
+                visit(UtxoEntryDB {
+                    hash: column_str(row, "hash")?,
+                    pos: column_u32(row, "pos")?,
+                    satoshis: row.try_get("satoshis").map_err(|err| format!("{err:?}"))?,
+                    height: row.try_get("height").map_err(|err| format!("{err:?}"))?,
+                    pubkeyhash: column_str(row, "pubkeyhash")?,
+                })?;
+            }
+        }
+        Ok(tip)
+    }
+
+    fn utxo_commitment(&mut self, height: u32) -> Result<Option<(Hash256, Hash256)>, String> {
+        let row = self
+            .client
+            .query_opt(
+                "SELECT hash, utxo_commitment FROM blocks \
+                 WHERE height = $1 AND utxo_commitment IS NOT NULL LIMIT 1",
+                &[&i64::from(height)],
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        match (
+            decode_stored_hash("hash", &column_str(&row, "hash")?),
+            decode_stored_hash("utxo_commitment", &column_str(&row, "utxo_commitment")?),
+        ) {
+            (Some(hash), Some(commitment)) => Ok(Some((hash, commitment))),
+            _ => Err(format!("Invalid utxo commitment stored at height {height}")),
+        }
+    }
+
+    fn collection_tx_write(
+        &mut self,
+        key: &MonitorKey,
+        hash: &Hash256,
+        tx_hex: &str,
+    ) -> Result<(), String> {
+        let hash = hash.encode();
+        self.with_retry(|client| {
+            client.execute(
+                // Ignore rows already written, e.g. by a backfill of the same monitor
+                "INSERT INTO collection (hash, owner, name, tx) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+                &[&hash, &key.stored_owner(), &key.name, &tx_hex],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn collection_delete(&mut self, key: &MonitorKey) -> Result<(), String> {
+        self.with_retry(|client| {
+            client.execute(
+                "DELETE FROM collection WHERE owner = $1 AND name = $2",
+                &[&key.stored_owner(), &key.name],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn load_collection_txs(&mut self, key: &MonitorKey) -> Result<Vec<Hash256>, String> {
+        let rows = self
+            .client
+            .query(
+                "SELECT hash FROM collection WHERE owner = $1 AND name = $2",
+                &[&key.stored_owner(), &key.name],
+            )
+            .map_err(|err| format!("{err:?}"))?;
+        let hashes = rows
+            .iter()
+            .map(|row| column_str(row, "hash"))
+            .collect::<Result<Vec<String>, String>>()?;
+        Ok(hashes
+            .iter()
+            .filter_map(|hash| decode_stored_hash("collection tx", hash))
+            .collect())
+    }
+
+    fn load_addresses(&mut self) -> Result<Vec<String>, String> {
+        let rows = self
+            .client
+            .query("SELECT ip FROM addr", &[])
+            .map_err(|err| format!("{err:?}"))?;
+        rows.iter().map(|row| column_str(row, "ip")).collect()
+    }
+
+    fn addr_write(&mut self, ip: &str, services: u64, port: u16) -> Result<(), String> {
+        self.with_retry(|client| {
+            client.execute(
+                "INSERT INTO addr (ip, services, port) VALUES ($1, $2, $3)",
+                // The service flags are stored bit for bit
+                &[&ip, &(services as i64), &i32::from(port)],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn connect_event_write(&mut self, date: &str, ip: &str, event: &str) -> Result<(), String> {
+        self.with_retry(|client| {
+            client.execute(
+                "INSERT INTO connect (date, ip, event) VALUES ($1, $2, $3)",
+                &[&date, &ip, &event],
+            )
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{StorageType, WriteErrorPolicy};
+
+    #[test]
+    fn postgres_storage_round_trip() {
+        let Some(url) = std::env::var("UAAS_TEST_POSTGRES_URL").ok() else {
+            eprintln!("skipping postgres_storage_round_trip: UAAS_TEST_POSTGRES_URL not set");
+            return;
+        };
+        let config = DatabaseConfig {
+            backend: StorageType::Sql,
+            mysql_url: url.clone(),
+            mysql_url_docker: url.clone(),
+            embedded_path: String::new(),
+            ms_delay: 300,
+            retries: 3,
+            startup_repair: true,
+            write_queue_size: 100,
+            on_write_error: WriteErrorPolicy::Retry,
+            utxo_cache_size: 1000,
+        };
+        let mut storage = PostgresBackend::new(&url, &config)
+            .expect("connect to UAAS_TEST_POSTGRES_URL")
+            .open("postgres test")
+            .expect("get connection for postgres test");
+        storage.migrate().expect("migrate database");
+
+        let hash = Hash256([7; 32]);
+        let entry = UtxoEntryDB {
+            hash: hash.encode(),
+            pos: 3,
+            satoshis: 1234,
+            height: -1,
+            pubkeyhash: "abcd".to_string(),
+        };
+        storage
+            .utxo_batch_write(std::slice::from_ref(&entry))
+            .expect("write mempool utxo");
+        // Written again when the tx is mined
+        storage
+            .utxo_batch_write(&[UtxoEntryDB {
+                height: 100,
+                ..entry.clone()
+            }])
+            .expect("upsert utxo");
+        let outpoint = OutPoint { hash, index: 3 };
+        let stored = storage
+            .get_utxo(&outpoint)
+            .expect("read utxo")
+            .expect("utxo present");
+        assert_eq!((stored.satoshis, stored.height), (1234, 100));
+
+        storage
+            .utxo_batch_delete(std::slice::from_ref(&outpoint))
+            .expect("delete utxo");
+        assert!(storage.get_utxo(&outpoint).expect("read utxo").is_none());
+    }
+}
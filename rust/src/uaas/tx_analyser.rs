@@ -1,20 +1,21 @@
-use std::{cmp, sync::mpsc};
-
-use mysql::{prelude::*, Pool, PooledConn};
+use std::{cmp, panic, sync::Arc, thread};
 
 use chain_gang::{
-    messages::{Block, Tx, TxOut},
+    messages::{Tx, TxOut},
     network::Network,
     script::Script,
     util::Hash256,
 };
 
 use crate::{
-    config::{CollectionConfig, Config},
+    config::{CollectionConfig, Config, SyncMode},
     dynamic_config::DynamicConfig,
     uaas::{
-        collection::{CollectionDatabase, WorkingCollection},
-        database::DBOperationType,
+        collection::{CollectionDatabase, WorkingCollection, BROADCAST_COLLECTION},
+        database::{BlockWriteDB, DBSender},
+        metrics::UtxoCacheMetrics,
+        monitor::{MonitorKey, MonitorRegistry, MonitorSource},
+        storage::StorageBackend,
         txdb::TxDB,
         utxo::Utxo,
     },
@@ -38,43 +39,127 @@ fn script_to_pubkeyhash(locking_script: &Script) -> String {
     "unknown".to_string()
 }
 
+fn is_spendable(vout: &TxOut) -> bool {
+    // Return true if the transaction output is spendable,
+    // and therefore should go in the unspent outputs (UTXO) set.
+    // OP_FALSE OP_RETURN (0x00, 0x61) is known to be unspendable.
+
+    if vout.lock_script.0.len() < 2 {
+        // We are assuming that [] is spendable
+        true
+    } else {
+        vout.lock_script.0[0..2] != vec![0x00, 0x6a]
+    }
+}
+
+// Blocks are split between the workers in chunks of at least this many txs,
+// so small blocks are processed on the calling thread
+const MIN_TXS_PER_WORKER: usize = 1000;
+
+// The work on a tx that does not depend on the txs before it, which can be done in parallel
+#[derive(Debug, PartialEq)]
+struct TxSummary {
+    hash: Hash256,
+    // (index, satoshis, pubkeyhash) of the spendable outputs
+    outputs: Vec<(usize, i64, String)>,
+    // Whether the locking scripts match each collection's pattern, in collection order
+    script_matches: Vec<bool>,
+}
+
+impl TxSummary {
+    fn new(tx: &Tx, collection: &[WorkingCollection]) -> Self {
+        let outputs = tx
+            .outputs
+            .iter()
+            .enumerate()
+            .filter(|(_index, vout)| is_spendable(vout))
+            .map(|(index, vout)| {
+                (
+                    index,
+                    vout.satoshis,
+                    script_to_pubkeyhash(&vout.lock_script),
+                )
+            })
+            .collect();
+        TxSummary {
+            hash: tx.hash(),
+            outputs,
+            script_matches: collection
+                .iter()
+                .map(|c| c.match_any_locking_script(tx))
+                .collect(),
+        }
+    }
+}
+
+// Summarise the txs of a block on up to workers threads, returning the summaries in block order
+fn summarise_txs(txns: &[Tx], collection: &[WorkingCollection], workers: usize) -> Vec<TxSummary> {
+    let chunk_size = txns.len().div_ceil(workers.max(1)).max(MIN_TXS_PER_WORKER);
+    if txns.len() <= chunk_size {
+        return txns
+            .iter()
+            .map(|tx| TxSummary::new(tx, collection))
+            .collect();
+    }
+    thread::scope(|scope| {
+        let handles: Vec<_> = txns
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|tx| TxSummary::new(tx, collection))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|err| panic::resume_unwind(err))
+            })
+            .collect()
+    })
+}
+
 pub struct TxAnalyser {
     save_txs: bool,
+    // Only collections are kept, there is no utxo set or mempool, see SyncMode::Headers
+    headers_only: bool,
     // Database interface
     pub txdb: TxDB,
     // Unspent tx - make public so logic can write to database when in ready state
     pub utxo: Utxo,
-    // Database connection
-    conn: PooledConn,
     // Collections
     collection: Vec<WorkingCollection>,
     collection_db: CollectionDatabase,
     dynamic_config: DynamicConfig,
+    // Monitor status shared with the REST API
+    monitors: Arc<MonitorRegistry>,
     network: Network,
+    // Threads used to summarise the txs of a block
+    workers: usize,
 }
 
 impl TxAnalyser {
-    fn pool_conn(pool: &Pool, label: &str) -> Result<PooledConn, String> {
-        pool.get_conn().map_err(|err| {
-            log::error!("Unable to get {label} database connection: {err:?}");
-            format!("Unable to get {label} database connection")
-        })
-    }
-
     pub fn new(
         config: &Config,
-        pool: Pool,
-        tx: mpsc::Sender<DBOperationType>,
+        backend: &dyn StorageBackend,
+        tx: DBSender,
+        monitors: Arc<MonitorRegistry>,
+        utxo_metrics: Arc<UtxoCacheMetrics>,
     ) -> Result<Self, String> {
-        let tx_conn = Self::pool_conn(&pool, "tx analyser")?;
-        let utxo_conn = Self::pool_conn(&pool, "utxo")?;
-        let txdb_conn = Self::pool_conn(&pool, "txdb")?;
-        let collection_conn = Self::pool_conn(&pool, "collection")?;
+        let utxo_storage = backend.open("utxo")?;
+        let txdb_storage = backend.open("txdb")?;
+        let collection_storage = backend.open("collection")?;
 
-        let save_txs = config
+        let settings = config
             .get_network_settings()
-            .map_err(|err| err.to_string())?
-            .save_txs;
+            .map_err(|err| err.to_string())?;
+        let save_txs = settings.save_txs;
+        let headers_only = settings.sync_mode == SyncMode::Headers;
         let network = config.get_network().map_err(|err| err.to_string())?;
         let dynamic_config = DynamicConfig::new(config);
         let mut collection: Vec<WorkingCollection> = Vec::new();
@@ -82,14 +167,20 @@ impl TxAnalyser {
         // Load the collections
         for c in &config.collection {
             match WorkingCollection::new(c.clone(), network) {
-                Ok(wc) => collection.push(wc),
+                Ok(wc) => {
+                    monitors.register(c, MonitorSource::Static);
+                    collection.push(wc);
+                }
                 Err(e) => println!("Error parsing collection {:?}", e),
             }
         }
         // load the dynamic collection
         for c in &dynamic_config.collection {
             match WorkingCollection::new(c.clone(), network) {
-                Ok(wc) => collection.push(wc),
+                Ok(wc) => {
+                    monitors.register(c, MonitorSource::Dynamic);
+                    collection.push(wc);
+                }
                 Err(e) => println!("Error parsing collection {:?}", e),
             }
         }
@@ -100,128 +191,95 @@ impl TxAnalyser {
 
         Ok(TxAnalyser {
             save_txs,
-            txdb: TxDB::new(txdb_conn, tx.clone(), save_txs),
-            utxo: Utxo::new(utxo_conn, tx),
-            conn: tx_conn,
+            headers_only,
+            txdb: TxDB::new(txdb_storage, tx.clone(), save_txs),
+            utxo: Utxo::new(
+                utxo_storage,
+                tx,
+                config.database.utxo_cache_size,
+                utxo_metrics,
+            ),
             collection,
-            collection_db: CollectionDatabase::new(collection_conn, config),
+            collection_db: CollectionDatabase::new(collection_storage),
             dynamic_config: dynamic_config.clone(),
+            monitors,
             network,
+            workers: thread::available_parallelism().map_or(1, |workers| workers.get()),
         })
     }
 
-    fn create_tables(&mut self) {
-        let tables: Vec<String> = match self.conn.query(
-            "SELECT TABLE_NAME FROM INFORMATION_SCHEMA.TABLES WHERE TABLE_TYPE = 'BASE TABLE';",
-        ) {
-            Ok(tables) => tables,
-            Err(err) => {
-                log::error!("Unable to list database tables for tx analyser: {err:?}");
-                return;
-            }
-        };
-
-        if self.save_txs && !tables.iter().any(|x| x.as_str() == "tx") {
-            self.txdb.create_tx_table();
-        }
-
-        if !tables.iter().any(|x| x.as_str() == "mempool") {
-            self.txdb.create_mempool_table();
-        }
-
-        // utxo
-        if !tables.iter().any(|x| x.as_str() == "utxo") {
-            self.utxo.create_table();
-        }
-
-        // Collection table - one table for all collections
-        if !tables.iter().any(|x| x.as_str() == "collection") {
-            self.collection_db.create_table(&mut self.conn);
-        }
-    }
-
     fn read_tables(&mut self) {
         // Load datastructures from the database tables
-        self.txdb.load_mempool();
+        if !self.headers_only {
+            self.txdb.load_mempool();
+        }
         if self.save_txs {
             self.txdb.load_tx();
         }
 
-        self.utxo.load_utxo();
         // Load Collections
         for c in self.collection.iter_mut() {
-            c.txs = self.collection_db.load_txs(c.name());
+            c.txs = self.collection_db.load_txs(c.key());
+            self.monitors.set_match_count(c.key(), c.txs.len() as u64);
         }
     }
 
     pub fn setup(&mut self) {
         // Do the startup setup that is required for tx analyser
-        self.create_tables();
         self.read_tables();
     }
 
-    fn is_spendable(&self, vout: &TxOut) -> bool {
-        // Return true if the transaction output is spendable,
-        // and therefore should go in the unspent outputs (UTXO) set.
-        // OP_FALSE OP_RETURN (0x00, 0x61) is known to be unspendable.
-
-        if vout.lock_script.0.len() < 2 {
-            // We are assuming that [] is spendable
-            true
-        } else {
-            vout.lock_script.0[0..2] != vec![0x00, 0x6a]
-        }
-    }
-
-    fn process_tx_outputs(&mut self, tx: &Tx, height: i32) {
-        // process the tx outputs and place them in the utxo
-
-        let hash = tx.hash();
-        // Process outputs - add to utxo
-        for (index, vout) in tx.outputs.iter().enumerate() {
-            if self.is_spendable(vout) {
-                // Get public key hash from locking script
-                let pubkeyhash = script_to_pubkeyhash(&vout.lock_script);
-                self.utxo
-                    .add(hash, index, vout.satoshis, height, &pubkeyhash);
-            }
+    fn process_tx_outputs(&mut self, summary: &TxSummary, height: i32) {
+        // place the spendable tx outputs in the utxo
+        for (index, satoshis, pubkeyhash) in &summary.outputs {
+            self.utxo
+                .add(summary.hash, *index, *satoshis, height, pubkeyhash);
         }
     }
 
-    fn process_tx_inputs(&mut self, tx: &Tx, blockindex: usize) {
+    fn process_tx_inputs(&mut self, tx: &Tx, height: i32, blockindex: usize) {
         if blockindex == 0 {
             // if is coinbase (blockindex 0)- nothing to process as these won't be in the utxo
         } else {
-            let _ = tx
-                .inputs
-                .iter()
-                .map(|vin| self.utxo.delete(&vin.prev_output));
+            for vin in tx.inputs.iter() {
+                self.utxo.delete(&vin.prev_output, height);
+            }
         }
     }
 
-    fn process_collection(&mut self, tx: &Tx, is_uaas_broadcast_tx: bool) {
-        for c in self.collection.iter_mut() {
-            // Check to see if we have already processed it if so quit
-            if c.have_tx(tx.hash()) {
-                return;
+    fn process_collection(&mut self, tx: &Tx, summary: &TxSummary, is_uaas_broadcast_tx: bool) {
+        let hash = summary.hash;
+        // Each collection is evaluated independently, so a tx can belong to several collections
+        let mut picked_up = false;
+        for (c, script_match) in self.collection.iter_mut().zip(&summary.script_matches) {
+            // Check to see if this collection has already processed it
+            if c.have_tx(hash) {
+                picked_up = true;
+                continue;
             }
 
-            if (c.track_descendants() && c.is_decendant(tx)) || c.match_any_locking_script(tx) {
+            // Descendants depend on the txs before this one, so are checked here in tx order
+            if *script_match || (c.track_descendants() && c.is_decendant(tx)) {
                 // Save tx hash and write to database
-                c.push(tx.hash());
-                self.collection_db.write_tx_to_database(c.name(), tx);
-                return;
+                c.push(hash);
+                self.collection_db.write_tx_to_database(c.key(), tx);
+                self.monitors.record_match(c.key(), hash);
+                picked_up = true;
             }
         }
         // write to a broadcast collection - if hasn't already been picked up by previous collections
-        if is_uaas_broadcast_tx {
+        if is_uaas_broadcast_tx && !picked_up {
             // get broadcast_collection
-            match self.collection.iter_mut().find(|c| c.name() == "broadcast") {
+            match self
+                .collection
+                .iter_mut()
+                .find(|c| c.name() == BROADCAST_COLLECTION)
+            {
                 Some(broadcast_collection) => {
                     // write to a broadcast collection - if hasn't already been picked up by previous collections
-                    broadcast_collection.push(tx.hash());
+                    broadcast_collection.push(hash);
                     self.collection_db
-                        .write_tx_to_database(broadcast_collection.name(), tx);
+                        .write_tx_to_database(broadcast_collection.key(), tx);
                 }
                 None => {
                     log::warn!("Unable to find broadcast collection");
@@ -230,47 +288,98 @@ impl TxAnalyser {
         }
     }
 
-    pub fn process_block_tx(&mut self, tx: &Tx, height: i32, blockindex: usize) {
+    fn process_block_tx(&mut self, tx: &Tx, summary: &TxSummary, height: i32, blockindex: usize) {
         // Process tx as received in a block from a peer
 
         // process inputs
-        self.process_tx_inputs(tx, blockindex);
+        self.process_tx_inputs(tx, height, blockindex);
 
         // Process outputs
         // Note this will overwrite the utxo outpoints with height = NOT_IN_BLOCK(-1)
         // and utxo entries
-        self.process_tx_outputs(tx, height);
+        self.process_tx_outputs(summary, height);
 
         // Collection processing
-        self.process_collection(tx, false);
+        self.process_collection(tx, summary, false);
     }
 
-    pub fn process_block(&mut self, block: &Block, height: i32) {
-        // Given a block process all the txs in it
-
-        self.txdb.process_block(block, height);
+    // Match the txs of a block against the collections, in headers mode
+    pub fn process_block_collections(&mut self, txns: Vec<Tx>) {
+        let batch_size = self.batch_size();
+        let mut txns = txns.into_iter();
+        loop {
+            let batch: Vec<Tx> = txns.by_ref().take(batch_size).collect();
+            if batch.is_empty() {
+                break;
+            }
+            let summaries = summarise_txs(&batch, &self.collection, self.workers);
+            for (tx, summary) in batch.iter().zip(&summaries) {
+                self.process_collection(tx, summary, false);
+            }
+        }
+    }
 
-        // now process Txs...
-        let _ = block
-            .txns
+    // True if there are monitors for the block txs to be matched against
+    pub fn has_monitors(&self) -> bool {
+        self.collection
             .iter()
-            .enumerate()
-            .map(|(blockindex, tx)| self.process_block_tx(tx, height, blockindex));
+            .any(|c| c.name() != BROADCAST_COLLECTION)
+    }
+
+    // Enough txs for each worker to summarise a full share
+    fn batch_size(&self) -> usize {
+        MIN_TXS_PER_WORKER * self.workers.max(1)
+    }
+
+    pub fn process_block(&mut self, txns: Vec<Tx>, height: i32) -> BlockWriteDB {
+        // Given the txs of a block process them all
+        // They are taken in batches, so each batch is freed once processed rather than holding the
+        // whole block. The tx hashes, output scripts and collection patterns of a batch are worked
+        // out in parallel, then the txs are applied in block order
+        let batch_size = self.batch_size();
+        let mut txns = txns.into_iter();
+        let mut first_index = 0;
+        loop {
+            let batch: Vec<Tx> = txns.by_ref().take(batch_size).collect();
+            if batch.is_empty() {
+                break;
+            }
+            let summaries = summarise_txs(&batch, &self.collection, self.workers);
+            let hashes: Vec<Hash256> = summaries.iter().map(|summary| summary.hash).collect();
+
+            self.txdb
+                .process_block_txs(&batch, &hashes, first_index, height);
+
+            // now process Txs...
+            for (blockindex, (tx, summary)) in (first_index..).zip(batch.iter().zip(&summaries)) {
+                self.process_block_tx(tx, summary, height, blockindex);
+            }
+            first_index += batch.len();
+        }
+
+        // Mempool txs are written before the block that removes them
+        self.txdb.batch_write_mempool();
+        self.take_block_writes()
+    }
 
-        // Do db writes here
-        self.flush_database_cache();
+    // The database writes of the block, which are written together with its header
+    fn take_block_writes(&mut self) -> BlockWriteDB {
+        let (utxo_writes, utxo_deletes) = self.utxo.take_writes();
+        BlockWriteDB {
+            utxo_writes,
+            utxo_deletes,
+            tx_writes: self.txdb.take_tx_entries(),
+            mempool_deletes: self.txdb.take_mempool_deletes(),
+            headers: Vec::new(),
+        }
     }
 
     pub fn flush_database_cache(&mut self) {
         self.utxo.update_db();
-        self.txdb.batch_delete_from_mempool();
         self.txdb.batch_write_mempool();
-        if self.save_txs {
-            self.txdb.batch_write_tx_to_table();
-        }
     }
 
-    fn calc_fee(&self, tx: &Tx) -> i64 {
+    fn calc_fee(&mut self, tx: &Tx) -> i64 {
         // Given the tx attempt to determine the fee, return 0 if unable to calculate
         let mut inputs = 0i64;
         for vin in tx.inputs.iter() {
@@ -292,6 +401,11 @@ impl TxAnalyser {
     pub fn process_standalone_tx(&mut self, tx: &Tx, is_uaas_broadcast_tx: bool) {
         // Process standalone tx as we receive them.
         // Note standalone tx are txs that are not in a block.
+        let summary = TxSummary::new(tx, &self.collection);
+        if self.headers_only {
+            self.process_collection(tx, &summary, is_uaas_broadcast_tx);
+            return;
+        }
         let fee = self.calc_fee(tx);
 
         self.txdb.add_to_mempool(tx, fee);
@@ -299,18 +413,18 @@ impl TxAnalyser {
         // Process inputs
         const NOT_A_COINBASE_TX: usize = 1;
 
-        self.process_tx_inputs(tx, NOT_A_COINBASE_TX);
+        self.process_tx_inputs(tx, NOT_IN_BLOCK, NOT_A_COINBASE_TX);
 
         // Process outputs
-        self.process_tx_outputs(tx, NOT_IN_BLOCK);
+        self.process_tx_outputs(&summary, NOT_IN_BLOCK);
 
         // Collection processing
-        self.process_collection(tx, is_uaas_broadcast_tx);
+        self.process_collection(tx, &summary, is_uaas_broadcast_tx);
 
         self.txdb.batch_write_mempool();
     }
 
-    pub fn tx_exists(&self, hash: Hash256) -> bool {
+    pub fn tx_exists(&mut self, hash: Hash256) -> bool {
         // Return true if txid is in txs or mempool
         // As we may not store all txs we assume that a collection has been setup for any that we are
         // interested in and so we have to search the collections
@@ -322,50 +436,105 @@ impl TxAnalyser {
         self.utxo.handle_orphan_block(height);
     }
 
-    fn is_name_in_collection(&self, name: &str) -> bool {
-        self.collection.iter().any(|c| c.collection.name == name)
+    fn is_in_collection(&self, key: &MonitorKey) -> bool {
+        self.collection.iter().any(|c| c.key() == key)
     }
 
-    fn is_name_in_dynamic_collection(&self, name: &str) -> bool {
+    fn is_in_dynamic_collection(&self, key: &MonitorKey) -> bool {
         self.dynamic_config
             .collection
             .iter()
-            .any(|c| c.name == name)
+            .any(|c| MonitorKey::of(c) == *key)
     }
 
-    pub fn add_monitor(&mut self, monitor: CollectionConfig) {
+    // Return true if the monitor was added
+    pub fn add_monitor(&mut self, monitor: CollectionConfig) -> bool {
         log::info!("add_monitor {:?}", &monitor);
-        // Check name is not in collection
-        if !self.is_name_in_collection(&monitor.name) {
-            // add to collection
-            match WorkingCollection::new(monitor.clone(), self.network) {
-                Ok(wc) => {
-                    self.collection.push(wc);
-                    // add to dynamic config
-                    self.dynamic_config.add(&monitor);
+        // Check the owner does not have a monitor with the name
+        let key = MonitorKey::of(&monitor);
+        if self.is_in_collection(&key) {
+            log::warn!("Monitor {key} already exists");
+            return false;
+        }
+        // add to collection
+        match WorkingCollection::new(monitor.clone(), self.network) {
+            Ok(wc) => {
+                self.collection.push(wc);
+                // add to dynamic config
+                self.dynamic_config.add(&monitor);
+                self.monitors.register(&monitor, MonitorSource::Dynamic);
+                true
+            }
+            Err(e) => {
+                log::error!("Error parsing collection {:?}", e);
+                self.monitors.remove(&key);
+                false
+            }
+        }
+    }
+
+    // Return true if the monitor was updated
+    pub fn update_monitor(&mut self, monitor: CollectionConfig) -> bool {
+        log::info!("update_monitor {:?}", &monitor);
+        // Only dynamic monitors can be changed at runtime
+        let key = MonitorKey::of(&monitor);
+        if !self.is_in_dynamic_collection(&key) {
+            log::warn!("Monitor {key} is not a dynamic monitor");
+            return false;
+        }
+        let mut wc = match WorkingCollection::new(monitor.clone(), self.network) {
+            Ok(wc) => wc,
+            Err(e) => {
+                log::error!("Error parsing collection {:?}", e);
+                return false;
+            }
+        };
+        match self.collection.iter_mut().find(|c| *c.key() == key) {
+            Some(existing) => {
+                if existing.same_filter(&monitor) {
+                    // Keep the txs already collected
+                    wc.txs = std::mem::take(&mut existing.txs);
+                } else {
+                    // The txs matched the old filter, the restarted backfill collects them again
+                    self.collection_db.delete_txs(&key);
+                    self.monitors.set_match_count(&key, 0);
+                }
+                *existing = wc;
+            }
+            None => self.collection.push(wc),
+        }
+        self.dynamic_config.update(&monitor);
+        self.monitors.update(&monitor);
+        true
+    }
+
+    pub fn add_collection_txs(&mut self, key: &MonitorKey, hashes: Vec<Hash256>) {
+        // Add txs found outside of the normal tx processing, e.g. by a backfill
+        if let Some(c) = self.collection.iter_mut().find(|c| c.key() == key) {
+            for hash in hashes {
+                if !c.have_tx(hash) {
+                    c.push(hash);
                 }
-                Err(e) => println!("Error parsing collection {:?}", e),
             }
+            self.monitors.set_match_count(key, c.txs.len() as u64);
         }
     }
 
-    pub fn delete_monitor(&mut self, monitor_name: &str) {
-        log::info!("delete_monitor {}", monitor_name);
+    pub fn delete_monitor(&mut self, key: &MonitorKey) {
+        log::info!("delete_monitor {}", key);
         // Check is in collection & dynamic config
-        if self.is_name_in_dynamic_collection(monitor_name) {
+        if self.is_in_dynamic_collection(key) {
             // Delete from to collection
-            match self
-                .collection
-                .iter()
-                .position(|c| c.collection.name == monitor_name)
-            {
+            match self.collection.iter().position(|c| c.key() == key) {
                 Some(index) => {
                     self.collection.remove(index);
                 }
-                None => println!("Error indexing collection {}", monitor_name),
+                None => println!("Error indexing collection {}", key),
             }
             // Delete from dynamic config
-            self.dynamic_config.delete(monitor_name);
+            self.dynamic_config.delete(key);
+            self.monitors.remove(key);
+            self.collection_db.delete_txs(key);
         }
     }
 }
@@ -387,4 +556,54 @@ mod tests {
 
         assert_eq!(&result, "7c78584493557fac782023a4ad591b64545929d9");
     }
+
+    #[test]
+    fn block_txs_are_summarised_in_block_order() {
+        let collection = WorkingCollection::new(
+            CollectionConfig {
+                name: "p2pkh".to_string(),
+                track_descendants: false,
+                address: None,
+                locking_script_pattern: Some("^76a914".to_string()),
+                owner: None,
+            },
+            Network::BSV_Testnet,
+        )
+        .expect("collection");
+        let p2pkh = Script(
+            hex::decode("76a9147c78584493557fac782023a4ad591b64545929d988ac")
+                .expect("valid test locking script hex"),
+        );
+        let txns: Vec<Tx> = (0..4001)
+            .map(|index| Tx {
+                version: 1,
+                inputs: Vec::new(),
+                outputs: vec![TxOut {
+                    satoshis: index,
+                    lock_script: if index % 3 == 0 {
+                        p2pkh.clone()
+                    } else {
+                        Script(vec![0x00, 0x6a])
+                    },
+                }],
+                lock_time: 0,
+            })
+            .collect();
+        let collection = [collection];
+
+        let serial: Vec<TxSummary> = txns
+            .iter()
+            .map(|tx| TxSummary::new(tx, &collection))
+            .collect();
+        assert_eq!(summarise_txs(&txns, &collection, 4), serial);
+        assert_eq!(serial[3].hash, txns[3].hash());
+        assert_eq!(
+            serial[3].outputs,
+            vec![(0, 3, "7c78584493557fac782023a4ad591b64545929d9".to_string())]
+        );
+        assert_eq!(serial[3].script_matches, vec![true]);
+        // OP_FALSE OP_RETURN outputs are not spendable
+        assert!(serial[4].outputs.is_empty());
+        assert_eq!(serial[4].script_matches, vec![false]);
+    }
 }
@@ -1,33 +1,21 @@
 use std::collections::HashMap;
-use std::sync::mpsc;
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use chain_gang::messages::{Block, Payload, Tx};
+use chain_gang::messages::{Payload, Tx};
 use chain_gang::util::{Hash256, Serializable};
 
 use super::hexslice::HexSlice;
 
-use mysql::prelude::*;
-use mysql::PooledConn;
-
-use super::database::{DBOperationType, MempoolEntryDB, TxEntryWriteDB};
-
-// Used for loading tx from mempool table
-pub struct MempoolEntryReadDB {
-    _hash: String,
-}
-
-// Used for loading tx from tx table
-struct TxEntryDB {
-    hash: String,
-    height: u32,
-}
+use super::database::{DBOperationType, DBSender, MempoolEntryDB, TxEntryWriteDB};
+use super::storage::Storage;
 
 // TxDB - wraps interface to tx and mempool database tables
 
 pub struct TxDB {
-    // Database connection
-    conn: PooledConn,
+    // Persistent store
+    storage: Box<dyn Storage>,
+    // The tx table is not loaded at startup, txs not in `txs` are looked up in storage
+    on_demand: bool,
     // All transactions
     pub txs: HashMap<Hash256, u32>,
     save_txs: bool,
@@ -45,29 +33,20 @@ pub struct TxDB {
     mempool_entries: Vec<MempoolEntryDB>,
 
     // Channel to database
-    tx: mpsc::Sender<DBOperationType>,
+    tx: DBSender,
 }
 
 impl TxDB {
     fn send_db_op(&self, op: DBOperationType) {
-        if self.tx.send(op).is_err() {
-            log::error!("Failed to send tx database operation; channel closed");
+        if let Err(err) = self.tx.send(op) {
+            log::error!("Failed to send tx database operation: {err}");
         }
     }
 
-    fn decode_stored_hash(value: &str) -> Option<Hash256> {
-        match Hash256::decode(value) {
-            Ok(hash) => Some(hash),
-            Err(err) => {
-                log::error!("Invalid stored tx hash {value}: {err:?}");
-                None
-            }
-        }
-    }
-
-    pub fn new(conn: PooledConn, tx: mpsc::Sender<DBOperationType>, save_txs: bool) -> Self {
+    pub fn new(storage: Box<dyn Storage>, tx: DBSender, save_txs: bool) -> Self {
         TxDB {
-            conn,
+            on_demand: storage.loads_on_demand(),
+            storage,
             txs: HashMap::new(),
             save_txs,
             mempool: HashMap::new(),
@@ -78,66 +57,22 @@ impl TxDB {
         }
     }
 
-    pub fn create_tx_table(&mut self) {
-        // Create tx table
-        log::info!("Table tx not found - creating");
-        if let Err(err) = self.conn.query_drop(
-            r"CREATE TABLE tx (
-                hash varchar(64) not null,
-                height int unsigned not null,
-                blockindex int unsigned not null,
-                txsize int unsigned not null,
-                satoshis bigint unsigned not null,
-                CONSTRAINT PK_Entry PRIMARY KEY (hash));",
-        ) {
-            log::error!("Unable to create tx table: {err:?}");
+    pub fn load_tx(&mut self) {
+        if self.on_demand {
+            log::info!("txs read from database on demand");
             return;
         }
-
-        if let Err(err) = self.conn.query_drop(
-            r"CREATE INDEX IF NOT EXISTS idx_tx_height_blockindex ON tx (height, blockindex);",
-        ) {
-            log::error!("Unable to create tx height index: {err:?}");
-        }
-    }
-
-    pub fn create_mempool_table(&mut self) {
-        log::info!("Table mempool not found - creating");
-        if let Err(err) = self.conn.query_drop(
-            r"CREATE TABLE mempool (
-                hash varchar(64) not null,
-                locktime int unsigned not null,
-                fee bigint unsigned not null,
-                time int unsigned not null,
-                tx longtext not null,
-                CONSTRAINT PK_Mempool PRIMARY KEY (hash))",
-        ) {
-            log::error!("Unable to create mempool table: {err:?}");
-        }
-        // Note that tx longtext should be good for 4GB txs
-    }
-
-    pub fn load_tx(&mut self) {
         // Load tx - (tx hash and height) from database
         let start = Instant::now();
 
-        let txs: Vec<TxEntryDB> = match self.conn.query_map(
-            "SELECT hash, height FROM tx ORDER BY height",
-            |(hash, height)| TxEntryDB { hash, height },
-        ) {
+        let txs = match self.storage.load_tx() {
             Ok(txs) => txs,
             Err(err) => {
-                log::error!("Unable to load txs from database: {err:?}");
+                log::error!("Unable to load txs from database: {err}");
                 return;
             }
         };
-
-        for tx in txs {
-            let Some(hash) = Self::decode_stored_hash(&tx.hash) else {
-                continue;
-            };
-            self.txs.insert(hash, tx.height);
-        }
+        self.txs.extend(txs);
         log::info!(
             "{} txs loaded in {} seconds",
             self.txs.len(),
@@ -149,24 +84,15 @@ impl TxDB {
         // load mempool - tx hash and height from database
         let start = Instant::now();
 
-        let txs: Vec<MempoolEntryReadDB> = match self
-            .conn
-            .query_map("SELECT hash FROM mempool ORDER BY time", |hash| {
-                MempoolEntryReadDB { _hash: hash }
-            }) {
-            Ok(txs) => txs,
+        let hashes = match self.storage.load_mempool() {
+            Ok(hashes) => hashes,
             Err(err) => {
-                log::error!("Unable to load mempool from database: {err:?}");
+                log::error!("Unable to load mempool from database: {err}");
                 return;
             }
         };
-
-        for tx in txs {
-            let Some(hash) = Self::decode_stored_hash(&tx._hash) else {
-                continue;
-            };
-            self.mempool.insert(hash, hash);
-        }
+        self.mempool
+            .extend(hashes.into_iter().map(|hash| (hash, hash)));
 
         log::info!(
             "{} Mempool tx Loaded in {} seconds",
@@ -198,7 +124,14 @@ impl TxDB {
         self.tx_entries.push(tx_entry);
     }
 
-    pub fn process_block(&mut self, block: &Block, height: i32) {
+    // Txs of a block from first_index on, with their hashes in block order as they are already worked out
+    pub fn process_block_txs(
+        &mut self,
+        txns: &[Tx],
+        hashes: &[Hash256],
+        first_index: usize,
+        height: i32,
+    ) {
         let height_usize = match height.try_into() {
             Ok(value) => value,
             Err(_) => {
@@ -215,8 +148,8 @@ impl TxDB {
         };
 
         // for each tx in block
-        for (blockindex, tx) in block.txns.iter().enumerate() {
-            let hash = tx.hash();
+        for (blockindex, (tx, hash)) in (first_index..).zip(txns.iter().zip(hashes)) {
+            let hash = *hash;
 
             // if in mempool - remove and append to list of hashes to delete
             if self.mempool.remove(&hash).is_some() {
@@ -240,17 +173,14 @@ impl TxDB {
         }
     }
 
-    pub fn batch_delete_from_mempool(&mut self) {
-        // Batch Delete from mempool
-        self.send_db_op(DBOperationType::MempoolBatchDelete(
-            self.hashes_to_delete.clone(),
-        ));
-        self.hashes_to_delete.clear();
+    // The txs of the block being written
+    pub fn take_tx_entries(&mut self) -> Vec<TxEntryWriteDB> {
+        std::mem::take(&mut self.tx_entries)
     }
 
-    pub fn batch_write_tx_to_table(&mut self) {
-        self.send_db_op(DBOperationType::TxBatchWrite(self.tx_entries.clone()));
-        self.tx_entries.clear();
+    // The mined txs to remove from the mempool table
+    pub fn take_mempool_deletes(&mut self) -> Vec<Hash256> {
+        std::mem::take(&mut self.hashes_to_delete)
     }
 
     pub fn batch_write_mempool(&mut self) {
@@ -294,9 +224,21 @@ impl TxDB {
         self.mempool_entries.push(mempool_entry);
     }
 
-    pub fn tx_exists(&self, hash: Hash256) -> bool {
+    pub fn tx_exists(&mut self, hash: Hash256) -> bool {
         // Return true if txid is in txs or mempool
-        self.txs.contains_key(&hash) || self.mempool.contains_key(&hash)
+        if self.txs.contains_key(&hash) || self.mempool.contains_key(&hash) {
+            return true;
+        }
+        if !self.on_demand {
+            return false;
+        }
+        match self.storage.tx_height(&hash) {
+            Ok(height) => height.is_some(),
+            Err(err) => {
+                log::error!("Unable to read tx {hash:?} from database: {err}");
+                false
+            }
+        }
     }
 
     pub fn handle_orphan_block(&mut self, height: u32) {
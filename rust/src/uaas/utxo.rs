@@ -1,16 +1,18 @@
-use std::collections::HashMap;
-use std::sync::mpsc;
-
-use std::time::Instant;
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+    num::NonZeroUsize,
+    sync::Arc,
+};
 
 use chain_gang::messages::OutPoint;
 use chain_gang::util::Hash256;
+use lru::LruCache;
 
-use mysql::prelude::*;
-use mysql::PooledConn;
-// use mysql::*;
-
-use super::database::{DBOperationType, UtxoEntryDB};
+use super::database::{DBOperationType, DBSender, UtxoEntryDB};
+use super::metrics::UtxoCacheMetrics;
+use super::muhash::MuHash;
+use super::storage::Storage;
 
 // Used to store the unspent txs (UTXO)
 #[derive(Clone)]
@@ -22,128 +24,112 @@ pub struct UtxoEntry {
     pubkeyhash: String,
 }
 
-// provides access to utxo state and wraps interface to utxo table
-pub struct Utxo {
-    // Unspent tx
-    utxo: HashMap<OutPoint, UtxoEntry>,
-    // Database connection
-    conn: PooledConn,
+// The changes taken for one write to the database
+struct Flush {
+    id: u64,
+    // The database operation that writes the changes, once it has been sent
+    sent: Option<u64>,
+    outpoints: Vec<OutPoint>,
+}
 
-    // Record for batch write to utxo table
-    utxo_entries: HashMap<OutPoint, UtxoEntryDB>,
+// Number of block commitments kept, to go back to on an orphan block
+const RECENT_COMMITMENTS: usize = 100;
 
-    // Process inputs - remove from utxo
-    utxo_deletes: Vec<OutPoint>,
+// provides access to utxo state and wraps interface to utxo table.
+//
+// Recently used outputs are held in a bounded cache and the rest are read from storage.
+// Changes are held until the database writer has written them, so that storage is
+// only read for outputs that it is up to date for.
+pub struct Utxo {
+    // Recently used outputs, as they are in storage
+    cache: LruCache<OutPoint, UtxoEntry>,
+    // Changes since the last flush, None for a spent output
+    changes: HashMap<OutPoint, Option<UtxoEntry>>,
+    // Changes flushed but not yet written, with the id of the flush that writes them
+    writing: HashMap<OutPoint, (u64, Option<UtxoEntry>)>,
+    // Flushes not yet written, oldest first
+    flushes: VecDeque<Flush>,
+    last_flush: u64,
+    // Persistent store
+    storage: Box<dyn Storage>,
+    // Hash of the outputs created and not spent in the blocks processed,
+    // mempool txs do not change it
+    set_hash: MuHash,
+    // The commitments after the most recent blocks, newest last
+    recent_commitments: VecDeque<Hash256>,
 
     // Channel to database
-    tx: mpsc::Sender<DBOperationType>,
+    tx: DBSender,
+    metrics: Arc<UtxoCacheMetrics>,
 }
 
 impl Utxo {
-    fn send_db_op(&self, op: DBOperationType) {
-        if self.tx.send(op).is_err() {
-            log::error!("Failed to send utxo database operation; channel closed");
-        }
-    }
-
-    fn decode_stored_hash(value: &str) -> Option<Hash256> {
-        match Hash256::decode(value) {
-            Ok(hash) => Some(hash),
+    fn send_db_op(&self, op: DBOperationType) -> Option<u64> {
+        match self.tx.send(op) {
+            Ok(sent) => Some(sent),
             Err(err) => {
-                log::error!("Invalid stored utxo hash {value}: {err:?}");
+                log::error!("Failed to send utxo database operation: {err}");
                 None
             }
         }
     }
 
-    pub fn new(conn: PooledConn, tx: mpsc::Sender<DBOperationType>) -> Self {
+    pub fn new(
+        storage: Box<dyn Storage>,
+        tx: DBSender,
+        cache_size: usize,
+        metrics: Arc<UtxoCacheMetrics>,
+    ) -> Self {
         Utxo {
-            utxo: HashMap::new(),
-            conn,
-            utxo_entries: HashMap::new(),
-            utxo_deletes: Vec::new(),
+            cache: LruCache::new(NonZeroUsize::new(cache_size).unwrap_or(NonZeroUsize::MIN)),
+            changes: HashMap::new(),
+            writing: HashMap::new(),
+            flushes: VecDeque::new(),
+            last_flush: 0,
+            storage,
+            set_hash: MuHash::new(),
+            recent_commitments: VecDeque::new(),
             tx,
+            metrics,
         }
     }
 
-    pub fn create_table(&mut self) {
-        // Create Utxo table
-        // utxo
-        log::info!("Table utxo not found - creating");
-        if let Err(err) = self.conn.query_drop(
-            r"CREATE TABLE utxo (
-                hash varchar(64) not null,
-                pos int unsigned not null,
-                satoshis bigint unsigned not null,
-                height int not null,
-                pubkeyhash varchar(64),
-                CONSTRAINT PK_Entry PRIMARY KEY (hash, pos));",
-        ) {
-            log::error!("Unable to create utxo table: {err:?}");
-            return;
-        }
-
-        if let Err(err) = self
-            .conn
-            .query_drop(r"CREATE INDEX IF NOT EXISTS speed_key ON utxo (pubkeyhash);")
-        {
-            log::error!("Unable to create utxo pubkeyhash index: {err:?}");
-        }
-
-        if let Err(err) = self
-            .conn
-            .query_drop(r"CREATE INDEX IF NOT EXISTS idx_utxo_height ON utxo (height);")
-        {
-            log::error!("Unable to create utxo height index: {err:?}");
-        }
+    fn record_size(&self) {
+        self.metrics
+            .record_size(self.cache.len(), self.changes.len() + self.writing.len());
     }
 
-    pub fn load_utxo(&mut self) {
-        // load outpoints from database
-        let start = Instant::now();
-
-        let txs: Vec<UtxoEntryDB> = match self.conn.query_map(
-            "SELECT * FROM utxo",
-            |(hash, pos, satoshis, height, pubkeyhash)| UtxoEntryDB {
-                hash,
-                pos,
-                satoshis,
-                height,
-                pubkeyhash,
-            },
-        ) {
-            Ok(txs) => txs,
-            Err(err) => {
-                log::error!("Unable to load utxo from database: {err:?}");
-                return;
+    fn insert_cache(&mut self, outpoint: OutPoint, entry: UtxoEntry) {
+        if let Some((evicted, _entry)) = self.cache.push(outpoint.clone(), entry) {
+            if evicted != outpoint {
+                self.metrics.evicted();
             }
-        };
-
-        // Load entries into utxo struct
-        for entry in txs {
-            let Some(hash) = Self::decode_stored_hash(&entry.hash) else {
-                continue;
-            };
+        }
+    }
 
-            let outpoint = OutPoint {
-                hash,
-                index: entry.pos,
-            };
-            let utxo_entry = UtxoEntry {
-                satoshis: entry.satoshis,
-                height: entry.height,
-                pubkeyhash: entry.pubkeyhash,
+    // Move the changes that the database writer has written into the cache
+    fn release_written(&mut self) {
+        let written = self.tx.written();
+        while self
+            .flushes
+            .front()
+            .is_some_and(|flush| flush.sent.is_some_and(|sent| sent <= written))
+        {
+            let Some(flush) = self.flushes.pop_front() else {
+                break;
             };
-            // add to list
-            self.utxo.insert(outpoint, utxo_entry);
+            for outpoint in flush.outpoints {
+                // Unless it was changed again by a later flush
+                match self.writing.get(&outpoint) {
+                    Some((id, _change)) if *id == flush.id => {}
+                    _ => continue,
+                }
+                if let Some((_id, Some(entry))) = self.writing.remove(&outpoint) {
+                    self.insert_cache(outpoint, entry);
+                }
+            }
         }
-
-        // How long did it take
-        log::info!(
-            "UTXO {} Loaded in {} seconds",
-            self.utxo.len(),
-            start.elapsed().as_millis() as f64 / 1000.0
-        );
+        self.record_size();
     }
 
     pub fn add(
@@ -162,7 +148,7 @@ impl Utxo {
             }
         };
 
-        // add a utxo outpoint, prepare a record to be written to database
+        // add a utxo outpoint, to be written to the database with the next flush
         let outpoint = OutPoint {
             hash,
             index: index_u32,
@@ -174,56 +160,323 @@ impl Utxo {
             height,
             pubkeyhash: pubkeyhash.to_string(),
         };
-        // add to utxo list
-        self.utxo.insert(outpoint.clone(), new_entry);
+        if height >= 0 {
+            self.set_hash.insert(&outpoint);
+        }
+        self.cache.pop(&outpoint);
+        self.changes.insert(outpoint, Some(new_entry));
+    }
 
-        // Record for batch write to utxo table
-        let utxo_entry = UtxoEntryDB {
-            hash: hash.encode(),
-            pos: index_u32,
-            satoshis,
-            height,
-            pubkeyhash: pubkeyhash.to_string(),
-        };
-        self.utxo_entries.insert(outpoint, utxo_entry);
+    // Spend an output, height is that of the spending tx (NOT_IN_BLOCK -1 for the mempool)
+    pub fn delete(&mut self, outpoint: &OutPoint, height: i32) {
+        if height >= 0 {
+            self.set_hash.remove(outpoint);
+        }
+        // Outputs from before startup are only in storage, so the delete is always written
+        self.cache.pop(outpoint);
+        self.changes.insert(outpoint.clone(), None);
     }
 
-    pub fn delete(&mut self, outpoint: &OutPoint) {
-        // Remove from utxo
-        if self.utxo.remove(outpoint).is_some() {
-            // Remove from utxo table
-            self.utxo_deletes.push(outpoint.clone());
-            // also remove from utxo entries if present
-            self.utxo_entries.remove(outpoint);
+    // The utxo commitment after the block just processed, stored with its header
+    pub fn block_commitment(&mut self) -> Hash256 {
+        let commitment = self.set_hash.finalize();
+        if self.recent_commitments.len() == RECENT_COMMITMENTS {
+            self.recent_commitments.pop_front();
         }
+        self.recent_commitments.push_back(commitment);
+        commitment
     }
 
-    pub fn get_satoshis(&self, outpoint: &OutPoint) -> Option<i64> {
+    // Continue from the commitment stored with the block at this height, called at startup
+    pub fn load_commitment(&mut self, height: u32) {
+        self.recent_commitments.clear();
+        match self.storage.utxo_commitment(height) {
+            Ok(Some((_hash, commitment))) => {
+                self.set_hash = MuHash::from_commitment(&commitment);
+                self.recent_commitments.push_back(commitment);
+            }
+            Ok(None) => {
+                log::warn!(
+                    "No utxo commitment stored for block {height}, commitments start from the next block"
+                );
+                self.set_hash = MuHash::new();
+            }
+            Err(err) => {
+                log::error!("Unable to read utxo commitment for block {height}: {err}");
+                self.set_hash = MuHash::new();
+            }
+        }
+    }
+
+    pub fn get_satoshis(&mut self, outpoint: &OutPoint) -> Option<i64> {
         // Return the satoshis associated with this outpoint
-        self.utxo.get(outpoint).map(|v| v.satoshis)
+        self.release_written();
+        let change = self
+            .changes
+            .get(outpoint)
+            .or_else(|| self.writing.get(outpoint).map(|(_id, change)| change));
+        if let Some(change) = change {
+            self.metrics.hit();
+            return change.as_ref().map(|entry| entry.satoshis);
+        }
+        if let Some(entry) = self.cache.get(outpoint) {
+            self.metrics.hit();
+            return Some(entry.satoshis);
+        }
+
+        self.metrics.miss();
+        match self.storage.get_utxo(outpoint) {
+            Ok(Some(entry)) => {
+                let satoshis = entry.satoshis;
+                self.insert_cache(
+                    outpoint.clone(),
+                    UtxoEntry {
+                        satoshis,
+                        height: entry.height,
+                        pubkeyhash: entry.pubkeyhash,
+                    },
+                );
+                Some(satoshis)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                log::error!("Unable to read utxo {outpoint:?} from database: {err}");
+                None
+            }
+        }
+    }
+
+    // The pending writes and deletes, for the block or batch being written.
+    // The caller sends them to the database writer and then calls writes_sent
+    pub fn take_writes(&mut self) -> (Vec<UtxoEntryDB>, Vec<OutPoint>) {
+        self.release_written();
+        let mut writes = Vec::new();
+        let mut deletes = Vec::new();
+        if self.changes.is_empty() {
+            return (writes, deletes);
+        }
+
+        self.last_flush += 1;
+        let id = self.last_flush;
+        let mut outpoints = Vec::with_capacity(self.changes.len());
+        for (outpoint, change) in mem::take(&mut self.changes) {
+            match &change {
+                Some(entry) => writes.push(UtxoEntryDB {
+                    hash: outpoint.hash.encode(),
+                    pos: outpoint.index,
+                    satoshis: entry.satoshis,
+                    height: entry.height,
+                    pubkeyhash: entry.pubkeyhash.clone(),
+                }),
+                None => deletes.push(outpoint.clone()),
+            }
+            outpoints.push(outpoint.clone());
+            self.writing.insert(outpoint, (id, change));
+        }
+        self.flushes.push_back(Flush {
+            id,
+            sent: None,
+            outpoints,
+        });
+        self.record_size();
+        (writes, deletes)
+    }
+
+    // Record the database operation that writes the changes returned by take_writes
+    pub fn writes_sent(&mut self, sent: u64) {
+        for flush in self.flushes.iter_mut().filter(|flush| flush.sent.is_none()) {
+            flush.sent = Some(sent);
+        }
     }
 
     pub fn update_db(&mut self) {
+        let (writes, deletes) = self.take_writes();
+        if writes.is_empty() && deletes.is_empty() {
+            return;
+        }
         // bulk/batch write tx output to utxo table
-        let request: Vec<UtxoEntryDB> = self.utxo_entries.clone().into_values().collect();
-        self.send_db_op(DBOperationType::UtxoBatchWrite(request));
-        self.utxo_entries.clear();
-
+        let written = self.send_db_op(DBOperationType::UtxoBatchWrite(writes));
         // bulk/batch delete utxo table entries
-        self.send_db_op(DBOperationType::UtxoBatchDelete(self.utxo_deletes.clone()));
-        self.utxo_deletes.clear();
+        let deleted = self.send_db_op(DBOperationType::UtxoBatchDelete(deletes));
+        if let (Some(_written), Some(sent)) = (written, deleted) {
+            self.writes_sent(sent);
+        }
     }
 
     pub fn handle_orphan_block(&mut self, height: u32) {
+        // Go back to the commitment of the block below
+        self.recent_commitments.pop_back();
+        match self.recent_commitments.back() {
+            Some(commitment) => self.set_hash = MuHash::from_commitment(commitment),
+            None => log::warn!("No utxo commitment held for the block below the orphan"),
+        }
+
         // Remove utxo of this block height
-        self.send_db_op(DBOperationType::UtxoDelete(height));
+        let sent = self.send_db_op(DBOperationType::UtxoDelete(height));
 
         let Ok(height_as_i32) = i32::try_from(height) else {
             log::error!("Block height {height} out of range while pruning utxo set");
             return;
         };
-        // Remove transactions at this height
-        self.utxo
-            .retain(|_outpoint, entry| entry.height != height_as_i32);
+        let at_height =
+            |change: &Option<UtxoEntry>| change.as_ref().is_some_and(|e| e.height == height_as_i32);
+
+        // Remove outputs at this height, those still being written are removed by the delete
+        self.changes.retain(|_outpoint, change| !at_height(change));
+        self.last_flush += 1;
+        let id = self.last_flush;
+        let mut outpoints = Vec::new();
+        for (outpoint, (flush, change)) in self.writing.iter_mut() {
+            if at_height(change) {
+                *flush = id;
+                *change = None;
+                outpoints.push(outpoint.clone());
+            }
+        }
+        self.flushes.push_back(Flush {
+            id,
+            sent,
+            outpoints,
+        });
+        let cached: Vec<OutPoint> = self
+            .cache
+            .iter()
+            .filter(|(_outpoint, entry)| entry.height == height_as_i32)
+            .map(|(outpoint, _entry)| outpoint.clone())
+            .collect();
+        for outpoint in cached {
+            self.cache.pop(&outpoint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    use crate::{
+        config::{DatabaseConfig, StorageType, WriteErrorPolicy},
+        uaas::{
+            database::{write_queue, Database},
+            metrics::DatabaseMetrics,
+            storage::{MemoryBackend, StorageBackend},
+        },
+    };
+
+    fn config() -> DatabaseConfig {
+        DatabaseConfig {
+            backend: StorageType::Sql,
+            mysql_url: String::new(),
+            mysql_url_docker: String::new(),
+            embedded_path: String::new(),
+            ms_delay: 10,
+            retries: 1,
+            startup_repair: true,
+            write_queue_size: 100,
+            on_write_error: WriteErrorPolicy::Retry,
+            utxo_cache_size: 2,
+        }
+    }
+
+    fn outpoint(value: u8) -> OutPoint {
+        OutPoint {
+            hash: Hash256([value; 32]),
+            index: 0,
+        }
+    }
+
+    fn wait_until_written(utxo: &Utxo, sent: u64) {
+        for _ in 0..500 {
+            if utxo.tx.written() >= sent {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("database writer did not write operation {sent}");
+    }
+
+    #[test]
+    fn cache_is_bounded_and_unwritten_changes_are_kept() {
+        let backend = MemoryBackend::new();
+        let config = config();
+        let db_metrics = Arc::new(DatabaseMetrics::new(config.write_queue_size));
+        let (tx, rx) = write_queue(config.write_queue_size, db_metrics.clone());
+        let mut database = Database::new(backend.open("db").unwrap(), rx, &config, db_metrics);
+        let metrics = Arc::new(UtxoCacheMetrics::new(config.utxo_cache_size));
+        let mut utxo = Utxo::new(
+            backend.open("utxo").unwrap(),
+            tx,
+            config.utxo_cache_size,
+            metrics.clone(),
+        );
+
+        for value in 1..=4 {
+            utxo.add(Hash256([value; 32]), 0, i64::from(value) * 100, 10, "");
+        }
+        utxo.update_db();
+
+        // Not yet written, so the changes are held rather than cached
+        assert_eq!(utxo.get_satoshis(&outpoint(1)), Some(100));
+        assert_eq!(metrics.snapshot().unflushed, 4);
+        assert!(backend.data().utxo.is_empty());
+
+        thread::spawn(move || database.perform_db_operations());
+        wait_until_written(&utxo, 2);
+        assert_eq!(utxo.get_satoshis(&outpoint(9)), None);
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.entries, snapshot.unflushed), (2, 0));
+        assert_eq!((snapshot.evictions, snapshot.misses), (2, 1));
+
+        // Evicted entries are read from storage
+        for value in 1..=4 {
+            assert_eq!(
+                utxo.get_satoshis(&outpoint(value)),
+                Some(i64::from(value) * 100)
+            );
+        }
+        assert!(metrics.snapshot().misses >= 3);
+
+        // A spent output is not read from storage before the delete is written
+        utxo.delete(&outpoint(2), 11);
+        utxo.update_db();
+        assert_eq!(utxo.get_satoshis(&outpoint(2)), None);
+        wait_until_written(&utxo, 4);
+        assert_eq!(utxo.get_satoshis(&outpoint(2)), None);
+        assert_eq!(backend.data().utxo.len(), 3);
+    }
+
+    #[test]
+    fn block_commitment_ignores_mempool_and_rolls_back_on_orphan() {
+        let backend = MemoryBackend::new();
+        let config = config();
+        let db_metrics = Arc::new(DatabaseMetrics::new(config.write_queue_size));
+        let (tx, _rx) = write_queue(config.write_queue_size, db_metrics);
+        let metrics = Arc::new(UtxoCacheMetrics::new(config.utxo_cache_size));
+        let mut utxo = Utxo::new(backend.open("utxo").unwrap(), tx, 10, metrics);
+
+        utxo.add(Hash256([1; 32]), 0, 100, 10, "");
+        utxo.add(Hash256([2; 32]), 0, 100, 10, "");
+        let first = utxo.block_commitment();
+
+        // A mempool tx spends one output and creates another
+        utxo.delete(&outpoint(1), -1);
+        utxo.add(Hash256([3; 32]), 0, 100, -1, "");
+        // Then the same tx is mined
+        utxo.delete(&outpoint(1), 11);
+        utxo.add(Hash256([3; 32]), 0, 100, 11, "");
+        let second = utxo.block_commitment();
+        assert_ne!(first, second);
+
+        let mut expected = MuHash::new();
+        expected.insert(&outpoint(2));
+        expected.insert(&outpoint(3));
+        assert_eq!(second, expected.finalize());
+
+        utxo.handle_orphan_block(11);
+        utxo.add(Hash256([4; 32]), 0, 100, 11, "");
+        let mut expected = MuHash::from_commitment(&first);
+        expected.insert(&outpoint(4));
+        assert_eq!(utxo.block_commitment(), expected.finalize());
     }
 }